@@ -0,0 +1,234 @@
+//! Blocking client for `embedded_io` transports, e.g. sockets on an RTOS thread.
+//!
+//! Configure a read timeout on the transport (shorter than half the keep-alive
+//! interval): a read failing with [`embedded_io::ErrorKind::TimedOut`] makes
+//! [`Client::poll_io`] return `Ok(None)`, so the loop gets to run the timers.
+
+use embedded_io::{Read, Write};
+use embedded_time::duration;
+
+use crate::{
+    connection::Connection,
+    packet::{connect, publish, subscribe},
+    session,
+};
+
+pub struct Client<
+    'c,
+    C,
+    T,
+    const N_PUB_IN: usize,
+    const N_PUB_OUT: usize,
+    const N_SUB: usize,
+    const OUT_QUEUE_SIZE: usize,
+    const MAX_FILTER_LEN: usize = 64,
+> where
+    T: Read + Write,
+    C: embedded_time::Clock,
+{
+    clock: C,
+    transport: T,
+    connection: Connection<'c, C, N_PUB_IN, N_PUB_OUT, N_SUB, OUT_QUEUE_SIZE, MAX_FILTER_LEN>,
+}
+
+impl<
+    'c,
+    C,
+    T,
+    const N_PUB_IN: usize,
+    const N_PUB_OUT: usize,
+    const N_SUB: usize,
+    const OUT_Q: usize,
+    const MAX_FILTER_LEN: usize,
+> Client<'c, C, T, N_PUB_IN, N_PUB_OUT, N_SUB, OUT_Q, MAX_FILTER_LEN>
+where
+    T: Read + Write,
+    C: embedded_time::Clock,
+{
+    pub fn try_new(
+        clock: C,
+        keep_alive: duration::Generic<C::T>,
+        transport: T,
+        rx_buf: &'c mut [u8],
+        tx_buf: &'c mut [u8],
+    ) -> Result<Self, crate::Error> {
+        let now = clock.try_now().map_err(|_| crate::Error::TimeError)?;
+
+        Ok(Self {
+            clock,
+            transport,
+            connection: Connection::new(now, keep_alive, rx_buf, tx_buf),
+        })
+    }
+
+    /// The connection, for configuring the session, e.g.
+    /// [`Connection::set_offline_queue`], or [acknowledging](Connection::ack)
+    /// messages in manual acknowledgement mode.
+    pub fn connection_mut(
+        &mut self,
+    ) -> &mut Connection<'c, C, N_PUB_IN, N_PUB_OUT, N_SUB, OUT_Q, MAX_FILTER_LEN> {
+        &mut self.connection
+    }
+
+    pub fn schedule_connect<'a>(&mut self, opts: connect::Options<'a>) -> Result<(), crate::Error> {
+        self.connection.schedule_connect(opts)
+    }
+
+    pub fn schedule_disconnect(&mut self) -> Result<(), crate::Error> {
+        self.connection.schedule_disconnect()
+    }
+
+    pub fn schedule_publish<'a>(&mut self, msg: publish::Msg<'a>) -> Result<(), crate::Error> {
+        self.connection.schedule_publish(msg)
+    }
+
+    pub fn schedule_subscribe<'a>(
+        &mut self,
+        msg: subscribe::Options<'a>,
+    ) -> Result<(), crate::Error> {
+        self.connection.schedule_subscribe(msg)
+    }
+
+    pub fn schedule_unsubscribe(&mut self, topic: &str) -> Result<(), crate::Error> {
+        self.connection.schedule_unsubscribe(topic)
+    }
+
+    /// High-level poll. Runs timers, then performs one I/O step.
+    pub fn poll(&mut self) -> Result<Option<session::Event<'_>>, crate::Error<T::Error>> {
+        if let Some(event) = self.poll_timers().map_err(crate::Error::widen)? {
+            return Ok(Some(event));
+        }
+        self.poll_io()
+    }
+
+    /// Timer-only step. Enqueues PINGREQ/DISCONNECT when needed, and reports
+    /// a request the broker didn't answer in time.
+    pub fn poll_timers(&mut self) -> Result<Option<session::Event<'static>>, crate::Error> {
+        let now = self.clock.try_now().map_err(|_| crate::Error::TimeError)?;
+
+        self.connection.handle_timeout(now)
+    }
+
+    /// I/O step. Writes what the transport takes of the next queued packet if
+    /// any; otherwise reads and processes one incoming packet.
+    /// Returns `Ok(None)` when the read timed out.
+    pub fn poll_io(&mut self) -> Result<Option<session::Event<'_>>, crate::Error<T::Error>> {
+        let now = self.clock.try_now().map_err(|_| crate::Error::TimeError)?;
+        self.connection.set_now(now);
+
+        if let Some(bytes) = self.connection.peek_outgoing() {
+            let n = self.transport.write(bytes).map_err(|err| {
+                let err = crate::TransportError(err).into();
+                self.connection.handle_error(err)
+            })?;
+            self.connection.commit_outgoing(n);
+
+            return Ok(None);
+        }
+
+        self.connection.read_blocking_from(&mut self.transport)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_io::{ErrorKind, ErrorType};
+    use embedded_time::rate::Fraction;
+    use heapless::Vec;
+
+    use super::*;
+    use crate::testing::{self, MockClock};
+
+    /// Returns the scripted chunks one per read, then times out.
+    struct Socket<'a> {
+        reads: &'a [&'a [u8]],
+        written: Vec<u8, 64>,
+    }
+
+    impl ErrorType for Socket<'_> {
+        type Error = ErrorKind;
+    }
+
+    impl Read for Socket<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let Some((chunk, rest)) = self.reads.split_first() else {
+                return Err(ErrorKind::TimedOut);
+            };
+            self.reads = rest;
+            buf[..chunk.len()].copy_from_slice(chunk);
+
+            Ok(chunk.len())
+        }
+    }
+
+    impl Write for Socket<'_> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.written
+                .extend_from_slice(buf)
+                .map_err(|_| ErrorKind::OutOfMemory)?;
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn connect_across_read_timeouts() {
+        // CONNACK split over two reads
+        let socket = Socket {
+            reads: &[&[0x20, 0x02], &[0x00, 0x00]],
+            written: Vec::new(),
+        };
+        let clock = MockClock::new();
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let keep_alive = duration::Generic::new(10, Fraction::new(1, 1));
+        let mut client: Client<'_, _, _, 1, 1, 1, 2> =
+            Client::try_new(&clock, keep_alive, socket, &mut rx_buf, &mut tx_buf).unwrap();
+
+        client
+            .schedule_connect(testing::connect_options("c"))
+            .unwrap();
+
+        assert!(client.poll().unwrap().is_none());
+        assert_eq!(client.transport.written[0], 0x10);
+
+        assert!(matches!(
+            client.poll().unwrap(),
+            Some(session::Event::Connected)
+        ));
+        assert!(client.poll().unwrap().is_none());
+    }
+
+    #[test]
+    fn partial_packet_survives_timeout() {
+        let socket = Socket {
+            reads: &[&[0x20]],
+            written: Vec::new(),
+        };
+        let clock = MockClock::new();
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let keep_alive = duration::Generic::new(0, Fraction::new(1, 1));
+        let mut client: Client<'_, _, _, 1, 1, 1, 2> =
+            Client::try_new(&clock, keep_alive, socket, &mut rx_buf, &mut tx_buf).unwrap();
+
+        client
+            .schedule_connect(connect::Options {
+                keep_alive: 0,
+                ..testing::connect_options("c")
+            })
+            .unwrap();
+        assert!(client.poll_io().unwrap().is_none());
+
+        // First byte of CONNACK, then the read times out
+        assert!(client.poll_io().unwrap().is_none());
+
+        client.transport.reads = &[&[0x02, 0x01, 0x00]];
+        assert!(matches!(
+            client.poll_io().unwrap(),
+            Some(session::Event::Connected)
+        ));
+    }
+}
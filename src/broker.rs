@@ -0,0 +1,916 @@
+//! Minimal MQTT 3.1.1 broker for a handful of local clients, e.g. sensor nodes behind a gateway.
+//!
+//! Accept connections the way the platform does and hand each one to
+//! [`Broker::add_client`] with its own buffers, then keep calling
+//! [`Broker::poll`]. Messages are forwarded at QoS 0 and 1, retained messages
+//! are replayed to new subscriptions and wills are published when a client
+//! goes away without DISCONNECT.
+//!
+//! Sessions aren't persisted: every connection starts clean, QoS 2 publishes
+//! are refused and a message that doesn't fit a subscriber's `tx_buf` is
+//! dropped for that subscriber. Keep-alive isn't enforced either, so let the
+//! transport time out dead connections.
+//!
+//! [`Broker::poll`] is cancellation-safe as long as the transports' `read` and
+//! `write` are, so it can be raced against accepting new connections.
+
+mod retained;
+mod slab;
+mod trie;
+
+use core::{future::poll_fn, pin::Pin, task::Poll};
+
+use embedded_io_async::{Read, Write};
+use heapless::Vec;
+
+use crate::{
+    ProtocolError, ProtocolErrorKind, buffer,
+    outbox::Outbox,
+    packet::{
+        Packet, PacketId, QoS,
+        connect::{ConnAck, Connect, ConnectReturnCode},
+        publish::{Flags, Publish},
+        subscribe::{SubAck, SubAckReturnCode, Subscribe},
+        unsubscribe::Unsubscribe,
+    },
+    parser::Decoder,
+    protocol::PacketType,
+    topic::{filter_matches, is_valid_topic},
+};
+
+use self::{retained::Retained, slab::Slab, trie::Trie};
+
+/// Slot of a client in the broker's client table.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ClientId(usize);
+
+impl ClientId {
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// The client sent CONNECT and was accepted.
+    Connected(ClientId),
+    /// The client's slot is free again and its transport was dropped.
+    Disconnected(ClientId),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Free,
+    AwaitingConnect,
+    Connected,
+    /// Sends what's queued, then disconnects.
+    Closing,
+}
+
+#[derive(Clone, Copy)]
+struct Will {
+    topic_len: usize,
+    qos: QoS,
+    retain: bool,
+}
+
+/// Session data stored as client id, will topic and will payload back to back.
+#[derive(Clone, Copy)]
+struct Slot {
+    state: State,
+    id_len: usize,
+    will: Option<Will>,
+    next_packet_id: u16,
+}
+
+impl Slot {
+    const FREE: Self = Self {
+        state: State::Free,
+        id_len: 0,
+        will: None,
+        next_packet_id: 1,
+    };
+
+    fn next_packet_id(&mut self) -> PacketId {
+        let id = PacketId(self.next_packet_id);
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        id
+    }
+}
+
+/// `N_SUBS` subscriptions of all clients fit the broker, and a single SUBSCRIBE
+/// or UNSUBSCRIBE may carry up to `N_FILTERS` topic filters. A client sending
+/// more is disconnected.
+pub struct Broker<
+    'b,
+    T,
+    const N_CLIENTS: usize,
+    const N_NODES: usize,
+    const N_SUBS: usize,
+    const N_RETAINED: usize,
+    const OUT_QUEUE_SIZE: usize,
+    const N_FILTERS: usize = 4,
+> where
+    T: Read + Write,
+{
+    transports: [Option<T>; N_CLIENTS],
+    decoders: [Option<Decoder<'b>>; N_CLIENTS],
+    core: Core<'b, N_CLIENTS, N_NODES, N_SUBS, N_RETAINED, OUT_QUEUE_SIZE, N_FILTERS>,
+    next_write: usize,
+}
+
+impl<
+    'b,
+    T,
+    const N_CLIENTS: usize,
+    const N_NODES: usize,
+    const N_SUBS: usize,
+    const N_RETAINED: usize,
+    const OUT_Q: usize,
+    const N_FILTERS: usize,
+> Broker<'b, T, N_CLIENTS, N_NODES, N_SUBS, N_RETAINED, OUT_Q, N_FILTERS>
+where
+    T: Read + Write,
+{
+    /// `topic_buf` holds the levels of all subscribed topic filters,
+    /// `retained_buf` the retained messages with their topics and
+    /// `session_buf` the client ids and wills of connected clients.
+    pub fn new(
+        topic_buf: &'b mut [u8],
+        retained_buf: &'b mut [u8],
+        session_buf: &'b mut [u8],
+    ) -> Self {
+        Self {
+            transports: [const { None }; N_CLIENTS],
+            decoders: [const { None }; N_CLIENTS],
+            core: Core {
+                slots: [Slot::FREE; N_CLIENTS],
+                outboxes: [const { None }; N_CLIENTS],
+                sessions: Slab::new(session_buf),
+                trie: Trie::new(topic_buf),
+                retained: Retained::new(retained_buf),
+            },
+            next_write: 0,
+        }
+    }
+
+    /// Takes a newly accepted connection. `rx_buf` must fit the largest packet
+    /// the client sends, `tx_buf` the packets queued for it.
+    pub fn add_client(
+        &mut self,
+        transport: T,
+        rx_buf: &'b mut [u8],
+        tx_buf: &'b mut [u8],
+    ) -> Result<ClientId, crate::Error> {
+        let index = self
+            .transports
+            .iter()
+            .position(Option::is_none)
+            .ok_or(crate::Error::VectorIsFull)?;
+
+        self.transports[index] = Some(transport);
+        self.decoders[index] = Some(Decoder::new(rx_buf));
+        self.core.outboxes[index] = Some(Outbox::new(tx_buf));
+        self.core.slots[index] = Slot {
+            state: State::AwaitingConnect,
+            ..Slot::FREE
+        };
+
+        Ok(ClientId(index))
+    }
+
+    /// MQTT client identifier of a connected client.
+    pub fn client_identifier(&self, id: ClientId) -> Option<&str> {
+        match self.core.slots.get(id.0)?.state {
+            State::Connected => self.core.identifier(id.0),
+            _ => None,
+        }
+    }
+
+    /// Processes buffered packets, sends one queued packet or waits for data from any client.
+    /// Returns once a client connected or disconnected.
+    pub async fn poll(&mut self) -> Event {
+        loop {
+            for index in 0..N_CLIENTS {
+                match self.process(index) {
+                    Ok(Some(event)) => return event,
+                    Ok(None) => {}
+                    Err(_) => return self.remove(index),
+                }
+            }
+
+            if let Some(index) = (0..N_CLIENTS).find(|&index| self.core.is_closed(index)) {
+                return self.remove(index);
+            }
+
+            if let Some(index) = self.next_outgoing() {
+                if !self.write(index).await {
+                    return self.remove(index);
+                }
+                continue;
+            }
+
+            match self.read_any().await {
+                (index, Some(n)) => {
+                    if let Some(decoder) = self.decoders[index].as_mut() {
+                        decoder.commit(n);
+                    }
+                }
+                (index, None) => return self.remove(index),
+            }
+        }
+    }
+
+    /// Handles every complete packet buffered for the client, stopping at the first event.
+    fn process(&mut self, index: usize) -> Result<Option<Event>, crate::Error> {
+        let Some(decoder) = self.decoders[index].as_mut() else {
+            return Ok(None);
+        };
+
+        while self.core.slots[index].state != State::Closing && decoder.is_ready()? {
+            let packet = decoder.take()?;
+
+            if let Some(event) = self.core.handle(index, packet)? {
+                return Ok(Some(event));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Next client with queued packets, round robin so that one busy client can't starve the others.
+    fn next_outgoing(&mut self) -> Option<usize> {
+        let index = (0..N_CLIENTS)
+            .map(|offset| (self.next_write + offset) % N_CLIENTS)
+            .find(|&index| {
+                self.transports[index].is_some()
+                    && self.core.outboxes[index]
+                        .as_ref()
+                        .is_some_and(|outbox| !outbox.is_empty())
+            })?;
+        self.next_write = index + 1;
+
+        Some(index)
+    }
+
+    /// Writes what the transport takes of the client's next packet. `false` if the client is gone.
+    async fn write(&mut self, index: usize) -> bool {
+        let (Some(transport), Some(outbox)) = (
+            self.transports[index].as_mut(),
+            self.core.outboxes[index].as_mut(),
+        ) else {
+            return false;
+        };
+        let Some(bytes) = outbox.front() else {
+            return true;
+        };
+
+        match transport.write(bytes).await {
+            Ok(n) if n > 0 => {
+                outbox.advance(n);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Waits until any client has data. Returns the client and the number of
+    /// bytes read, or `None` if its connection is gone.
+    async fn read_any(&mut self) -> (usize, Option<usize>) {
+        let mut reads = Vec::<_, N_CLIENTS>::new();
+
+        for (index, (transport, decoder)) in self
+            .transports
+            .iter_mut()
+            .zip(self.decoders.iter_mut())
+            .enumerate()
+        {
+            let (Some(transport), Some(decoder)) = (transport, decoder) else {
+                continue;
+            };
+            if self.core.slots[index].state == State::Closing {
+                continue;
+            }
+            let Ok(spare) = decoder.spare_mut() else {
+                return (index, None);
+            };
+
+            let _ = reads.push((index, transport.read(spare)));
+        }
+
+        poll_fn(|cx| {
+            for (index, read) in reads.iter_mut() {
+                // SAFETY: `reads` stays in place until it's dropped at the end of this function.
+                let read = unsafe { Pin::new_unchecked(read) };
+
+                if let Poll::Ready(result) = read.poll(cx) {
+                    return Poll::Ready((*index, result.ok().filter(|&n| n > 0)));
+                }
+            }
+
+            Poll::Pending
+        })
+        .await
+    }
+
+    fn remove(&mut self, index: usize) -> Event {
+        self.transports[index] = None;
+        self.decoders[index] = None;
+        self.core.remove(index);
+
+        Event::Disconnected(ClientId(index))
+    }
+}
+
+/// Protocol state of the broker, apart from the transports and decoders so that
+/// a packet borrowed from a decoder can be routed to the other clients.
+struct Core<
+    'b,
+    const N_CLIENTS: usize,
+    const N_NODES: usize,
+    const N_SUBS: usize,
+    const N_RETAINED: usize,
+    const OUT_Q: usize,
+    const N_FILTERS: usize,
+> {
+    slots: [Slot; N_CLIENTS],
+    outboxes: [Option<Outbox<'b, OUT_Q>>; N_CLIENTS],
+    sessions: Slab<'b, N_CLIENTS>,
+    trie: Trie<'b, N_NODES, N_SUBS>,
+    retained: Retained<'b, N_RETAINED>,
+}
+
+impl<
+    const N_CLIENTS: usize,
+    const N_NODES: usize,
+    const N_SUBS: usize,
+    const N_RETAINED: usize,
+    const OUT_Q: usize,
+    const N_FILTERS: usize,
+> Core<'_, N_CLIENTS, N_NODES, N_SUBS, N_RETAINED, OUT_Q, N_FILTERS>
+{
+    /// An error means the client broke the protocol and is disconnected.
+    fn handle(
+        &mut self,
+        index: usize,
+        packet: Packet<'_, N_FILTERS>,
+    ) -> Result<Option<Event>, crate::Error> {
+        let (packet_type, packet_id) = (packet.packet_type(), packet.packet_id());
+
+        match (self.slots[index].state, packet) {
+            (State::AwaitingConnect, Packet::Connect(connect)) => self.on_connect(index, connect),
+            (State::Connected, Packet::Publish(publish)) => {
+                self.on_publish(index, publish)?;
+                Ok(None)
+            }
+            // Deliveries aren't retried, so there's nothing to release.
+            (State::Connected, Packet::PubAck(_)) => Ok(None),
+            (State::Connected, Packet::Subscribe(subscribe)) => {
+                self.on_subscribe(index, subscribe)?;
+                Ok(None)
+            }
+            (State::Connected, Packet::Unsubscribe(unsubscribe)) => {
+                self.on_unsubscribe(index, unsubscribe)?;
+                Ok(None)
+            }
+            (State::Connected, Packet::PingReq) => {
+                self.send(index, Packet::PingResp)?;
+                Ok(None)
+            }
+            (State::Connected, Packet::Disconnect) => {
+                // A clean disconnect discards the will (3.14.4)
+                self.slots[index].will = None;
+                self.slots[index].state = State::Closing;
+                Ok(None)
+            }
+            _ => {
+                Err(
+                    ProtocolError::new(packet_type, packet_id, ProtocolErrorKind::UnexpectedPacket)
+                        .into(),
+                )
+            }
+        }
+    }
+
+    fn on_connect(
+        &mut self,
+        index: usize,
+        connect: Connect<'_>,
+    ) -> Result<Option<Event>, crate::Error> {
+        let client_id = connect.client_id.as_str()?;
+
+        if client_id.is_empty() && !connect.clean_session {
+            return self.refuse(index, ConnectReturnCode::IdentifierRejected);
+        }
+
+        // A second connection with the same client id takes over (3.1.4-2)
+        if !client_id.is_empty() {
+            for other in 0..N_CLIENTS {
+                if other != index
+                    && self.slots[other].state == State::Connected
+                    && self.identifier(other) == Some(client_id)
+                {
+                    self.slots[other].state = State::Closing;
+                }
+            }
+        }
+
+        let mut session: [&[u8]; 3] = [client_id.as_bytes(), &[], &[]];
+        let will = match &connect.will {
+            Some(will) => {
+                let topic = will.topic.as_str()?;
+                if !is_valid_topic(topic) {
+                    return Err(crate::DecodeError::InvalidTopicName.into());
+                }
+                session[1] = topic.as_bytes();
+                session[2] = will.payload.as_bytes();
+
+                Some(Will {
+                    topic_len: topic.len(),
+                    qos: min_qos(will.qos, QoS::AtLeastOnce),
+                    retain: will.retain,
+                })
+            }
+            None => None,
+        };
+
+        if self.sessions.insert(index, &session).is_err() {
+            return self.refuse(index, ConnectReturnCode::ServerUnavailable);
+        }
+
+        self.slots[index] = Slot {
+            state: State::Connected,
+            id_len: client_id.len(),
+            will,
+            ..Slot::FREE
+        };
+        self.send(
+            index,
+            Packet::ConnAck(ConnAck {
+                session_present: false,
+                return_code: ConnectReturnCode::Accepted,
+            }),
+        )?;
+
+        Ok(Some(Event::Connected(ClientId(index))))
+    }
+
+    fn refuse(
+        &mut self,
+        index: usize,
+        return_code: ConnectReturnCode,
+    ) -> Result<Option<Event>, crate::Error> {
+        self.send(
+            index,
+            Packet::ConnAck(ConnAck {
+                session_present: false,
+                return_code,
+            }),
+        )?;
+        self.slots[index].state = State::Closing;
+
+        Ok(None)
+    }
+
+    fn on_publish(&mut self, index: usize, publish: Publish<'_>) -> Result<(), crate::Error> {
+        let topic = publish.topic.as_str()?;
+        let payload = publish.payload.as_bytes();
+        let qos = publish.flags.qos;
+
+        match (qos, publish.packet_id) {
+            (QoS::AtMostOnce, _) => {}
+            (QoS::AtLeastOnce, Some(packet_id)) => self.send(index, Packet::PubAck(packet_id))?,
+            _ => {
+                let kind = ProtocolErrorKind::Unsupported;
+                return Err(
+                    ProtocolError::new(PacketType::Publish, publish.packet_id, kind).into(),
+                );
+            }
+        }
+
+        // A full store only costs new subscribers the retained message
+        if publish.flags.retain {
+            let _ = self.retained.set(topic, payload, qos);
+        }
+
+        route(
+            &self.trie,
+            &mut self.slots,
+            &mut self.outboxes,
+            topic,
+            payload,
+            qos,
+        );
+
+        Ok(())
+    }
+
+    fn on_subscribe(
+        &mut self,
+        index: usize,
+        subscribe: Subscribe<'_, N_FILTERS>,
+    ) -> Result<(), crate::Error> {
+        let mut return_codes = Vec::<_, N_FILTERS>::new();
+
+        for sub in &subscribe.topics {
+            let qos = min_qos(sub.qos, QoS::AtLeastOnce);
+            let code = match self.trie.subscribe(index, sub.topic_filter.as_str()?, qos) {
+                Ok(()) if qos == QoS::AtMostOnce => SubAckReturnCode::SuccessMaxQoS0,
+                Ok(()) => SubAckReturnCode::SuccessMaxQoS1,
+                Err(_) => SubAckReturnCode::Failure,
+            };
+            return_codes
+                .push(code)
+                .map_err(|_| crate::Error::VectorIsFull)?;
+        }
+
+        self.send(
+            index,
+            Packet::SubAck(SubAck {
+                packet_id: subscribe.packet_id,
+                return_codes: return_codes.clone(),
+            }),
+        )?;
+
+        // Retained messages follow the SUBACK (3.3.1.3)
+        for (sub, code) in subscribe.topics.iter().zip(return_codes) {
+            if code == SubAckReturnCode::Failure {
+                continue;
+            }
+            let filter = sub.topic_filter.as_str()?;
+            let granted = min_qos(sub.qos, QoS::AtLeastOnce);
+
+            for (topic, payload, qos) in self.retained.iter() {
+                if filter_matches(filter, topic) {
+                    deliver(
+                        &mut self.slots[index],
+                        &mut self.outboxes[index],
+                        topic,
+                        payload,
+                        min_qos(qos, granted),
+                        true,
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn on_unsubscribe(
+        &mut self,
+        index: usize,
+        unsubscribe: Unsubscribe<'_, N_FILTERS>,
+    ) -> Result<(), crate::Error> {
+        for topic in &unsubscribe.topics {
+            self.trie.unsubscribe(index, topic.as_str()?);
+        }
+
+        self.send(index, Packet::UnsubAck(unsubscribe.packet_id))
+    }
+
+    fn send(&mut self, index: usize, packet: Packet<'_, N_FILTERS>) -> Result<(), crate::Error> {
+        self.outboxes[index]
+            .as_mut()
+            .ok_or(crate::Error::RemoteClosed)?
+            .enqueue(packet)
+    }
+
+    fn identifier(&self, index: usize) -> Option<&str> {
+        let session = self.sessions.get(index)?;
+
+        core::str::from_utf8(&session[..self.slots[index].id_len]).ok()
+    }
+
+    fn is_closed(&self, index: usize) -> bool {
+        self.slots[index].state == State::Closing
+            && self.outboxes[index]
+                .as_ref()
+                .is_none_or(|outbox| outbox.is_empty())
+    }
+
+    /// Frees the client's slot, publishing its will if it has one.
+    fn remove(&mut self, index: usize) {
+        let slot = core::mem::replace(&mut self.slots[index], Slot::FREE);
+        self.outboxes[index] = None;
+        self.trie.unsubscribe_all(index);
+
+        if let Some(will) = slot.will
+            && let Some(session) = self.sessions.get(index)
+        {
+            let (topic, payload) = session[slot.id_len..].split_at(will.topic_len);
+
+            if let Ok(topic) = core::str::from_utf8(topic) {
+                if will.retain {
+                    let _ = self.retained.set(topic, payload, will.qos);
+                }
+
+                route(
+                    &self.trie,
+                    &mut self.slots,
+                    &mut self.outboxes,
+                    topic,
+                    payload,
+                    will.qos,
+                );
+            }
+        }
+
+        self.sessions.remove(index);
+    }
+}
+
+/// Queues a message for every client subscribed to `topic`, once per client at
+/// the highest QoS of its matching subscriptions.
+fn route<const N_CLIENTS: usize, const N_NODES: usize, const N_SUBS: usize, const OUT_Q: usize>(
+    trie: &Trie<'_, N_NODES, N_SUBS>,
+    slots: &mut [Slot; N_CLIENTS],
+    outboxes: &mut [Option<Outbox<'_, OUT_Q>>; N_CLIENTS],
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+) {
+    let mut granted = [None; N_CLIENTS];
+    trie.matches(topic, |client, sub_qos| {
+        let qos = min_qos(qos, sub_qos);
+        granted[client] = Some(granted[client].map_or(qos, |other| max_qos(other, qos)));
+    });
+
+    for (client, qos) in granted.into_iter().enumerate() {
+        if let Some(qos) = qos {
+            deliver(
+                &mut slots[client],
+                &mut outboxes[client],
+                topic,
+                payload,
+                qos,
+                false,
+            );
+        }
+    }
+}
+
+fn deliver<const OUT_Q: usize>(
+    slot: &mut Slot,
+    outbox: &mut Option<Outbox<'_, OUT_Q>>,
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+    retain: bool,
+) {
+    let (State::Connected, Some(outbox)) = (slot.state, outbox) else {
+        return;
+    };
+
+    let packet_id = match qos {
+        QoS::AtMostOnce => None,
+        _ => Some(slot.next_packet_id()),
+    };
+
+    // A subscriber that can't keep up misses the message
+    let _ = outbox.enqueue(Packet::<1>::Publish(Publish {
+        flags: Flags {
+            dup: false,
+            qos,
+            retain,
+        },
+        topic: buffer::String::from(topic),
+        packet_id,
+        payload: buffer::Slice::from(payload),
+    }));
+}
+
+fn min_qos(a: QoS, b: QoS) -> QoS {
+    if (a as u8) <= (b as u8) { a } else { b }
+}
+
+fn max_qos(a: QoS, b: QoS) -> QoS {
+    if (a as u8) >= (b as u8) { a } else { b }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        cell::RefCell,
+        convert::Infallible,
+        pin::pin,
+        task::{Context, Waker},
+    };
+
+    use embedded_io_async::ErrorType;
+    use heapless::Deque;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Pipe {
+        rx: Deque<u8, 128>,
+        tx: Vec<u8, 128>,
+        closed: bool,
+    }
+
+    /// Client end of a connection, as seen by the broker.
+    struct Link<'a>(&'a RefCell<Pipe>);
+
+    impl ErrorType for Link<'_> {
+        type Error = Infallible;
+    }
+
+    impl Read for Link<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            poll_fn(|_| {
+                let mut pipe = self.0.borrow_mut();
+                if pipe.rx.is_empty() && !pipe.closed {
+                    return Poll::Pending;
+                }
+
+                let mut n = 0;
+                while n < buf.len()
+                    && let Some(byte) = pipe.rx.pop_front()
+                {
+                    buf[n] = byte;
+                    n += 1;
+                }
+
+                Poll::Ready(Ok(n))
+            })
+            .await
+        }
+    }
+
+    impl Write for Link<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.0.borrow_mut().tx.extend_from_slice(buf).unwrap();
+            Ok(buf.len())
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    type TestBroker<'a> = Broker<'a, Link<'a>, 3, 8, 8, 2, 4, 2>;
+
+    /// Polls until the broker waits for data, dropping the future each time.
+    fn run(broker: &mut TestBroker<'_>) -> Vec<Event, 8> {
+        let mut events = Vec::new();
+        let mut cx = Context::from_waker(Waker::noop());
+
+        while let Poll::Ready(event) = pin!(broker.poll()).poll(&mut cx) {
+            events.push(event).unwrap();
+        }
+
+        events
+    }
+
+    fn send(pipe: &RefCell<Pipe>, parts: &[&[u8]]) {
+        for byte in parts.iter().flat_map(|part| part.iter()) {
+            pipe.borrow_mut().rx.push_back(*byte).unwrap();
+        }
+    }
+
+    fn received(pipe: &RefCell<Pipe>) -> Vec<u8, 128> {
+        core::mem::take(&mut pipe.borrow_mut().tx)
+    }
+
+    /// CONNECT with keep-alive 60 and a one-character client id.
+    fn connect(pipe: &RefCell<Pipe>, id: &[u8; 1], will: Option<(&[u8; 3], &[u8; 1])>) {
+        match will {
+            Some((topic, payload)) => send(
+                pipe,
+                &[
+                    b"\x10\x15\x00\x04MQTT\x04\x06\x00\x3c\x00\x01",
+                    id,
+                    b"\x00\x03",
+                    topic,
+                    b"\x00\x01",
+                    payload,
+                ],
+            ),
+            None => send(pipe, &[b"\x10\x0d\x00\x04MQTT\x04\x02\x00\x3c\x00\x01", id]),
+        }
+    }
+
+    const CONNACK: &[u8] = b"\x20\x02\x00\x00";
+
+    #[test]
+    fn forwards_publishes_and_retained_messages() {
+        let (a, b, c) = Default::default();
+        let (mut topics, mut retained, mut sessions) = ([0u8; 64], [0u8; 64], [0u8; 64]);
+        let mut bufs = [[0u8; 64]; 6];
+        let [rx_a, tx_a, rx_b, tx_b, rx_c, tx_c] = &mut bufs;
+        let mut broker = TestBroker::new(&mut topics, &mut retained, &mut sessions);
+
+        let id_a = broker.add_client(Link(&a), rx_a, tx_a).unwrap();
+        let id_b = broker.add_client(Link(&b), rx_b, tx_b).unwrap();
+        connect(&a, b"a", None);
+        connect(&b, b"b", None);
+        assert_eq!(
+            run(&mut broker),
+            [Event::Connected(id_a), Event::Connected(id_b)]
+        );
+        assert_eq!(broker.client_identifier(id_b), Some("b"));
+        assert_eq!(received(&a), CONNACK);
+        assert_eq!(received(&b), CONNACK);
+
+        send(&b, &[b"\x82\x0e\x00\x01\x00\x09sensors/+\x01"]);
+        assert_eq!(run(&mut broker), []);
+        assert_eq!(received(&b), b"\x90\x03\x00\x01\x01");
+
+        // Retained QoS 1 publish is acknowledged and forwarded with the subscriber's packet id
+        send(&a, &[b"\x33\x0f\x00\x09sensors/t\x00\x0721"]);
+        assert_eq!(run(&mut broker), []);
+        assert_eq!(received(&a), b"\x40\x02\x00\x07");
+        assert_eq!(received(&b), b"\x32\x0f\x00\x09sensors/t\x00\x0121");
+
+        let id_c = broker.add_client(Link(&c), rx_c, tx_c).unwrap();
+        connect(&c, b"c", None);
+        send(&c, &[b"\x82\x0e\x00\x01\x00\x09sensors/#\x00"]);
+        assert_eq!(run(&mut broker), [Event::Connected(id_c)]);
+        assert_eq!(
+            received(&c),
+            b"\x20\x02\x00\x00\x90\x03\x00\x01\x00\x31\x0d\x00\x09sensors/t21"
+        );
+    }
+
+    #[test]
+    fn subscribes_to_several_filters_at_once() {
+        let a = RefCell::default();
+        let (mut topics, mut retained, mut sessions) = ([0u8; 64], [0u8; 64], [0u8; 64]);
+        let (mut rx, mut tx) = ([0u8; 64], [0u8; 64]);
+        let mut broker = TestBroker::new(&mut topics, &mut retained, &mut sessions);
+
+        let id = broker.add_client(Link(&a), &mut rx, &mut tx).unwrap();
+        connect(&a, b"a", None);
+        send(&a, &[b"\x82\x0c\x00\x01\x00\x03s/#\x02\x00\x01t\x00"]);
+        assert_eq!(run(&mut broker), [Event::Connected(id)]);
+        assert_eq!(received(&a), b"\x20\x02\x00\x00\x90\x04\x00\x01\x01\x00");
+
+        send(&a, &[b"\x30\x04\x00\x01tx"]);
+        assert_eq!(run(&mut broker), []);
+        assert_eq!(received(&a), b"\x30\x04\x00\x01tx");
+
+        send(&a, &[b"\xa2\x0a\x00\x02\x00\x03s/#\x00\x01t"]);
+        send(&a, &[b"\x30\x04\x00\x01tx"]);
+        assert_eq!(run(&mut broker), []);
+        assert_eq!(received(&a), b"\xb0\x02\x00\x02");
+
+        // One filter more than N_FILTERS, though the broker has room for them
+        send(
+            &a,
+            &[b"\x82\x0e\x00\x03\x00\x01a\x00\x00\x01b\x00\x00\x01c\x00"],
+        );
+        assert_eq!(run(&mut broker), [Event::Disconnected(id)]);
+    }
+
+    #[test]
+    fn publishes_will_unless_disconnected_cleanly() {
+        let (a, b, c) = Default::default();
+        let (mut topics, mut retained, mut sessions) = ([0u8; 64], [0u8; 64], [0u8; 64]);
+        let mut bufs = [[0u8; 64]; 6];
+        let [rx_a, tx_a, rx_b, tx_b, rx_c, tx_c] = &mut bufs;
+        let mut broker = TestBroker::new(&mut topics, &mut retained, &mut sessions);
+
+        let id_a = broker.add_client(Link(&a), rx_a, tx_a).unwrap();
+        let id_b = broker.add_client(Link(&b), rx_b, tx_b).unwrap();
+        let id_c = broker.add_client(Link(&c), rx_c, tx_c).unwrap();
+        connect(&a, b"a", Some((b"s/a", b"x")));
+        connect(&b, b"b", None);
+        connect(&c, b"c", Some((b"s/c", b"y")));
+        send(&b, &[b"\x82\x08\x00\x01\x00\x03s/#\x00"]);
+        assert_eq!(
+            run(&mut broker),
+            [
+                Event::Connected(id_a),
+                Event::Connected(id_b),
+                Event::Connected(id_c)
+            ]
+        );
+        received(&b);
+
+        a.borrow_mut().closed = true;
+        assert_eq!(run(&mut broker), [Event::Disconnected(id_a)]);
+        assert_eq!(received(&b), b"\x30\x06\x00\x03s/ax");
+        assert_eq!(broker.client_identifier(id_a), None);
+
+        send(&c, &[b"\xe0\x00"]);
+        assert_eq!(run(&mut broker), [Event::Disconnected(id_c)]);
+        assert_eq!(received(&b), b"");
+    }
+
+    #[test]
+    fn refuses_empty_client_id_without_clean_session() {
+        let a = RefCell::default();
+        let (mut topics, mut retained, mut sessions) = ([0u8; 64], [0u8; 64], [0u8; 64]);
+        let (mut rx, mut tx) = ([0u8; 64], [0u8; 64]);
+        let mut broker = TestBroker::new(&mut topics, &mut retained, &mut sessions);
+
+        let id = broker.add_client(Link(&a), &mut rx, &mut tx).unwrap();
+        send(&a, &[b"\x10\x0c\x00\x04MQTT\x04\x00\x00\x3c\x00\x00"]);
+
+        assert_eq!(run(&mut broker), [Event::Disconnected(id)]);
+        assert_eq!(received(&a), b"\x20\x02\x00\x02");
+    }
+}
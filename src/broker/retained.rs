@@ -0,0 +1,65 @@
+use crate::{broker::slab::Slab, packet::QoS};
+
+#[derive(Clone, Copy)]
+struct Entry {
+    topic_len: usize,
+    qos: QoS,
+}
+
+/// Last retained message per topic, topic and payload stored back to back.
+pub(crate) struct Retained<'b, const N: usize> {
+    entries: [Option<Entry>; N],
+    messages: Slab<'b, N>,
+}
+
+impl<'b, const N: usize> Retained<'b, N> {
+    pub(crate) fn new(buf: &'b mut [u8]) -> Self {
+        Self {
+            entries: [None; N],
+            messages: Slab::new(buf),
+        }
+    }
+
+    /// Replaces the message retained for `topic`; an empty payload just removes it (3.3.1.3).
+    pub(crate) fn set(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+    ) -> Result<(), crate::Error> {
+        let existing =
+            (0..N).find(|&index| matches!(self.get(index), Some((t, _, _)) if t == topic));
+
+        if payload.is_empty() {
+            if let Some(index) = existing {
+                self.entries[index] = None;
+                self.messages.remove(index);
+            }
+            return Ok(());
+        }
+
+        let index = existing
+            .or_else(|| self.entries.iter().position(Option::is_none))
+            .ok_or(crate::Error::VectorIsFull)?;
+
+        self.entries[index] = None;
+        self.messages.insert(index, &[topic.as_bytes(), payload])?;
+        self.entries[index] = Some(Entry {
+            topic_len: topic.len(),
+            qos,
+        });
+
+        Ok(())
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &[u8], QoS)> {
+        (0..N).filter_map(|index| self.get(index))
+    }
+
+    fn get(&self, index: usize) -> Option<(&str, &[u8], QoS)> {
+        let entry = self.entries[index]?;
+        let (topic, payload) = self.messages.get(index)?.split_at(entry.topic_len);
+
+        Some((core::str::from_utf8(topic).ok()?, payload, entry.qos))
+    }
+}
@@ -0,0 +1,87 @@
+use core::ops::Range;
+
+/// Byte arena with `N` keyed entries, for variable-length data in borrowed storage.
+///
+/// Entries are packed from the start of `buf`; removing one moves the ones
+/// behind it down, so the free space is always in one piece.
+pub(crate) struct Slab<'b, const N: usize> {
+    buf: &'b mut [u8],
+    len: usize,
+    entries: [Option<Range<usize>>; N],
+}
+
+impl<'b, const N: usize> Slab<'b, N> {
+    pub(crate) fn new(buf: &'b mut [u8]) -> Self {
+        Self {
+            buf,
+            len: 0,
+            entries: [const { None }; N],
+        }
+    }
+
+    /// Stores the concatenation of `parts` under `key`. The previous entry is
+    /// dropped even if the new one doesn't fit.
+    pub(crate) fn insert(&mut self, key: usize, parts: &[&[u8]]) -> Result<(), crate::Error> {
+        self.remove(key);
+
+        let needed: usize = parts.iter().map(|part| part.len()).sum();
+        if self.len + needed > self.buf.len() {
+            return Err(crate::Error::BufferTooSmall);
+        }
+
+        let start = self.len;
+        for part in parts {
+            self.buf[self.len..self.len + part.len()].copy_from_slice(part);
+            self.len += part.len();
+        }
+        self.entries[key] = Some(start..self.len);
+
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, key: usize) -> Option<&[u8]> {
+        self.entries[key].clone().map(|range| &self.buf[range])
+    }
+
+    pub(crate) fn remove(&mut self, key: usize) {
+        let Some(removed) = self.entries[key].take() else {
+            return;
+        };
+
+        self.buf.copy_within(removed.end..self.len, removed.start);
+        self.len -= removed.len();
+
+        for range in self.entries.iter_mut().flatten() {
+            if range.start >= removed.end {
+                *range = range.start - removed.len()..range.end - removed.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_moves_later_entries_down() {
+        let mut buf = [0u8; 8];
+        let mut slab = Slab::<'_, 3>::new(&mut buf);
+
+        slab.insert(0, &[b"ab"]).unwrap();
+        slab.insert(1, &[b"c", b"de"]).unwrap();
+        slab.insert(2, &[b"fgh"]).unwrap();
+        assert!(matches!(
+            slab.insert(0, &[b"too long"]),
+            Err(crate::Error::BufferTooSmall)
+        ));
+        assert_eq!(slab.get(0), None);
+
+        slab.insert(0, &[b"xy"]).unwrap();
+        slab.remove(1);
+
+        assert_eq!(slab.get(0), Some(&b"xy"[..]));
+        assert_eq!(slab.get(1), None);
+        assert_eq!(slab.get(2), Some(&b"fgh"[..]));
+    }
+}
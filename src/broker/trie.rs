@@ -0,0 +1,244 @@
+use heapless::Vec;
+
+use crate::{broker::slab::Slab, packet::QoS, topic::is_valid_filter};
+
+struct Node {
+    parent: Option<usize>,
+}
+
+struct Subscription {
+    node: usize,
+    client: usize,
+    qos: QoS,
+}
+
+/// Topic filters of all clients, one node per filter level.
+///
+/// Level names live in `names` under the node's index, so filters sharing a
+/// prefix share its storage.
+pub(crate) struct Trie<'b, const N_NODES: usize, const N_SUBS: usize> {
+    nodes: [Option<Node>; N_NODES],
+    names: Slab<'b, N_NODES>,
+    subs: Vec<Subscription, N_SUBS>,
+}
+
+impl<'b, const N_NODES: usize, const N_SUBS: usize> Trie<'b, N_NODES, N_SUBS> {
+    pub(crate) fn new(buf: &'b mut [u8]) -> Self {
+        Self {
+            nodes: [const { None }; N_NODES],
+            names: Slab::new(buf),
+            subs: Vec::new(),
+        }
+    }
+
+    /// Adds `client`'s subscription to `filter`, or updates its QoS.
+    pub(crate) fn subscribe(
+        &mut self,
+        client: usize,
+        filter: &str,
+        qos: QoS,
+    ) -> Result<(), crate::Error> {
+        if !is_valid_filter(filter) {
+            return Err(crate::DecodeError::MalformedPacket.into());
+        }
+
+        let mut parent = None;
+        for level in filter.split('/') {
+            let node = match self.child(parent, level) {
+                Some(node) => node,
+                None => self
+                    .add_node(parent, level)
+                    .inspect_err(|_| self.prune(parent))?,
+            };
+            parent = Some(node);
+        }
+        let Some(node) = parent else {
+            return Err(crate::DecodeError::MalformedPacket.into());
+        };
+
+        if let Some(sub) = self
+            .subs
+            .iter_mut()
+            .find(|sub| sub.node == node && sub.client == client)
+        {
+            sub.qos = qos;
+            return Ok(());
+        }
+
+        self.subs
+            .push(Subscription { node, client, qos })
+            .map_err(|_| {
+                self.prune(Some(node));
+                crate::Error::SubVectorIsFull
+            })
+    }
+
+    /// Removes `client`'s subscription to `filter`. Returns whether there was one.
+    pub(crate) fn unsubscribe(&mut self, client: usize, filter: &str) -> bool {
+        let mut node = None;
+        for level in filter.split('/') {
+            match self.child(node, level) {
+                Some(child) => node = Some(child),
+                None => return false,
+            }
+        }
+
+        let Some(pos) = self
+            .subs
+            .iter()
+            .position(|sub| Some(sub.node) == node && sub.client == client)
+        else {
+            return false;
+        };
+
+        self.subs.swap_remove(pos);
+        self.prune(node);
+
+        true
+    }
+
+    pub(crate) fn unsubscribe_all(&mut self, client: usize) {
+        while let Some(pos) = self.subs.iter().position(|sub| sub.client == client) {
+            let sub = self.subs.swap_remove(pos);
+            self.prune(Some(sub.node));
+        }
+    }
+
+    /// Calls `on_match` with every client and granted QoS whose filter matches `topic`.
+    /// A client with several matching filters is reported once per filter.
+    pub(crate) fn matches(&self, topic: &str, mut on_match: impl FnMut(usize, QoS)) {
+        let levels = topic.split('/').count();
+        // Wildcards at the first level don't match topics starting with '$' (4.7.2)
+        let system = topic.starts_with('$');
+
+        // Every node is visited from its parent only, so it's pushed at most once.
+        let mut pending = Vec::<(usize, usize), N_NODES>::new();
+        let mut visit = |parent: Option<usize>, depth: usize, pending: &mut Vec<_, N_NODES>| {
+            let level = topic.split('/').nth(depth);
+            let wildcards = depth > 0 || !system;
+
+            for (node, name) in self.children(parent) {
+                if name == b"#" && wildcards {
+                    self.subscribers(node, &mut on_match);
+                } else if let Some(level) = level
+                    && (name == level.as_bytes() || (name == b"+" && wildcards))
+                {
+                    if depth + 1 == levels {
+                        self.subscribers(node, &mut on_match);
+                    }
+                    let _ = pending.push((node, depth + 1));
+                }
+            }
+        };
+
+        visit(None, 0, &mut pending);
+        while let Some((node, depth)) = pending.pop() {
+            visit(Some(node), depth, &mut pending);
+        }
+    }
+
+    fn subscribers(&self, node: usize, on_match: &mut impl FnMut(usize, QoS)) {
+        for sub in self.subs.iter().filter(|sub| sub.node == node) {
+            on_match(sub.client, sub.qos);
+        }
+    }
+
+    fn children(&self, parent: Option<usize>) -> impl Iterator<Item = (usize, &[u8])> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(move |(_, node)| matches!(node, Some(node) if node.parent == parent))
+            .filter_map(|(index, _)| Some((index, self.names.get(index)?)))
+    }
+
+    fn child(&self, parent: Option<usize>, level: &str) -> Option<usize> {
+        self.children(parent)
+            .find(|(_, name)| *name == level.as_bytes())
+            .map(|(index, _)| index)
+    }
+
+    fn add_node(&mut self, parent: Option<usize>, level: &str) -> Result<usize, crate::Error> {
+        let index = self
+            .nodes
+            .iter()
+            .position(Option::is_none)
+            .ok_or(crate::Error::VectorIsFull)?;
+
+        self.names.insert(index, &[level.as_bytes()])?;
+        self.nodes[index] = Some(Node { parent });
+
+        Ok(index)
+    }
+
+    /// Removes `node` and its ancestors as long as they have neither subscriptions nor children.
+    fn prune(&mut self, mut node: Option<usize>) {
+        while let Some(index) = node {
+            let in_use = self.subs.iter().any(|sub| sub.node == index)
+                || self.children(Some(index)).next().is_some();
+            if in_use {
+                return;
+            }
+
+            node = self.nodes[index].take().and_then(|node| node.parent);
+            self.names.remove(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matching<const N: usize, const S: usize>(trie: &Trie<'_, N, S>, topic: &str) -> [bool; 4] {
+        let mut clients = [false; 4];
+        trie.matches(topic, |client, _| clients[client] = true);
+        clients
+    }
+
+    #[test]
+    fn wildcard_matching() {
+        let mut buf = [0u8; 64];
+        let mut trie = Trie::<'_, 8, 8>::new(&mut buf);
+
+        trie.subscribe(0, "sensors/+/temp", QoS::AtMostOnce)
+            .unwrap();
+        trie.subscribe(1, "sensors/#", QoS::AtLeastOnce).unwrap();
+        trie.subscribe(2, "sensors/kitchen/temp", QoS::AtMostOnce)
+            .unwrap();
+        trie.subscribe(3, "#", QoS::AtMostOnce).unwrap();
+
+        assert_eq!(
+            matching(&trie, "sensors/kitchen/temp"),
+            [true, true, true, true]
+        );
+        assert_eq!(
+            matching(&trie, "sensors/hall/temp"),
+            [true, true, false, true]
+        );
+        assert_eq!(matching(&trie, "sensors"), [false, true, false, true]);
+        assert_eq!(
+            matching(&trie, "sensors/kitchen/temp/raw"),
+            [false, true, false, true]
+        );
+        assert_eq!(matching(&trie, "$SYS/uptime"), [false; 4]);
+    }
+
+    #[test]
+    fn unsubscribe_prunes_nodes() {
+        let mut buf = [0u8; 16];
+        let mut trie = Trie::<'_, 3, 2>::new(&mut buf);
+
+        trie.subscribe(0, "a/b/c", QoS::AtMostOnce).unwrap();
+        assert!(matches!(
+            trie.subscribe(1, "a/d", QoS::AtMostOnce),
+            Err(crate::Error::VectorIsFull)
+        ));
+
+        assert!(trie.unsubscribe(0, "a/b/c"));
+        assert!(!trie.unsubscribe(0, "a/b/c"));
+        assert!(trie.nodes.iter().all(Option::is_none));
+
+        trie.subscribe(1, "a/d", QoS::AtMostOnce).unwrap();
+        assert_eq!(matching(&trie, "a/d"), [false, true, false, false]);
+    }
+}
@@ -1,11 +1,5 @@
 use crate::packet::encode;
 
-trait Provider<'buf> {
-    type Error: core::fmt::Debug;
-
-    fn provide(&mut self, len: usize) -> Result<&'buf mut [u8], Self::Error>;
-}
-
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Slice<'buf> {
@@ -13,16 +7,12 @@ pub struct Slice<'buf> {
 }
 
 impl<'buf> Slice<'buf> {
-    fn as_bytes(&self) -> &[u8] {
+    pub fn as_bytes(&self) -> &'buf [u8] {
         self.inner
     }
 }
 
 impl<'buf> Slice<'buf> {
-    fn len(&self) -> usize {
-        self.inner.len()
-    }
-
     pub(crate) fn encode_bytes(&self, cursor: &mut encode::Cursor) -> Result<(), crate::Error> {
         cursor.write_bytes(self.inner)
     }
@@ -64,46 +54,19 @@ impl<'buf> PartialEq<&[u8]> for Slice<'buf> {
     }
 }
 
-pub(crate) struct Bump<'buf> {
-    buf: &'buf mut [u8],
-    index: usize,
-}
-
-impl<'buf> Bump<'buf> {
-    pub(crate) fn new(buf: &'buf mut [u8]) -> Self {
-        Self { buf, index: 0 }
-    }
-}
-
-impl<'buf> Provider<'buf> for Bump<'buf> {
-    type Error = crate::Error;
-
-    fn provide(&mut self, len: usize) -> Result<&'buf mut [u8], Self::Error> {
-        if self.index + len > self.buf.len() {
-            return Err(crate::Error::UnexpectedEof);
-        }
-
-        // SAFETY:
-        // - self.buf is an exclusive &mut [u8]
-        // - self.index is monotonically increasing
-        // - returned slices never overlap
-        // - slices do not outlive 'buf
-
-        let start = self.index;
-        let ptr = unsafe { self.buf.as_mut_ptr().add(start) };
-        self.index += len;
-
-        let slice = unsafe { core::slice::from_raw_parts_mut(ptr, len) };
-        Ok(slice)
-    }
-}
-
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct String<'buf> {
     inner: Slice<'buf>,
 }
 
+impl<'buf> String<'buf> {
+    pub fn as_str(&self) -> Result<&'buf str, crate::Error> {
+        core::str::from_utf8(self.inner.as_bytes())
+            .map_err(|_| crate::DecodeError::InvalidUtf8.into())
+    }
+}
+
 impl<'buf> From<Slice<'buf>> for String<'buf> {
     fn from(value: Slice<'buf>) -> Self {
         Self { inner: value }
@@ -1,14 +1,10 @@
-use core::ops::Range;
-
 use embedded_io_async::{Read, Write};
 use embedded_time::duration;
-use heapless::Deque;
 
 use crate::{
-    keep_alive::KeepAlive,
-    packet::{self, Packet, connect, publish, subscribe},
-    parser,
-    session::{self, Session},
+    connection::Connection,
+    packet::{connect, publish, subscribe},
+    session,
 };
 
 pub struct Client<
@@ -19,16 +15,14 @@ pub struct Client<
     const N_PUB_OUT: usize,
     const N_SUB: usize,
     const OUT_QUEUE_SIZE: usize,
+    const MAX_FILTER_LEN: usize = 64,
 > where
     T: Read + Write,
     C: embedded_time::Clock,
 {
     clock: C,
     transport: T,
-    keep_alive: KeepAlive<C>,
-    session: Session<'c, N_PUB_IN, N_PUB_OUT, N_SUB>,
-    parser: parser::StreamParser<'c>,
-    outbox: Outbox<'c, OUT_QUEUE_SIZE>,
+    connection: Connection<'c, C, N_PUB_IN, N_PUB_OUT, N_SUB, OUT_QUEUE_SIZE, MAX_FILTER_LEN>,
 }
 
 impl<
@@ -39,7 +33,8 @@ impl<
     const N_PUB_OUT: usize,
     const N_SUB: usize,
     const OUT_Q: usize,
-> Client<'c, C, T, N_PUB_IN, N_PUB_OUT, N_SUB, OUT_Q>
+    const MAX_FILTER_LEN: usize,
+> Client<'c, C, T, N_PUB_IN, N_PUB_OUT, N_SUB, OUT_Q, MAX_FILTER_LEN>
 where
     T: Read + Write,
     C: embedded_time::Clock,
@@ -51,202 +46,429 @@ where
         rx_buf: &'c mut [u8],
         tx_buf: &'c mut [u8],
     ) -> Result<Self, crate::Error> {
-        let keep_alive = KeepAlive::try_new(&clock, keep_alive)?;
+        let now = clock.try_now().map_err(|_| crate::Error::TimeError)?;
 
         Ok(Self {
             clock,
             transport,
-            session: Session::new(),
-            keep_alive,
-            parser: parser::StreamParser::new(rx_buf),
-            outbox: Outbox::new(tx_buf),
+            connection: Connection::new(now, keep_alive, rx_buf, tx_buf),
         })
     }
 
-    pub fn schedule_connect<'a>(&mut self, opts: connect::Options<'a>) -> Result<(), crate::Error> {
-        let packet = self.session.connect(opts)?;
-        self.outbox.enqueue(packet)
+    /// The connection, for configuring the session, e.g.
+    /// [`Connection::set_offline_queue`], or [acknowledging](Connection::ack)
+    /// messages in manual acknowledgement mode.
+    pub fn connection_mut(
+        &mut self,
+    ) -> &mut Connection<'c, C, N_PUB_IN, N_PUB_OUT, N_SUB, OUT_Q, MAX_FILTER_LEN> {
+        &mut self.connection
     }
 
-    pub fn schedule_disconnect(&mut self) -> Result<(), crate::Error> {
-        if let Some(packet) = self.session.disconnect() {
-            self.outbox.enqueue(packet)?;
-        };
-
-        Ok(())
+    pub fn schedule_connect<'a>(&mut self, opts: connect::Options<'a>) -> Result<(), crate::Error> {
+        self.connection.schedule_connect(opts)
     }
 
-    fn schedule_ping(&mut self) -> Result<(), crate::Error> {
-        let packet = self.session.ping()?;
-        self.outbox.enqueue(packet)
+    pub fn schedule_disconnect(&mut self) -> Result<(), crate::Error> {
+        self.connection.schedule_disconnect()
     }
 
     pub fn schedule_publish<'a>(&mut self, msg: publish::Msg<'a>) -> Result<(), crate::Error> {
-        let packet = self.session.publish(msg)?;
-        self.outbox.enqueue(packet)
+        self.connection.schedule_publish(msg)
     }
 
-    pub fn schedule_subscribe<'a: 'c>(
+    pub fn schedule_subscribe<'a>(
         &mut self,
         msg: subscribe::Options<'a>,
     ) -> Result<(), crate::Error> {
-        if let Some(packet) = self.session.subscribe(msg)? {
-            self.outbox.enqueue(packet)?;
-        };
-
-        Ok(())
+        self.connection.schedule_subscribe(msg)
     }
 
     pub fn schedule_unsubscribe(&mut self, topic: &str) -> Result<(), crate::Error> {
-        if let Some(packet) = self.session.unsubscribe(topic)? {
-            self.outbox.enqueue(packet)?;
-        };
-
-        Ok(())
+        self.connection.schedule_unsubscribe(topic)
     }
 
     /// High-level poll. Runs timers, then performs one I/O step.
     /// Recommended default for simple loops.
-    pub async fn poll<'a>(&'a mut self) -> Result<Option<session::Event<'a>>, crate::Error> {
-        self.poll_timers()?;
+    pub async fn poll<'a>(
+        &'a mut self,
+    ) -> Result<Option<session::Event<'a>>, crate::Error<T::Error>> {
+        if let Some(event) = self.poll_timers().map_err(crate::Error::widen)? {
+            return Ok(Some(event));
+        }
         self.poll_io().await
     }
 
-    /// Timer-only step. Enqueues PINGREQ/DISCONNECT when needed.
+    /// Timer-only step. Enqueues PINGREQ/DISCONNECT when needed, and reports
+    /// a request the broker didn't answer in time.
     /// Use when your framework schedules timers separately.
-    pub fn poll_timers(&mut self) -> Result<(), crate::Error> {
+    pub fn poll_timers(&mut self) -> Result<Option<session::Event<'static>>, crate::Error> {
         let now = self.clock.try_now().map_err(|_| crate::Error::TimeError)?;
 
-        if self.keep_alive.should_ping(now)? {
-            self.schedule_ping()?;
-        }
-
-        if self.keep_alive.timed_out(now)? {
-            self.schedule_disconnect()?;
-            // @todo return some status maybe? E.g. enum TimedOut { Yes, No }
-            // @todo reconnect
-        }
-
-        Ok(())
+        self.connection.handle_timeout(now)
     }
 
-    /// I/O step. Sends one queued packet if any; otherwise reads and processes one incoming packet.
-    pub async fn poll_io<'a>(&'a mut self) -> Result<Option<session::Event<'a>>, crate::Error> {
+    /// I/O step. Writes what the transport takes of the next queued packet if
+    /// any; otherwise reads and processes one incoming packet.
+    ///
+    /// Bytes only leave the queue once written, so dropping the future loses nothing.
+    pub async fn poll_io<'a>(
+        &'a mut self,
+    ) -> Result<Option<session::Event<'a>>, crate::Error<T::Error>> {
         let now = self.clock.try_now().map_err(|_| crate::Error::TimeError)?;
+        self.connection.set_now(now);
 
-        if self.outbox.has_pending() {
-            self.outbox.flush_one(&mut self.transport).await?;
-            self.keep_alive.on_send(now);
+        if let Some(bytes) = self.connection.peek_outgoing() {
+            let n = self.transport.write(bytes).await.map_err(|err| {
+                let err = crate::TransportError(err).into();
+                self.connection.handle_error(err)
+            })?;
+            self.connection.commit_outgoing(n);
 
             return Ok(None);
         }
 
-        let packet = self.parser.read(&mut self.transport).await?;
-        self.keep_alive.on_receive(now);
-
-        let action = match packet {
-            Packet::ConnAck(conn_ack) => self.session.on_connack(&conn_ack)?,
-            Packet::Publish(publish) => self.session.on_publish(publish)?,
-            Packet::PubAck(packet_id) => self.session.on_puback(&packet_id)?,
-            Packet::PubRec(packet_id) => self.session.on_pubrec(&packet_id)?,
-            Packet::PubRel(packet_id) => self.session.on_pubrel(&packet_id)?,
-            Packet::PubComp(packet_id) => self.session.on_pubcomp(&packet_id)?,
-            Packet::SubAck(sub_ack) => self.session.on_suback(&sub_ack)?,
-            Packet::UnsubAck(packet_id) => self.session.on_unsuback(&packet_id)?,
-            Packet::PingReq => self.session.on_pingreq()?,
-            Packet::PingResp => self.session.on_pingresp()?,
-            Packet::Disconnect => self.session.on_disconnect(),
-            _ => session::Action::Nothing,
-        };
-
-        apply_action(&mut self.outbox, action)
+        self.connection.read_from(&mut self.transport).await
     }
 }
 
-fn apply_action<'a, 'b, const Q: usize>(
-    tx: &mut Outbox<'b, Q>,
-    action: session::Action<'a>,
-) -> Result<Option<session::Event<'a>>, crate::Error> {
-    match action {
-        session::Action::Send(packet) => {
-            tx.enqueue(packet)?;
-            Ok(None)
-        }
-        session::Action::Event(event) => Ok(Some(event)),
-        session::Action::Nothing => Ok(None),
+#[cfg(test)]
+mod tests {
+    use embedded_time::rate::Fraction;
+
+    use core::{
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use super::*;
+    use crate::{
+        QoS,
+        testing::{self, MockClock, MockTransport, block_on},
+    };
+
+    fn opts() -> connect::Options<'static> {
+        testing::connect_options("c")
     }
-}
 
-struct Outbox<'a, const QUEUE_SIZE: usize> {
-    buf: &'a mut [u8],
-    cursor: usize,
-    queue: Deque<Range<usize>, QUEUE_SIZE>,
-}
+    type TestClient<'c> = Client<'c, &'c MockClock, &'c mut MockTransport, 2, 2, 2, 4>;
 
-impl<'a, const QUEUE_SIZE: usize> Outbox<'a, QUEUE_SIZE> {
-    fn new(buf: &'a mut [u8]) -> Self {
-        Self {
-            buf,
-            cursor: 0,
-            queue: Deque::new(),
-        }
+    fn connected<'c>(
+        clock: &'c MockClock,
+        broker: &'c mut MockTransport,
+        rx_buf: &'c mut [u8],
+        tx_buf: &'c mut [u8],
+    ) -> TestClient<'c> {
+        let keep_alive = duration::Generic::new(10, Fraction::new(1, 1));
+        let mut client = Client::try_new(clock, keep_alive, broker, rx_buf, tx_buf).unwrap();
+
+        client.schedule_connect(opts()).unwrap();
+        assert!(block_on(client.poll()).unwrap().is_none());
+        assert!(matches!(
+            block_on(client.poll()).unwrap(),
+            Some(session::Event::Connected)
+        ));
+
+        client
     }
 
-    fn has_pending(&self) -> bool {
-        !self.queue.is_empty()
+    #[test]
+    fn publish_flows() {
+        let clock = MockClock::new();
+        let mut broker = MockTransport::new();
+        broker
+            .expect(testing::connect(opts()))
+            .reply(testing::connack(false))
+            .expect(testing::subscribe(1, "t", QoS::AtLeastOnce))
+            .reply(testing::suback(1, Some(QoS::AtLeastOnce)))
+            .reply(testing::publish("t", b"in", QoS::AtMostOnce, None))
+            .reply(testing::publish("t", b"in", QoS::AtLeastOnce, Some(7)))
+            .expect(testing::puback(7))
+            .expect(testing::publish("u", b"out", QoS::AtLeastOnce, Some(2)))
+            .reply(testing::puback(2));
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let mut client = connected(&clock, &mut broker, &mut rx_buf, &mut tx_buf);
+
+        client
+            .schedule_subscribe(subscribe::Options {
+                qos: Some(QoS::AtLeastOnce),
+                topic: "t",
+            })
+            .unwrap();
+        assert!(block_on(client.poll()).unwrap().is_none());
+        assert!(matches!(
+            block_on(client.poll()).unwrap(),
+            Some(session::Event::Subscribed {
+                topic: "t",
+                qos: QoS::AtLeastOnce
+            })
+        ));
+
+        match block_on(client.poll()).unwrap() {
+            Some(session::Event::Received(publish)) => {
+                assert_eq!(publish.topic, "t");
+                assert_eq!(publish.payload, &b"in"[..]);
+            }
+            _ => panic!("Expected Received"),
+        }
+        assert!(matches!(
+            block_on(client.poll()).unwrap(),
+            Some(session::Event::Received(publish)) if publish.flags.qos == QoS::AtLeastOnce
+        ));
+        assert!(block_on(client.poll()).unwrap().is_none());
+
+        client
+            .schedule_publish(publish::Msg {
+                qos: QoS::AtLeastOnce,
+                retain: false,
+                topic: "u",
+                payload: b"out",
+            })
+            .unwrap();
+        assert!(block_on(client.poll()).unwrap().is_none());
+        assert!(matches!(
+            block_on(client.poll()).unwrap(),
+            Some(session::Event::Published)
+        ));
+
+        drop(client);
+        broker.assert_done();
     }
 
-    fn enqueue(&mut self, packet: Packet<'_>) -> Result<(), crate::Error> {
-        if self.queue.is_empty() {
-            self.cursor = 0;
+    #[test]
+    fn manual_ack() {
+        fn received(client: &mut TestClient<'_>) -> crate::AckToken {
+            match block_on(client.poll()).unwrap() {
+                Some(session::Event::Received(publish)) => publish.ack_token().unwrap(),
+                _ => panic!("Expected Received"),
+            }
         }
 
-        let needed = packet.required_space()?;
+        let clock = MockClock::new();
+        let mut broker = MockTransport::new();
+        broker
+            .expect(testing::connect(opts()))
+            .reply(testing::connack(false))
+            .expect(testing::subscribe(1, "t", QoS::ExactlyOnce))
+            .reply(testing::suback(1, Some(QoS::ExactlyOnce)))
+            .reply(testing::publish("t", b"a", QoS::AtLeastOnce, Some(7)))
+            .reply(testing::publish("t", b"b", QoS::ExactlyOnce, Some(8)))
+            .reply(testing::publish("t", b"c", QoS::AtLeastOnce, Some(9)))
+            .expect(testing::pubrec(8))
+            .expect(testing::puback(7))
+            .reply(testing::pubrel(8))
+            .expect(testing::pubcomp(8));
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let mut client = connected(&clock, &mut broker, &mut rx_buf, &mut tx_buf);
+        client.connection_mut().set_manual_ack(true);
+
+        client
+            .schedule_subscribe(subscribe::Options {
+                qos: Some(QoS::ExactlyOnce),
+                topic: "t",
+            })
+            .unwrap();
+        assert!(block_on(client.poll()).unwrap().is_none());
+        assert!(block_on(client.poll()).unwrap().is_some());
+
+        // Nothing is acknowledged until the application says so
+        let first = received(&mut client);
+        let second = received(&mut client);
+        // No room to hold back a third, it's left for the broker to send again
+        assert!(matches!(
+            block_on(client.poll()).unwrap(),
+            Some(session::Event::Warning(crate::ProtocolError {
+                kind: crate::ProtocolErrorKind::TooManyUnacked,
+                ..
+            }))
+        ));
+        client.connection_mut().ack(second).unwrap();
+        assert!(block_on(client.poll()).unwrap().is_none());
+        client.connection_mut().ack(first).unwrap();
+        assert!(block_on(client.poll()).unwrap().is_none());
+        assert!(matches!(
+            client.connection_mut().ack(first),
+            Err(crate::Error::State(crate::StateError::NotAwaitingAck))
+        ));
+
+        assert!(block_on(client.poll()).unwrap().is_none());
+        assert!(block_on(client.poll()).unwrap().is_none());
+
+        drop(client);
+        broker.assert_done();
+    }
 
-        if self.cursor + needed > self.buf.len() {
-            return Err(crate::Error::BufferTooSmall);
-        }
+    #[test]
+    fn keep_alive_pings_then_gives_up() {
+        let clock = MockClock::new();
+        let mut broker = MockTransport::new();
+        broker
+            .expect(testing::connect(opts()))
+            .reply(testing::connack(false))
+            .expect(testing::pingreq())
+            .reply(testing::pingresp())
+            .expect(testing::pingreq())
+            .expect(testing::disconnect());
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let mut client = connected(&clock, &mut broker, &mut rx_buf, &mut tx_buf);
+
+        clock.advance(5_000);
+        assert!(block_on(client.poll()).unwrap().is_none());
+        assert!(block_on(client.poll()).unwrap().is_none());
+
+        // PINGRESP doesn't come this time
+        clock.advance(5_000);
+        assert!(block_on(client.poll()).unwrap().is_none());
+        clock.advance(9_999);
+        client.poll_timers().unwrap();
+        clock.advance(1);
+        assert!(block_on(client.poll()).unwrap().is_none());
+
+        drop(client);
+        broker.assert_done();
+    }
 
-        let start = self.cursor;
-        let end = start + needed;
-        let mut cursor = packet::encode::Cursor::new(&mut self.buf[start..end]);
-        packet.encode(&mut cursor)?;
+    #[test]
+    fn refused_connection_isnt_connected() {
+        let clock = MockClock::new();
+        let mut broker = MockTransport::new();
+        broker
+            .expect(testing::connect(opts()))
+            .reply(testing::connack_refused(5));
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let keep_alive = duration::Generic::new(10, Fraction::new(1, 1));
+        let mut client: TestClient<'_> =
+            Client::try_new(&clock, keep_alive, &mut broker, &mut rx_buf, &mut tx_buf).unwrap();
+
+        client.schedule_connect(opts()).unwrap();
+        // Subscribed before connecting, so it would go out right after CONNACK
+        client
+            .schedule_subscribe(subscribe::Options {
+                qos: None,
+                topic: "t",
+            })
+            .unwrap();
+        assert!(block_on(client.poll()).unwrap().is_none());
+
+        let Err(err) = block_on(client.poll()) else {
+            panic!("Expected an error")
+        };
+        assert!(matches!(
+            err,
+            crate::Error::ConnectionRefused(connect::ConnectReturnCode::NotAuthorized)
+        ));
+        assert!(err.is_fatal());
+        // Nothing is sent on the refused connection
+        assert!(matches!(
+            client.schedule_publish(publish::Msg {
+                qos: QoS::AtMostOnce,
+                retain: false,
+                topic: "u",
+                payload: b"",
+            }),
+            Err(crate::Error::State(_))
+        ));
+        assert!(client.connection.peek_outgoing().is_none());
+
+        drop(client);
+        broker.assert_done();
+    }
 
-        self.queue
-            .push_back(start..end)
-            .map_err(|_| crate::Error::VectorIsFull)?;
-        self.cursor = end;
+    /// Takes at most two bytes per write, and only when polled a second time.
+    /// Never has anything to read.
+    #[derive(Default)]
+    struct Stalling {
+        written: heapless::Vec<u8, 64>,
+        polled: bool,
+    }
 
-        Ok(())
+    impl embedded_io_async::ErrorType for Stalling {
+        type Error = core::convert::Infallible;
     }
 
-    async fn flush_one<T: Write>(&mut self, transport: &mut T) -> Result<(), crate::Error> {
-        if let Some(range) = self.queue.pop_front() {
-            transport
-                .write_all(&self.buf[range])
-                .await
-                .map_err(|_| crate::Error::TransportError)?;
+    impl Read for Stalling {
+        async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Self::Error> {
+            core::future::pending().await
         }
-
-        self.compact()
     }
 
-    fn compact(&mut self) -> Result<(), crate::Error> {
-        let mut cursor = 0;
-        for range in &self.queue {
-            if range.start < cursor {
-                return Err(crate::Error::QueueRangeError);
-            }
+    impl Write for Stalling {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            core::future::poll_fn(|_| {
+                self.polled = !self.polled;
+                if self.polled {
+                    return Poll::Pending;
+                }
+
+                let n = buf.len().min(2);
+                self.written.extend_from_slice(&buf[..n]).unwrap();
+                Poll::Ready(Ok(n))
+            })
+            .await
+        }
 
-            if range.start - cursor > 0 {
-                self.buf.copy_within(range.clone(), cursor);
-            }
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
 
-            cursor += range.len();
+    #[test]
+    fn dropped_writes_lose_nothing() {
+        let clock = MockClock::new();
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let keep_alive = duration::Generic::new(10, Fraction::new(1, 1));
+        let mut client: Client<'_, _, _, 2, 2, 2, 4> = Client::try_new(
+            &clock,
+            keep_alive,
+            Stalling::default(),
+            &mut rx_buf,
+            &mut tx_buf,
+        )
+        .unwrap();
+        client.schedule_connect(opts()).unwrap();
+
+        // Every other poll is dropped while the write is pending
+        let mut cx = Context::from_waker(Waker::noop());
+        while client.connection.peek_outgoing().is_some() {
+            let _ = pin!(client.poll_io()).poll(&mut cx);
         }
 
-        self.cursor = cursor;
+        let mut expected = [0u8; 32];
+        let len = testing::connect(opts()).encode_to(&mut expected).unwrap();
+        assert_eq!(client.transport.written, expected[..len]);
+    }
 
-        Ok(())
+    #[test]
+    fn reconnects_after_broker_disconnect() {
+        let clock = MockClock::new();
+        let mut broker = MockTransport::new();
+        broker
+            .expect(testing::connect(opts()))
+            .reply(testing::connack(false))
+            .reply(testing::disconnect())
+            .expect(testing::connect(opts()))
+            .reply(testing::connack(false));
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let mut client = connected(&clock, &mut broker, &mut rx_buf, &mut tx_buf);
+
+        assert!(matches!(
+            block_on(client.poll()).unwrap(),
+            Some(session::Event::Disconnected)
+        ));
+
+        client.schedule_connect(opts()).unwrap();
+        assert!(block_on(client.poll()).unwrap().is_none());
+        assert!(matches!(
+            block_on(client.poll()).unwrap(),
+            Some(session::Event::Connected)
+        ));
+
+        // Script is over, the broker closes the connection
+        assert!(matches!(
+            block_on(client.poll()),
+            Err(crate::Error::RemoteClosed)
+        ));
+
+        drop(client);
+        broker.assert_done();
     }
 }
@@ -0,0 +1,1329 @@
+use embedded_io_async::Read;
+use embedded_time::{Instant, duration};
+
+use crate::{
+    ProtocolError, ProtocolErrorKind,
+    inbox::Inbox,
+    keep_alive::KeepAlive,
+    offline::OfflineQueue,
+    outbox::Outbox,
+    packet::{Packet, QoS, connect, publish, subscribe},
+    parser,
+    persistent::{self, PersistentQueue},
+    protocol::PacketType,
+    response_timer::{Expired, ResponseTimer},
+    session::{self, Session, SessionState},
+};
+
+/// Sans-I/O MQTT connection: the protocol state machine without a transport or executor.
+///
+/// Feed received bytes to [`Self::handle_incoming`], write whatever
+/// [`Self::poll_outgoing`] returns, and call [`Self::handle_timeout`] once the
+/// instant from [`Self::next_timeout`] has passed.
+///
+/// The session keeps a copy of every subscribed topic filter, of up to
+/// `MAX_FILTER_LEN` bytes, so filters can be built at runtime.
+pub struct Connection<
+    'c,
+    C,
+    const N_PUB_IN: usize,
+    const N_PUB_OUT: usize,
+    const N_SUB: usize,
+    const OUT_QUEUE_SIZE: usize,
+    const MAX_FILTER_LEN: usize = 64,
+> where
+    C: embedded_time::Clock,
+{
+    now: Instant<C>,
+    keep_alive: KeepAlive<C>,
+    responses: ResponseTimer<C, N_SUB>,
+    session: Session<N_PUB_IN, N_PUB_OUT, N_SUB, MAX_FILTER_LEN>,
+    parser: parser::StreamParser<'c>,
+    outbox: Outbox<'c, OUT_QUEUE_SIZE>,
+    offline: Option<OfflineQueue<'c>>,
+    persistent: persistent::Drain<'c>,
+    inbox: Option<Inbox<'c>>,
+}
+
+impl<
+    'c,
+    C,
+    const N_PUB_IN: usize,
+    const N_PUB_OUT: usize,
+    const N_SUB: usize,
+    const OUT_Q: usize,
+    const MAX_FILTER_LEN: usize,
+> Connection<'c, C, N_PUB_IN, N_PUB_OUT, N_SUB, OUT_Q, MAX_FILTER_LEN>
+where
+    C: embedded_time::Clock,
+{
+    pub fn new(
+        now: Instant<C>,
+        keep_alive: duration::Generic<C::T>,
+        rx_buf: &'c mut [u8],
+        tx_buf: &'c mut [u8],
+    ) -> Self {
+        Self {
+            now,
+            keep_alive: KeepAlive::new(now, keep_alive),
+            responses: ResponseTimer::new(),
+            session: Session::new(),
+            parser: parser::StreamParser::new(rx_buf),
+            outbox: Outbox::new(tx_buf),
+            offline: None,
+            persistent: persistent::Drain::new(),
+            inbox: None,
+        }
+    }
+
+    /// Keeps publishes scheduled while not connected in `queue`, see [`crate::offline`].
+    pub fn set_offline_queue(&mut self, queue: OfflineQueue<'c>) {
+        self.offline = Some(queue);
+    }
+
+    /// Keeps publishes scheduled while not connected in `queue`, see
+    /// [`crate::persistent`]. Takes precedence over an offline queue.
+    pub fn set_persistent_queue<'q: 'c, S: persistent::Storage + 'c>(
+        &mut self,
+        queue: &'c mut PersistentQueue<'q, S>,
+    ) {
+        self.persistent.set(queue);
+    }
+
+    /// Copies received messages into `inbox` instead of reporting them as
+    /// [`session::Event::Received`], see [`crate::inbox`].
+    pub fn set_inbox(&mut self, inbox: Inbox<'c>) {
+        self.inbox = Some(inbox);
+    }
+
+    /// Received messages, if [`Self::set_inbox`] was called.
+    pub fn inbox(&mut self) -> Option<&mut Inbox<'c>> {
+        self.inbox.as_mut()
+    }
+
+    /// Gives up on a CONNACK after `timeout`, see [`session::Event::ConnectTimeout`].
+    pub fn set_connect_timeout(&mut self, timeout: duration::Generic<C::T>) {
+        self.responses.set_connect_timeout(timeout);
+    }
+
+    /// Sends SUBSCRIBE or UNSUBSCRIBE again without an answer after `timeout`,
+    /// see [`session::Event::SubscribeTimeout`].
+    pub fn set_subscribe_timeout(&mut self, timeout: duration::Generic<C::T>) {
+        self.responses.set_subscribe_timeout(timeout);
+    }
+
+    /// Holds back PUBACK and PUBREC for received QoS 1 and 2 messages until
+    /// [`Self::ack`], e.g. once the message has been persisted. At most `N_PUB_IN`
+    /// messages can wait for that; further ones are dropped with a
+    /// [`crate::ProtocolErrorKind::TooManyUnacked`] warning until some are acked.
+    pub fn set_manual_ack(&mut self, enabled: bool) {
+        self.session.set_manual_ack(enabled);
+    }
+
+    /// Reports publishes with a higher QoS than their subscription was granted as
+    /// [`crate::ProtocolErrorKind::QoSNotGranted`] warnings instead of delivering
+    /// them. They're still acknowledged.
+    pub fn set_strict_qos(&mut self, enabled: bool) {
+        self.session.set_strict_qos(enabled);
+    }
+
+    /// Acknowledges a message received in manual acknowledgement mode, with the
+    /// token from [`publish::Publish::ack_token`].
+    pub fn ack(&mut self, token: publish::AckToken) -> Result<(), crate::Error> {
+        let packet = self.session.ack(token)?;
+        self.outbox.enqueue(packet)
+    }
+
+    pub fn schedule_connect<'a>(&mut self, opts: connect::Options<'a>) -> Result<(), crate::Error> {
+        opts.validate()?;
+        let packet = self.session.connect(opts)?;
+        self.responses.on_send(&packet, self.now);
+        self.outbox.enqueue(packet)
+    }
+
+    pub fn schedule_disconnect(&mut self) -> Result<(), crate::Error> {
+        if let Some(packet) = self.session.disconnect() {
+            self.outbox.enqueue(packet)?;
+        };
+
+        Ok(())
+    }
+
+    fn schedule_ping(&mut self) -> Result<(), crate::Error> {
+        let packet = self.session.ping()?;
+        self.outbox.enqueue(packet)
+    }
+
+    /// With an offline or persistent queue, messages are queued while not
+    /// connected, and behind any messages still queued after reconnecting. An
+    /// invalid topic name fails before anything is queued.
+    pub fn schedule_publish<'a>(&mut self, msg: publish::Msg<'a>) -> Result<(), crate::Error> {
+        msg.validate()?;
+
+        if let Some(result) = push_queued(
+            self.session.is_connected(),
+            &mut self.persistent,
+            &mut self.offline,
+            &msg,
+        ) {
+            return result;
+        }
+
+        let packet = self.session.publish(msg)?;
+        self.outbox.enqueue(Packet::<1>::Publish(packet))
+    }
+
+    /// Publishes `payload`, serialized straight into the outbox, e.g. `&Json(&reading)`.
+    /// Queued like [`Self::schedule_publish`]: the payload is then serialized into
+    /// the outbox's free space and copied into the queue from there.
+    #[cfg(any(feature = "serde-json-core", feature = "postcard"))]
+    pub fn schedule_publish_serialized<P: crate::payload::ToPayload + ?Sized>(
+        &mut self,
+        topic: &str,
+        payload: &P,
+        qos: QoS,
+    ) -> Result<(), crate::Error> {
+        let msg = publish::Msg {
+            qos,
+            retain: false,
+            topic,
+            payload: &[],
+        };
+        msg.validate()?;
+
+        // Serialize first, so that a failure doesn't leave a packet id allocated
+        let buf = self.outbox.payload_buf(&msg.into())?;
+        let payload_len = payload.to_payload(buf)?;
+
+        let queued = publish::Msg {
+            payload: &buf[..payload_len],
+            ..msg
+        };
+        if let Some(result) = push_queued(
+            self.session.is_connected(),
+            &mut self.persistent,
+            &mut self.offline,
+            &queued,
+        ) {
+            return result;
+        }
+
+        let packet = self.session.publish(msg)?;
+        self.outbox.commit_publish(&packet, payload_len)
+    }
+
+    /// Before CONNACK the subscription is recorded and SUBSCRIBE goes out once connected.
+    /// Subscribing again with another QoS fails with
+    /// [`crate::StateError::QoSMismatch`] once the SUBSCRIBE has gone out.
+    pub fn schedule_subscribe<'a>(
+        &mut self,
+        msg: subscribe::Options<'a>,
+    ) -> Result<(), crate::Error> {
+        msg.validate()?;
+
+        if let Some(packet) = self.session.subscribe(msg)? {
+            self.responses.on_send(&packet, self.now);
+            self.outbox.enqueue(packet)?;
+        };
+
+        Ok(())
+    }
+
+    /// Before CONNACK, or while the subscription awaits its SUBACK, UNSUBSCRIBE
+    /// goes out later.
+    pub fn schedule_unsubscribe(&mut self, topic: &str) -> Result<(), crate::Error> {
+        if let Some(packet) = self.session.unsubscribe(topic)? {
+            self.responses.on_send(&packet, self.now);
+            self.outbox.enqueue(packet)?;
+        };
+
+        Ok(())
+    }
+
+    /// Feeds bytes received from the transport.
+    ///
+    /// Returns how many bytes were consumed and the event produced by the packet
+    /// they completed, if any. Call again with the unconsumed rest.
+    ///
+    /// After a [fatal](crate::Error::is_fatal) error the session is disconnected
+    /// and anything still queued for the broker is dropped.
+    pub fn handle_incoming(
+        &mut self,
+        bytes: &[u8],
+    ) -> Result<(usize, Option<session::Event<'_>>), crate::Error> {
+        let (consumed, packet) = self
+            .parser
+            .feed(bytes)
+            .map_err(|err| on_error(&mut self.session, &mut self.outbox, err))?;
+
+        let event = match packet {
+            Some(packet) => {
+                self.keep_alive.on_receive(self.now);
+                self.persistent.on_packet(&packet);
+                self.responses.on_packet(&packet);
+                handle_packet(
+                    &mut self.session,
+                    &mut self.outbox,
+                    self.inbox.as_mut(),
+                    packet,
+                )
+                .map_err(|err| on_error(&mut self.session, &mut self.outbox, err))?
+            }
+            None => Handled::Event(None),
+        };
+
+        Ok((consumed, event.into_event(&self.session)))
+    }
+
+    /// Next encoded packet to write to the transport, if any.
+    /// The bytes stay valid until the connection is used again.
+    pub fn poll_outgoing(&mut self) -> Option<&[u8]> {
+        self.flush_deferred();
+        self.flush_persistent();
+        self.flush_offline();
+
+        let bytes = self.outbox.dequeue();
+
+        if bytes.is_some() {
+            self.keep_alive.on_send(self.now);
+        }
+
+        bytes
+    }
+
+    /// Like [`Self::poll_outgoing`], but the bytes stay queued until
+    /// [`Self::commit_outgoing`] marks them as written. Nothing is lost if
+    /// writing them fails or is cancelled.
+    pub fn peek_outgoing(&mut self) -> Option<&[u8]> {
+        self.flush_deferred();
+        self.flush_persistent();
+        self.flush_offline();
+
+        self.outbox.front()
+    }
+
+    /// Marks the first `n` bytes of [`Self::peek_outgoing`] as written.
+    pub fn commit_outgoing(&mut self, n: usize) {
+        if n > 0 {
+            self.keep_alive.on_send(self.now);
+        }
+
+        self.outbox.advance(n);
+    }
+
+    /// Runs timers at `now`. Enqueues PINGREQ/DISCONNECT when needed, and
+    /// reports requests the broker didn't answer in time, all that expired by
+    /// `now` at once.
+    pub fn handle_timeout(
+        &mut self,
+        now: Instant<C>,
+    ) -> Result<Option<session::Event<'static>>, crate::Error> {
+        self.now = now;
+
+        let mut timed_out = None;
+        while let Some(expired) = self.responses.expired(now, self.session.state())? {
+            match expired {
+                Expired::ConnAck => {
+                    self.session.drop_connection();
+                    self.outbox.clear();
+                    return Ok(Some(session::Event::ConnectTimeout));
+                }
+                Expired::SubAck(packet_id) => {
+                    timed_out = self.session.on_response_timeout(packet_id).or(timed_out);
+                }
+            }
+        }
+        if timed_out.is_some() {
+            return Ok(timed_out);
+        }
+
+        if !self.session.is_connected() {
+            return Ok(None);
+        }
+
+        if self.keep_alive.should_ping(now)? {
+            self.schedule_ping()?;
+        }
+
+        if self.keep_alive.timed_out(now)? {
+            self.schedule_disconnect()?;
+            // @todo return some status maybe? E.g. enum TimedOut { Yes, No }
+            // @todo reconnect
+        }
+
+        Ok(None)
+    }
+
+    /// Instant at which [`Self::handle_timeout`] should be called next.
+    pub fn next_timeout(&self) -> Option<Instant<C>> {
+        let state = self.session.state();
+        let response = self.responses.next_deadline(state);
+
+        if state != SessionState::Connected {
+            return response;
+        }
+
+        [self.keep_alive.next_deadline(), response]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Moves SUBSCRIBEs and UNSUBSCRIBEs put off until connected to the outbox.
+    fn flush_deferred(&mut self) {
+        while let Some(packet) = self.session.next_deferred(|packet| {
+            packet
+                .encoded_len()
+                .is_ok_and(|len| self.outbox.has_room(len))
+        }) {
+            self.responses.on_send(&packet, self.now);
+            if self.outbox.enqueue(packet).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Sends the oldest record of the persistent queue with QoS 1, unless the
+    /// previous one still waits for its PUBACK.
+    fn flush_persistent(&mut self) {
+        if !self.session.is_connected() {
+            return;
+        }
+        let Some((seq, msg)) = self.persistent.next() else {
+            return;
+        };
+
+        let msg = publish::Msg {
+            qos: QoS::AtLeastOnce,
+            ..msg
+        };
+        let fits = publish::Publish::from(msg)
+            .encoded_len()
+            .is_ok_and(|len| self.outbox.has_room(len));
+        if !fits {
+            return;
+        }
+        let Ok(packet) = self.session.publish(msg) else {
+            return;
+        };
+        let packet_id = packet.packet_id;
+
+        if self.outbox.enqueue(Packet::<1>::Publish(packet)).is_ok()
+            && let Some(packet_id) = packet_id
+        {
+            self.persistent.sent(packet_id, seq);
+        }
+    }
+
+    /// Moves queued messages to the outbox, oldest first, as far as outbox space
+    /// and packet ids allow.
+    fn flush_offline(&mut self) {
+        let Some(queue) = &mut self.offline else {
+            return;
+        };
+
+        while self.session.is_connected()
+            && let Some(msg) = queue.front()
+        {
+            let fits = publish::Publish::from(msg)
+                .encoded_len()
+                .is_ok_and(|len| self.outbox.has_room(len));
+            if !fits {
+                break;
+            }
+            let Ok(packet) = self.session.publish(msg) else {
+                break;
+            };
+            if self.outbox.enqueue(Packet::<1>::Publish(packet)).is_err() {
+                break;
+            }
+
+            queue.pop_front();
+        }
+    }
+
+    pub(crate) fn set_now(&mut self, now: Instant<C>) {
+        self.now = now;
+    }
+
+    /// Disconnects the session if `err` is fatal, e.g. after the transport failed to write.
+    pub(crate) fn handle_error<E>(&mut self, err: crate::Error<E>) -> crate::Error<E> {
+        on_error(&mut self.session, &mut self.outbox, err)
+    }
+
+    /// Reads one packet straight from `read` and processes it.
+    pub(crate) async fn read_from<R: Read>(
+        &mut self,
+        read: &mut R,
+    ) -> Result<Option<session::Event<'_>>, crate::Error<R::Error>> {
+        let packet = self
+            .parser
+            .read(read)
+            .await
+            .map_err(|err| on_error(&mut self.session, &mut self.outbox, err))?;
+        self.keep_alive.on_receive(self.now);
+        self.persistent.on_packet(&packet);
+        self.responses.on_packet(&packet);
+
+        let handled = handle_packet(
+            &mut self.session,
+            &mut self.outbox,
+            self.inbox.as_mut(),
+            packet,
+        )
+        .map_err(|err| on_error(&mut self.session, &mut self.outbox, err.widen()))?;
+
+        Ok(handled.into_event(&self.session))
+    }
+
+    /// Blocking counterpart of [`Self::read_from`]. `Ok(None)` also covers a read timeout.
+    pub(crate) fn read_blocking_from<R: embedded_io::Read>(
+        &mut self,
+        read: &mut R,
+    ) -> Result<Option<session::Event<'_>>, crate::Error<R::Error>> {
+        let packet = self
+            .parser
+            .read_blocking(read)
+            .map_err(|err| on_error(&mut self.session, &mut self.outbox, err))?;
+        let Some(packet) = packet else {
+            return Ok(None);
+        };
+        self.keep_alive.on_receive(self.now);
+        self.persistent.on_packet(&packet);
+        self.responses.on_packet(&packet);
+
+        let handled = handle_packet(
+            &mut self.session,
+            &mut self.outbox,
+            self.inbox.as_mut(),
+            packet,
+        )
+        .map_err(|err| on_error(&mut self.session, &mut self.outbox, err.widen()))?;
+
+        Ok(handled.into_event(&self.session))
+    }
+}
+
+/// What a packet from the broker produced. SUBACK events borrow the session,
+/// so they are only built once error handling is done with it.
+enum Handled<'a> {
+    Event(Option<session::Event<'a>>),
+    SubAck(usize),
+}
+
+impl<'a> Handled<'a> {
+    fn into_event<
+        const N_PUB_IN: usize,
+        const N_PUB_OUT: usize,
+        const N_SUB: usize,
+        const F: usize,
+    >(
+        self,
+        session: &'a Session<N_PUB_IN, N_PUB_OUT, N_SUB, F>,
+    ) -> Option<session::Event<'a>> {
+        match self {
+            Self::Event(event) => event,
+            Self::SubAck(index) => session.suback_event(index),
+        }
+    }
+}
+
+fn handle_packet<
+    'a,
+    const N_PUB_IN: usize,
+    const N_PUB_OUT: usize,
+    const N_SUB: usize,
+    const Q: usize,
+    const F: usize,
+>(
+    session: &mut Session<N_PUB_IN, N_PUB_OUT, N_SUB, F>,
+    outbox: &mut Outbox<'_, Q>,
+    inbox: Option<&mut Inbox<'_>>,
+    packet: Packet<'a>,
+) -> Result<Handled<'a>, crate::Error> {
+    let action = match packet {
+        Packet::ConnAck(conn_ack) => session.on_connack(&conn_ack),
+        Packet::Publish(publish) => match inbox.as_ref().map(|inbox| inbox.check(&publish)) {
+            // Left unacknowledged before the session tracks it
+            Some(Err(crate::Error::QueueFull | crate::Error::BufferTooSmall)) => {
+                let kind = ProtocolErrorKind::InboxFull;
+                Err(ProtocolError::new(PacketType::Publish, publish.packet_id, kind).into())
+            }
+            Some(Err(err)) => Err(err),
+            Some(Ok(())) | None => session.on_publish(publish),
+        },
+        Packet::PubAck(packet_id) => session.on_puback(&packet_id),
+        Packet::PubRec(packet_id) => session.on_pubrec(&packet_id),
+        Packet::PubRel(packet_id) => session.on_pubrel(&packet_id),
+        Packet::PubComp(packet_id) => session.on_pubcomp(&packet_id),
+        Packet::SubAck(sub_ack) => session.on_suback(&sub_ack),
+        Packet::UnsubAck(packet_id) => session.on_unsuback(&packet_id),
+        Packet::PingReq => session.on_pingreq(),
+        Packet::PingResp => session.on_pingresp(),
+        Packet::Disconnect => Ok(session.on_disconnect()),
+        _ => Ok(session::Action::Nothing),
+    };
+
+    let event = match action {
+        Ok(session::Action::Send(packet)) => {
+            outbox.enqueue(packet)?;
+            None
+        }
+        Ok(session::Action::Event(event)) => queue_received(inbox, event)?,
+        Ok(session::Action::SendAndEvent(packet, event)) => {
+            let event = queue_received(inbox, event)?;
+            outbox.enqueue(packet)?;
+            event
+        }
+        Ok(session::Action::SubAck(index)) => return Ok(Handled::SubAck(index)),
+        Ok(session::Action::Nothing) => None,
+        Err(crate::Error::Protocol(err)) if !err.is_fatal() => Some(session::Event::Warning(err)),
+        Err(err) => return Err(err),
+    };
+
+    Ok(Handled::Event(event))
+}
+
+/// Copies a received message into the inbox, if there is one, rather than
+/// reporting it.
+fn queue_received<'a>(
+    inbox: Option<&mut Inbox<'_>>,
+    event: session::Event<'a>,
+) -> Result<Option<session::Event<'a>>, crate::Error> {
+    match (inbox, event) {
+        (Some(inbox), session::Event::Received(publish)) => {
+            inbox.push(&publish)?;
+            Ok(None)
+        }
+        (_, event) => Ok(Some(event)),
+    }
+}
+
+/// Pushes `msg` to the persistent or offline queue while not connected, or
+/// behind messages still queued. `None` means it goes out right away.
+fn push_queued(
+    connected: bool,
+    persistent: &mut persistent::Drain<'_>,
+    offline: &mut Option<OfflineQueue<'_>>,
+    msg: &publish::Msg<'_>,
+) -> Option<Result<(), crate::Error>> {
+    if let Some(queue) = persistent.queue()
+        && (!connected || !queue.is_empty())
+    {
+        return Some(queue.push(msg));
+    }
+
+    if let Some(queue) = offline
+        && queue.accepts(msg)
+        && (!connected || !queue.is_empty())
+    {
+        return Some(queue.push(msg));
+    }
+
+    None
+}
+
+/// Drops the connection on a fatal error, so that the next CONNECT starts from scratch.
+fn on_error<
+    E,
+    const N_PUB_IN: usize,
+    const N_PUB_OUT: usize,
+    const N_SUB: usize,
+    const Q: usize,
+    const F: usize,
+>(
+    session: &mut Session<N_PUB_IN, N_PUB_OUT, N_SUB, F>,
+    outbox: &mut Outbox<'_, Q>,
+    err: crate::Error<E>,
+) -> crate::Error<E> {
+    if err.is_fatal() {
+        session.drop_connection();
+        outbox.clear();
+    }
+
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_time::rate::Fraction;
+
+    use super::*;
+    use crate::testing::{self, MockClock};
+
+    // Instants are passed in explicitly, so the clock itself is never read
+    type TestConnection<'c> = Connection<'c, &'static MockClock, 2, 2, 2, 4>;
+
+    fn at(ms: u32) -> Instant<&'static MockClock> {
+        Instant::new(ms)
+    }
+
+    fn connected<'c>(rx_buf: &'c mut [u8], tx_buf: &'c mut [u8]) -> TestConnection<'c> {
+        let keep_alive = duration::Generic::new(10, Fraction::new(1, 1));
+        let mut connection = Connection::new(at(0), keep_alive, rx_buf, tx_buf);
+
+        connection
+            .schedule_connect(testing::connect_options("c"))
+            .unwrap();
+        assert_eq!(connection.poll_outgoing().unwrap()[0], 0x10);
+        assert!(connection.poll_outgoing().is_none());
+
+        let (consumed, event) = connection
+            .handle_incoming(&[0x20, 0x02, 0x00, 0x00])
+            .unwrap();
+        assert_eq!(consumed, 4);
+        assert!(matches!(event, Some(session::Event::Connected)));
+
+        connection
+    }
+
+    #[test]
+    fn incoming_bytes_in_pieces() {
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let mut connection = connected(&mut rx_buf, &mut tx_buf);
+
+        // PINGREQ from the broker, split across two calls, followed by a PINGRESP
+        let (consumed, event) = connection.handle_incoming(&[0xC0]).unwrap();
+        assert_eq!(consumed, 1);
+        assert!(event.is_none());
+
+        let (consumed, event) = connection.handle_incoming(&[0x00, 0xD0, 0x00]).unwrap();
+        assert_eq!(consumed, 1);
+        assert!(event.is_none());
+        assert_eq!(connection.poll_outgoing(), Some(&[0xD0, 0x00][..]));
+
+        let (consumed, _) = connection.handle_incoming(&[0xD0, 0x00]).unwrap();
+        assert_eq!(consumed, 2);
+        assert!(connection.poll_outgoing().is_none());
+    }
+
+    #[test]
+    fn recoverable_errors_become_warnings() {
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let mut connection = connected(&mut rx_buf, &mut tx_buf);
+
+        // QoS 1 PUBLISH on "a", which was never subscribed, is still acknowledged
+        let (_, event) = connection
+            .handle_incoming(&[0x32, 0x06, 0x00, 0x01, b'a', 0x00, 0x05, b'x'])
+            .unwrap();
+        assert!(matches!(
+            event,
+            Some(session::Event::Warning(crate::ProtocolError {
+                kind: crate::ProtocolErrorKind::NotSubscribed,
+                ..
+            }))
+        ));
+        assert_eq!(
+            connection.poll_outgoing(),
+            Some(&[0x40, 0x02, 0x00, 0x05][..])
+        );
+
+        // PUBACK for nothing in flight
+        let (_, event) = connection
+            .handle_incoming(&[0x40, 0x02, 0x00, 0x09])
+            .unwrap();
+        let Some(session::Event::Warning(err)) = event else {
+            panic!("Expected a warning")
+        };
+        assert_eq!(err.kind, crate::ProtocolErrorKind::UnknownPacketId);
+        assert!(!err.is_fatal());
+        assert!(connection.next_timeout().is_some());
+    }
+
+    #[test]
+    fn fatal_errors_disconnect() {
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let mut connection = connected(&mut rx_buf, &mut tx_buf);
+
+        connection
+            .schedule_publish(publish::Msg {
+                qos: QoS::AtLeastOnce,
+                retain: false,
+                topic: "a",
+                payload: b"",
+            })
+            .unwrap();
+
+        // A second CONNACK
+        let Err(err) = connection.handle_incoming(&[0x20, 0x02, 0x00, 0x00]) else {
+            panic!("Expected an error")
+        };
+        assert!(err.is_fatal());
+        assert!(connection.next_timeout().is_none());
+        assert!(connection.poll_outgoing().is_none());
+
+        connection
+            .schedule_connect(testing::connect_options("c"))
+            .unwrap();
+        assert_eq!(connection.poll_outgoing().unwrap()[0], 0x10);
+    }
+
+    #[cfg(feature = "serde-json-core")]
+    #[test]
+    fn serialized_publish_json() {
+        use crate::payload::Json;
+
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Reading<'a> {
+            room: &'a str,
+            celsius: u8,
+        }
+
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let mut connection = connected(&mut rx_buf, &mut tx_buf);
+
+        // Doesn't fit, and takes no packet id with it
+        assert!(matches!(
+            connection.schedule_publish_serialized("t", &Json(&[0u32; 32]), QoS::AtLeastOnce),
+            Err(crate::Error::BufferTooSmall)
+        ));
+
+        let reading = Reading {
+            room: "hall",
+            celsius: 21,
+        };
+        connection
+            .schedule_publish_serialized("t", &Json(&reading), QoS::AtLeastOnce)
+            .unwrap();
+        // Queued behind the gap the short header left
+        connection
+            .schedule_publish(publish::Msg {
+                qos: QoS::AtMostOnce,
+                retain: false,
+                topic: "u",
+                payload: b"x",
+            })
+            .unwrap();
+
+        {
+            let (packet, _) = Packet::<1>::decode(connection.poll_outgoing().unwrap()).unwrap();
+            let Packet::Publish(publish) = packet else {
+                panic!("Expected Publish")
+            };
+            assert_eq!(publish.topic, "t");
+            assert_eq!(publish.packet_id.map(|id| id.get()), Some(1));
+            assert_eq!(
+                publish.payload,
+                br#"{"room":"hall","celsius":21}"#.as_slice()
+            );
+            let Json(received) = publish.deserialize::<Json<Reading>>().unwrap();
+            assert_eq!(received, reading);
+        }
+
+        assert_eq!(
+            connection.poll_outgoing(),
+            Some(&[0x30, 0x04, 0x00, 0x01, b'u', b'x'][..])
+        );
+        assert!(connection.poll_outgoing().is_none());
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn serialized_publish_postcard() {
+        use crate::payload::Postcard;
+
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let mut connection = connected(&mut rx_buf, &mut tx_buf);
+
+        connection
+            .schedule_publish_serialized("t", &Postcard((300u16, "hi")), QoS::AtMostOnce)
+            .unwrap();
+
+        let bytes = connection.poll_outgoing().unwrap();
+        assert_eq!(
+            bytes,
+            &[0x30, 0x08, 0x00, 0x01, b't', 0xAC, 0x02, 0x02, b'h', b'i']
+        );
+        let (Packet::Publish(publish), _) = Packet::<1>::decode(bytes).unwrap() else {
+            panic!("Expected Publish")
+        };
+        assert!(matches!(
+            publish.deserialize::<Postcard<(u16, &str)>>(),
+            Ok(Postcard((300, "hi")))
+        ));
+        assert!(matches!(
+            publish.deserialize::<Postcard<(u16, [u8; 8])>>(),
+            Err(crate::Error::InvalidPayload)
+        ));
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn serialized_publish_waits_in_offline_queue() {
+        use crate::payload::Postcard;
+
+        let (mut rx_buf, mut tx_buf, mut queue_buf) = ([0u8; 64], [0u8; 64], [0u8; 64]);
+        let keep_alive = duration::Generic::new(10, Fraction::new(1, 1));
+        let mut connection: TestConnection<'_> =
+            Connection::new(at(0), keep_alive, &mut rx_buf, &mut tx_buf);
+        connection.set_offline_queue(OfflineQueue::new(
+            &mut queue_buf,
+            crate::offline::Options::default(),
+        ));
+
+        connection
+            .schedule_publish(publish::Msg {
+                qos: QoS::AtMostOnce,
+                retain: false,
+                topic: "a",
+                payload: b"x",
+            })
+            .unwrap();
+        connection
+            .schedule_publish_serialized("t", &Postcard(7u8), QoS::AtMostOnce)
+            .unwrap();
+        assert!(connection.poll_outgoing().is_none());
+
+        connection
+            .schedule_connect(testing::connect_options("c"))
+            .unwrap();
+        assert_eq!(connection.poll_outgoing().unwrap()[0], 0x10);
+        connection
+            .handle_incoming(&[0x20, 0x02, 0x00, 0x00])
+            .unwrap();
+
+        // Sent in the order they were scheduled, after the offline queue drained
+        assert_eq!(
+            connection.poll_outgoing(),
+            Some(&[0x30, 0x04, 0x00, 0x01, b'a', b'x'][..])
+        );
+        assert_eq!(
+            connection.poll_outgoing(),
+            Some(&[0x30, 0x04, 0x00, 0x01, b't', 0x07][..])
+        );
+        assert!(connection.poll_outgoing().is_none());
+    }
+
+    #[test]
+    fn offline_queue_flushes_after_connack() {
+        fn msg(topic: &str, qos: QoS) -> publish::Msg<'_> {
+            publish::Msg {
+                qos,
+                retain: false,
+                topic,
+                payload: b"",
+            }
+        }
+
+        fn next_topic(connection: &mut TestConnection<'_>) -> Option<heapless::String<4>> {
+            let (packet, _) = Packet::<1>::decode(connection.poll_outgoing()?).unwrap();
+            let Packet::Publish(publish) = packet else {
+                panic!("Expected Publish")
+            };
+
+            Some(publish.topic.as_str().unwrap().try_into().unwrap())
+        }
+
+        let (mut rx_buf, mut tx_buf, mut queue_buf) = ([0u8; 64], [0u8; 64], [0u8; 64]);
+        let keep_alive = duration::Generic::new(10, Fraction::new(1, 1));
+        let mut connection: TestConnection<'_> =
+            Connection::new(at(0), keep_alive, &mut rx_buf, &mut tx_buf);
+        connection.set_offline_queue(OfflineQueue::new(
+            &mut queue_buf,
+            crate::offline::Options {
+                overflow: crate::offline::Overflow::Reject,
+                exclude_qos0: true,
+            },
+        ));
+
+        for topic in ["a", "b", "c"] {
+            connection
+                .schedule_publish(msg(topic, QoS::AtLeastOnce))
+                .unwrap();
+        }
+        assert!(matches!(
+            connection.schedule_publish(msg("x", QoS::AtMostOnce)),
+            Err(crate::Error::State(_))
+        ));
+
+        connection
+            .schedule_connect(testing::connect_options("c"))
+            .unwrap();
+        assert_eq!(connection.poll_outgoing().unwrap()[0], 0x10);
+        assert!(connection.poll_outgoing().is_none());
+        connection
+            .handle_incoming(&[0x20, 0x02, 0x00, 0x00])
+            .unwrap();
+
+        // Two packet ids, so "c" waits for a PUBACK, and "d" waits behind it
+        assert_eq!(next_topic(&mut connection).unwrap(), "a");
+        assert_eq!(next_topic(&mut connection).unwrap(), "b");
+        assert!(next_topic(&mut connection).is_none());
+        connection
+            .schedule_publish(msg("d", QoS::AtLeastOnce))
+            .unwrap();
+        assert!(next_topic(&mut connection).is_none());
+
+        connection
+            .handle_incoming(&[0x40, 0x02, 0x00, 0x01])
+            .unwrap();
+        assert_eq!(next_topic(&mut connection).unwrap(), "c");
+        assert!(next_topic(&mut connection).is_none());
+        connection
+            .handle_incoming(&[0x40, 0x02, 0x00, 0x02])
+            .unwrap();
+        assert_eq!(next_topic(&mut connection).unwrap(), "d");
+    }
+
+    #[test]
+    fn subscriptions_sent_after_connack() {
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let keep_alive = duration::Generic::new(10, Fraction::new(1, 1));
+        let mut connection: TestConnection<'_> =
+            Connection::new(at(0), keep_alive, &mut rx_buf, &mut tx_buf);
+
+        connection
+            .schedule_subscribe(subscribe::Options {
+                qos: Some(QoS::AtLeastOnce),
+                topic: "a",
+            })
+            .unwrap();
+        assert!(connection.poll_outgoing().is_none());
+
+        connection
+            .schedule_connect(testing::connect_options("c"))
+            .unwrap();
+        assert_eq!(connection.poll_outgoing().unwrap()[0], 0x10);
+        assert!(connection.poll_outgoing().is_none());
+        connection
+            .handle_incoming(&[0x20, 0x02, 0x00, 0x00])
+            .unwrap();
+
+        let mut expected = [0u8; 16];
+        let len = testing::subscribe(1, "a", QoS::AtLeastOnce)
+            .encode_to(&mut expected)
+            .unwrap();
+        assert_eq!(connection.poll_outgoing().unwrap(), &expected[..len]);
+        assert!(connection.poll_outgoing().is_none());
+
+        assert!(matches!(
+            connection.handle_incoming(&[0x90, 0x03, 0x00, 0x01, 0x00]),
+            Ok((
+                5,
+                Some(session::Event::Subscribed {
+                    topic: "a",
+                    qos: QoS::AtMostOnce
+                })
+            ))
+        ));
+    }
+
+    #[test]
+    fn invalid_requests_rejected_before_outbox() {
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let mut connection = connected(&mut rx_buf, &mut tx_buf);
+        let msg = |topic| publish::Msg {
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic,
+            payload: b"",
+        };
+
+        let long = core::str::from_utf8(&[b'a'; 65536]).unwrap();
+        for (topic, expected) in [
+            ("", crate::Error::InvalidTopicName),
+            ("a/+", crate::Error::InvalidTopicName),
+            ("a/#", crate::Error::InvalidTopicName),
+            ("a\0", crate::Error::InvalidString),
+            (long, crate::Error::StringTooLong),
+        ] {
+            let err = connection.schedule_publish(msg(topic)).unwrap_err();
+            assert_eq!(
+                core::mem::discriminant(&err),
+                core::mem::discriminant(&expected)
+            );
+            assert!(!err.is_fatal());
+        }
+
+        for topic in ["", "a/#/b", "a+"] {
+            assert!(matches!(
+                connection.schedule_subscribe(subscribe::Options { qos: None, topic }),
+                Err(crate::Error::InvalidTopicFilter)
+            ));
+        }
+        assert!(connection.poll_outgoing().is_none());
+
+        // Neither took a packet id
+        connection.schedule_publish(msg("a")).unwrap();
+        {
+            let (packet, _) = Packet::<1>::decode(connection.poll_outgoing().unwrap()).unwrap();
+            let Packet::Publish(publish) = packet else {
+                panic!("Expected Publish")
+            };
+            assert_eq!(publish.packet_id.map(|id| id.get()), Some(1));
+        }
+
+        connection.schedule_disconnect().unwrap();
+        assert!(matches!(
+            connection.schedule_connect(connect::Options {
+                clean_session: false,
+                ..testing::connect_options("")
+            }),
+            Err(crate::Error::InvalidClientId)
+        ));
+    }
+
+    #[test]
+    fn inbox_holds_received_messages() {
+        let (mut rx_buf, mut tx_buf, mut inbox_buf) = ([0u8; 64], [0u8; 64], [0u8; 16]);
+        let mut connection = connected(&mut rx_buf, &mut tx_buf);
+        connection.set_inbox(Inbox::new(&mut inbox_buf, crate::offline::Overflow::Reject));
+
+        connection
+            .schedule_subscribe(subscribe::Options {
+                qos: Some(QoS::AtLeastOnce),
+                topic: "a",
+            })
+            .unwrap();
+        assert_eq!(connection.poll_outgoing().unwrap()[0], 0x82);
+        connection
+            .handle_incoming(&[0x90, 0x03, 0x00, 0x01, 0x01])
+            .unwrap();
+
+        // Queued and acknowledged, without an event
+        let (_, event) = connection
+            .handle_incoming(&[0x32, 0x06, 0x00, 0x01, b'a', 0x00, 0x05, b'x'])
+            .unwrap();
+        assert!(event.is_none());
+        assert_eq!(
+            connection.poll_outgoing(),
+            Some(&[0x40, 0x02, 0x00, 0x05][..])
+        );
+
+        // No room for a second message, which only warns and isn't acknowledged
+        let Ok((8, Some(session::Event::Warning(err)))) =
+            connection.handle_incoming(&[0x32, 0x06, 0x00, 0x01, b'a', 0x00, 0x06, b'y'])
+        else {
+            panic!("Expected a warning")
+        };
+        assert_eq!(err.kind, ProtocolErrorKind::InboxFull);
+        assert!(connection.poll_outgoing().is_none());
+
+        // Neither is one that could never fit
+        let mut big = [0u8; 24];
+        big[..7].copy_from_slice(&[0x32, 0x16, 0x00, 0x01, b'a', 0x00, 0x07]);
+        assert!(matches!(
+            connection.handle_incoming(&big),
+            Ok((
+                24,
+                Some(session::Event::Warning(ProtocolError {
+                    kind: ProtocolErrorKind::InboxFull,
+                    ..
+                }))
+            ))
+        ));
+        assert!(connection.poll_outgoing().is_none());
+        assert!(connection.session.is_connected());
+
+        let inbox = connection.inbox().unwrap();
+        let msg = inbox.front().unwrap();
+        assert_eq!((msg.topic, msg.payload), ("a", &b"x"[..]));
+        assert_eq!(msg.qos, QoS::AtLeastOnce);
+        inbox.pop_front();
+
+        // Delivered once sent again
+        connection
+            .handle_incoming(&[0x3A, 0x06, 0x00, 0x01, b'a', 0x00, 0x06, b'y'])
+            .unwrap();
+        assert_eq!(
+            connection.poll_outgoing(),
+            Some(&[0x40, 0x02, 0x00, 0x06][..])
+        );
+        assert_eq!(connection.inbox().unwrap().front().unwrap().payload, b"y");
+    }
+
+    #[test]
+    fn persistent_queue_drains_one_at_a_time() {
+        fn connect(connection: &mut TestConnection<'_>) {
+            connection
+                .schedule_connect(testing::connect_options("c"))
+                .unwrap();
+            assert_eq!(connection.poll_outgoing().unwrap()[0], 0x10);
+            assert!(connection.poll_outgoing().is_none());
+            connection
+                .handle_incoming(&[0x20, 0x02, 0x00, 0x00])
+                .unwrap();
+        }
+
+        let mut storage = testing::RamStorage::<128>::new();
+        let mut queue_buf = [0u8; 16];
+        let mut queue = PersistentQueue::new(
+            &mut storage,
+            &mut queue_buf,
+            crate::offline::Overflow::Reject,
+        )
+        .unwrap();
+
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let keep_alive = duration::Generic::new(10, Fraction::new(1, 1));
+        let mut connection: TestConnection<'_> =
+            Connection::new(at(0), keep_alive, &mut rx_buf, &mut tx_buf);
+        connection.set_persistent_queue(&mut queue);
+
+        for topic in ["a", "b"] {
+            connection
+                .schedule_publish(publish::Msg {
+                    qos: QoS::AtMostOnce,
+                    retain: false,
+                    topic,
+                    payload: b"x",
+                })
+                .unwrap();
+        }
+        connect(&mut connection);
+
+        // Sent with QoS 1, and the next only after the PUBACK
+        assert_eq!(
+            connection.poll_outgoing(),
+            Some(&[0x32, 0x06, 0x00, 0x01, b'a', 0x00, 0x01, b'x'][..])
+        );
+        assert!(connection.poll_outgoing().is_none());
+        connection
+            .handle_incoming(&[0x40, 0x02, 0x00, 0x01])
+            .unwrap();
+        assert_eq!(
+            connection.poll_outgoing(),
+            Some(&[0x32, 0x06, 0x00, 0x01, b'b', 0x00, 0x02, b'x'][..])
+        );
+
+        // Connection lost before the PUBACK, so "b" goes out again
+        assert!(
+            connection
+                .handle_incoming(&[0x20, 0x02, 0x00, 0x00])
+                .is_err()
+        );
+        connect(&mut connection);
+        assert_eq!(
+            connection.poll_outgoing(),
+            Some(&[0x32, 0x06, 0x00, 0x01, b'b', 0x00, 0x01, b'x'][..])
+        );
+        connection
+            .handle_incoming(&[0x40, 0x02, 0x00, 0x01])
+            .unwrap();
+        assert!(connection.poll_outgoing().is_none());
+
+        drop(connection);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn keep_alive_timeouts() {
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let mut connection = connected(&mut rx_buf, &mut tx_buf);
+
+        assert_eq!(connection.next_timeout(), Some(at(5_000)));
+
+        connection.handle_timeout(at(4_999)).unwrap();
+        assert!(connection.poll_outgoing().is_none());
+
+        connection.handle_timeout(at(5_000)).unwrap();
+        assert_eq!(connection.poll_outgoing(), Some(&[0xC0, 0x00][..]));
+        assert_eq!(connection.next_timeout(), Some(at(15_000)));
+
+        connection.handle_timeout(at(15_000)).unwrap();
+        assert_eq!(connection.poll_outgoing(), Some(&[0xE0, 0x00][..]));
+        assert_eq!(connection.next_timeout(), None);
+    }
+
+    #[test]
+    fn no_timers_before_connected() {
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let keep_alive = duration::Generic::new(10, Fraction::new(1, 1));
+        let mut connection: TestConnection<'_> =
+            Connection::new(at(0), keep_alive, &mut rx_buf, &mut tx_buf);
+
+        assert_eq!(connection.next_timeout(), None);
+        connection.handle_timeout(at(60_000)).unwrap();
+        assert!(connection.poll_outgoing().is_none());
+    }
+
+    #[test]
+    fn response_timeouts() {
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let keep_alive = duration::Generic::new(10, Fraction::new(1, 1));
+        let mut connection: TestConnection<'_> =
+            Connection::new(at(0), keep_alive, &mut rx_buf, &mut tx_buf);
+        connection.set_connect_timeout(duration::Generic::new(2, Fraction::new(1, 1)));
+        connection.set_subscribe_timeout(duration::Generic::new(3, Fraction::new(1, 1)));
+        let opts = || testing::connect_options("c");
+
+        connection.schedule_connect(opts()).unwrap();
+        assert_eq!(connection.next_timeout(), Some(at(2_000)));
+        assert!(connection.handle_timeout(at(1_999)).unwrap().is_none());
+        assert!(matches!(
+            connection.handle_timeout(at(2_000)),
+            Ok(Some(session::Event::ConnectTimeout))
+        ));
+        // The unsent CONNECT is dropped with the connection
+        assert!(connection.poll_outgoing().is_none());
+        assert_eq!(connection.next_timeout(), None);
+
+        connection.schedule_connect(opts()).unwrap();
+        assert_eq!(connection.poll_outgoing().unwrap()[0], 0x10);
+        connection
+            .handle_incoming(&[0x20, 0x02, 0x00, 0x00])
+            .unwrap();
+        connection
+            .schedule_subscribe(subscribe::Options {
+                qos: Some(QoS::AtMostOnce),
+                topic: "a",
+            })
+            .unwrap();
+        assert_eq!(connection.poll_outgoing().unwrap()[0], 0x82);
+        assert_eq!(connection.next_timeout(), Some(at(5_000)));
+
+        // Sent again with a new packet id
+        assert!(matches!(
+            connection.handle_timeout(at(5_000)),
+            Ok(Some(session::Event::SubscribeTimeout))
+        ));
+        let mut expected = [0u8; 16];
+        let len = testing::subscribe(2, "a", QoS::AtMostOnce)
+            .encode_to(&mut expected)
+            .unwrap();
+        assert_eq!(connection.poll_outgoing().unwrap(), &expected[..len]);
+
+        // Too late for the first SUBSCRIBE
+        assert!(matches!(
+            connection.handle_incoming(&[0x90, 0x03, 0x00, 0x01, 0x00]),
+            Ok((5, Some(session::Event::Warning(_))))
+        ));
+        assert!(matches!(
+            connection.handle_incoming(&[0x90, 0x03, 0x00, 0x02, 0x00]),
+            Ok((5, Some(session::Event::Subscribed { .. })))
+        ));
+        assert_eq!(connection.next_timeout(), Some(at(10_000)));
+    }
+
+    #[test]
+    fn requests_expire_together() {
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let mut connection = connected(&mut rx_buf, &mut tx_buf);
+        connection.set_subscribe_timeout(duration::Generic::new(3, Fraction::new(1, 1)));
+
+        for topic in ["a", "b"] {
+            connection
+                .schedule_subscribe(subscribe::Options {
+                    qos: Some(QoS::AtMostOnce),
+                    topic,
+                })
+                .unwrap();
+            assert_eq!(connection.poll_outgoing().unwrap()[0], 0x82);
+        }
+
+        // Both are sent again after a single call
+        assert!(matches!(
+            connection.handle_timeout(at(3_000)),
+            Ok(Some(session::Event::SubscribeTimeout))
+        ));
+        for (id, topic) in [(3, "a"), (4, "b")] {
+            let mut expected = [0u8; 16];
+            let len = testing::subscribe(id, topic, QoS::AtMostOnce)
+                .encode_to(&mut expected)
+                .unwrap();
+            assert_eq!(connection.poll_outgoing().unwrap(), &expected[..len]);
+        }
+        assert!(connection.handle_timeout(at(3_000)).unwrap().is_none());
+        assert_eq!(connection.next_timeout(), Some(at(6_000)));
+    }
+}
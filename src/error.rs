@@ -0,0 +1,364 @@
+use core::{convert::Infallible, fmt};
+
+use crate::{
+    packet::{PacketId, connect::ConnectReturnCode},
+    protocol::PacketType,
+    session::SessionState,
+};
+
+/// Error of any operation of the crate.
+///
+/// `E` is the transport's error type. Operations that don't touch the transport
+/// return `Error<Infallible>`, the default, which converts into any `Error<E>`
+/// with [`Error::widen`].
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E = Infallible> {
+    Decode(DecodeError),
+    Protocol(ProtocolError),
+    State(StateError),
+    Transport(TransportError<E>),
+    /// The transport reached end of stream.
+    RemoteClosed,
+    /// The broker refused the CONNECT. The session is disconnected.
+    ConnectionRefused(ConnectReturnCode),
+    /// A packet doesn't fit the buffer for it.
+    BufferTooSmall,
+    /// A fixed-capacity table is full.
+    VectorIsFull,
+    /// All `N_SUB` subscription slots are taken.
+    SubVectorIsFull,
+    NoPacketIdAvailable,
+    /// The packet is longer than a remaining length can express (256 MB).
+    PacketTooLarge,
+    /// The clock failed or went backwards.
+    TimeError,
+    /// A topic filter breaks the wildcard rules (4.7.1).
+    InvalidTopicFilter,
+    /// A topic filter is longer than the client's `MAX_FILTER_LEN`.
+    FilterTooLong,
+    /// A topic name to publish to is empty or contains wildcards (4.7.3).
+    InvalidTopicName,
+    /// An empty client id without a clean session (3.1.3-7).
+    InvalidClientId,
+    /// A string or binary field is longer than 65535 bytes (1.5.3).
+    StringTooLong,
+    /// A string contains U+0000 (1.5.3).
+    InvalidString,
+    /// The offline queue is full and rejects new messages.
+    QueueFull,
+    /// The storage of a persistent queue failed.
+    Storage,
+    /// A payload doesn't (de)serialize in the chosen format.
+    InvalidPayload,
+}
+
+impl<E> Error<E> {
+    /// Whether the connection is unusable after this error came out of receiving
+    /// or sending a packet. The session is already disconnected by then and needs
+    /// a new CONNECT.
+    ///
+    /// Recoverable protocol errors never leave the client: they're reported as
+    /// [`crate::Event::Warning`] instead.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Self::Protocol(err) => err.is_fatal(),
+            Self::State(_)
+            | Self::NoPacketIdAvailable
+            | Self::SubVectorIsFull
+            | Self::TimeError
+            | Self::InvalidTopicFilter
+            | Self::FilterTooLong
+            | Self::InvalidTopicName
+            | Self::InvalidClientId
+            | Self::StringTooLong
+            | Self::InvalidString
+            | Self::QueueFull
+            | Self::Storage
+            | Self::InvalidPayload => false,
+            _ => true,
+        }
+    }
+}
+
+impl Error {
+    /// Converts into the error type of an operation with transport `E`.
+    pub fn widen<E>(self) -> Error<E> {
+        match self {
+            Self::Decode(err) => Error::Decode(err),
+            Self::Protocol(err) => Error::Protocol(err),
+            Self::State(err) => Error::State(err),
+            Self::Transport(TransportError(never)) => match never {},
+            Self::RemoteClosed => Error::RemoteClosed,
+            Self::ConnectionRefused(code) => Error::ConnectionRefused(code),
+            Self::BufferTooSmall => Error::BufferTooSmall,
+            Self::VectorIsFull => Error::VectorIsFull,
+            Self::SubVectorIsFull => Error::SubVectorIsFull,
+            Self::NoPacketIdAvailable => Error::NoPacketIdAvailable,
+            Self::PacketTooLarge => Error::PacketTooLarge,
+            Self::TimeError => Error::TimeError,
+            Self::InvalidTopicFilter => Error::InvalidTopicFilter,
+            Self::FilterTooLong => Error::FilterTooLong,
+            Self::InvalidTopicName => Error::InvalidTopicName,
+            Self::InvalidClientId => Error::InvalidClientId,
+            Self::StringTooLong => Error::StringTooLong,
+            Self::InvalidString => Error::InvalidString,
+            Self::QueueFull => Error::QueueFull,
+            Self::Storage => Error::Storage,
+            Self::InvalidPayload => Error::InvalidPayload,
+        }
+    }
+}
+
+impl<E> From<DecodeError> for Error<E> {
+    fn from(err: DecodeError) -> Self {
+        Self::Decode(err)
+    }
+}
+
+impl<E> From<ProtocolError> for Error<E> {
+    fn from(err: ProtocolError) -> Self {
+        Self::Protocol(err)
+    }
+}
+
+impl<E> From<StateError> for Error<E> {
+    fn from(err: StateError) -> Self {
+        Self::State(err)
+    }
+}
+
+impl<E> From<TransportError<E>> for Error<E> {
+    fn from(err: TransportError<E>) -> Self {
+        Self::Transport(err)
+    }
+}
+
+impl<E: embedded_io::Error> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Decode(err) => write!(f, "malformed packet: {err}"),
+            Self::Protocol(err) => write!(f, "protocol violation: {err}"),
+            Self::State(err) => write!(f, "invalid call: {err}"),
+            Self::Transport(err) => write!(f, "{err}"),
+            Self::RemoteClosed => f.write_str("connection closed by peer"),
+            Self::ConnectionRefused(code) => write!(f, "connection refused: {code:?}"),
+            Self::BufferTooSmall => f.write_str("buffer too small"),
+            Self::VectorIsFull => f.write_str("table full"),
+            Self::SubVectorIsFull => f.write_str("subscription table full"),
+            Self::NoPacketIdAvailable => f.write_str("no packet id available"),
+            Self::PacketTooLarge => f.write_str("packet too large"),
+            Self::TimeError => f.write_str("clock error"),
+            Self::InvalidTopicFilter => f.write_str("invalid topic filter"),
+            Self::FilterTooLong => f.write_str("topic filter too long"),
+            Self::InvalidTopicName => f.write_str("invalid topic name"),
+            Self::InvalidClientId => f.write_str("empty client id needs a clean session"),
+            Self::StringTooLong => f.write_str("string longer than 65535 bytes"),
+            Self::InvalidString => f.write_str("U+0000 in string"),
+            Self::QueueFull => f.write_str("offline queue full"),
+            Self::Storage => f.write_str("storage error"),
+            Self::InvalidPayload => f.write_str("invalid payload"),
+        }
+    }
+}
+
+impl<E: embedded_io::Error> core::error::Error for Error<E> {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            Self::Decode(err) => Some(err),
+            Self::Protocol(err) => Some(err),
+            Self::State(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Bytes that aren't a valid MQTT 3.1.1 packet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecodeError {
+    InvalidPacketType,
+    /// Reserved fixed header flags don't have their required value.
+    InvalidFlags,
+    MalformedRemainingLength,
+    InvalidQoS,
+    InvalidConnectReturnCode,
+    InvalidUtf8,
+    /// A UTF-8 string contains U+0000 (1.5.3).
+    NullCharacter,
+    /// A PUBLISH topic name is empty or contains wildcards (4.7.3).
+    InvalidTopicName,
+    /// The body ends in the middle of a field.
+    UnexpectedEof,
+    /// Any other violation of the packet format, e.g. bytes left over after the body.
+    MalformedPacket,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidPacketType => "invalid packet type",
+            Self::InvalidFlags => "invalid fixed header flags",
+            Self::MalformedRemainingLength => "malformed remaining length",
+            Self::InvalidQoS => "invalid QoS",
+            Self::InvalidConnectReturnCode => "invalid CONNACK return code",
+            Self::InvalidUtf8 => "invalid UTF-8 string",
+            Self::NullCharacter => "U+0000 in UTF-8 string",
+            Self::InvalidTopicName => "invalid topic name",
+            Self::UnexpectedEof => "unexpected end of packet",
+            Self::MalformedPacket => "invalid packet format",
+        })
+    }
+}
+
+impl core::error::Error for DecodeError {}
+
+/// The peer sent a well-formed packet the protocol doesn't allow at this point.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ProtocolError {
+    pub packet_type: PacketType,
+    pub packet_id: Option<PacketId>,
+    pub kind: ProtocolErrorKind,
+    /// State the session has to be in for the packet, if that was the problem.
+    pub expected_state: Option<SessionState>,
+}
+
+impl ProtocolError {
+    pub(crate) fn new(
+        packet_type: PacketType,
+        packet_id: Option<PacketId>,
+        kind: ProtocolErrorKind,
+    ) -> Self {
+        Self {
+            packet_type,
+            packet_id,
+            kind,
+            expected_state: None,
+        }
+    }
+
+    /// Whether the session can't be trusted any more. Stale packet ids and
+    /// publishes racing an UNSUBSCRIBE happen with well-behaved brokers too, a
+    /// QoS above the granted one only matters in strict mode, and a message
+    /// without room to wait for its manual ack or in the inbox is simply sent
+    /// again later.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(
+            self.kind,
+            ProtocolErrorKind::UnknownPacketId
+                | ProtocolErrorKind::NotSubscribed
+                | ProtocolErrorKind::QoSNotGranted
+                | ProtocolErrorKind::TooManyUnacked
+                | ProtocolErrorKind::InboxFull
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ProtocolErrorKind {
+    /// Not valid in the session's state, e.g. PUBACK before CONNACK.
+    UnexpectedPacket,
+    /// Nothing in flight has the packet id.
+    UnknownPacketId,
+    /// The packet id is in flight but waits for another packet, e.g. PUBCOMP before PUBREC.
+    OutOfOrder,
+    /// PUBLISH on a topic without an active subscription.
+    NotSubscribed,
+    /// Required packet id missing, or DUP set on QoS 0.
+    InvalidPublish,
+    /// PUBLISH with a higher QoS than the subscription was granted, in strict mode.
+    QoSNotGranted,
+    /// QoS 1 or 2 PUBLISH while `N_PUB_IN` messages wait for a manual ack. It's
+    /// dropped unacknowledged, so the broker sends it again after reconnecting.
+    TooManyUnacked,
+    /// PUBLISH that doesn't fit an inbox with [`crate::offline::Overflow::Reject`],
+    /// as it's full or the message is bigger than all of it. It's dropped
+    /// unacknowledged, like [`Self::TooManyUnacked`].
+    InboxFull,
+    /// Valid MQTT this side doesn't handle, e.g. a SUBACK for several filters.
+    Unsupported,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.kind {
+            ProtocolErrorKind::UnexpectedPacket => "unexpected",
+            ProtocolErrorKind::UnknownPacketId => "unknown packet id in",
+            ProtocolErrorKind::OutOfOrder => "out of order",
+            ProtocolErrorKind::NotSubscribed => "topic not subscribed in",
+            ProtocolErrorKind::InvalidPublish => "invalid",
+            ProtocolErrorKind::QoSNotGranted => "QoS above granted in",
+            ProtocolErrorKind::TooManyUnacked => "no room to hold back",
+            ProtocolErrorKind::InboxFull => "no room in the inbox for",
+            ProtocolErrorKind::Unsupported => "unsupported",
+        })?;
+        write!(f, " {}", self.packet_type)?;
+
+        if let Some(id) = self.packet_id {
+            write!(f, " (packet id {})", id.get())?;
+        }
+
+        match self.expected_state {
+            Some(state) => write!(f, ", session not {state:?}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl core::error::Error for ProtocolError {}
+
+/// The call isn't valid in the session's current state.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StateError {
+    WrongState {
+        expected: SessionState,
+        actual: SessionState,
+    },
+    /// No subscription with this topic filter.
+    NotSubscribed,
+    /// The subscription waits for its SUBACK or UNSUBACK.
+    SubscriptionPending,
+    /// Subscribed to the filter with another QoS already. Unsubscribe first to
+    /// change it.
+    QoSMismatch,
+    /// The message was acknowledged already, or was lost with the connection.
+    NotAwaitingAck,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WrongState { expected, actual } => {
+                write!(f, "session is {actual:?}, needs to be {expected:?}")
+            }
+            Self::NotSubscribed => f.write_str("not subscribed to topic"),
+            Self::SubscriptionPending => f.write_str("subscription change pending"),
+            Self::QoSMismatch => f.write_str("subscribed with another QoS"),
+            Self::NotAwaitingAck => f.write_str("message not awaiting acknowledgement"),
+        }
+    }
+}
+
+impl core::error::Error for StateError {}
+
+/// Error of the underlying transport, e.g. a socket or TLS error.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TransportError<E>(pub E);
+
+impl<E: embedded_io::Error> TransportError<E> {
+    pub fn kind(&self) -> embedded_io::ErrorKind {
+        self.0.kind()
+    }
+}
+
+impl<E: embedded_io::Error> fmt::Display for TransportError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "transport error: {:?}", self.kind())
+    }
+}
+
+impl<E: embedded_io::Error> core::error::Error for TransportError<E> {}
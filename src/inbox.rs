@@ -0,0 +1,254 @@
+//! Buffering of received messages, so that handling them doesn't borrow the
+//! receive buffer.
+//!
+//! Hand an [`Inbox`] to [`crate::Connection::set_inbox`], through
+//! [`crate::Client::connection_mut`] with a client. Received publishes are then
+//! copied into it instead of being reported as [`crate::Event::Received`], and
+//! the application takes them out through [`crate::Connection::inbox`] whenever
+//! it gets to them, polling in between as often as it likes.
+
+use crate::{
+    offline::Overflow,
+    packet::{PacketId, QoS, publish},
+};
+
+/// A message copied out of the receive buffer.
+pub struct ReceivedMsg<'a> {
+    pub qos: QoS,
+    pub retain: bool,
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    packet_id: Option<PacketId>,
+}
+
+impl ReceivedMsg<'_> {
+    /// Token to acknowledge the message with in manual acknowledgement mode,
+    /// `None` for QoS 0.
+    pub fn ack_token(&self) -> Option<publish::AckToken> {
+        self.packet_id.map(publish::AckToken)
+    }
+}
+
+// Flags, packet id (0 without one), topic length and payload length, followed
+// by the topic and the payload
+const RECORD_HEADER_LEN: usize = 1 + 2 + 2 + 4;
+
+/// FIFO of received messages, stored back to back in a caller-provided buffer.
+///
+/// With [`Overflow::Reject`], a message that doesn't fit is reported as a
+/// [`crate::ProtocolErrorKind::InboxFull`] warning and isn't acknowledged, so the
+/// broker sends QoS 1 and 2 messages again after reconnecting.
+pub struct Inbox<'q> {
+    buf: &'q mut [u8],
+    start: usize,
+    end: usize,
+    overflow: Overflow,
+    dropped: usize,
+}
+
+impl<'q> Inbox<'q> {
+    pub fn new(buf: &'q mut [u8], overflow: Overflow) -> Self {
+        Self {
+            buf,
+            start: 0,
+            end: 0,
+            overflow,
+            dropped: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Number of messages dropped by the overflow policy so far.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Oldest message.
+    pub fn front(&self) -> Option<ReceivedMsg<'_>> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let header = &self.buf[self.start..self.start + RECORD_HEADER_LEN];
+        let packet_id = u16::from_be_bytes([header[1], header[2]]);
+        let topic_len = usize::from(u16::from_be_bytes([header[3], header[4]]));
+        let body = &self.buf[self.start + RECORD_HEADER_LEN..self.start + self.record_len()];
+        let (topic, payload) = body.split_at(topic_len);
+
+        Some(ReceivedMsg {
+            // Only ever written from a `QoS` and a `&str`
+            qos: QoS::try_from((header[0] >> 1) & 0b11).ok()?,
+            retain: header[0] & 1 != 0,
+            topic: core::str::from_utf8(topic).ok()?,
+            payload,
+            packet_id: PacketId::try_from(packet_id).ok(),
+        })
+    }
+
+    pub fn pop_front(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+
+        self.start += self.record_len();
+        if self.is_empty() {
+            self.start = 0;
+            self.end = 0;
+        }
+    }
+
+    /// Fails as [`Self::push`] would under [`Overflow::Reject`], before the
+    /// session has seen the message.
+    pub(crate) fn check(&self, publish: &publish::Publish<'_>) -> Result<(), crate::Error> {
+        if self.overflow != Overflow::Reject {
+            return Ok(());
+        }
+
+        let len =
+            RECORD_HEADER_LEN + publish.topic.as_str()?.len() + publish.payload.as_bytes().len();
+        if len > self.buf.len() {
+            Err(crate::Error::BufferTooSmall)
+        } else if self.end - self.start + len > self.buf.len() {
+            Err(crate::Error::QueueFull)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn push(&mut self, publish: &publish::Publish<'_>) -> Result<(), crate::Error> {
+        let topic = publish.topic.as_str()?.as_bytes();
+        let payload = publish.payload.as_bytes();
+        let len = RECORD_HEADER_LEN + topic.len() + payload.len();
+        let topic_len = u16::try_from(topic.len()).map_err(|_| crate::Error::BufferTooSmall)?;
+        let payload_len = u32::try_from(payload.len()).map_err(|_| crate::Error::BufferTooSmall)?;
+
+        if len > self.buf.len() {
+            return self.overflow(len);
+        }
+
+        if self.end - self.start + len > self.buf.len() {
+            match self.overflow {
+                Overflow::DropOldest => {
+                    while self.end - self.start + len > self.buf.len() {
+                        self.pop_front();
+                        self.dropped += 1;
+                    }
+                }
+                Overflow::DropNewest | Overflow::Reject => return self.overflow(len),
+            }
+        }
+
+        if self.end + len > self.buf.len() {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+
+        let record = &mut self.buf[self.end..self.end + len];
+        let (header, body) = record.split_at_mut(RECORD_HEADER_LEN);
+        header[0] = (publish.flags.qos as u8) << 1 | publish.flags.retain as u8;
+        header[1..3].copy_from_slice(&publish.packet_id.map_or(0, PacketId::get).to_be_bytes());
+        header[3..5].copy_from_slice(&topic_len.to_be_bytes());
+        header[5..].copy_from_slice(&payload_len.to_be_bytes());
+        let (topic_dst, payload_dst) = body.split_at_mut(topic.len());
+        topic_dst.copy_from_slice(topic);
+        payload_dst.copy_from_slice(payload);
+
+        self.end += len;
+
+        Ok(())
+    }
+
+    /// Drops a message of `len` bytes that doesn't fit, or rejects it.
+    fn overflow(&mut self, len: usize) -> Result<(), crate::Error> {
+        match self.overflow {
+            Overflow::Reject if len > self.buf.len() => Err(crate::Error::BufferTooSmall),
+            Overflow::Reject => Err(crate::Error::QueueFull),
+            Overflow::DropOldest | Overflow::DropNewest => {
+                self.dropped += 1;
+                Ok(())
+            }
+        }
+    }
+
+    fn record_len(&self) -> usize {
+        let header = &self.buf[self.start..self.start + RECORD_HEADER_LEN];
+        let topic_len = u16::from_be_bytes([header[3], header[4]]);
+        let payload_len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+
+        RECORD_HEADER_LEN + usize::from(topic_len) + payload_len as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publish<'a>(topic: &'a str, payload: &'a [u8], packet_id: u16) -> publish::Publish<'a> {
+        let mut publish = publish::Publish::from(publish::Msg {
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic,
+            payload,
+        });
+        publish.packet_id = PacketId::try_from(packet_id).ok();
+
+        publish
+    }
+
+    fn pop(inbox: &mut Inbox<'_>) -> Option<heapless::String<4>> {
+        let topic = inbox.front()?.topic.try_into().unwrap();
+        inbox.pop_front();
+
+        Some(topic)
+    }
+
+    #[test]
+    fn fifo_with_ack_tokens() {
+        // Room for two 12 byte records
+        let mut buf = [0u8; 30];
+        let mut inbox = Inbox::new(&mut buf, Overflow::DropOldest);
+
+        inbox.push(&publish("a", b"12", 1)).unwrap();
+        inbox.push(&publish("b", b"34", 2)).unwrap();
+        assert_eq!(pop(&mut inbox).unwrap(), "a");
+        inbox.push(&publish("c", b"56", 3)).unwrap();
+        assert_eq!(inbox.dropped(), 0);
+
+        let msg = inbox.front().unwrap();
+        assert_eq!(
+            (msg.topic, msg.payload, msg.qos),
+            ("b", &b"34"[..], QoS::AtLeastOnce)
+        );
+        assert_eq!(msg.ack_token(), Some(publish::AckToken(PacketId(2))));
+        inbox.pop_front();
+
+        // Overflowing drops "c"
+        inbox.push(&publish("d", b"78", 4)).unwrap();
+        inbox.push(&publish("e", b"90", 5)).unwrap();
+        assert_eq!(inbox.dropped(), 1);
+        assert_eq!(pop(&mut inbox).unwrap(), "d");
+        assert_eq!(pop(&mut inbox).unwrap(), "e");
+        assert!(inbox.is_empty());
+    }
+
+    #[test]
+    fn rejects_when_full() {
+        let mut buf = [0u8; 12];
+        let mut inbox = Inbox::new(&mut buf, Overflow::Reject);
+
+        inbox.push(&publish("a", b"12", 1)).unwrap();
+        assert!(matches!(
+            inbox.push(&publish("b", b"12", 2)),
+            Err(crate::Error::QueueFull)
+        ));
+        assert!(matches!(
+            inbox.push(&publish("b", &[0; 20], 2)),
+            Err(crate::Error::BufferTooSmall)
+        ));
+        assert_eq!(pop(&mut inbox).unwrap(), "a");
+    }
+}
@@ -1,9 +1,14 @@
 use heapless::Vec;
 
-use crate::packet::PacketId;
-
-#[derive(PartialEq)]
-enum PubInState {
+use crate::{
+    ProtocolErrorKind,
+    packet::{PacketId, QoS},
+};
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum PubInState {
+    /// Delivered in manual acknowledgement mode, the application hasn't acked it yet.
+    AwaitAck(QoS),
     AwaitPubRel,
     Done,
 }
@@ -31,21 +36,32 @@ impl<const N_PUB_IN: usize> Publish<N_PUB_IN> {
         self.cursor = 0;
     }
 
+    /// Starts tracking a QoS 1 or 2 PUBLISH. Returns the state of an earlier
+    /// PUBLISH with `packet_id` if this is a duplicate of it.
+    ///
+    /// Messages held back for `manual_ack` count against `N_PUB_IN` until acked.
     pub(crate) fn track(
         &mut self,
         packet_id: &PacketId,
-        just_ack: bool,
-    ) -> Result<(), crate::Error> {
-        if self.pubs.iter().any(|p| p.id == *packet_id) {
-            return Ok(());
-        }
-
-        let state = if just_ack {
-            PubInState::Done
-        } else {
-            PubInState::AwaitPubRel
+        qos: QoS,
+        manual_ack: bool,
+    ) -> Result<Option<PubInState>, crate::Error> {
+        let state = match (manual_ack, qos) {
+            (true, _) => PubInState::AwaitAck(qos),
+            (false, QoS::ExactlyOnce) => PubInState::AwaitPubRel,
+            (false, _) => PubInState::Done,
         };
 
+        // A finished id may be reused by the broker for the next message
+        if let Some(existing) = self.pubs.iter_mut().find(|p| p.id == *packet_id) {
+            if existing.state != PubInState::Done {
+                return Ok(Some(existing.state));
+            }
+
+            existing.state = state;
+            return Ok(None);
+        }
+
         let entry = PubInFlightIn {
             id: *packet_id,
             state,
@@ -55,14 +71,14 @@ impl<const N_PUB_IN: usize> Publish<N_PUB_IN> {
             self.pubs
                 .push(entry)
                 .map_err(|_| crate::Error::VectorIsFull)?;
-            return Ok(());
+            return Ok(None);
         }
 
         for _ in 0..self.pubs.len() {
             if self.pubs[self.cursor].state == PubInState::Done {
                 self.pubs[self.cursor] = entry;
                 self.shift_cursor();
-                return Ok(());
+                return Ok(None);
             }
 
             self.shift_cursor();
@@ -75,17 +91,36 @@ impl<const N_PUB_IN: usize> Publish<N_PUB_IN> {
         self.cursor = (self.cursor + 1) % N_PUB_IN;
     }
 
-    pub(crate) fn mark_complete(&mut self, packet_id: &PacketId) -> Result<(), crate::Error> {
+    pub(crate) fn mark_complete(&mut self, packet_id: &PacketId) -> Result<(), ProtocolErrorKind> {
         let entry = self
             .pubs
             .iter_mut()
             .find(|p| p.id == *packet_id)
-            .ok_or(crate::Error::ProtocolViolation)?;
+            .ok_or(ProtocolErrorKind::UnknownPacketId)?;
 
-        if entry.state == PubInState::AwaitPubRel {
-            entry.state = PubInState::Done;
+        match entry.state {
+            PubInState::AwaitPubRel => entry.state = PubInState::Done,
+            // PUBREC hasn't been sent yet
+            PubInState::AwaitAck(_) => return Err(ProtocolErrorKind::OutOfOrder),
+            PubInState::Done => {}
         }
 
         Ok(())
     }
+
+    /// Acknowledges a message held back for manual acknowledgement. Returns
+    /// its QoS, or `None` if it doesn't wait for that.
+    pub(crate) fn ack(&mut self, packet_id: &PacketId) -> Option<QoS> {
+        let entry = self.pubs.iter_mut().find(|p| p.id == *packet_id)?;
+        let PubInState::AwaitAck(qos) = entry.state else {
+            return None;
+        };
+
+        entry.state = match qos {
+            QoS::ExactlyOnce => PubInState::AwaitPubRel,
+            _ => PubInState::Done,
+        };
+
+        Some(qos)
+    }
 }
@@ -1,4 +1,4 @@
-use embedded_time::{Instant, duration, rate};
+use embedded_time::{Instant, TimeInt, duration, rate};
 
 pub(crate) struct KeepAlive<C: embedded_time::Clock> {
     keep_alive: duration::Generic<C::T>,
@@ -12,10 +12,7 @@ impl<C> KeepAlive<C>
 where
     C: embedded_time::Clock,
 {
-    pub(crate) fn try_new(
-        clock: &C,
-        keep_alive: duration::Generic<C::T>,
-    ) -> Result<Self, crate::Error> {
+    pub(crate) fn new(now: Instant<C>, keep_alive: duration::Generic<C::T>) -> Self {
         let enabled = keep_alive.integer() != 0u32.into();
         let half_keep_alive = if enabled {
             duration::Generic::new(
@@ -26,13 +23,13 @@ where
             keep_alive
         };
 
-        Ok(Self {
+        Self {
             keep_alive,
             half_keep_alive,
-            last_activity: clock.try_now().map_err(|_| crate::Error::TimeError)?,
+            last_activity: now,
             ping_outstanding: false,
             enabled,
-        })
+        }
     }
 
     pub(crate) fn on_send(&mut self, now: Instant<C>) {
@@ -65,8 +62,38 @@ where
         Ok(self.elapsed(now)? >= self.keep_alive)
     }
 
+    /// Instant at which [`Self::should_ping`] or [`Self::timed_out`] flips next.
+    pub(crate) fn next_deadline(&self) -> Option<Instant<C>> {
+        if !self.enabled {
+            return None;
+        }
+
+        if self.ping_outstanding {
+            after(self.last_activity, self.keep_alive)
+        } else {
+            after(self.last_activity, self.half_keep_alive)
+        }
+    }
+
     fn elapsed(&self, now: Instant<C>) -> Result<duration::Generic<C::T>, crate::Error> {
         now.checked_duration_since(&self.last_activity)
             .ok_or(crate::Error::TimeError)
     }
 }
+
+pub(crate) fn after<C: embedded_time::Clock>(
+    instant: Instant<C>,
+    duration: duration::Generic<C::T>,
+) -> Option<Instant<C>> {
+    let ticks = duration
+        .integer()
+        .checked_mul_fraction(&(*duration.scaling_factor() / C::SCALING_FACTOR))?;
+    let since_epoch = instant.duration_since_epoch().integer();
+
+    Some(Instant::new(wrapping_add(since_epoch, ticks)))
+}
+
+// `TimeInt`'s supertraits aren't visible through `Clock::T`, only through a type parameter.
+fn wrapping_add<T: TimeInt>(a: T, b: T) -> T {
+    a.wrapping_add(&b)
+}
@@ -1,48 +1,50 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 
+pub mod blocking;
+pub mod broker;
 pub mod buffer;
 pub mod client;
+pub mod connection;
+pub(crate) mod error;
+pub mod inbox;
 pub(crate) mod incoming;
 pub(crate) mod keep_alive;
+#[cfg(feature = "tokio")]
+pub mod net;
+pub mod offline;
+pub(crate) mod outbox;
 pub mod packet;
 pub(crate) mod packet_id_pool;
 pub mod parser;
+#[cfg(any(feature = "serde-json-core", feature = "postcard"))]
+pub mod payload;
+pub mod persistent;
 pub mod protocol;
+pub(crate) mod response_timer;
+pub mod router;
 pub(crate) mod session;
-#[cfg(feature = "embassy")]
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(any(feature = "embassy", feature = "std"))]
 pub mod time;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub(crate) mod topic;
+#[cfg(feature = "ws")]
+pub mod ws;
 
 pub use client::Client;
+pub use connection::Connection;
+pub use error::{DecodeError, Error, ProtocolError, ProtocolErrorKind, StateError, TransportError};
+pub use inbox::{Inbox, ReceivedMsg};
+pub use offline::OfflineQueue;
+pub use packet::Packet;
 pub use packet::QoS;
 pub use packet::connect::Options as ConnectOptions;
+pub use packet::publish::AckToken;
 pub use packet::publish::Msg as PublishMsg;
 pub use packet::subscribe::Options as SubscribeOptions;
-pub use session::Event;
-
-#[derive(Debug)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub enum Error {
-    InvalidFlags,
-    MalformedRemainingLength,
-    InvalidPacketType,
-    MalformedPacket,
-    InvalidConnectReturnCode,
-    VectorIsFull,
-    InvalidQoS,
-    InvalidUtf8,
-    EncodeNotImplemented,
-    UnexpectedEof,
-    TransportError,
-    RemoteClosed,
-    UnsupportedIncomingPacket,
-    BufferTooSmall,
-    TimeError,
-    TimedOut,
-    ProtocolViolation,
-    NoPacketIdAvailable,
-    SubVectorIsFull,
-    WrongTopicToUnsubscribe,
-    Unsubscribed,
-    PingOutstanding,
-    QueueRangeError,
-}
+pub use parser::Decoder;
+pub use persistent::PersistentQueue;
+pub use router::Router;
+pub use session::{Event, SessionState};
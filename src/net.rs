@@ -0,0 +1,258 @@
+//! Tokio adapters for running the client on a host, e.g. in gateways and test harnesses.
+
+use std::time::Duration;
+
+use embedded_io_async::{ErrorType, Read, Write};
+use embedded_time::{duration, rate::Fraction};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, ToSocketAddrs},
+    time::Instant,
+};
+
+use crate::{Client, TransportError, packet::connect, session, time::StdClock};
+
+/// `embedded_io_async` transport over any tokio stream, [`TcpStream`] by default.
+pub struct TokioTransport<S = TcpStream> {
+    inner: S,
+}
+
+impl<S> TokioTransport<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S> ErrorType for TokioTransport<S> {
+    type Error = std::io::Error;
+}
+
+impl<S: AsyncRead + Unpin> Read for TokioTransport<S> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.inner.read(buf).await
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Write for TokioTransport<S> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.inner.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush().await
+    }
+}
+
+impl<
+    'c,
+    const N_PUB_IN: usize,
+    const N_PUB_OUT: usize,
+    const N_SUB: usize,
+    const OUT_Q: usize,
+    const MAX_FILTER_LEN: usize,
+> Client<'c, StdClock, TokioTransport, N_PUB_IN, N_PUB_OUT, N_SUB, OUT_Q, MAX_FILTER_LEN>
+{
+    /// Opens a TCP connection to `addr`, sends CONNECT and waits for CONNACK,
+    /// giving up after `timeout` altogether. The keep-alive interval is taken
+    /// from `opts`.
+    ///
+    /// A refused CONNECT fails with [`crate::Error::ConnectionRefused`], running
+    /// out of time with a [`std::io::ErrorKind::TimedOut`] transport error.
+    pub async fn connect_tcp<A: ToSocketAddrs>(
+        addr: A,
+        opts: connect::Options<'_>,
+        timeout: Duration,
+        rx_buf: &'c mut [u8],
+        tx_buf: &'c mut [u8],
+    ) -> Result<Self, crate::Error<std::io::Error>> {
+        let timed_out = || TransportError(std::io::Error::from(std::io::ErrorKind::TimedOut));
+        let deadline = Instant::now() + timeout;
+
+        let stream = tokio::time::timeout_at(deadline, TcpStream::connect(addr))
+            .await
+            .map_err(|_| timed_out())?
+            .map_err(TransportError)?;
+        stream.set_nodelay(true).map_err(TransportError)?;
+
+        let keep_alive = duration::Generic::new(u64::from(opts.keep_alive), Fraction::new(1, 1));
+        let mut client = Self::try_new(
+            StdClock::default(),
+            keep_alive,
+            TokioTransport::new(stream),
+            rx_buf,
+            tx_buf,
+        )
+        .map_err(crate::Error::widen)?;
+
+        // The session gives up on CONNACK by itself, too. Its timers compare whole
+        // seconds, so round up and let the deadline above cut it short.
+        let left = deadline.saturating_duration_since(Instant::now());
+        client
+            .connection_mut()
+            .set_connect_timeout(duration::Generic::new(
+                left.as_millis().div_ceil(1_000) as u64,
+                Fraction::new(1, 1),
+            ));
+        client.schedule_connect(opts).map_err(crate::Error::widen)?;
+
+        loop {
+            let event = tokio::time::timeout_at(deadline, client.poll())
+                .await
+                .map_err(|_| timed_out())??;
+
+            match event {
+                Some(session::Event::Connected) => break,
+                Some(session::Event::ConnectTimeout) => return Err(timed_out().into()),
+                _ => {}
+            }
+        }
+
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{Decoder, QoS, packet::Packet, packet::publish, testing};
+
+    /// Reads from `stream` until `decoder` yields a packet, then checks it with `check`.
+    async fn expect_packet(
+        stream: &mut TcpStream,
+        decoder: &mut Decoder<'_>,
+        check: impl FnOnce(Packet<'_>),
+    ) {
+        let mut buf = [0u8; 64];
+        let mut len = 0;
+
+        loop {
+            len += stream.read(&mut buf[len..]).await.unwrap();
+
+            let (consumed, packet) = decoder.feed(&buf[..len]).unwrap();
+            assert_eq!(consumed, len);
+
+            if let Some(packet) = packet {
+                return check(packet);
+            }
+            len = 0;
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_and_publish_to_local_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let broker = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            let mut decoder = Decoder::new(&mut buf);
+
+            expect_packet(&mut stream, &mut decoder, |packet| {
+                assert!(matches!(packet, Packet::Connect(_)));
+            })
+            .await;
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+
+            expect_packet(&mut stream, &mut decoder, |packet| match packet {
+                Packet::Publish(publish) => {
+                    assert_eq!(publish.topic, "sensors/t");
+                    assert_eq!(publish.payload, b"21.5".as_slice());
+                }
+                _ => panic!("Expected Publish"),
+            })
+            .await;
+        });
+
+        let (mut rx_buf, mut tx_buf) = ([0u8; 128], [0u8; 128]);
+        let opts = connect::Options {
+            keep_alive: 30,
+            ..testing::connect_options("gateway")
+        };
+        let mut client: Client<'_, _, _, 1, 1, 1, 4> =
+            Client::connect_tcp(addr, opts, Duration::from_secs(5), &mut rx_buf, &mut tx_buf)
+                .await
+                .unwrap();
+
+        client
+            .schedule_publish(publish::Msg {
+                qos: QoS::AtMostOnce,
+                retain: false,
+                topic: "sensors/t",
+                payload: b"21.5",
+            })
+            .unwrap();
+        client.poll_io().await.unwrap();
+
+        broker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_fails_on_refusal_and_silence() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let broker = tokio::spawn(async move {
+            // Refuses the first client, ignores the second
+            let (mut refused, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            let mut decoder = Decoder::new(&mut buf);
+            expect_packet(&mut refused, &mut decoder, |packet| {
+                assert!(matches!(packet, Packet::Connect(_)));
+            })
+            .await;
+            refused.write_all(&[0x20, 0x02, 0x00, 0x05]).await.unwrap();
+
+            let (ignored, _) = listener.accept().await.unwrap();
+            (refused, ignored)
+        });
+
+        let opts = || connect::Options {
+            keep_alive: 30,
+            ..testing::connect_options("gateway")
+        };
+        let (mut rx_buf, mut tx_buf) = ([0u8; 128], [0u8; 128]);
+        let refused: Result<Client<'_, _, _, 1, 1, 1, 4>, _> = Client::connect_tcp(
+            addr,
+            opts(),
+            Duration::from_secs(5),
+            &mut rx_buf,
+            &mut tx_buf,
+        )
+        .await;
+        assert!(matches!(
+            refused,
+            Err(crate::Error::ConnectionRefused(
+                connect::ConnectReturnCode::NotAuthorized
+            ))
+        ));
+
+        let (mut rx_buf, mut tx_buf) = ([0u8; 128], [0u8; 128]);
+        let timeout = Duration::from_millis(50);
+        let silent: Result<Client<'_, _, _, 1, 1, 1, 4>, _> =
+            Client::connect_tcp(addr, opts(), timeout, &mut rx_buf, &mut tx_buf).await;
+        let Err(crate::Error::Transport(err)) = silent else {
+            panic!("Expected a transport error")
+        };
+        assert_eq!(err.0.kind(), std::io::ErrorKind::TimedOut);
+
+        broker.await.unwrap();
+    }
+
+    #[test]
+    fn std_clock_is_monotonic() {
+        use embedded_time::Clock;
+
+        let clock = StdClock::default();
+        let first = clock.try_now().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1));
+
+        assert!(clock.try_now().unwrap() > first);
+    }
+}
@@ -0,0 +1,240 @@
+//! Store-and-forward of publishes scheduled while the connection is down.
+//!
+//! Hand an [`OfflineQueue`] to [`crate::Connection::set_offline_queue`], through
+//! [`crate::Client::connection_mut`] with a client. While the session isn't
+//! connected, [`crate::Connection::schedule_publish`] copies messages into the
+//! queue instead of failing, and they go out in order once CONNACK has arrived.
+
+use crate::packet::{QoS, publish};
+
+/// What to do with a message that doesn't fit the queue any more.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Overflow {
+    /// Drop the oldest messages until the new one fits.
+    #[default]
+    DropOldest,
+    /// Drop the new message.
+    DropNewest,
+    /// Fail with [`crate::Error::QueueFull`].
+    Reject,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Options {
+    pub overflow: Overflow,
+    /// Don't queue QoS 0 messages: scheduling them offline fails as without a queue.
+    pub exclude_qos0: bool,
+}
+
+// Flags, topic length and payload length, followed by the topic and the payload
+const RECORD_HEADER_LEN: usize = 1 + 2 + 4;
+
+/// FIFO of messages, stored back to back in a caller-provided buffer.
+pub struct OfflineQueue<'q> {
+    buf: &'q mut [u8],
+    start: usize,
+    end: usize,
+    options: Options,
+    dropped: usize,
+}
+
+impl<'q> OfflineQueue<'q> {
+    pub fn new(buf: &'q mut [u8], options: Options) -> Self {
+        Self {
+            buf,
+            start: 0,
+            end: 0,
+            options,
+            dropped: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Number of messages dropped by the overflow policy so far.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    pub(crate) fn accepts(&self, msg: &publish::Msg<'_>) -> bool {
+        !(self.options.exclude_qos0 && msg.qos == QoS::AtMostOnce)
+    }
+
+    pub(crate) fn push(&mut self, msg: &publish::Msg<'_>) -> Result<(), crate::Error> {
+        let len = RECORD_HEADER_LEN + msg.topic.len() + msg.payload.len();
+        let topic_len = u16::try_from(msg.topic.len()).map_err(|_| crate::Error::BufferTooSmall)?;
+        let payload_len =
+            u32::try_from(msg.payload.len()).map_err(|_| crate::Error::BufferTooSmall)?;
+
+        if len > self.buf.len() {
+            return Err(crate::Error::BufferTooSmall);
+        }
+
+        if self.end - self.start + len > self.buf.len() {
+            match self.options.overflow {
+                Overflow::DropOldest => {
+                    while self.end - self.start + len > self.buf.len() {
+                        self.pop_front();
+                        self.dropped += 1;
+                    }
+                }
+                Overflow::DropNewest => {
+                    self.dropped += 1;
+                    return Ok(());
+                }
+                Overflow::Reject => return Err(crate::Error::QueueFull),
+            }
+        }
+
+        if self.end + len > self.buf.len() {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+
+        let record = &mut self.buf[self.end..self.end + len];
+        let (header, body) = record.split_at_mut(RECORD_HEADER_LEN);
+        header[0] = (msg.qos as u8) << 1 | msg.retain as u8;
+        header[1..3].copy_from_slice(&topic_len.to_be_bytes());
+        header[3..].copy_from_slice(&payload_len.to_be_bytes());
+        let (topic, payload) = body.split_at_mut(msg.topic.len());
+        topic.copy_from_slice(msg.topic.as_bytes());
+        payload.copy_from_slice(msg.payload);
+
+        self.end += len;
+
+        Ok(())
+    }
+
+    /// Oldest message.
+    pub(crate) fn front(&self) -> Option<publish::Msg<'_>> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let header = &self.buf[self.start..self.start + RECORD_HEADER_LEN];
+        let topic_len = usize::from(u16::from_be_bytes([header[1], header[2]]));
+        let body = &self.buf[self.start + RECORD_HEADER_LEN..self.start + self.record_len()];
+        let (topic, payload) = body.split_at(topic_len);
+
+        Some(publish::Msg {
+            // Only ever written from a `QoS` and a `&str`
+            qos: QoS::try_from((header[0] >> 1) & 0b11).ok()?,
+            retain: header[0] & 1 != 0,
+            topic: core::str::from_utf8(topic).ok()?,
+            payload,
+        })
+    }
+
+    pub(crate) fn pop_front(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+
+        self.start += self.record_len();
+        if self.is_empty() {
+            self.start = 0;
+            self.end = 0;
+        }
+    }
+
+    fn record_len(&self) -> usize {
+        let header = &self.buf[self.start..self.start + RECORD_HEADER_LEN];
+        let topic_len = u16::from_be_bytes([header[1], header[2]]);
+        let payload_len = u32::from_be_bytes([header[3], header[4], header[5], header[6]]);
+
+        RECORD_HEADER_LEN + usize::from(topic_len) + payload_len as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg<'a>(topic: &'a str, payload: &'a [u8]) -> publish::Msg<'a> {
+        publish::Msg {
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic,
+            payload,
+        }
+    }
+
+    fn pop(queue: &mut OfflineQueue<'_>) -> Option<(heapless::String<4>, heapless::Vec<u8, 4>)> {
+        let msg = queue.front()?;
+        let entry = (
+            msg.topic.try_into().unwrap(),
+            msg.payload.try_into().unwrap(),
+        );
+        queue.pop_front();
+
+        Some(entry)
+    }
+
+    fn options(overflow: Overflow) -> Options {
+        Options {
+            overflow,
+            exclude_qos0: false,
+        }
+    }
+
+    #[test]
+    fn fifo_across_compaction() {
+        // Room for two 10 byte records
+        let mut buf = [0u8; 24];
+        let mut queue = OfflineQueue::new(&mut buf, Options::default());
+
+        queue.push(&msg("a", b"12")).unwrap();
+        queue.push(&msg("b", b"34")).unwrap();
+        assert_eq!(pop(&mut queue).unwrap().0, "a");
+
+        // Only fits after moving "b" to the front
+        queue.push(&msg("c", b"56")).unwrap();
+        assert_eq!(queue.dropped(), 0);
+
+        let (topic, payload) = pop(&mut queue).unwrap();
+        assert_eq!((topic.as_str(), payload.as_slice()), ("b", &b"34"[..]));
+        assert_eq!(pop(&mut queue).unwrap().0, "c");
+        assert!(pop(&mut queue).is_none());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let mut buf = [0u8; 20];
+        let mut queue = OfflineQueue::new(&mut buf, options(Overflow::DropOldest));
+        for topic in ["a", "b", "c"] {
+            queue.push(&msg(topic, b"12")).unwrap();
+        }
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(pop(&mut queue).unwrap().0, "b");
+        assert_eq!(pop(&mut queue).unwrap().0, "c");
+
+        let mut buf = [0u8; 20];
+        let mut queue = OfflineQueue::new(&mut buf, options(Overflow::DropNewest));
+        for topic in ["a", "b", "c"] {
+            queue.push(&msg(topic, b"12")).unwrap();
+        }
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(pop(&mut queue).unwrap().0, "a");
+        assert_eq!(pop(&mut queue).unwrap().0, "b");
+
+        let mut buf = [0u8; 20];
+        let mut queue = OfflineQueue::new(&mut buf, options(Overflow::Reject));
+        queue.push(&msg("a", b"12")).unwrap();
+        queue.push(&msg("b", b"12")).unwrap();
+        assert!(matches!(
+            queue.push(&msg("c", b"12")),
+            Err(crate::Error::QueueFull)
+        ));
+        assert!(matches!(
+            queue.push(&msg("d", &[0; 20])),
+            Err(crate::Error::BufferTooSmall)
+        ));
+        assert_eq!(queue.dropped(), 0);
+    }
+}
@@ -0,0 +1,172 @@
+use core::ops::Range;
+
+use heapless::Deque;
+
+use crate::packet::{self, Packet};
+
+pub(crate) struct Outbox<'a, const QUEUE_SIZE: usize> {
+    buf: &'a mut [u8],
+    cursor: usize,
+    queue: Deque<Range<usize>, QUEUE_SIZE>,
+}
+
+impl<'a, const QUEUE_SIZE: usize> Outbox<'a, QUEUE_SIZE> {
+    pub(crate) fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf,
+            cursor: 0,
+            queue: Deque::new(),
+        }
+    }
+
+    pub(crate) fn enqueue<const N: usize>(
+        &mut self,
+        packet: Packet<'_, N>,
+    ) -> Result<(), crate::Error> {
+        self.compact();
+
+        let needed = packet.encoded_len()?;
+
+        if self.cursor + needed > self.buf.len() {
+            return Err(crate::Error::BufferTooSmall);
+        }
+
+        let start = self.cursor;
+        let end = start + needed;
+        let mut cursor = packet::encode::Cursor::new(&mut self.buf[start..end]);
+        packet.encode(&mut cursor)?;
+
+        self.queue
+            .push_back(start..end)
+            .map_err(|_| crate::Error::VectorIsFull)?;
+        self.cursor = end;
+
+        Ok(())
+    }
+
+    /// Free space for the payload of `publish`, after room for its longest header.
+    /// Write the payload there, then enqueue with [`Self::commit_publish`].
+    #[cfg(any(feature = "serde-json-core", feature = "postcard"))]
+    pub(crate) fn payload_buf(
+        &mut self,
+        publish: &packet::publish::Publish<'_>,
+    ) -> Result<&mut [u8], crate::Error> {
+        self.compact();
+
+        if self.queue.is_full() {
+            return Err(crate::Error::VectorIsFull);
+        }
+
+        let start = self.cursor
+            + packet::publish::Publish::MAX_FIXED_HEADER_LEN
+            + publish.variable_header_len();
+        self.buf
+            .get_mut(start..)
+            .ok_or(crate::Error::BufferTooSmall)
+    }
+
+    /// Enqueues `publish` with the first `payload_len` bytes of [`Self::payload_buf`]
+    /// as its payload. The header goes right in front of the payload, so a short
+    /// remaining length leaves a gap the next compaction closes.
+    #[cfg(any(feature = "serde-json-core", feature = "postcard"))]
+    pub(crate) fn commit_publish(
+        &mut self,
+        publish: &packet::publish::Publish<'_>,
+        payload_len: usize,
+    ) -> Result<(), crate::Error> {
+        let payload_start = self.cursor
+            + packet::publish::Publish::MAX_FIXED_HEADER_LEN
+            + publish.variable_header_len();
+        let start = payload_start - publish.header_len(payload_len)?;
+        let end = payload_start + payload_len;
+
+        let mut cursor = packet::encode::Cursor::new(&mut self.buf[start..payload_start]);
+        publish.encode_header(payload_len, &mut cursor)?;
+
+        self.queue
+            .push_back(start..end)
+            .map_err(|_| crate::Error::VectorIsFull)?;
+        self.cursor = end;
+
+        Ok(())
+    }
+
+    /// Whether a packet of `len` bytes can be enqueued.
+    pub(crate) fn has_room(&mut self, len: usize) -> bool {
+        self.compact();
+
+        !self.queue.is_full() && self.cursor + len <= self.buf.len()
+    }
+
+    /// Dequeues the next encoded packet. Its bytes stay valid until the outbox is used again.
+    pub(crate) fn dequeue(&mut self) -> Option<&[u8]> {
+        self.compact();
+
+        self.queue.pop_front().map(|range| &self.buf[range])
+    }
+
+    /// What's left to send of the oldest packet. Follow with [`Self::advance`].
+    pub(crate) fn front(&self) -> Option<&[u8]> {
+        self.queue.front().map(|range| &self.buf[range.clone()])
+    }
+
+    /// Marks `n` bytes of [`Self::front`] as sent.
+    pub(crate) fn advance(&mut self, n: usize) {
+        if let Some(range) = self.queue.front_mut() {
+            range.start = (range.start + n).min(range.end);
+
+            if range.start == range.end {
+                self.queue.pop_front();
+            }
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.queue.clear();
+        self.cursor = 0;
+    }
+
+    // Queued ranges are always in ascending order, so moving them down one by one never
+    // overwrites a range that hasn't been moved yet. Gaps between them are closed too.
+    fn compact(&mut self) {
+        let mut cursor = 0;
+        for range in self.queue.iter_mut() {
+            let len = range.len();
+            if range.start > cursor {
+                self.buf.copy_within(range.clone(), cursor);
+                *range = cursor..cursor + len;
+            }
+
+            cursor += len;
+        }
+
+        self.cursor = cursor;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::PacketId;
+
+    #[test]
+    fn dequeue_in_order_after_compaction() {
+        let mut buf = [0u8; 8];
+        let mut outbox = Outbox::<'_, 4>::new(&mut buf);
+
+        outbox.enqueue(Packet::<1>::PubAck(PacketId(1))).unwrap();
+        outbox.enqueue(Packet::<1>::PubComp(PacketId(2))).unwrap();
+
+        assert_eq!(outbox.dequeue(), Some(&[0x40, 0x02, 0x00, 0x01][..]));
+
+        outbox.enqueue(Packet::<1>::PingReq).unwrap();
+
+        assert_eq!(outbox.dequeue(), Some(&[0x70, 0x02, 0x00, 0x02][..]));
+        assert_eq!(outbox.dequeue(), Some(&[0xC0, 0x00][..]));
+        assert_eq!(outbox.dequeue(), None);
+    }
+}
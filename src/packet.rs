@@ -1,3 +1,17 @@
+//! MQTT 3.1.1 packet codec, usable on its own, e.g. for a sniffer or a custom session.
+//!
+//! ```
+//! use mqtt_client::packet::{Packet, PacketId};
+//!
+//! let (packet, len) = Packet::<1>::decode(&[0x40, 0x02, 0x00, 0x07, 0xD0]).unwrap();
+//! assert_eq!(len, 4);
+//! assert_eq!(packet.packet_id(), PacketId::try_from(7).ok());
+//!
+//! let mut buf = [0u8; 4];
+//! assert_eq!(packet.encode_to(&mut buf).unwrap(), 4);
+//! assert_eq!(buf, [0x40, 0x02, 0x00, 0x07]);
+//! ```
+
 use crate::{
     packet::{
         connect::{ConnAck, Connect},
@@ -5,6 +19,7 @@ use crate::{
         subscribe::{SubAck, Subscribe},
         unsubscribe::Unsubscribe,
     },
+    parser::parse_fixed_header,
     protocol::{FixedHeader, PacketType},
 };
 
@@ -15,8 +30,10 @@ pub mod publish;
 pub mod subscribe;
 pub mod unsubscribe;
 
+/// `N` is the most topic filters a decoded SUBSCRIBE, SUBACK or UNSUBSCRIBE may
+/// hold; more fail with [`crate::Error::VectorIsFull`].
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub(crate) enum Packet<'a> {
+pub enum Packet<'a, const N: usize = 1> {
     Connect(Connect<'a>),
     ConnAck(ConnAck),
     Publish(publish::Publish<'a>),
@@ -24,21 +41,83 @@ pub(crate) enum Packet<'a> {
     PubRec(PacketId),
     PubRel(PacketId),
     PubComp(PacketId),
-    Subscribe(Subscribe<'a>),
-    SubAck(SubAck),
-    Unsubscribe(Unsubscribe<'a>),
+    Subscribe(Subscribe<'a, N>),
+    SubAck(SubAck<N>),
+    Unsubscribe(Unsubscribe<'a, N>),
     UnsubAck(PacketId),
     PingReq,
     PingResp,
     Disconnect,
 }
 
-impl<'buf> Packet<'buf> {
-    pub(crate) fn encode(&self, cursor: &mut encode::Cursor) -> Result<(), crate::Error> {
+impl<'buf, const N: usize> Packet<'buf, N> {
+    /// Decodes the packet at the start of `bytes`. Returns it with the number of
+    /// bytes it took; [`crate::DecodeError::UnexpectedEof`] means `bytes` holds only part of it.
+    pub fn decode(bytes: &'buf [u8]) -> Result<(Self, usize), crate::Error> {
+        let (header, header_len) =
+            parse_fixed_header(bytes)?.ok_or(crate::DecodeError::UnexpectedEof)?;
+        let len = header_len + header.remaining_len;
+        let body = bytes
+            .get(header_len..len)
+            .ok_or(crate::DecodeError::UnexpectedEof)?;
+
+        Ok((Self::decode_body(&header, body)?, len))
+    }
+
+    /// Encodes the packet into the start of `buf`. Returns the number of bytes written.
+    pub fn encode_to(&self, buf: &mut [u8]) -> Result<usize, crate::Error> {
+        let len = self.encoded_len()?;
+        let buf = buf.get_mut(..len).ok_or(crate::Error::BufferTooSmall)?;
+        self.encode(&mut encode::Cursor::new(buf))?;
+
+        Ok(len)
+    }
+
+    pub fn packet_type(&self) -> PacketType {
+        match self {
+            Self::Connect(_) => PacketType::Connect,
+            Self::ConnAck(_) => PacketType::ConnAck,
+            Self::Publish(_) => PacketType::Publish,
+            Self::PubAck(_) => PacketType::PubAck,
+            Self::PubRec(_) => PacketType::PubRec,
+            Self::PubRel(_) => PacketType::PubRel,
+            Self::PubComp(_) => PacketType::PubComp,
+            Self::Subscribe(_) => PacketType::Subscribe,
+            Self::SubAck(_) => PacketType::SubAck,
+            Self::Unsubscribe(_) => PacketType::Unsubscribe,
+            Self::UnsubAck(_) => PacketType::UnsubAck,
+            Self::PingReq => PacketType::PingReq,
+            Self::PingResp => PacketType::PingResp,
+            Self::Disconnect => PacketType::Disconnect,
+        }
+    }
+
+    pub fn packet_id(&self) -> Option<PacketId> {
+        match self {
+            Self::Publish(packet) => packet.packet_id,
+            Self::Subscribe(packet) => Some(packet.packet_id()),
+            Self::SubAck(packet) => Some(packet.packet_id()),
+            Self::Unsubscribe(packet) => Some(packet.packet_id()),
+            Self::PubAck(packet_id)
+            | Self::PubRec(packet_id)
+            | Self::PubRel(packet_id)
+            | Self::PubComp(packet_id)
+            | Self::UnsubAck(packet_id) => Some(*packet_id),
+            Self::Connect(_)
+            | Self::ConnAck(_)
+            | Self::PingReq
+            | Self::PingResp
+            | Self::Disconnect => None,
+        }
+    }
+
+    pub fn encode(&self, cursor: &mut encode::Cursor) -> Result<(), crate::Error> {
         match self {
             Self::Connect(packet) => encode_packet(packet, cursor),
+            Self::ConnAck(packet) => encode_packet(packet, cursor),
             Self::Publish(packet) => encode_packet(packet, cursor),
             Self::Subscribe(packet) => encode_packet(packet, cursor),
+            Self::SubAck(packet) => encode_packet(packet, cursor),
             Self::Unsubscribe(packet) => encode_packet(packet, cursor),
             Self::PubAck(packet_id) => encode_packet_id(PacketType::PubAck, 0, packet_id, cursor),
             Self::PubRec(packet_id) => encode_packet_id(PacketType::PubRec, 0, packet_id, cursor),
@@ -46,40 +125,43 @@ impl<'buf> Packet<'buf> {
                 encode_packet_id(PacketType::PubRel, 0b0010, packet_id, cursor)
             }
             Self::PubComp(packet_id) => encode_packet_id(PacketType::PubComp, 0, packet_id, cursor),
+            Self::UnsubAck(packet_id) => {
+                encode_packet_id(PacketType::UnsubAck, 0, packet_id, cursor)
+            }
             Self::PingReq => empty_body(cursor, PacketType::PingReq),
             Self::PingResp => empty_body(cursor, PacketType::PingResp),
             Self::Disconnect => empty_body(cursor, PacketType::Disconnect),
-            _ => Err(crate::Error::EncodeNotImplemented),
         }
     }
 
-    pub(crate) fn required_space(&self) -> Result<usize, crate::Error> {
+    /// Length of the whole packet, fixed header included.
+    pub fn encoded_len(&self) -> Result<usize, crate::Error> {
         let body_len = match self {
             Self::Connect(packet) => packet.required_space(),
+            Self::ConnAck(packet) => packet.required_space(),
             Self::Publish(packet) => packet.required_space(),
             Self::Subscribe(packet) => packet.required_space(),
+            Self::SubAck(packet) => packet.required_space(),
             Self::Unsubscribe(packet) => packet.required_space(),
             Self::PubAck(packet_id)
             | Self::PubRec(packet_id)
             | Self::PubRel(packet_id)
             | Self::PubComp(packet_id)
             | Self::UnsubAck(packet_id) => packet_id.required_space(),
-            Self::PingReq
-            | Self::PingResp
-            | Self::Disconnect
-            | Self::ConnAck(_)
-            | Self::SubAck(_) => 0,
+            Self::PingReq | Self::PingResp | Self::Disconnect => 0,
         };
 
         Ok(encode::calculate_remaining_length(body_len)? + body_len + 1)
     }
 
-    pub(crate) fn decode(header: &FixedHeader, body: &'buf [u8]) -> Result<Self, crate::Error> {
-        let cursor = &mut decode::Cursor::new(&body);
+    pub(crate) fn decode_body(
+        header: &FixedHeader,
+        body: &'buf [u8],
+    ) -> Result<Self, crate::Error> {
+        let cursor = &mut decode::Cursor::new(body);
 
-        // @todo this looks wrong
-        if header.remaining_len as usize != cursor.remaining() {
-            return Err(crate::Error::MalformedPacket);
+        if header.remaining_len != cursor.remaining() {
+            return Err(crate::DecodeError::MalformedPacket.into());
         }
 
         let flags = header.flags;
@@ -141,7 +223,7 @@ pub enum QoS {
 }
 
 impl QoS {
-    fn decode<'cursor>(cursor: &'cursor mut decode::Cursor) -> Result<Self, crate::Error> {
+    fn decode(cursor: &mut decode::Cursor) -> Result<Self, crate::Error> {
         let byte = cursor.read_u8()?;
         Self::try_from(byte)
     }
@@ -155,7 +237,7 @@ impl TryFrom<u8> for QoS {
             0 => Self::AtMostOnce,
             1 => Self::AtLeastOnce,
             2 => Self::ExactlyOnce,
-            _ => return Err(crate::Error::InvalidQoS),
+            _ => return Err(crate::DecodeError::InvalidQoS.into()),
         };
 
         Ok(qos)
@@ -178,6 +260,10 @@ impl encode::Encode for QoS {
 pub struct PacketId(pub(crate) u16);
 
 impl PacketId {
+    pub const fn get(self) -> u16 {
+        self.0
+    }
+
     fn decode(cursor: &mut decode::Cursor) -> Result<Self, crate::Error> {
         Self::try_from(cursor.read_u16()?)
     }
@@ -188,19 +274,25 @@ impl TryFrom<u16> for PacketId {
 
     fn try_from(id: u16) -> Result<Self, Self::Error> {
         if id == 0 {
-            return Err(crate::Error::MalformedPacket);
+            return Err(crate::DecodeError::MalformedPacket.into());
         }
 
         Ok(Self(id))
     }
 }
 
+impl From<PacketId> for u16 {
+    fn from(id: PacketId) -> Self {
+        id.0
+    }
+}
+
 impl TryFrom<&[u8]> for PacketId {
     type Error = crate::Error;
 
     fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
         if bytes.len() != 2 {
-            return Err(crate::Error::MalformedPacket);
+            return Err(crate::DecodeError::MalformedPacket.into());
         }
 
         let res = u16::from_be_bytes([bytes[0], bytes[1]]);
@@ -229,137 +321,162 @@ pub(super) fn empty_body(
     cursor: &mut encode::Cursor,
     packet_type: PacketType,
 ) -> Result<(), crate::Error> {
-    let header = ((packet_type as u8) << 4) | 0b0000;
+    let header = (packet_type as u8) << 4;
 
     header.encode(cursor)?;
     0u8.encode(cursor)
 }
 
-// pub struct Assembled<'a> {
-//     pub header: FixedHeader,
-//     pub body: &'a [u8],
-// }
-
-// pub struct Assembler {
-//     parser: parser::Parser,
-//     header: Option<FixedHeader>,
-//     // body_chunk: Option<&'a [u8]>,
-// }
-
-// impl Assembler {
-//     pub fn new() -> Self {
-//         Self {
-//             parser: parser::Parser::new(),
-//             header: None,
-//             // body_chunk: None,
-//         }
-//     }
-
-//     pub fn feed<'p, P: buffer::Provider<'p>>(
-//         &mut self,
-//         input: &[u8],
-//         provider: P,
-//     ) -> Result<(usize, Option<Assembled>), crate::Error> {
-//         let mut offset = 0;
-
-//         loop {
-//             let (consumed, event) = self.parser.parse(&input[offset..])?;
-
-//             offset += consumed;
-
-//             if let Some(event) = event {
-//                 match event {
-//                     parser::Event::PacketStart { header } => {
-//                         self.header = Some(header);
-//                         // self.body_chunk = None;
-//                     }
-//                     parser::Event::PacketBody { chunk } => {
-//                         // self.body_chunk = Some(chunk);
-//                     }
-//                     parser::Event::PacketEnd => {
-//                         let header = self.header.take().ok_or(crate::Error::MalformedPacket)?;
-//                         Packet::decode(&header, cursor, provider)?;
-//                         // let body = self
-//                         //     .body_chunk
-//                         //     .take()
-//                         //     .ok_or(crate::Error::MalformedPacket)?;
-//                         let body = &input[0..2];
-
-//                         return Ok((offset, Some(Assembled { header, body })));
-//                     }
-//                 }
-//             }
-
-//             if consumed == 0 {
-//                 break;
-//             }
-//         }
-
-//         Ok((offset, None))
-//     }
-// }
-
-// #[cfg(test)]
-// mod tests {
-//     use crate::{
-//         buffer,
-//         packet::{QoS, connect, encode, encode_packet, publish},
-//         protocol::PacketType,
-//     };
-
-//     #[test]
-//     fn test_assembler_connect() {
-//         let packet = connect::Connect {
-//             client_id: buffer::String::from("Client"),
-//             keep_alive: 60,
-//             clean_session: true,
-//             password: None,
-//             username: None,
-//             will: None,
-//         };
-
-//         let mut buf = [0u8; 128];
-//         let mut cursor = encode::Cursor::new(&mut buf);
-//         encode_packet(&packet, &mut cursor).unwrap();
-//         let buf = cursor.written();
-//         let mut assembler = Assembler::new();
-
-//         // [16, 18, 0, 4, 77, 81, 84, 84, 4, 0, 0, 60, 0, 6, 67, 108, 105, 101, 110, 116]
-
-//         let (consumed, packet) = assembler.feed(&buf).unwrap();
-
-//         assert_eq!(consumed, buf.len());
-//         let packet = packet.expect("Packet should be ready");
-
-//         assert!(matches!(packet.header.packet_type, PacketType::Connect));
-//         assert_eq!(packet.header.remaining_len as usize, packet.body.len());
-//     }
-
-//     #[test]
-//     fn test_assembler_publish() {
-//         let packet = publish::Publish {
-//             topic: buffer::String::from("topic/test"),
-//             payload: buffer::Slice::from(b"hello mqtt".as_slice()),
-//             flags: publish::Flags {
-//                 dup: false,
-//                 qos: QoS::AtMostOnce,
-//                 retain: false,
-//             },
-//             packet_id: None,
-//         };
-
-//         let mut buf = [0u8; 128];
-//         let mut cursor = encode::Cursor::new(&mut buf);
-//         crate::packet::encode_packet(&packet, &mut cursor).unwrap();
-
-//         let buf = cursor.written();
-//         let mut assembler = Assembler::new();
-
-//         let (consumed, packet) = assembler.feed(&buf).unwrap();
-
-//         assert_eq!(consumed, buf.len());
-//         let packet = packet.expect("Packet should be ready");
-
-//         assert!(matches!(packet.header.packet_type, PacketType::Publish));
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Decoder;
+
+    /// Decodes `bytes` as one packet and checks that encoding it gives `bytes` back.
+    fn assert_round_trip(bytes: &[u8]) {
+        let mut rx_buf = [0u8; 64];
+        let mut decoder = Decoder::new(&mut rx_buf);
+        let (consumed, packet) = decoder.feed::<2>(bytes).unwrap();
+        assert_eq!(consumed, bytes.len());
+        let packet = packet.unwrap();
+
+        let mut buf = [0u8; 64];
+        let len = packet.encode_to(&mut buf).unwrap();
+        assert_eq!(&buf[..len], bytes);
+    }
+
+    #[test]
+    fn round_trip_connect() {
+        // Clean session, keep alive 60, client id "c"
+        assert_round_trip(&[
+            0x10, 0x0D, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3C, 0x00, 0x01,
+            b'c',
+        ]);
+
+        // Retained QoS 1 will, username and password
+        assert_round_trip(&[
+            0x10, 0x1A, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0xEC, 0x00, 0x78, 0x00, 0x01,
+            b'c', 0x00, 0x01, b't', 0x00, 0x02, b'h', b'i', 0x00, 0x01, b'u', 0x00, 0x01, b'p',
+        ]);
+    }
+
+    #[test]
+    fn round_trip_connack() {
+        assert_round_trip(&[0x20, 0x02, 0x00, 0x00]);
+        assert_round_trip(&[0x20, 0x02, 0x01, 0x00]);
+        assert_round_trip(&[0x20, 0x02, 0x00, 0x05]);
+    }
+
+    #[test]
+    fn round_trip_publish() {
+        // QoS 0
+        assert_round_trip(&[0x30, 0x05, 0x00, 0x01, b't', b'h', b'i']);
+        // QoS 1, DUP, retain
+        assert_round_trip(&[0x3B, 0x07, 0x00, 0x01, b't', 0x00, 0x07, b'h', b'i']);
+        // QoS 2, empty payload
+        assert_round_trip(&[0x34, 0x05, 0x00, 0x01, b't', 0x00, 0x08]);
+    }
+
+    #[test]
+    fn round_trip_packet_id_only() {
+        assert_round_trip(&[0x40, 0x02, 0x00, 0x01]); // PUBACK
+        assert_round_trip(&[0x50, 0x02, 0x00, 0x02]); // PUBREC
+        assert_round_trip(&[0x62, 0x02, 0x00, 0x03]); // PUBREL
+        assert_round_trip(&[0x70, 0x02, 0x00, 0x04]); // PUBCOMP
+        assert_round_trip(&[0xB0, 0x02, 0x00, 0x05]); // UNSUBACK
+    }
+
+    #[test]
+    fn round_trip_subscribe() {
+        assert_round_trip(&[0x82, 0x08, 0x00, 0x0A, 0x00, 0x03, b'a', b'/', b'#', 0x01]);
+        assert_round_trip(&[
+            0x82, 0x0C, 0x00, 0x0A, 0x00, 0x03, b'a', b'/', b'#', 0x01, 0x00, 0x01, b'b', 0x00,
+        ]);
+    }
+
+    #[test]
+    fn round_trip_suback() {
+        assert_round_trip(&[0x90, 0x03, 0x00, 0x0A, 0x01]);
+        assert_round_trip(&[0x90, 0x03, 0x00, 0x0A, 0x80]);
+        assert_round_trip(&[0x90, 0x04, 0x00, 0x0A, 0x01, 0x80]);
+    }
+
+    #[test]
+    fn round_trip_unsubscribe() {
+        assert_round_trip(&[0xA2, 0x07, 0x00, 0x0A, 0x00, 0x03, b'a', b'/', b'+']);
+        assert_round_trip(&[
+            0xA2, 0x0A, 0x00, 0x0A, 0x00, 0x03, b'a', b'/', b'+', 0x00, 0x01, b'b',
+        ]);
+    }
+
+    #[test]
+    fn filters_beyond_capacity() {
+        let suback = [0x90, 0x05, 0x00, 0x0A, 0x01, 0x80, 0x00];
+
+        let (Packet::SubAck(packet), _) = Packet::<3>::decode(&suback).unwrap() else {
+            panic!("Expected SubAck")
+        };
+        assert_eq!(packet.return_codes().len(), 3);
+
+        assert!(matches!(
+            Packet::<2>::decode(&suback),
+            Err(crate::Error::VectorIsFull)
+        ));
+    }
+
+    #[test]
+    fn round_trip_empty_body() {
+        assert_round_trip(&[0xC0, 0x00]); // PINGREQ
+        assert_round_trip(&[0xD0, 0x00]); // PINGRESP
+        assert_round_trip(&[0xE0, 0x00]); // DISCONNECT
+    }
+
+    #[test]
+    fn connect_options_decode_back() {
+        let connect = connect::Connect::from(connect::Options {
+            clean_session: false,
+            keep_alive: 30,
+            client_id: "client",
+            will: Some(connect::WillOptions {
+                qos: QoS::ExactlyOnce,
+                retain: false,
+                topic: "status",
+                payload: b"offline",
+            }),
+            username: Some("user"),
+            password: None,
+        });
+        let packet: Packet = Packet::Connect(connect);
+
+        let mut buf = [0u8; 64];
+        let len = packet.encode_to(&mut buf).unwrap();
+
+        assert_round_trip(&buf[..len]);
+    }
+
+    #[test]
+    fn decode_from_slice() {
+        // PUBLISH QoS 1 followed by the first byte of the next packet
+        let bytes = [0x32, 0x07, 0x00, 0x01, b't', 0x00, 0x07, b'h', b'i', 0xD0];
+
+        let (packet, len) = Packet::<1>::decode(&bytes).unwrap();
+        assert_eq!(len, 9);
+        assert_eq!(packet.packet_type(), PacketType::Publish);
+        assert_eq!(packet.packet_id().map(PacketId::get), Some(7));
+
+        assert!(matches!(
+            Packet::<1>::decode(&bytes[..8]),
+            Err(crate::Error::Decode(crate::DecodeError::UnexpectedEof))
+        ));
+        assert!(matches!(
+            Packet::<1>::decode(&bytes[9..]),
+            Err(crate::Error::Decode(crate::DecodeError::UnexpectedEof))
+        ));
+
+        let mut buf = [0u8; 8];
+        assert!(matches!(
+            packet.encode_to(&mut buf),
+            Err(crate::Error::BufferTooSmall)
+        ));
+    }
+}
@@ -25,13 +25,44 @@ pub struct WillOptions<'a> {
 
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub(crate) struct Connect<'a> {
-    clean_session: bool,
-    keep_alive: u16,
-    client_id: buffer::String<'a>,
-    will: Option<Will<'a>>,
-    username: Option<buffer::String<'a>>,
-    password: Option<buffer::Slice<'a>>,
+pub struct Connect<'a> {
+    pub(crate) clean_session: bool,
+    pub(crate) keep_alive: u16,
+    pub(crate) client_id: buffer::String<'a>,
+    pub(crate) will: Option<Will<'a>>,
+    pub(crate) username: Option<buffer::String<'a>>,
+    pub(crate) password: Option<buffer::Slice<'a>>,
+}
+
+impl Options<'_> {
+    /// Fails on fields the broker would disconnect for.
+    pub(crate) fn validate(&self) -> Result<(), crate::Error> {
+        encode::check_utf8(self.client_id)?;
+
+        // Brokers only assign a client id for a clean session (3.1.3-7)
+        if self.client_id.is_empty() && !self.clean_session {
+            return Err(crate::Error::InvalidClientId);
+        }
+
+        if let Some(will) = &self.will {
+            encode::check_utf8(will.topic)?;
+            encode::check_len(will.payload)?;
+
+            if !crate::topic::is_valid_topic(will.topic) {
+                return Err(crate::Error::InvalidTopicName);
+            }
+        }
+
+        if let Some(username) = self.username {
+            encode::check_utf8(username)?;
+        }
+
+        if let Some(password) = self.password {
+            encode::check_len(password.as_bytes())?;
+        }
+
+        Ok(())
+    }
 }
 
 impl<'b, 'a: 'b> From<Options<'a>> for Connect<'b> {
@@ -40,37 +71,70 @@ impl<'b, 'a: 'b> From<Options<'a>> for Connect<'b> {
             clean_session: opts.clean_session,
             client_id: buffer::String::from(opts.client_id),
             keep_alive: opts.keep_alive,
-            password: opts.password.map(|p| buffer::Slice::from(p)),
-            username: opts.username.map(|u| buffer::String::from(u)),
-            will: opts.will.map(|w| Will::from(w)),
+            password: opts.password.map(buffer::Slice::from),
+            username: opts.username.map(buffer::String::from),
+            will: opts.will.map(Will::from),
         }
     }
 }
 
 impl<'buf> Connect<'buf> {
+    pub fn clean_session(&self) -> bool {
+        self.clean_session
+    }
+
+    /// Keep-alive interval in seconds, `0` if disabled.
+    pub fn keep_alive(&self) -> u16 {
+        self.keep_alive
+    }
+
+    pub fn client_id(&self) -> &buffer::String<'buf> {
+        &self.client_id
+    }
+
+    pub fn will(&self) -> Option<&Will<'buf>> {
+        self.will.as_ref()
+    }
+
+    pub fn username(&self) -> Option<&buffer::String<'buf>> {
+        self.username.as_ref()
+    }
+
+    pub fn password(&self) -> Option<&buffer::Slice<'buf>> {
+        self.password.as_ref()
+    }
+
     pub(crate) fn decode(cursor: &mut decode::Cursor<'buf>) -> Result<Self, crate::Error> {
         let protocol_name = cursor.read_utf8()?;
         if protocol_name != "MQTT" {
-            return Err(crate::Error::MalformedPacket);
+            return Err(crate::DecodeError::MalformedPacket.into());
         }
 
         // @note: MQTT v3.1.1
         let level = cursor.read_u8()?;
         if level != 4 {
-            return Err(crate::Error::MalformedPacket);
+            return Err(crate::DecodeError::MalformedPacket.into());
         }
 
         let flags = cursor.read_u8()?;
         if flags & 0b0000_0001 != 0 {
-            return Err(crate::Error::MalformedPacket);
+            return Err(crate::DecodeError::MalformedPacket.into());
         }
 
-        let clean_session = flags & 0b0000_0010 == 1;
-        let will_flag = flags & 0b0000_0100 == 1;
+        let clean_session = flags & 0b0000_0010 != 0;
+        let will_flag = flags & 0b0000_0100 != 0;
         let qos = QoS::try_from((flags >> 3) & 0b11)?;
-        let retain = flags & 0b0010_0000 == 1;
-        let password_flag = flags & 0b0100_0000 == 1;
-        let username_flag = flags & 0b1000_0000 == 1;
+        let retain = flags & 0b0010_0000 != 0;
+        let password_flag = flags & 0b0100_0000 != 0;
+        let username_flag = flags & 0b1000_0000 != 0;
+
+        // Will QoS and retain must be 0 without a will, and a password needs a username
+        if !will_flag && (qos != QoS::AtMostOnce || retain) {
+            return Err(crate::DecodeError::MalformedPacket.into());
+        }
+        if password_flag && !username_flag {
+            return Err(crate::DecodeError::MalformedPacket.into());
+        }
 
         let keep_alive = cursor.read_u16()?;
 
@@ -80,7 +144,7 @@ impl<'buf> Connect<'buf> {
         let will = if will_flag {
             Some(Will {
                 topic: buffer::String::from(cursor.read_utf8()?),
-                payload: buffer::Slice::from(cursor.read_bytes(cursor.remaining())?),
+                payload: buffer::Slice::from(cursor.read_binary()?),
                 qos,
                 retain,
             })
@@ -100,6 +164,8 @@ impl<'buf> Connect<'buf> {
             None
         };
 
+        cursor.expect_empty()?;
+
         Ok(Connect {
             clean_session,
             keep_alive,
@@ -175,11 +241,29 @@ impl<'buf> encode::EncodePacket for &Connect<'buf> {
 
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-struct Will<'a> {
-    qos: QoS,
-    retain: bool,
-    topic: buffer::String<'a>,
-    payload: buffer::Slice<'a>,
+pub struct Will<'a> {
+    pub(crate) qos: QoS,
+    pub(crate) retain: bool,
+    pub(crate) topic: buffer::String<'a>,
+    pub(crate) payload: buffer::Slice<'a>,
+}
+
+impl<'a> Will<'a> {
+    pub fn qos(&self) -> QoS {
+        self.qos
+    }
+
+    pub fn retain(&self) -> bool {
+        self.retain
+    }
+
+    pub fn topic(&self) -> &buffer::String<'a> {
+        &self.topic
+    }
+
+    pub fn payload(&self) -> &buffer::Slice<'a> {
+        &self.payload
+    }
 }
 
 impl<'b, 'a: 'b> From<WillOptions<'a>> for Will<'b> {
@@ -194,17 +278,33 @@ impl<'b, 'a: 'b> From<WillOptions<'a>> for Will<'b> {
 }
 
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub(crate) struct ConnAck {
+pub struct ConnAck {
     pub(crate) session_present: bool,
-    return_code: ConnectReturnCode,
+    pub(crate) return_code: ConnectReturnCode,
 }
 
 impl ConnAck {
+    /// A refusal can't have `session_present` set (3.2.2-4).
+    pub fn new(session_present: bool, return_code: ConnectReturnCode) -> Self {
+        Self {
+            session_present: session_present && return_code == ConnectReturnCode::Accepted,
+            return_code,
+        }
+    }
+
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
+
+    pub fn return_code(&self) -> ConnectReturnCode {
+        self.return_code
+    }
+
     pub(crate) fn decode(cursor: &mut decode::Cursor) -> Result<Self, crate::Error> {
         let flags = cursor.read_u8()?;
 
         if flags & 0b1111_1110 != 0 {
-            return Err(crate::Error::MalformedPacket);
+            return Err(crate::DecodeError::MalformedPacket.into());
         }
 
         let return_code = ConnectReturnCode::try_from(cursor.read_u8()?)?;
@@ -212,7 +312,7 @@ impl ConnAck {
         let session_present = (flags & 0b0000_0001) == 1;
 
         if return_code != ConnectReturnCode::Accepted && session_present {
-            return Err(crate::Error::MalformedPacket);
+            return Err(crate::DecodeError::MalformedPacket.into());
         }
 
         cursor.expect_empty()?;
@@ -224,11 +324,28 @@ impl ConnAck {
     }
 }
 
+impl encode::EncodePacket for &ConnAck {
+    const PACKET_TYPE: PacketType = PacketType::ConnAck;
+
+    fn flags(&self) -> u8 {
+        0
+    }
+
+    fn required_space(&self) -> usize {
+        2
+    }
+
+    fn encode_body(&self, cursor: &mut encode::Cursor) -> Result<(), crate::Error> {
+        (self.session_present as u8).encode(cursor)?;
+        (self.return_code as u8).encode(cursor)
+    }
+}
+
 // @note: for MQTT 5.0 it is a whole another story
 #[repr(u8)]
-#[derive(PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-enum ConnectReturnCode {
+pub enum ConnectReturnCode {
     Accepted = 0,
     UnacceptableProtocolVersion = 1,
     IdentifierRejected = 2,
@@ -248,7 +365,7 @@ impl TryFrom<u8> for ConnectReturnCode {
             3 => Self::ServerUnavailable,
             4 => Self::BadUserNameOrPassword,
             5 => Self::NotAuthorized,
-            _ => return Err(crate::Error::InvalidConnectReturnCode),
+            _ => return Err(crate::DecodeError::InvalidConnectReturnCode.into()),
         };
 
         Ok(code)
@@ -261,31 +378,40 @@ mod tests {
 
     use super::*;
 
-    // #[test]
-    // fn connack_accepted() {
-    //     let body = [0x00, 0x00];
-    //     let mut cursor = decode::Cursor::new(&body);
-    //     let mut buf = [0u8; 16];
-    //     let mut buf = buffer::Bump::new(&mut buf[..]);
-    //     let packet = ConnAck::decode(&mut cursor, &mut buf, 0).unwrap();
-
-    //     assert!(matches!(
-    //         packet,
-    //         ConnAck {
-    //             session_present: false,
-    //             return_code: ConnectReturnCode::Accepted
-    //         }
-    //     ));
-    // }
-
-    // #[test]
-    // fn connack_invalid_flags() {
-    //     let body = [0b0000_0010, 0x00];
-    //     let mut cursor = decode::Cursor::new(&body);
-    //     let mut buf = [0u8; 16];
-    //     let mut buf = buffer::Bump::new(&mut buf[..]);
-    //     assert!(ConnAck::decode(&mut cursor, &mut buf, 0).is_err());
-    // }
+    #[test]
+    fn connack_accepted() {
+        let body = [0x00, 0x00];
+        let mut cursor = decode::Cursor::new(&body);
+        let packet = ConnAck::decode(&mut cursor).unwrap();
+
+        assert!(matches!(
+            packet,
+            ConnAck {
+                session_present: false,
+                return_code: ConnectReturnCode::Accepted
+            }
+        ));
+    }
+
+    #[test]
+    fn connack_invalid_flags() {
+        let body = [0b0000_0010, 0x00];
+        let mut cursor = decode::Cursor::new(&body);
+        assert!(ConnAck::decode(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn connack_encode() {
+        let packet = ConnAck {
+            session_present: true,
+            return_code: ConnectReturnCode::Accepted,
+        };
+        let mut buf = [0u8; 2];
+        let mut cursor = encode::Cursor::new(&mut buf);
+        (&packet).encode_body(&mut cursor).unwrap();
+
+        assert_eq!(buf, [0x01, 0x00]);
+    }
 
     #[test]
     fn connect_encode_flags() {
@@ -374,26 +500,60 @@ mod tests {
         assert_eq!(&buf[50..50 + len], b"long-pass");
     }
 
-    // #[test]
-    // fn connect_with_invalid_flags() {
-    //     let bytes = [
-    //         0x00,        // "MQTT"
-    //         0x04,        // |
-    //         b'M',        // |
-    //         b'Q',        // |
-    //         b'T',        // |
-    //         b'T',        // ___
-    //         0x04,        // MQTT version
-    //         0b0000_0001, // Flags - invalid
-    //         0x00,        // keep_alive = 60
-    //         0x3C,        // ___
-    //     ];
-    //     let mut cursor = decode::Cursor::new(&bytes);
-    //     let mut buf = [0u8; 32];
-    //     let mut buf = buffer::Bump::new(&mut buf[..]);
-    //     read
-    //     // let err = Connect::decode(&mut cursor, &mut buf, 0).unwrap_err();
-
-    //     assert!(matches!(err, crate::Error::MalformedPacket));
-    // }
+    #[test]
+    fn connect_with_invalid_flags() {
+        let bytes = [
+            0x00,        // "MQTT"
+            0x04,        // |
+            b'M',        // |
+            b'Q',        // |
+            b'T',        // |
+            b'T',        // ___
+            0x04,        // MQTT version
+            0b0000_0001, // Flags - invalid
+            0x00,        // keep_alive = 60
+            0x3C,        // ___
+        ];
+        let mut cursor = decode::Cursor::new(&bytes);
+        let err = Connect::decode(&mut cursor).unwrap_err();
+
+        assert!(matches!(
+            err,
+            crate::Error::Decode(crate::DecodeError::MalformedPacket)
+        ));
+    }
+
+    #[test]
+    fn connect_decode_with_will_username_password() {
+        // "MQTT", level 4, flags 0b1110_1110, keep alive 120, client id "c",
+        // will topic "t", will payload "hi", username "u", password "p"
+        let bytes = b"\x00\x04MQTT\x04\xEE\x00\x78\x00\x01c\x00\x01t\x00\x02hi\x00\x01u\x00\x01p";
+        let mut cursor = decode::Cursor::new(bytes);
+        let connect = Connect::decode(&mut cursor).unwrap();
+
+        assert!(connect.clean_session);
+        assert_eq!(connect.keep_alive, 120);
+        assert_eq!(connect.client_id, "c");
+
+        let will = connect.will.unwrap();
+        assert_eq!(will.qos, QoS::AtLeastOnce);
+        assert!(will.retain);
+        assert_eq!(will.topic, "t");
+        assert_eq!(will.payload, b"hi".as_slice());
+
+        assert_eq!(connect.username.unwrap(), "u");
+        assert_eq!(connect.password.unwrap(), b"p".as_slice());
+    }
+
+    #[test]
+    fn connect_will_qos_without_will() {
+        // "MQTT", level 4, flags 0b0000_1010 (will QoS 1 without will flag), keep alive 60, client id "c"
+        let bytes = b"\x00\x04MQTT\x04\x0A\x00\x3C\x00\x01c";
+        let mut cursor = decode::Cursor::new(bytes);
+
+        assert!(matches!(
+            Connect::decode(&mut cursor),
+            Err(crate::Error::Decode(crate::DecodeError::MalformedPacket))
+        ));
+    }
 }
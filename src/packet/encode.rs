@@ -1,19 +1,36 @@
 use crate::protocol;
 
-pub(crate) trait EncodePacket {
+/// Body and fixed header details of a packet type, see [`crate::packet::Packet::encode`].
+pub trait EncodePacket {
     const PACKET_TYPE: protocol::PacketType;
     fn flags(&self) -> u8;
     fn required_space(&self) -> usize;
     fn encode_body(&self, cursor: &mut Cursor) -> Result<(), crate::Error>;
 }
 
-pub(crate) trait Encode {
+/// A field of a packet body.
+pub trait Encode {
     fn encode(&self, cursor: &mut Cursor) -> Result<(), crate::Error>;
     fn required_space(&self) -> usize;
 }
 
-trait RequiredSize {
-    fn required_space(&self) -> usize;
+/// Fails unless `bytes` fit behind a two byte length (1.5.3).
+pub(crate) fn check_len(bytes: &[u8]) -> Result<(), crate::Error> {
+    match u16::try_from(bytes.len()) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(crate::Error::StringTooLong),
+    }
+}
+
+/// Fails on a string the receiver has to reject: too long, or containing U+0000 (1.5.3).
+pub(crate) fn check_utf8(value: &str) -> Result<(), crate::Error> {
+    check_len(value.as_bytes())?;
+
+    if value.contains('\0') {
+        return Err(crate::Error::InvalidString);
+    }
+
+    Ok(())
 }
 
 pub(super) fn calculate_remaining_length(mut len: usize) -> Result<usize, crate::Error> {
@@ -28,7 +45,7 @@ pub(super) fn calculate_remaining_length(mut len: usize) -> Result<usize, crate:
         }
 
         if i == 4 {
-            return Err(crate::Error::MalformedPacket);
+            return Err(crate::Error::PacketTooLarge);
         }
     }
 
@@ -54,28 +71,29 @@ pub(super) fn remaining_length(mut len: usize, cursor: &mut Cursor) -> Result<us
         }
 
         if i == 4 {
-            return Err(crate::Error::MalformedPacket);
+            return Err(crate::Error::PacketTooLarge);
         }
     }
 
     Ok(i)
 }
 
-pub(crate) struct Cursor<'buf> {
+/// Writes into a buffer, failing with [`crate::Error::BufferTooSmall`] at its end.
+pub struct Cursor<'buf> {
     buf: &'buf mut [u8],
     pos: usize,
 }
 
 impl<'buf> Cursor<'buf> {
-    pub(crate) const fn new(buf: &'buf mut [u8]) -> Self {
+    pub const fn new(buf: &'buf mut [u8]) -> Self {
         Self { buf, pos: 0 }
     }
 
-    pub(crate) fn written(&self) -> &[u8] {
+    pub fn written(&self) -> &[u8] {
         &self.buf[..self.pos]
     }
 
-    pub(crate) fn write_u8(&mut self, byte: u8) -> Result<(), crate::Error> {
+    pub fn write_u8(&mut self, byte: u8) -> Result<(), crate::Error> {
         self.ensure_remaining(1)?;
         self.buf[self.pos] = byte;
         self.pos += 1;
@@ -83,7 +101,7 @@ impl<'buf> Cursor<'buf> {
         Ok(())
     }
 
-    fn write_u16(&mut self, value: u16) -> Result<(), crate::Error> {
+    pub fn write_u16(&mut self, value: u16) -> Result<(), crate::Error> {
         self.ensure_remaining(2)?;
         let [one, two] = value.to_be_bytes();
         self.buf[self.pos] = one;
@@ -93,7 +111,7 @@ impl<'buf> Cursor<'buf> {
         Ok(())
     }
 
-    pub(crate) fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), crate::Error> {
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), crate::Error> {
         let len = bytes.len();
         self.ensure_remaining(len)?;
 
@@ -103,26 +121,23 @@ impl<'buf> Cursor<'buf> {
         Ok(())
     }
 
-    pub(crate) fn write_binary_chunk(&mut self, bytes: &[u8]) -> Result<(), crate::Error> {
-        self.write_u16(bytes.len() as u16)?;
+    pub fn write_binary_chunk(&mut self, bytes: &[u8]) -> Result<(), crate::Error> {
+        let len = u16::try_from(bytes.len()).map_err(|_| crate::Error::StringTooLong)?;
+        self.write_u16(len)?;
         self.write_bytes(bytes)
     }
 
-    fn write_utf8(&mut self, value: &str) -> Result<(), crate::Error> {
+    pub fn write_utf8(&mut self, value: &str) -> Result<(), crate::Error> {
         self.write_binary_chunk(value.as_bytes())
     }
 
-    fn remaining(&self) -> usize {
+    pub fn remaining(&self) -> usize {
         self.buf.len() - self.pos
     }
 
-    fn is_empty(&self) -> bool {
-        self.remaining() == 0
-    }
-
     fn ensure_remaining(&self, n: usize) -> Result<(), crate::Error> {
         if self.remaining() < n {
-            Err(crate::Error::UnexpectedEof)
+            Err(crate::Error::BufferTooSmall)
         } else {
             Ok(())
         }
@@ -151,21 +166,20 @@ impl Encode for u8 {
 
 impl Encode for &str {
     fn encode(&self, cursor: &mut Cursor) -> Result<(), crate::Error> {
-        cursor.write_utf8(&self)
+        cursor.write_utf8(self)
     }
 
     fn required_space(&self) -> usize {
-        self.as_bytes().len() + 2
+        self.len() + 2
     }
 }
 
 impl Encode for &[u8] {
     fn encode(&self, cursor: &mut Cursor) -> Result<(), crate::Error> {
-        cursor.write_u16(self.len() as u16)?;
-        cursor.write_bytes(&self)
+        cursor.write_binary_chunk(self)
     }
 
     fn required_space(&self) -> usize {
-        self.len()
+        self.len() + 2
     }
 }
@@ -30,6 +30,7 @@ impl<'a: 'b, 'b> From<Msg<'a>> for Publish<'b> {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct Msg<'a> {
     pub qos: QoS,
     pub retain: bool,
@@ -37,6 +38,25 @@ pub struct Msg<'a> {
     pub payload: &'a [u8],
 }
 
+impl Msg<'_> {
+    /// Fails on a topic name the broker would disconnect for.
+    pub(crate) fn validate(&self) -> Result<(), crate::Error> {
+        encode::check_utf8(self.topic)?;
+
+        if !crate::topic::is_valid_topic(self.topic) {
+            return Err(crate::Error::InvalidTopicName);
+        }
+
+        Ok(())
+    }
+}
+
+/// Acknowledges a received QoS 1 or 2 message in manual acknowledgement mode,
+/// see [`crate::Connection::ack`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AckToken(pub(crate) PacketId);
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Flags {
     pub dup: bool,
@@ -53,7 +73,7 @@ impl TryFrom<u8> for Flags {
         let retain = (value & 0b0001) != 0;
 
         if qos == QoS::AtMostOnce && dup {
-            return Err(crate::Error::MalformedPacket);
+            return Err(crate::DecodeError::MalformedPacket.into());
         }
 
         Ok(Self { dup, qos, retain })
@@ -90,10 +110,75 @@ impl<'a> encode::EncodePacket for &Publish<'a> {
     }
 }
 
+impl Publish<'_> {
+    /// Longest fixed header: the type byte and a 4 byte remaining length.
+    #[cfg(any(feature = "serde-json-core", feature = "postcard"))]
+    pub(crate) const MAX_FIXED_HEADER_LEN: usize = 5;
+
+    /// Length of the topic and packet id, which is known before the id is assigned.
+    pub(crate) fn variable_header_len(&self) -> usize {
+        let packet_id_len = match self.flags.qos {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce | QoS::ExactlyOnce => 2,
+        };
+
+        self.topic.required_space() + packet_id_len
+    }
+
+    /// Length of everything before a payload of `payload_len` bytes.
+    pub(crate) fn header_len(&self, payload_len: usize) -> Result<usize, crate::Error> {
+        let variable_header_len = self.variable_header_len();
+        let remaining_len = encode::calculate_remaining_length(variable_header_len + payload_len)?;
+
+        Ok(1 + remaining_len + variable_header_len)
+    }
+
+    /// Length of the whole packet, with room for a packet id yet to be assigned.
+    pub(crate) fn encoded_len(&self) -> Result<usize, crate::Error> {
+        let payload_len = self.payload.required_space();
+
+        Ok(self.header_len(payload_len)? + payload_len)
+    }
+
+    /// Encodes everything before a payload of `payload_len` bytes, ignoring `self.payload`.
+    #[cfg(any(feature = "serde-json-core", feature = "postcard"))]
+    pub(crate) fn encode_header(
+        &self,
+        payload_len: usize,
+        cursor: &mut encode::Cursor,
+    ) -> Result<(), crate::Error> {
+        cursor.write_u8(((PacketType::Publish as u8) << 4) | u8::from(&self.flags))?;
+        encode::remaining_length(self.variable_header_len() + payload_len, cursor)?;
+
+        self.topic.encode(cursor)?;
+        if let Some(id) = self.packet_id {
+            id.0.encode(cursor)?;
+        }
+
+        Ok(())
+    }
+}
+
 impl<'a> Publish<'a> {
+    /// Token to acknowledge the message with in manual acknowledgement mode,
+    /// `None` for QoS 0.
+    pub fn ack_token(&self) -> Option<AckToken> {
+        self.packet_id.map(AckToken)
+    }
+
+    /// Deserializes the payload, borrowing from the receive buffer where the format allows.
+    #[cfg(any(feature = "serde-json-core", feature = "postcard"))]
+    pub fn deserialize<T: crate::payload::FromPayload<'a>>(&self) -> Result<T, crate::Error> {
+        T::from_payload(self.payload.as_bytes())
+    }
+
     pub(crate) fn decode(cursor: &mut decode::Cursor<'a>, flags: u8) -> Result<Self, crate::Error> {
         let flags = Flags::try_from(flags)?;
-        let topic = buffer::String::from(buffer::Slice::from(cursor.read_binary()?));
+        let topic = cursor.read_utf8()?;
+        if !crate::topic::is_valid_topic(topic) {
+            return Err(crate::DecodeError::InvalidTopicName.into());
+        }
+        let topic = buffer::String::from(topic);
 
         let packet_id = if let QoS::AtMostOnce = flags.qos {
             None
@@ -114,29 +199,53 @@ impl<'a> Publish<'a> {
 
 #[cfg(test)]
 mod tests {
-    // use super::*;
-
-    // #[test]
-    // fn parse_simple_packet() {
-    //     let flags = 0b0000_0000;
-    //     let body = [
-    //         0x00, 0x05, b't', b'o', b'p', b'i', b'c', b'p', b'a', b'y', b'l', b'o', b'a', b'd',
-    //     ];
-    //     let mut cursor = decode::Cursor::new(&body);
-    //     let mut buf = [0u8; 32];
-    //     let mut provider = buffer::Bump::new(&mut buf);
-    //     let packet = Publish::decode(&mut cursor, &mut provider, flags).unwrap();
-
-    //     assert!(matches!(
-    //         packet.flags,
-    //         Flags {
-    //             dup: false,
-    //             qos: QoS::AtMostOnce,
-    //             retain: false
-    //         }
-    //     ));
-    //     assert_eq!(packet.packet_id, None);
-    //     assert_eq!(packet.topic, "topic");
-    //     assert_eq!(packet.payload, b"payload".as_slice());
-    // }
+    use super::*;
+
+    #[test]
+    fn parse_simple_packet() {
+        let flags = 0b0000_0000;
+        let body = [
+            0x00, 0x05, b't', b'o', b'p', b'i', b'c', b'p', b'a', b'y', b'l', b'o', b'a', b'd',
+        ];
+        let mut cursor = decode::Cursor::new(&body);
+        let packet = Publish::decode(&mut cursor, flags).unwrap();
+
+        assert!(matches!(
+            packet.flags,
+            Flags {
+                dup: false,
+                qos: QoS::AtMostOnce,
+                retain: false
+            }
+        ));
+        assert_eq!(packet.packet_id, None);
+        assert_eq!(packet.topic, "topic");
+        assert_eq!(packet.payload, b"payload".as_slice());
+    }
+
+    #[test]
+    fn invalid_topic_names() {
+        let decode = |body: &[u8]| Publish::decode(&mut decode::Cursor::new(body), 0).map(|_| ());
+
+        assert!(decode(&[0x00, 0x03, b'a', b'/', b'b', b'x']).is_ok());
+        for body in [
+            &[0x00, 0x00, b'x'][..],
+            &[0x00, 0x01, b'#'],
+            &[0x00, 0x03, b'a', b'/', b'+'],
+        ] {
+            assert!(matches!(
+                decode(body),
+                Err(crate::Error::Decode(crate::DecodeError::InvalidTopicName))
+            ));
+        }
+        assert!(matches!(
+            decode(&[0x00, 0x02, b'a', 0x00]),
+            Err(crate::Error::Decode(crate::DecodeError::NullCharacter))
+        ));
+        // A lone surrogate, U+D800
+        assert!(matches!(
+            decode(&[0x00, 0x03, 0xED, 0xA0, 0x80]),
+            Err(crate::Error::Decode(crate::DecodeError::InvalidUtf8))
+        ));
+    }
 }
@@ -7,13 +7,12 @@ use crate::{
         encode::{self, Encode},
     },
     protocol::PacketType,
-    session,
 };
 
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub(crate) struct Subscribe<'a, const N: usize = 1> {
-    packet_id: PacketId,
-    topics: Vec<Subscription<'a>, N>,
+pub struct Subscribe<'a, const N: usize = 1> {
+    pub(crate) packet_id: PacketId,
+    pub(crate) topics: Vec<Subscription<'a>, N>,
 }
 
 pub struct Options<'a> {
@@ -21,11 +20,36 @@ pub struct Options<'a> {
     pub topic: &'a str,
 }
 
-impl<'a> Subscribe<'a> {
+impl Options<'_> {
+    /// Fails on a topic filter the broker would disconnect for.
+    pub(crate) fn validate(&self) -> Result<(), crate::Error> {
+        encode::check_utf8(self.topic)?;
+
+        if !crate::topic::is_valid_filter(self.topic) {
+            return Err(crate::Error::InvalidTopicFilter);
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a, const N: usize> Subscribe<'a, N> {
+    pub fn new(packet_id: PacketId, topics: Vec<Subscription<'a>, N>) -> Self {
+        Self { packet_id, topics }
+    }
+
+    pub fn packet_id(&self) -> PacketId {
+        self.packet_id
+    }
+
+    pub fn topics(&self) -> &[Subscription<'a>] {
+        &self.topics
+    }
+
     pub(crate) fn decode(cursor: &mut decode::Cursor<'a>) -> Result<Self, crate::Error> {
         let packet_id = PacketId::decode(cursor)?;
 
-        let mut topics = Vec::<Subscription<'a>, 1>::new();
+        let mut topics = Vec::<Subscription<'a>, N>::new();
 
         while !cursor.is_empty() {
             let topic_filter = buffer::String::from(cursor.read_utf8()?);
@@ -37,21 +61,18 @@ impl<'a> Subscribe<'a> {
         }
 
         if topics.is_empty() {
-            return Err(crate::Error::MalformedPacket);
+            return Err(crate::DecodeError::MalformedPacket.into());
         }
 
         Ok(Subscribe { packet_id, topics })
     }
+}
 
-    pub(crate) fn single(packet_id: PacketId, sub: session::Subscription<'a>) -> Self {
+impl<'a> Subscribe<'a> {
+    pub(crate) fn single(packet_id: PacketId, topic_filter: &'a str, qos: QoS) -> Self {
         let mut topics = Vec::new();
 
-        topics
-            .push(Subscription {
-                topic_filter: buffer::String::from(sub.topic),
-                qos: sub.qos,
-            })
-            .unwrap();
+        topics.push(Subscription::new(topic_filter, qos)).unwrap();
 
         Self { packet_id, topics }
     }
@@ -87,9 +108,27 @@ impl<'a, const P: usize> encode::EncodePacket for &Subscribe<'a, P> {
 
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-struct Subscription<'a> {
-    topic_filter: buffer::String<'a>,
-    qos: QoS,
+pub struct Subscription<'a> {
+    pub(crate) topic_filter: buffer::String<'a>,
+    pub(crate) qos: QoS,
+}
+
+impl<'a> Subscription<'a> {
+    pub fn new(topic_filter: &'a str, qos: QoS) -> Self {
+        Self {
+            topic_filter: buffer::String::from(topic_filter),
+            qos,
+        }
+    }
+
+    pub fn topic_filter(&self) -> &buffer::String<'a> {
+        &self.topic_filter
+    }
+
+    /// Maximum QoS requested for the filter.
+    pub fn qos(&self) -> QoS {
+        self.qos
+    }
 }
 
 impl<'a> encode::Encode for Subscription<'a> {
@@ -104,12 +143,28 @@ impl<'a> encode::Encode for Subscription<'a> {
 }
 
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub(crate) struct SubAck<const N: usize = 1> {
+pub struct SubAck<const N: usize = 1> {
     pub(crate) packet_id: PacketId,
     pub(crate) return_codes: Vec<SubAckReturnCode, N>,
 }
 
 impl<const N: usize> SubAck<N> {
+    pub fn new(packet_id: PacketId, return_codes: Vec<SubAckReturnCode, N>) -> Self {
+        Self {
+            packet_id,
+            return_codes,
+        }
+    }
+
+    pub fn packet_id(&self) -> PacketId {
+        self.packet_id
+    }
+
+    /// One per topic filter of the SUBSCRIBE, in the same order.
+    pub fn return_codes(&self) -> &[SubAckReturnCode] {
+        &self.return_codes
+    }
+
     pub(crate) fn decode(cursor: &mut decode::Cursor<'_>) -> Result<SubAck<N>, crate::Error> {
         let packet_id = PacketId::decode(cursor)?;
         let mut return_codes = Vec::<SubAckReturnCode, N>::new();
@@ -122,7 +177,7 @@ impl<const N: usize> SubAck<N> {
         }
 
         if return_codes.is_empty() {
-            return Err(crate::Error::MalformedPacket);
+            return Err(crate::DecodeError::MalformedPacket.into());
         }
 
         Ok(SubAck {
@@ -132,9 +187,32 @@ impl<const N: usize> SubAck<N> {
     }
 }
 
+impl<const N: usize> encode::EncodePacket for &SubAck<N> {
+    const PACKET_TYPE: PacketType = PacketType::SubAck;
+
+    fn flags(&self) -> u8 {
+        0
+    }
+
+    fn required_space(&self) -> usize {
+        self.packet_id.required_space() + self.return_codes.len()
+    }
+
+    fn encode_body(&self, cursor: &mut encode::Cursor) -> Result<(), crate::Error> {
+        self.packet_id.encode(cursor)?;
+
+        for code in &self.return_codes {
+            (*code as u8).encode(cursor)?;
+        }
+
+        Ok(())
+    }
+}
+
 #[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub(crate) enum SubAckReturnCode {
+pub enum SubAckReturnCode {
     SuccessMaxQoS0 = 0x00,
     SuccessMaxQoS1 = 0x01,
     SuccessMaxQoS2 = 0x02,
@@ -150,7 +228,7 @@ impl TryFrom<u8> for SubAckReturnCode {
             0x01 => Self::SuccessMaxQoS1,
             0x02 => Self::SuccessMaxQoS2,
             0x80 => Self::Failure,
-            _ => return Err(crate::Error::MalformedPacket),
+            _ => return Err(crate::DecodeError::MalformedPacket.into()),
         };
 
         Ok(code)
@@ -163,35 +241,57 @@ mod tests {
 
     use super::*;
 
-    // fn parse_suback<const N: usize>(
-    //     body: &[u8],
-    //     buf: &mut [u8],
-    // ) -> Result<SubAck<N>, crate::Error> {
-    //     let mut provider = buffer::Bump::new(buf);
-    //     SubAck::<N>::decode(&mut decode::Cursor::new(&body), &mut provider, 0)
-    // }
-
-    // #[test]
-    // fn suback_single_success() {
-    //     // packet_id = 16, return code = 1
-    //     let body = [0x00, 0x10, 0x01];
-    //     let mut buf = [0u8; 16];
-    //     let packet = parse_suback::<1>(&body, &mut buf[..]).unwrap();
-
-    //     assert_eq!(packet.packet_id.0, 16);
-    //     assert_eq!(packet.return_codes.len(), 1);
-    //     assert!(matches!(
-    //         packet.return_codes[0],
-    //         SubAckReturnCode::SuccessMaxQoS1
-    //     ));
-    // }
-
-    // #[test]
-    // fn suback_invalid_return_code() {
-    //     let body = [0x00, 0x10, 0x05];
-    //     let mut buf = [0u8; 16];
-    //     assert!(parse_suback::<1>(&body, &mut buf[..]).is_err());
-    // }
+    fn parse_suback<const N: usize>(body: &[u8]) -> Result<SubAck<N>, crate::Error> {
+        SubAck::<N>::decode(&mut decode::Cursor::new(body))
+    }
+
+    #[test]
+    fn suback_single_success() {
+        // packet_id = 16, return code = 1
+        let body = [0x00, 0x10, 0x01];
+        let packet = parse_suback::<1>(&body).unwrap();
+
+        assert_eq!(packet.packet_id.0, 16);
+        assert_eq!(packet.return_codes.len(), 1);
+        assert!(matches!(
+            packet.return_codes[0],
+            SubAckReturnCode::SuccessMaxQoS1
+        ));
+    }
+
+    #[test]
+    fn suback_invalid_return_code() {
+        let body = [0x00, 0x10, 0x05];
+        assert!(parse_suback::<1>(&body).is_err());
+    }
+
+    #[test]
+    fn encode_suback() {
+        let packet = parse_suback::<2>(&[0x00, 0x10, 0x02, 0x80]).unwrap();
+        let mut buf = [0u8; 4];
+        let mut cursor = encode::Cursor::new(&mut buf);
+
+        (&packet).encode_body(&mut cursor).unwrap();
+
+        assert_eq!(buf, [0x00, 0x10, 0x02, 0x80]);
+    }
+
+    #[test]
+    fn decode_subscribe_multiple_topics() {
+        let body = [0x00, 0x0A, 0x00, 0x01, b'a', 0x00, 0x00, 0x01, b'b', 0x02];
+        let packet = Subscribe::<2>::decode(&mut decode::Cursor::new(&body)).unwrap();
+
+        assert_eq!(packet.packet_id, PacketId(10));
+        assert_eq!(packet.topics[0].topic_filter, "a");
+        assert_eq!(packet.topics[0].qos, QoS::AtMostOnce);
+        assert_eq!(packet.topics[1].topic_filter, "b");
+        assert_eq!(packet.topics[1].qos, QoS::ExactlyOnce);
+
+        assert!(matches!(
+            Subscribe::<1>::decode(&mut decode::Cursor::new(&body)),
+            Err(crate::Error::VectorIsFull)
+        ));
+    }
 
     fn make_subscribe<'a, const N: usize>() -> Subscribe<'a, N> {
         let mut topics: Vec<Subscription, N> = Vec::new();
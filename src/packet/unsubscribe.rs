@@ -10,12 +10,24 @@ use crate::{
 };
 
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub(crate) struct Unsubscribe<'a, const N: usize = 1> {
-    packet_id: PacketId,
-    topics: Vec<buffer::String<'a>, N>,
+pub struct Unsubscribe<'a, const N: usize = 1> {
+    pub(crate) packet_id: PacketId,
+    pub(crate) topics: Vec<buffer::String<'a>, N>,
 }
 
 impl<'a, const N: usize> Unsubscribe<'a, N> {
+    pub fn new(packet_id: PacketId, topics: Vec<buffer::String<'a>, N>) -> Self {
+        Self { packet_id, topics }
+    }
+
+    pub fn packet_id(&self) -> PacketId {
+        self.packet_id
+    }
+
+    pub fn topics(&self) -> &[buffer::String<'a>] {
+        &self.topics
+    }
+
     pub(crate) fn single(packet_id: PacketId, topic: &'a str) -> Self {
         let mut topics = Vec::new();
         topics.push(buffer::String::from(topic)).unwrap();
@@ -34,7 +46,7 @@ impl<'a, const N: usize> Unsubscribe<'a, N> {
         }
 
         if topics.is_empty() {
-            return Err(crate::Error::MalformedPacket);
+            return Err(crate::DecodeError::MalformedPacket.into());
         }
 
         Ok(Unsubscribe { packet_id, topics })
@@ -68,9 +80,3 @@ impl<'a, const P: usize> encode::EncodePacket for &Unsubscribe<'a, P> {
         Ok(())
     }
 }
-
-// impl<'buf, P, const N: usize> decode::DecodePacket<'buf, P> for Unsubscribe<'buf, N>
-// where
-//     P: buffer::Provider<'buf>,
-// {
-// }
@@ -1,4 +1,4 @@
-use crate::packet::PacketId;
+use crate::{ProtocolErrorKind, packet::PacketId};
 
 enum Kind {
     Sub,
@@ -6,6 +6,8 @@ enum Kind {
 }
 
 #[derive(Clone, PartialEq)]
+// Named after the packet each state waits for, so they all start with AwaitPub
+#[allow(clippy::enum_variant_names)]
 enum PubInFlightState {
     AwaitPubAck,
     AwaitPubRec,
@@ -105,16 +107,13 @@ impl<const N_PUB_OUT: usize, const N_SUB: usize> PacketIdPool<N_PUB_OUT, N_SUB>
         Err(crate::Error::NoPacketIdAvailable)
     }
 
-    pub(crate) fn set_pubrel(&mut self, packet_id: &PacketId) -> Result<(), crate::Error> {
-        let index = self
+    pub(crate) fn set_pubrel(&mut self, packet_id: &PacketId) -> Result<(), ProtocolErrorKind> {
+        let publ = self
             .in_flight_pub
-            .iter()
-            .position(|p| p.as_ref().map(|p| &p.id) == Some(packet_id))
-            .ok_or(crate::Error::ProtocolViolation)?;
-
-        let publ = self.in_flight_pub[index]
-            .as_mut()
-            .ok_or(crate::Error::ProtocolViolation)?;
+            .iter_mut()
+            .flatten()
+            .find(|p| p.id == *packet_id)
+            .ok_or(ProtocolErrorKind::UnknownPacketId)?;
 
         match publ.state {
             PubInFlightState::AwaitPubRec => {
@@ -122,7 +121,7 @@ impl<const N_PUB_OUT: usize, const N_SUB: usize> PacketIdPool<N_PUB_OUT, N_SUB>
                 Ok(())
             }
             PubInFlightState::AwaitPubComp => Ok(()),
-            _ => Err(crate::Error::ProtocolViolation),
+            _ => Err(ProtocolErrorKind::OutOfOrder),
         }
     }
 
@@ -130,7 +129,7 @@ impl<const N_PUB_OUT: usize, const N_SUB: usize> PacketIdPool<N_PUB_OUT, N_SUB>
         &mut self,
         packet_id: &PacketId,
         just_ack: bool,
-    ) -> Result<(), crate::Error> {
+    ) -> Result<(), ProtocolErrorKind> {
         let compare_pub =
             |publ: &Option<PubInFlight>| publ.as_ref().map(|p| p.id == *packet_id).unwrap_or(false);
 
@@ -138,7 +137,7 @@ impl<const N_PUB_OUT: usize, const N_SUB: usize> PacketIdPool<N_PUB_OUT, N_SUB>
             Some(index) => {
                 let entry = self.in_flight_pub[index]
                     .as_ref()
-                    .ok_or(crate::Error::ProtocolViolation)?;
+                    .ok_or(ProtocolErrorKind::UnknownPacketId)?;
                 let state = &entry.state;
 
                 match (just_ack, state) {
@@ -147,22 +146,25 @@ impl<const N_PUB_OUT: usize, const N_SUB: usize> PacketIdPool<N_PUB_OUT, N_SUB>
                         self.in_flight_pub[index] = None;
                         Ok(())
                     }
-                    _ => Err(crate::Error::ProtocolViolation),
+                    _ => Err(ProtocolErrorKind::OutOfOrder),
                 }
             }
-            None => Err(crate::Error::ProtocolViolation),
+            None => Err(ProtocolErrorKind::UnknownPacketId),
         }
     }
 
-    pub(crate) fn release_sub_id(&mut self, packet_id: &PacketId) -> Result<(), crate::Error> {
+    pub(crate) fn release_sub_id(&mut self, packet_id: &PacketId) -> Result<(), ProtocolErrorKind> {
         self.release_for(Kind::Sub, packet_id)
     }
 
-    pub(crate) fn release_unsub_id(&mut self, packet_id: &PacketId) -> Result<(), crate::Error> {
+    pub(crate) fn release_unsub_id(
+        &mut self,
+        packet_id: &PacketId,
+    ) -> Result<(), ProtocolErrorKind> {
         self.release_for(Kind::Unsub, packet_id)
     }
 
-    fn release_for(&mut self, kind: Kind, packet_id: &PacketId) -> Result<(), crate::Error> {
+    fn release_for(&mut self, kind: Kind, packet_id: &PacketId) -> Result<(), ProtocolErrorKind> {
         let array = self.array_mut(&kind);
 
         match array.iter().position(|id| *id == packet_id.0) {
@@ -170,7 +172,7 @@ impl<const N_PUB_OUT: usize, const N_SUB: usize> PacketIdPool<N_PUB_OUT, N_SUB>
                 array[index] = 0;
                 Ok(())
             }
-            None => Err(crate::Error::ProtocolViolation),
+            None => Err(ProtocolErrorKind::UnknownPacketId),
         }
     }
 
@@ -1,12 +1,13 @@
 use embedded_io_async::Read;
 
 use crate::{
+    TransportError,
     packet::{Packet, decode},
     protocol::{FixedHeader, PacketType},
 };
 
-fn parse_fixed_header(buf: &[u8]) -> Result<Option<(FixedHeader, usize)>, crate::Error> {
-    if buf.len() == 0 {
+pub(crate) fn parse_fixed_header(buf: &[u8]) -> Result<Option<(FixedHeader, usize)>, crate::Error> {
+    if buf.is_empty() {
         return Ok(None);
     }
 
@@ -36,7 +37,7 @@ fn parse_first_byte(byte: u8) -> Result<(PacketType, u8), crate::Error> {
     let flags = byte & 0x0F;
 
     if !packet_type.validate_flags(flags) {
-        return Err(crate::Error::InvalidFlags);
+        return Err(crate::DecodeError::InvalidFlags.into());
     }
 
     Ok((packet_type, flags))
@@ -60,87 +61,141 @@ fn parse_remaining_len(
         let digit = (byte & 0x7F) as usize;
         remaining_len = remaining_len
             .checked_add(digit * multiplier)
-            .ok_or(crate::Error::MalformedRemainingLength)?;
+            .ok_or(crate::DecodeError::MalformedRemainingLength)?;
 
         if (byte & 0x80) == 0 {
             return Ok(Some((remaining_len, bytes_read)));
         }
 
         if bytes_read >= 4 {
-            return Err(crate::Error::MalformedRemainingLength);
+            return Err(crate::DecodeError::MalformedRemainingLength.into());
         }
 
         multiplier *= 128;
     }
 }
 
-pub(crate) struct StreamParser<'a> {
-    buf: &'a mut [u8],
+/// Push-based (sans-I/O) packet decoder.
+///
+/// Bytes can come from anywhere: a DMA ring buffer, an interrupt handler or a
+/// blocking read. The decoder copies them into `buf` and yields a packet once
+/// its body is complete; the packet borrows `buf` until the next call.
+pub struct Decoder<'buf> {
+    buf: &'buf mut [u8],
     start: usize,
     end: usize,
+    header: Option<FixedHeader>,
 }
 
-impl<'a> StreamParser<'a> {
-    pub(crate) fn new(buf: &'a mut [u8]) -> Self {
+impl<'buf> Decoder<'buf> {
+    pub fn new(buf: &'buf mut [u8]) -> Self {
         Self {
             buf,
             start: 0,
             end: 0,
+            header: None,
         }
     }
 
-    pub(crate) async fn read<R: Read>(&mut self, read: &mut R) -> Result<Packet<'_>, crate::Error> {
-        let mut fixed_header = None;
-
-        loop {
-            if fixed_header.is_none() {
-                if let Some((header, header_len)) =
-                    parse_fixed_header(&self.buf[self.start..self.end])?
-                {
-                    fixed_header = Some(header);
-                    self.start += header_len;
+    /// Feeds `input` into the decoder.
+    ///
+    /// Returns the number of bytes consumed and the packet they completed, if any.
+    /// No bytes past the end of the current packet are consumed, so the caller
+    /// feeds the rest of `input` on the next call.
+    pub fn feed<const N: usize>(
+        &mut self,
+        input: &[u8],
+    ) -> Result<(usize, Option<Packet<'_, N>>), crate::Error> {
+        self.compact();
+        let mut consumed = 0;
+
+        while consumed < input.len() && !self.is_ready()? {
+            // The header length is unknown until it's parsed, so take it byte by byte.
+            let len = match self.header {
+                Some(header) => {
+                    (header.remaining_len - self.available_data_len()).min(input.len() - consumed)
                 }
-            }
+                None => 1,
+            };
 
-            if let Some(header) = fixed_header {
-                let remaining_len = header.remaining_len;
+            self.push(&input[consumed..consumed + len])?;
+            consumed += len;
+        }
 
-                if remaining_len > self.buf.len() {
-                    return Err(crate::Error::BufferTooSmall);
-                }
+        if self.is_ready()? {
+            Ok((consumed, Some(self.take()?)))
+        } else {
+            Ok((consumed, None))
+        }
+    }
 
-                if self.available_data_len() >= remaining_len {
-                    let packet =
-                        Packet::decode(&header, &self.buf[self.start..self.start + remaining_len])?;
-                    self.start += remaining_len;
+    /// Drops any partially received packet, e.g. after the transport reconnected.
+    pub fn reset(&mut self) {
+        self.start = 0;
+        self.end = 0;
+        self.header = None;
+    }
 
-                    if self.start == self.end {
-                        self.start = 0;
-                        self.end = 0;
-                    }
+    /// Free space to read transport data into directly. Follow with [`Self::commit`].
+    pub(crate) fn spare_mut(&mut self) -> Result<&mut [u8], crate::Error> {
+        self.compact();
 
-                    return Ok(packet);
-                }
-            };
+        if self.end == self.buf.len() {
+            #[cfg(feature = "defmt")]
+            defmt::warn!("Read buffer full, cannot read more data");
+            return Err(crate::Error::BufferTooSmall);
+        }
+
+        Ok(&mut self.buf[self.end..])
+    }
 
+    pub(crate) fn commit(&mut self, len: usize) {
+        self.end = (self.end + len).min(self.buf.len());
+    }
+
+    /// Parses the fixed header if needed and reports whether a whole packet is buffered.
+    pub(crate) fn is_ready(&mut self) -> Result<bool, crate::Error> {
+        if self.header.is_none()
+            && let Some((header, header_len)) = parse_fixed_header(&self.buf[self.start..self.end])?
+        {
+            self.header = Some(header);
+            self.start += header_len;
+            // The body may need all of `buf`
             self.compact();
-            let read_buf = &mut self.buf[self.end..];
-            if read_buf.is_empty() {
-                #[cfg(feature = "defmt")]
-                defmt::warn!("Read buffer full, cannot read more data");
-                return Err(crate::Error::BufferTooSmall);
-            }
-            let n = read
-                .read(read_buf)
-                .await
-                .map_err(|_| crate::Error::TransportError)?;
+        }
 
-            if n == 0 {
-                return Err(crate::Error::RemoteClosed);
+        match self.header {
+            Some(header) if header.remaining_len > self.buf.len() => {
+                Err(crate::Error::BufferTooSmall)
             }
+            Some(header) => Ok(self.available_data_len() >= header.remaining_len),
+            None => Ok(false),
+        }
+    }
+
+    /// Decodes the buffered packet. Only valid after [`Self::is_ready`] returned `true`.
+    pub(crate) fn take<const N: usize>(&mut self) -> Result<Packet<'_, N>, crate::Error> {
+        let header = self
+            .header
+            .take()
+            .ok_or(crate::DecodeError::MalformedPacket)?;
+        let body_start = self.start;
+        self.start += header.remaining_len;
+
+        Packet::decode_body(&header, &self.buf[body_start..self.start])
+    }
 
-            self.end += n;
+    fn push(&mut self, bytes: &[u8]) -> Result<(), crate::Error> {
+        let end = self.end + bytes.len();
+
+        if end > self.buf.len() {
+            return Err(crate::Error::BufferTooSmall);
         }
+
+        self.buf[self.end..end].copy_from_slice(bytes);
+        self.end = end;
+
+        Ok(())
     }
 
     fn available_data_len(&self) -> usize {
@@ -164,135 +219,335 @@ impl<'a> StreamParser<'a> {
     }
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use heapless::Vec;
+/// [`Decoder`] fed straight from an [`embedded_io_async::Read`] transport.
+pub(crate) struct StreamParser<'a> {
+    decoder: Decoder<'a>,
+}
+
+impl<'a> StreamParser<'a> {
+    pub(crate) fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            decoder: Decoder::new(buf),
+        }
+    }
+
+    pub(crate) fn feed(
+        &mut self,
+        input: &[u8],
+    ) -> Result<(usize, Option<Packet<'_>>), crate::Error> {
+        self.decoder.feed(input)
+    }
+
+    /// Reads the next packet from `read`.
+    ///
+    /// Cancellation-safe: all decoding state lives in the decoder, so the returned
+    /// future can be dropped at any await point and a later call resumes where
+    /// the previous one stopped.
+    pub(crate) async fn read<R: Read>(
+        &mut self,
+        read: &mut R,
+    ) -> Result<Packet<'_>, crate::Error<R::Error>> {
+        loop {
+            if self.decoder.is_ready().map_err(crate::Error::widen)? {
+                return self.decoder.take().map_err(crate::Error::widen);
+            }
+
+            let n = read
+                .read(self.decoder.spare_mut().map_err(crate::Error::widen)?)
+                .await
+                .map_err(TransportError)?;
+
+            if n == 0 {
+                return Err(crate::Error::RemoteClosed);
+            }
 
-//     use crate::Error;
+            self.decoder.commit(n);
+        }
+    }
 
-//     use super::*;
+    /// Blocking counterpart of [`Self::read`].
+    ///
+    /// A read that fails with [`embedded_io::ErrorKind::TimedOut`] yields `Ok(None)`;
+    /// anything received so far is kept for the next call.
+    pub(crate) fn read_blocking<R: embedded_io::Read>(
+        &mut self,
+        read: &mut R,
+    ) -> Result<Option<Packet<'_>>, crate::Error<R::Error>> {
+        use embedded_io::Error as _;
 
-//     fn collect_events<'a, const N: usize>(
-//         parser: &mut Parser,
-//         mut input: &'a [u8],
-//     ) -> Result<Vec<Event<'a>, N>, Error> {
-//         let mut events = Vec::new();
+        loop {
+            if self.decoder.is_ready().map_err(crate::Error::widen)? {
+                return self.decoder.take().map(Some).map_err(crate::Error::widen);
+            }
 
-//         loop {
-//             let (consumed, event) = parser.parse(input)?;
+            let n = match read.read(self.decoder.spare_mut().map_err(crate::Error::widen)?) {
+                Ok(0) => return Err(crate::Error::RemoteClosed),
+                Ok(n) => n,
+                Err(err) if err.kind() == embedded_io::ErrorKind::TimedOut => return Ok(None),
+                Err(err) => return Err(TransportError(err).into()),
+            };
 
-//             if let Some(event) = event {
-//                 events.push(event).unwrap();
-//             };
+            self.decoder.commit(n);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        convert::Infallible,
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use embedded_io_async::ErrorType;
 
-//             if input.is_empty() {
-//                 return Ok(events);
-//             }
+    use super::*;
+    use crate::packet::PacketId;
 
-//             input = &input[consumed..];
-//         }
-//     }
+    /// Hands out `chunk` bytes per read and returns `Pending` before every read,
+    /// so each byte boundary is an await point.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        chunk: usize,
+        stall: bool,
+    }
 
-//     #[test]
-//     fn remaining_len_single_byte() {
-//         let mut parser = Parser::new();
-
-//         // PingReq, remaining length = 0
-//         let data = [0b1100_0000, 0x00];
-//         let events = collect_events::<2>(&mut parser, &data).unwrap();
-
-//         assert_eq!(events.len(), 2);
-
-//         assert!(matches!(events[0], Event::PacketStart { header } if header.remaining_len == 0));
-//         assert!(matches!(events[1], Event::PacketEnd));
-//     }
-
-//     #[test]
-//     fn remaining_len_multibyte() {
-//         let mut parser = Parser::new();
-
-//         // PingReq, remaining length = 321
-//         let data = [0b1100_0000, 0xC1, 0x02];
-//         let events = collect_events::<1>(&mut parser, &data).unwrap();
-//         assert_eq!(events.len(), 1);
-
-//         match &events[0] {
-//             Event::PacketStart { header } => assert_eq!(header.remaining_len, 321),
-//             _ => panic!("Expected PacketStart"),
-//         }
-//     }
-
-//     #[test]
-//     fn body_single_chunk() {
-//         let mut parser = Parser::new();
-
-//         // Publish, remaining length = 3
-//         let data = [0b0011_0000, 0x03, 0xAA, 0xBB, 0xCC];
-//         let events = collect_events::<3>(&mut parser, &data).unwrap();
-
-//         assert_eq!(events.len(), 3);
-//         assert!(matches!(events[0], Event::PacketStart { .. }));
-
-//         match events[1] {
-//             Event::PacketBody { chunk } => assert_eq!(chunk, &[0xAA, 0xBB, 0xCC]),
-//             _ => panic!("Expected PacketBody"),
-//         }
-
-//         assert!(matches!(events[2], Event::PacketEnd));
-//     }
-
-//     #[test]
-//     fn body_split_across_inputs() {
-//         let mut parser = Parser::new();
-
-//         let data1 = [0b0011_0000, 0x04, 0x01];
-//         let data2 = [0x23, 0x45];
-//         let data3 = [0x67];
-
-//         let mut events: Vec<Event<'_>, 6> = Vec::new();
-
-//         for chunk in [&data1[..], &data2[..], &data3[..]] {
-//             for event in collect_events::<2>(&mut parser, &chunk)
-//                 .unwrap()
-//                 .into_iter()
-//             {
-//                 events.push(event).unwrap();
-//             }
-//         }
-
-//         assert!(matches!(events[0], Event::PacketStart { .. }));
-
-//         let body: Vec<u8, 4> = events
-//             .iter()
-//             .filter(|event| matches!(event, Event::PacketBody { .. }))
-//             .flat_map(|event| match event {
-//                 Event::PacketBody { chunk } => chunk.into_iter().copied(),
-//                 _ => unreachable!(),
-//             })
-//             .collect();
-
-//         assert_eq!(&body, &[0x01, 0x23, 0x45, 0x67]);
-//         assert!(matches!(events.last(), Some(Event::PacketEnd)));
-//     }
-
-//     #[test]
-//     fn two_packets_back_to_back() {
-//         let mut parser = Parser::new();
-//         let data = [0b1100_0000, 0x00, 0b1100_0000, 0x00];
-
-//         let events = collect_events::<4>(&mut parser, &data).unwrap();
-
-//         assert_eq!(events.len(), 4);
-//         assert!(matches!(events[1], Event::PacketEnd));
-//         assert!(matches!(events[3], Event::PacketEnd));
-//     }
-
-//     #[test]
-//     fn remaining_len_too_long() {
-//         let mut parser = Parser::new();
-//         let data = [0b1100_0000, 0xFF, 0xFF, 0xFF, 0xFF, 0x01];
-//         let err = collect_events::<2>(&mut parser, &data).unwrap_err();
-
-//         assert!(matches!(err, crate::Error::MalformedRemainingLength));
-//     }
-// }
+    impl<'a> Trickle<'a> {
+        fn new(data: &'a [u8], chunk: usize) -> Self {
+            Self {
+                data,
+                chunk,
+                stall: true,
+            }
+        }
+    }
+
+    impl ErrorType for Trickle<'_> {
+        type Error = Infallible;
+    }
+
+    impl Read for Trickle<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            core::future::poll_fn(|cx| {
+                if core::mem::take(&mut self.stall) {
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+
+                self.stall = true;
+                let n = self.chunk.min(self.data.len()).min(buf.len());
+                buf[..n].copy_from_slice(&self.data[..n]);
+                self.data = &self.data[n..];
+
+                Poll::Ready(Ok(n))
+            })
+            .await
+        }
+    }
+
+    fn poll_once<F: Future>(fut: F) -> Poll<F::Output> {
+        let mut cx = Context::from_waker(Waker::noop());
+        pin!(fut).poll(&mut cx)
+    }
+
+    fn assert_publish(packet: Packet<'_>, topic: &str, payload: &[u8]) {
+        match packet {
+            Packet::Publish(publish) => {
+                assert_eq!(publish.topic, topic);
+                assert_eq!(publish.payload, payload);
+            }
+            _ => panic!("Expected Publish"),
+        }
+    }
+
+    // PUBLISH QoS 0, topic "a/b", 200-byte payload (two-byte remaining length), then PINGRESP
+    fn stream() -> [u8; 3 + 5 + 200 + 2] {
+        let mut data = [0xAB; 3 + 5 + 200 + 2];
+        data[..8].copy_from_slice(&[0x30, 0xCD, 0x01, 0x00, 0x03, b'a', b'/', b'b']);
+        data[208..].copy_from_slice(&[0xD0, 0x00]);
+        data
+    }
+
+    #[test]
+    fn read_whole_stream() {
+        let data = stream();
+        let mut transport = Trickle::new(&data, data.len());
+        let mut buf = [0u8; 256];
+        let mut parser = StreamParser::new(&mut buf);
+
+        let packet = loop {
+            if let Poll::Ready(res) = poll_once(parser.read(&mut transport)) {
+                break res.unwrap();
+            }
+        };
+        assert_publish(packet, "a/b", &[0xAB; 200]);
+    }
+
+    #[test]
+    fn read_survives_drop_at_every_await_point() {
+        let data = stream();
+        let mut transport = Trickle::new(&data, 1);
+        let mut buf = [0u8; 256];
+        let mut parser = StreamParser::new(&mut buf);
+
+        // Every future is polled exactly once and then dropped.
+        let mut drops = 0;
+        loop {
+            match poll_once(parser.read(&mut transport)) {
+                Poll::Ready(res) => {
+                    assert_publish(res.unwrap(), "a/b", &[0xAB; 200]);
+                    break;
+                }
+                Poll::Pending => drops += 1,
+            }
+        }
+        assert!(drops > 200);
+
+        let packet = loop {
+            if let Poll::Ready(res) = poll_once(parser.read(&mut transport)) {
+                break res.unwrap();
+            }
+        };
+        assert!(matches!(packet, Packet::PingResp));
+    }
+
+    #[test]
+    fn read_survives_drop_with_varying_chunk_sizes() {
+        let data = stream();
+
+        for chunk in 1..8 {
+            let mut transport = Trickle::new(&data, chunk);
+            let mut buf = [0u8; 256];
+            let mut parser = StreamParser::new(&mut buf);
+
+            let mut pending = true;
+            while pending {
+                if let Poll::Ready(res) = poll_once(parser.read(&mut transport)) {
+                    assert_publish(res.unwrap(), "a/b", &[0xAB; 200]);
+                    pending = false;
+                }
+            }
+
+            let packet = loop {
+                if let Poll::Ready(res) = poll_once(parser.read(&mut transport)) {
+                    break res.unwrap();
+                }
+            };
+            assert!(matches!(packet, Packet::PingResp));
+        }
+    }
+
+    #[test]
+    fn decoder_remaining_len_single_byte() {
+        let mut buf = [0u8; 16];
+        let mut decoder = Decoder::new(&mut buf);
+
+        // PingReq, remaining length = 0
+        let (consumed, packet) = decoder.feed::<1>(&[0b1100_0000, 0x00]).unwrap();
+
+        assert_eq!(consumed, 2);
+        assert!(matches!(packet, Some(Packet::PingReq)));
+    }
+
+    #[test]
+    fn decoder_remaining_len_multibyte() {
+        let data = stream();
+        let mut buf = [0u8; 256];
+        let mut decoder = Decoder::new(&mut buf);
+
+        let (consumed, packet) = decoder.feed::<1>(&data[..3]).unwrap();
+        assert_eq!(consumed, 3);
+        assert!(packet.is_none());
+        drop(packet);
+        assert_eq!(decoder.header.unwrap().remaining_len, 205);
+    }
+
+    #[test]
+    fn decoder_body_split_across_inputs() {
+        let mut buf = [0u8; 16];
+        let mut decoder = Decoder::new(&mut buf);
+
+        // PubAck, packet_id = 0x1234
+        for chunk in [&[0b0100_0000][..], &[0x02, 0x12][..]] {
+            let (consumed, packet) = decoder.feed::<1>(chunk).unwrap();
+            assert_eq!(consumed, chunk.len());
+            assert!(packet.is_none());
+        }
+
+        let (consumed, packet) = decoder.feed::<1>(&[0x34]).unwrap();
+        assert_eq!(consumed, 1);
+        assert!(matches!(packet, Some(Packet::PubAck(PacketId(0x1234)))));
+    }
+
+    #[test]
+    fn decoder_two_packets_back_to_back() {
+        let data = stream();
+        let mut buf = [0u8; 256];
+        let mut decoder = Decoder::new(&mut buf);
+
+        let (consumed, packet) = decoder.feed::<1>(&data).unwrap();
+        assert_eq!(consumed, data.len() - 2);
+        assert_publish(packet.unwrap(), "a/b", &[0xAB; 200]);
+
+        let (consumed, packet) = decoder.feed::<1>(&data[data.len() - 2..]).unwrap();
+        assert_eq!(consumed, 2);
+        assert!(matches!(packet, Some(Packet::PingResp)));
+    }
+
+    #[test]
+    fn decoder_byte_by_byte() {
+        let data = stream();
+        let mut buf = [0u8; 256];
+        let mut decoder = Decoder::new(&mut buf);
+        let mut packets = 0;
+
+        for byte in &data {
+            let (consumed, packet) = decoder.feed::<1>(core::slice::from_ref(byte)).unwrap();
+            assert_eq!(consumed, 1);
+            packets += packet.is_some() as usize;
+        }
+
+        assert_eq!(packets, 2);
+    }
+
+    #[test]
+    fn decoder_remaining_len_too_long() {
+        let mut buf = [0u8; 16];
+        let mut decoder = Decoder::new(&mut buf);
+        assert!(matches!(
+            decoder.feed::<1>(&[0b1100_0000, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]),
+            Err(crate::Error::Decode(
+                crate::DecodeError::MalformedRemainingLength
+            ))
+        ));
+    }
+
+    #[test]
+    fn decoder_body_filling_whole_buffer() {
+        let mut buf = [0u8; 2];
+        let mut decoder = Decoder::new(&mut buf);
+
+        // PubAck, packet_id = 1, whose body takes all of `buf` once the header is gone
+        let (consumed, packet) = decoder.feed::<1>(&[0x40, 0x02, 0x00, 0x01]).unwrap();
+        assert_eq!(consumed, 4);
+        assert!(matches!(packet, Some(Packet::PubAck(PacketId(1)))));
+    }
+
+    #[test]
+    fn decoder_packet_larger_than_buffer() {
+        let data = stream();
+        let mut buf = [0u8; 64];
+        let mut decoder = Decoder::new(&mut buf);
+
+        assert!(matches!(
+            decoder.feed::<1>(&data),
+            Err(crate::Error::BufferTooSmall)
+        ));
+
+        decoder.reset();
+        let (_, packet) = decoder.feed::<1>(&data[data.len() - 2..]).unwrap();
+        assert!(matches!(packet, Some(Packet::PingResp)));
+    }
+}
@@ -0,0 +1,84 @@
+//! Typed payloads for [`crate::Connection::schedule_publish_serialized`] and
+//! [`crate::packet::publish::Publish::deserialize`].
+//!
+//! Wrap a value in the format it goes over the wire in, e.g. `Json(&reading)`.
+//! Serialization writes straight into the outbox and deserialization borrows
+//! from the receive buffer, so neither needs a buffer of its own.
+
+/// A value that serializes into a PUBLISH payload.
+pub trait ToPayload {
+    /// Serializes into `buf` and returns the number of bytes written.
+    fn to_payload(&self, buf: &mut [u8]) -> Result<usize, crate::Error>;
+}
+
+/// A value that deserializes from a PUBLISH payload, possibly borrowing from it.
+pub trait FromPayload<'a>: Sized {
+    fn from_payload(bytes: &'a [u8]) -> Result<Self, crate::Error>;
+}
+
+/// JSON payload, without allocation through `serde-json-core`.
+///
+/// ```
+/// use mqtt_client::{PublishMsg, QoS, packet::publish::Publish, payload::Json};
+///
+/// #[derive(serde::Deserialize)]
+/// struct Setpoint<'a> {
+///     room: &'a str,
+///     celsius: u8,
+/// }
+///
+/// let publish = Publish::from(PublishMsg {
+///     qos: QoS::AtMostOnce,
+///     retain: false,
+///     topic: "setpoint",
+///     payload: br#"{"room":"hall","celsius":21}"#,
+/// });
+///
+/// let Json(setpoint) = publish.deserialize::<Json<Setpoint>>().unwrap();
+/// assert_eq!((setpoint.room, setpoint.celsius), ("hall", 21));
+/// ```
+#[cfg(feature = "serde-json-core")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Json<T>(pub T);
+
+#[cfg(feature = "serde-json-core")]
+impl<T: serde::Serialize> ToPayload for Json<T> {
+    fn to_payload(&self, buf: &mut [u8]) -> Result<usize, crate::Error> {
+        serde_json_core::to_slice(&self.0, buf).map_err(|_| crate::Error::BufferTooSmall)
+    }
+}
+
+#[cfg(feature = "serde-json-core")]
+impl<'a, T: serde::Deserialize<'a>> FromPayload<'a> for Json<T> {
+    fn from_payload(bytes: &'a [u8]) -> Result<Self, crate::Error> {
+        let (value, _) =
+            serde_json_core::from_slice(bytes).map_err(|_| crate::Error::InvalidPayload)?;
+
+        Ok(Self(value))
+    }
+}
+
+/// Compact binary payload in the [postcard](https://postcard.jamesmunns.com) wire format.
+#[cfg(feature = "postcard")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Postcard<T>(pub T);
+
+#[cfg(feature = "postcard")]
+impl<T: serde::Serialize> ToPayload for Postcard<T> {
+    fn to_payload(&self, buf: &mut [u8]) -> Result<usize, crate::Error> {
+        match postcard::to_slice(&self.0, buf) {
+            Ok(written) => Ok(written.len()),
+            Err(postcard::Error::SerializeBufferFull) => Err(crate::Error::BufferTooSmall),
+            Err(_) => Err(crate::Error::InvalidPayload),
+        }
+    }
+}
+
+#[cfg(feature = "postcard")]
+impl<'a, T: serde::Deserialize<'a>> FromPayload<'a> for Postcard<T> {
+    fn from_payload(bytes: &'a [u8]) -> Result<Self, crate::Error> {
+        postcard::from_bytes(bytes)
+            .map(Self)
+            .map_err(|_| crate::Error::InvalidPayload)
+    }
+}
@@ -0,0 +1,712 @@
+//! Flash-backed store-and-forward, for outages longer than a RAM queue bridges.
+//!
+//! [`PersistentQueue`] appends messages to a circular log on a [`Storage`], one
+//! CRC-checked record each, and finds them again after a reboot. Hand it to
+//! [`crate::Connection::set_persistent_queue`], through
+//! [`crate::Client::connection_mut`] with a client: publishes scheduled while not
+//! connected go to the log, and once CONNACK has arrived they're sent one at a
+//! time with QoS 1, each record deleted when its PUBACK comes back.
+//!
+//! Records don't span sectors. The sector after the newest record is erased
+//! when the log moves into it, so the log needs at least two sectors.
+
+use crate::{
+    offline::Overflow,
+    packet::{Packet, PacketId, QoS, publish},
+};
+
+/// Flash-like storage: erased bytes read as `0xFF`, bytes are written once
+/// between erases, and erasing works on whole sectors.
+///
+/// Mirrors `embedded_storage::nor_flash::NorFlash`, which `Flash` adapts with
+/// the `embedded-storage` feature.
+pub trait Storage {
+    type Error;
+
+    /// Alignment of write offsets and lengths.
+    const WRITE_SIZE: usize;
+    /// Size of the unit [`Self::erase`] works on.
+    const SECTOR_SIZE: usize;
+
+    fn capacity(&self) -> usize;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error>;
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error>;
+
+    /// Erases the sectors in `from..to`.
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error>;
+}
+
+impl<S: Storage> Storage for &mut S {
+    type Error = S::Error;
+
+    const WRITE_SIZE: usize = S::WRITE_SIZE;
+    const SECTOR_SIZE: usize = S::SECTOR_SIZE;
+
+    fn capacity(&self) -> usize {
+        S::capacity(self)
+    }
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        S::read(self, offset, bytes)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        S::write(self, offset, bytes)
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        S::erase(self, from, to)
+    }
+}
+
+/// [`Storage`] on an `embedded-storage` NOR flash, e.g. a partition of the
+/// MCU's own flash. The flash has to allow reads of any offset and length.
+#[cfg(feature = "embedded-storage")]
+pub struct Flash<F>(pub F);
+
+#[cfg(feature = "embedded-storage")]
+impl<F: embedded_storage::nor_flash::NorFlash> Storage for Flash<F> {
+    type Error = F::Error;
+
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const SECTOR_SIZE: usize = F::ERASE_SIZE;
+
+    fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read(offset, bytes)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.0.write(offset, bytes)
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.0.erase(from, to)
+    }
+}
+
+// A record is a deletion marker of `WRITE_SIZE` bytes, left erased until the
+// record is deleted, then this header, the topic and the payload, padded to
+// `WRITE_SIZE`. The CRC covers the rest of the header, the topic and the payload.
+const HEADER_LEN: usize = 4 + 2 + 4 + 1 + 4;
+const CRC_OFFSET: usize = HEADER_LEN - 4;
+
+#[derive(Clone, Copy)]
+struct Header {
+    seq: u32,
+    topic_len: usize,
+    payload_len: usize,
+    flags: u8,
+}
+
+impl Header {
+    fn encode(&self, crc: u32) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[4..6].copy_from_slice(&(self.topic_len as u16).to_le_bytes());
+        bytes[6..10].copy_from_slice(&(self.payload_len as u32).to_le_bytes());
+        bytes[10] = self.flags;
+        bytes[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8; HEADER_LEN]) -> (Self, u32) {
+        let header = Self {
+            seq: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            topic_len: usize::from(u16::from_le_bytes([bytes[4], bytes[5]])),
+            payload_len: u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]) as usize,
+            flags: bytes[10],
+        };
+        let crc = u32::from_le_bytes([bytes[11], bytes[12], bytes[13], bytes[14]]);
+
+        (header, crc)
+    }
+
+    fn body_len(&self) -> usize {
+        self.topic_len + self.payload_len
+    }
+}
+
+/// CRC-32 (IEEE 802.3), bit by bit to keep it small.
+fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+
+    !crc
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Pos {
+    sector: usize,
+    offset: usize,
+}
+
+/// FIFO of messages in a circular log on `S`.
+///
+/// `buf` holds one record's topic and payload while it's read back, so it
+/// bounds the size of a message, and has to be at least `S::WRITE_SIZE` long.
+pub struct PersistentQueue<'q, S: Storage> {
+    storage: S,
+    buf: &'q mut [u8],
+    overflow: Overflow,
+    sectors: usize,
+    head: Pos,
+    tail: Pos,
+    next_seq: u32,
+    dropped: usize,
+}
+
+impl<'q, S: Storage> PersistentQueue<'q, S> {
+    /// Opens the log on `storage`, picking up any messages left from before a
+    /// reboot. Storage that never held a log is fine, it's treated as empty.
+    pub fn new(storage: S, buf: &'q mut [u8], overflow: Overflow) -> Result<Self, crate::Error> {
+        let sectors = storage.capacity() / S::SECTOR_SIZE;
+        if sectors < 2 || buf.len() < S::WRITE_SIZE {
+            return Err(crate::Error::BufferTooSmall);
+        }
+
+        let start = Pos {
+            sector: 0,
+            offset: 0,
+        };
+        let mut queue = Self {
+            storage,
+            buf,
+            overflow,
+            sectors,
+            head: start,
+            tail: start,
+            next_seq: 0,
+            dropped: 0,
+        };
+        queue.recover()?;
+
+        Ok(queue)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head == self.tail
+    }
+
+    /// Number of messages dropped by the overflow policy so far.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Appends `msg` to the log.
+    pub fn push(&mut self, msg: &publish::Msg<'_>) -> Result<(), crate::Error> {
+        let header = Header {
+            seq: self.next_seq,
+            topic_len: msg.topic.len(),
+            payload_len: msg.payload.len(),
+            flags: (msg.qos as u8) << 1 | msg.retain as u8,
+        };
+        let len = Self::record_len(&header);
+
+        if u16::try_from(header.topic_len).is_err()
+            || header.body_len() > self.buf.len()
+            || len > S::SECTOR_SIZE
+        {
+            return Err(crate::Error::BufferTooSmall);
+        }
+
+        if self.tail.offset + len > S::SECTOR_SIZE {
+            let next = self.next_sector(self.tail.sector);
+
+            if next.sector == self.head.sector && !self.is_empty() {
+                match self.overflow {
+                    Overflow::DropOldest => self.drop_head_sector()?,
+                    Overflow::DropNewest => {
+                        self.dropped += 1;
+                        return Ok(());
+                    }
+                    Overflow::Reject => return Err(crate::Error::QueueFull),
+                }
+            }
+
+            let start = Self::address(next);
+            self.storage
+                .erase(start, start + S::SECTOR_SIZE as u32)
+                .map_err(|_| crate::Error::Storage)?;
+
+            let was_empty = self.is_empty();
+            self.tail = next;
+            if was_empty {
+                self.head = self.tail;
+            }
+        }
+
+        let mut crc = crc32(0, &header.encode(0)[..CRC_OFFSET]);
+        crc = crc32(crc, msg.topic.as_bytes());
+        crc = crc32(crc, msg.payload);
+
+        let at = Self::address(self.tail) + S::WRITE_SIZE as u32;
+        self.write(
+            at,
+            &[&header.encode(crc), msg.topic.as_bytes(), msg.payload],
+        )?;
+
+        self.tail.offset += len;
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        Ok(())
+    }
+
+    /// Oldest message.
+    pub fn front(&mut self) -> Result<Option<publish::Msg<'_>>, crate::Error> {
+        Ok(self.read_front()?.map(|(_, msg)| msg))
+    }
+
+    /// Deletes the oldest message.
+    pub fn pop_front(&mut self) -> Result<(), crate::Error> {
+        if let Some(header) = self.read_front_header()? {
+            self.delete_front(&header)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_front(&mut self) -> Result<Option<(u32, publish::Msg<'_>)>, crate::Error> {
+        let Some(header) = self.read_front_header()? else {
+            return Ok(None);
+        };
+
+        let (topic, payload) = self.buf[..header.body_len()].split_at(header.topic_len);
+        let msg = publish::Msg {
+            qos: QoS::try_from((header.flags >> 1) & 0b11)?,
+            retain: header.flags & 1 != 0,
+            topic: core::str::from_utf8(topic).map_err(|_| crate::DecodeError::InvalidUtf8)?,
+            payload,
+        };
+
+        Ok(Some((header.seq, msg)))
+    }
+
+    /// Header of the oldest live record, with its body read into `buf`.
+    fn read_front_header(&mut self) -> Result<Option<Header>, crate::Error> {
+        while !self.is_empty() {
+            match self.read_record(self.head)? {
+                Some((header, false)) => return Ok(Some(header)),
+                // Deleted just before a power loss
+                Some((header, true)) => self.advance_head(Self::record_len(&header))?,
+                None => self.skip_head_sector(),
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn delete_front(&mut self, header: &Header) -> Result<(), crate::Error> {
+        self.buf[..S::WRITE_SIZE].fill(0);
+        self.storage
+            .write(Self::address(self.head), &self.buf[..S::WRITE_SIZE])
+            .map_err(|_| crate::Error::Storage)?;
+
+        self.advance_head(Self::record_len(header))
+    }
+
+    fn advance_head(&mut self, len: usize) -> Result<(), crate::Error> {
+        self.head.offset += len;
+
+        if self.head != self.tail && self.read_record(self.head)?.is_none() {
+            self.skip_head_sector();
+        }
+
+        Ok(())
+    }
+
+    /// Moves on from the last record in the head's sector.
+    fn skip_head_sector(&mut self) {
+        self.head = if self.head.sector == self.tail.sector {
+            self.tail
+        } else {
+            self.next_sector(self.head.sector)
+        };
+    }
+
+    /// Erases the sector of the oldest records, making room for the newest.
+    fn drop_head_sector(&mut self) -> Result<(), crate::Error> {
+        let sector = self.head.sector;
+
+        while self.head.sector == sector && !self.is_empty() {
+            match self.read_record(self.head)? {
+                Some((header, _)) => {
+                    self.dropped += 1;
+                    self.advance_head(Self::record_len(&header))?;
+                }
+                None => self.skip_head_sector(),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finds the oldest live record and the end of the newest one.
+    fn recover(&mut self) -> Result<(), crate::Error> {
+        let mut oldest: Option<(u32, Pos)> = None;
+        let mut newest: Option<(u32, Pos)> = None;
+
+        for sector in 0..self.sectors {
+            let mut pos = Pos { sector, offset: 0 };
+
+            while let Some((header, deleted)) = self.read_record(pos)? {
+                let end = Pos {
+                    sector,
+                    offset: pos.offset + Self::record_len(&header),
+                };
+
+                if newest.is_none_or(|(seq, _)| header.seq > seq) {
+                    newest = Some((header.seq, end));
+                }
+                if !deleted && oldest.is_none_or(|(seq, _)| header.seq < seq) {
+                    oldest = Some((header.seq, pos));
+                }
+
+                pos = end;
+            }
+        }
+
+        if let Some((seq, end)) = newest {
+            self.tail = end;
+            self.next_seq = seq.wrapping_add(1);
+        }
+
+        // A record torn by a power loss, or storage that never held a log:
+        // leave the rest of the sector alone and start over in the next one
+        if !self.is_erased(self.tail)? {
+            self.tail.offset = S::SECTOR_SIZE;
+        }
+
+        self.head = oldest.map_or(self.tail, |(_, pos)| pos);
+
+        Ok(())
+    }
+
+    /// Reads the record at `pos` and its body into `buf`, or `None` if there's
+    /// no intact record.
+    fn read_record(&mut self, pos: Pos) -> Result<Option<(Header, bool)>, crate::Error> {
+        if pos.offset + S::WRITE_SIZE + HEADER_LEN > S::SECTOR_SIZE {
+            return Ok(None);
+        }
+
+        let at = Self::address(pos);
+        let mut marker = [0; 1];
+        let mut bytes = [0; HEADER_LEN];
+        self.read(at, &mut marker)?;
+        self.read(at + S::WRITE_SIZE as u32, &mut bytes)?;
+
+        let (header, crc) = Header::decode(&bytes);
+        if header.body_len() > self.buf.len()
+            || pos.offset + Self::record_len(&header) > S::SECTOR_SIZE
+        {
+            return Ok(None);
+        }
+
+        let body_at = at + (S::WRITE_SIZE + HEADER_LEN) as u32;
+        let body_len = header.body_len();
+        self.storage
+            .read(body_at, &mut self.buf[..body_len])
+            .map_err(|_| crate::Error::Storage)?;
+
+        let actual = crc32(crc32(0, &bytes[..CRC_OFFSET]), &self.buf[..body_len]);
+        if actual != crc {
+            return Ok(None);
+        }
+
+        Ok(Some((header, marker[0] != 0xFF)))
+    }
+
+    /// Whether the sector is erased from `pos` on.
+    fn is_erased(&mut self, pos: Pos) -> Result<bool, crate::Error> {
+        let mut offset = pos.offset;
+
+        while offset < S::SECTOR_SIZE {
+            let len = self.buf.len().min(S::SECTOR_SIZE - offset);
+            let at = Self::address(Pos { offset, ..pos });
+            self.storage
+                .read(at, &mut self.buf[..len])
+                .map_err(|_| crate::Error::Storage)?;
+
+            if self.buf[..len].iter().any(|byte| *byte != 0xFF) {
+                return Ok(false);
+            }
+            offset += len;
+        }
+
+        Ok(true)
+    }
+
+    /// Writes `parts` back to back at `at`, padded to `WRITE_SIZE`, staging them in `buf`.
+    fn write(&mut self, mut at: u32, parts: &[&[u8]]) -> Result<(), crate::Error> {
+        let chunk_len = self.buf.len() - self.buf.len() % S::WRITE_SIZE;
+        let mut filled = 0;
+
+        for byte in parts.iter().flat_map(|part| part.iter()) {
+            self.buf[filled] = *byte;
+            filled += 1;
+
+            if filled == chunk_len {
+                self.storage
+                    .write(at, &self.buf[..filled])
+                    .map_err(|_| crate::Error::Storage)?;
+                at += filled as u32;
+                filled = 0;
+            }
+        }
+
+        let padded = filled.next_multiple_of(S::WRITE_SIZE);
+        self.buf[filled..padded].fill(0xFF);
+        if padded > 0 {
+            self.storage
+                .write(at, &self.buf[..padded])
+                .map_err(|_| crate::Error::Storage)?;
+        }
+
+        Ok(())
+    }
+
+    fn read(&mut self, at: u32, bytes: &mut [u8]) -> Result<(), crate::Error> {
+        self.storage
+            .read(at, bytes)
+            .map_err(|_| crate::Error::Storage)
+    }
+
+    fn next_sector(&self, sector: usize) -> Pos {
+        Pos {
+            sector: (sector + 1) % self.sectors,
+            offset: 0,
+        }
+    }
+
+    fn record_len(header: &Header) -> usize {
+        S::WRITE_SIZE + (HEADER_LEN + header.body_len()).next_multiple_of(S::WRITE_SIZE)
+    }
+
+    fn address(pos: Pos) -> u32 {
+        (pos.sector * S::SECTOR_SIZE + pos.offset) as u32
+    }
+}
+
+/// What the connection needs of a [`PersistentQueue`], without its storage type.
+pub(crate) trait Backlog {
+    fn is_empty(&self) -> bool;
+
+    fn push(&mut self, msg: &publish::Msg<'_>) -> Result<(), crate::Error>;
+
+    /// Oldest message and its sequence number.
+    fn front(&mut self) -> Result<Option<(u32, publish::Msg<'_>)>, crate::Error>;
+
+    /// Deletes the oldest message if it's still the one with sequence number `seq`.
+    fn remove(&mut self, seq: u32) -> Result<(), crate::Error>;
+}
+
+impl<S: Storage> Backlog for PersistentQueue<'_, S> {
+    fn is_empty(&self) -> bool {
+        PersistentQueue::is_empty(self)
+    }
+
+    fn push(&mut self, msg: &publish::Msg<'_>) -> Result<(), crate::Error> {
+        PersistentQueue::push(self, msg)
+    }
+
+    fn front(&mut self) -> Result<Option<(u32, publish::Msg<'_>)>, crate::Error> {
+        self.read_front()
+    }
+
+    fn remove(&mut self, seq: u32) -> Result<(), crate::Error> {
+        match self.read_front_header()? {
+            Some(header) if header.seq == seq => self.delete_front(&header),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The connection's side of a persistent queue: the record it has in flight.
+pub(crate) struct Drain<'c> {
+    queue: Option<&'c mut dyn Backlog>,
+    in_flight: Option<(PacketId, u32)>,
+}
+
+impl<'c> Drain<'c> {
+    pub(crate) fn new() -> Self {
+        Self {
+            queue: None,
+            in_flight: None,
+        }
+    }
+
+    pub(crate) fn set(&mut self, queue: &'c mut dyn Backlog) {
+        self.queue = Some(queue);
+        self.in_flight = None;
+    }
+
+    pub(crate) fn queue(&mut self) -> Option<&mut (dyn Backlog + 'c)> {
+        self.queue.as_deref_mut()
+    }
+
+    /// Next record to send, unless one is still waiting for its PUBACK.
+    pub(crate) fn next(&mut self) -> Option<(u32, publish::Msg<'_>)> {
+        if self.in_flight.is_some() {
+            return None;
+        }
+
+        // A storage error leaves the record for the next attempt
+        self.queue.as_deref_mut()?.front().ok().flatten()
+    }
+
+    pub(crate) fn sent(&mut self, packet_id: PacketId, seq: u32) {
+        self.in_flight = Some((packet_id, seq));
+    }
+
+    /// Deletes the record in flight once it's acknowledged. A new session starts over.
+    pub(crate) fn on_packet(&mut self, packet: &Packet<'_>) {
+        match (packet, self.in_flight) {
+            (Packet::ConnAck(_), _) => self.in_flight = None,
+            (Packet::PubAck(id), Some((in_flight, seq))) if *id == in_flight => {
+                self.in_flight = None;
+
+                if let Some(queue) = self.queue.as_deref_mut() {
+                    // A record that can't be deleted is sent again, which QoS 1 allows
+                    let _ = queue.remove(seq);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::RamStorage;
+
+    // Two 24 byte records fit a sector
+    type Ram = RamStorage<128, 64, 4>;
+
+    fn push<S: Storage>(queue: &mut PersistentQueue<'_, S>, topic: &str) {
+        queue
+            .push(&publish::Msg {
+                qos: QoS::AtLeastOnce,
+                retain: false,
+                topic,
+                payload: b"xy",
+            })
+            .unwrap();
+    }
+
+    fn pop<S: Storage>(queue: &mut PersistentQueue<'_, S>) -> Option<heapless::String<4>> {
+        let msg = queue.front().unwrap()?;
+        assert_eq!(msg.payload, b"xy");
+        let topic = msg.topic.try_into().unwrap();
+        queue.pop_front().unwrap();
+
+        Some(topic)
+    }
+
+    fn topics<S: Storage>(
+        queue: &mut PersistentQueue<'_, S>,
+    ) -> heapless::Vec<heapless::String<4>, 8> {
+        core::iter::from_fn(|| pop(queue)).collect()
+    }
+
+    #[test]
+    fn survives_reboot() {
+        let mut storage = Ram::new();
+        let mut buf = [0u8; 16];
+
+        let mut queue = PersistentQueue::new(&mut storage, &mut buf, Overflow::Reject).unwrap();
+        assert!(queue.is_empty());
+        for topic in ["a", "b", "c"] {
+            push(&mut queue, topic);
+        }
+        assert_eq!(pop(&mut queue).unwrap(), "a");
+
+        let mut queue = PersistentQueue::new(&mut storage, &mut buf, Overflow::Reject).unwrap();
+        assert_eq!(pop(&mut queue).unwrap(), "b");
+        push(&mut queue, "d");
+
+        let mut queue = PersistentQueue::new(&mut storage, &mut buf, Overflow::Reject).unwrap();
+        assert_eq!(topics(&mut queue), ["c", "d"]);
+        assert!(queue.is_empty());
+
+        let mut queue = PersistentQueue::new(&mut storage, &mut buf, Overflow::Reject).unwrap();
+        assert!(queue.front().unwrap().is_none());
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let mut buf = [0u8; 16];
+
+        let mut storage = Ram::new();
+        let mut queue = PersistentQueue::new(&mut storage, &mut buf, Overflow::DropOldest).unwrap();
+        for topic in ["a", "b", "c", "d", "e"] {
+            push(&mut queue, topic);
+        }
+        assert_eq!(queue.dropped(), 2);
+        let mut queue = PersistentQueue::new(&mut storage, &mut buf, Overflow::DropOldest).unwrap();
+        assert_eq!(topics(&mut queue), ["c", "d", "e"]);
+
+        let mut storage = Ram::new();
+        let mut queue = PersistentQueue::new(&mut storage, &mut buf, Overflow::DropNewest).unwrap();
+        for topic in ["a", "b", "c", "d", "e"] {
+            push(&mut queue, topic);
+        }
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(topics(&mut queue), ["a", "b", "c", "d"]);
+
+        let mut storage = Ram::new();
+        let mut queue = PersistentQueue::new(&mut storage, &mut buf, Overflow::Reject).unwrap();
+        for topic in ["a", "b", "c", "d"] {
+            push(&mut queue, topic);
+        }
+        let msg = publish::Msg {
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic: "e",
+            payload: b"",
+        };
+        assert!(matches!(queue.push(&msg), Err(crate::Error::QueueFull)));
+        // Doesn't fit `buf` for reading back
+        let msg = publish::Msg {
+            payload: &[0; 16],
+            ..msg
+        };
+        assert!(matches!(
+            queue.push(&msg),
+            Err(crate::Error::BufferTooSmall)
+        ));
+
+        // Space freed by popping is reused once the log wraps around
+        assert_eq!(pop(&mut queue).unwrap(), "a");
+        assert_eq!(pop(&mut queue).unwrap(), "b");
+        push(&mut queue, "e");
+        assert_eq!(topics(&mut queue), ["c", "d", "e"]);
+    }
+
+    #[test]
+    fn torn_record_is_dropped() {
+        let mut storage = Ram::new();
+        let mut buf = [0u8; 16];
+
+        let mut queue = PersistentQueue::new(&mut storage, &mut buf, Overflow::Reject).unwrap();
+        push(&mut queue, "a");
+        push(&mut queue, "b");
+
+        // Power lost while writing the payload of "b"
+        storage.as_mut_bytes()[24 + 4 + HEADER_LEN + 1] = 0xFF;
+
+        let mut queue = PersistentQueue::new(&mut storage, &mut buf, Overflow::Reject).unwrap();
+        push(&mut queue, "c");
+        assert_eq!(topics(&mut queue), ["a", "c"]);
+    }
+}
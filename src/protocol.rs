@@ -1,7 +1,7 @@
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub(crate) enum PacketType {
+pub enum PacketType {
     Connect = 1,
     ConnAck = 2,
     Publish = 3,
@@ -30,16 +30,39 @@ impl PacketType {
     }
 }
 
+impl core::fmt::Display for PacketType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Self::Connect => "CONNECT",
+            Self::ConnAck => "CONNACK",
+            Self::Publish => "PUBLISH",
+            Self::PubAck => "PUBACK",
+            Self::PubRec => "PUBREC",
+            Self::PubRel => "PUBREL",
+            Self::PubComp => "PUBCOMP",
+            Self::Subscribe => "SUBSCRIBE",
+            Self::SubAck => "SUBACK",
+            Self::Unsubscribe => "UNSUBSCRIBE",
+            Self::UnsubAck => "UNSUBACK",
+            Self::PingReq => "PINGREQ",
+            Self::PingResp => "PINGRESP",
+            Self::Disconnect => "DISCONNECT",
+            #[cfg(feature = "v50")]
+            Self::Auth => "AUTH",
+        })
+    }
+}
+
 impl TryFrom<u8> for PacketType {
     type Error = crate::Error;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
             #[cfg(not(feature = "v50"))]
-            1..=14 => Ok(unsafe { core::mem::transmute(value) }),
+            1..=14 => Ok(unsafe { core::mem::transmute::<u8, Self>(value) }),
             #[cfg(feature = "v50")]
-            1..=15 => Ok(unsafe { core::mem::transmute(value) }),
-            _ => Err(crate::Error::InvalidPacketType),
+            1..=15 => Ok(unsafe { core::mem::transmute::<u8, Self>(value) }),
+            _ => Err(crate::DecodeError::InvalidPacketType.into()),
         }
     }
 }
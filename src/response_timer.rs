@@ -0,0 +1,156 @@
+use embedded_time::{Instant, duration};
+use heapless::Vec;
+
+use crate::{
+    SessionState,
+    keep_alive::after,
+    packet::{Packet, PacketId},
+};
+
+/// A request the broker didn't answer in time.
+pub(crate) enum Expired {
+    ConnAck,
+    /// SUBACK or UNSUBACK.
+    SubAck(PacketId),
+}
+
+/// Deadlines for the broker's answers to CONNECT, SUBSCRIBE and UNSUBSCRIBE.
+/// Nothing expires until a timeout has been set.
+pub(crate) struct ResponseTimer<C: embedded_time::Clock, const N_SUB: usize> {
+    connect_timeout: Option<duration::Generic<C::T>>,
+    subscribe_timeout: Option<duration::Generic<C::T>>,
+    connect_sent: Option<Instant<C>>,
+    // Subscribes and unsubscribes each have N_SUB packet ids
+    subscribes_sent: Vec<(PacketId, Instant<C>), N_SUB>,
+    unsubscribes_sent: Vec<(PacketId, Instant<C>), N_SUB>,
+}
+
+impl<C, const N_SUB: usize> ResponseTimer<C, N_SUB>
+where
+    C: embedded_time::Clock,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            connect_timeout: None,
+            subscribe_timeout: None,
+            connect_sent: None,
+            subscribes_sent: Vec::new(),
+            unsubscribes_sent: Vec::new(),
+        }
+    }
+
+    pub(crate) fn set_connect_timeout(&mut self, timeout: duration::Generic<C::T>) {
+        self.connect_timeout = Some(timeout);
+    }
+
+    pub(crate) fn set_subscribe_timeout(&mut self, timeout: duration::Generic<C::T>) {
+        self.subscribe_timeout = Some(timeout);
+    }
+
+    /// Starts waiting for the answer to `packet`, if it has one.
+    pub(crate) fn on_send(&mut self, packet: &Packet<'_>, now: Instant<C>) {
+        match packet {
+            // Anything from the previous connection won't be answered any more
+            Packet::Connect(_) => {
+                self.connect_sent = Some(now);
+                self.subscribes_sent.clear();
+                self.unsubscribes_sent.clear();
+            }
+            // Can't be full, the packet id pool has as many ids
+            Packet::Subscribe(subscribe) => {
+                let _ = self.subscribes_sent.push((subscribe.packet_id, now));
+            }
+            Packet::Unsubscribe(unsubscribe) => {
+                let _ = self.unsubscribes_sent.push((unsubscribe.packet_id, now));
+            }
+            _ => {}
+        }
+    }
+
+    /// Stops waiting for whatever `packet` answers.
+    pub(crate) fn on_packet(&mut self, packet: &Packet<'_>) {
+        match packet {
+            Packet::ConnAck(_) => self.connect_sent = None,
+            Packet::SubAck(suback) => self
+                .subscribes_sent
+                .retain(|(id, _)| *id != suback.packet_id),
+            Packet::UnsubAck(packet_id) => self.unsubscribes_sent.retain(|(id, _)| id != packet_id),
+            _ => {}
+        }
+    }
+
+    /// Takes the next request that has waited too long at `now`, given the
+    /// session's `state`.
+    pub(crate) fn expired(
+        &mut self,
+        now: Instant<C>,
+        state: SessionState,
+    ) -> Result<Option<Expired>, crate::Error> {
+        let timeout = match state {
+            SessionState::Disconnected => return Ok(None),
+            SessionState::Connecting => {
+                if let (Some(sent), Some(timeout)) = (self.connect_sent, self.connect_timeout)
+                    && elapsed(now, sent)? >= timeout
+                {
+                    self.connect_sent = None;
+                    return Ok(Some(Expired::ConnAck));
+                }
+
+                return Ok(None);
+            }
+            SessionState::Connected => match self.subscribe_timeout {
+                Some(timeout) => timeout,
+                None => return Ok(None),
+            },
+        };
+
+        for sent in [&mut self.subscribes_sent, &mut self.unsubscribes_sent] {
+            if let Some(index) = position_expired(sent, now, timeout)? {
+                let (packet_id, _) = sent.remove(index);
+                return Ok(Some(Expired::SubAck(packet_id)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Instant at which [`Self::expired`] returns something next.
+    pub(crate) fn next_deadline(&self, state: SessionState) -> Option<Instant<C>> {
+        let timeout = match state {
+            SessionState::Disconnected => return None,
+            SessionState::Connecting => {
+                return after(self.connect_sent?, self.connect_timeout?);
+            }
+            SessionState::Connected => self.subscribe_timeout?,
+        };
+
+        // The oldest request is first
+        [&self.subscribes_sent, &self.unsubscribes_sent]
+            .into_iter()
+            .filter_map(|sent| sent.first())
+            .filter_map(|(_, sent)| after(*sent, timeout))
+            .min()
+    }
+}
+
+fn position_expired<C: embedded_time::Clock, const N: usize>(
+    sent: &Vec<(PacketId, Instant<C>), N>,
+    now: Instant<C>,
+    timeout: duration::Generic<C::T>,
+) -> Result<Option<usize>, crate::Error> {
+    for (index, (_, at)) in sent.iter().enumerate() {
+        if elapsed(now, *at)? >= timeout {
+            return Ok(Some(index));
+        }
+    }
+
+    Ok(None)
+}
+
+fn elapsed<C: embedded_time::Clock>(
+    now: Instant<C>,
+    since: Instant<C>,
+) -> Result<duration::Generic<C::T>, crate::Error> {
+    now.checked_duration_since(&since)
+        .ok_or(crate::Error::TimeError)
+}
@@ -0,0 +1,276 @@
+//! Dispatch of received messages to handlers by topic filter.
+//!
+//! Handlers are plain function pointers sharing one context, the application
+//! state passed to [`Router::dispatch`], so nothing is allocated or boxed.
+//!
+//! ```
+//! use mqtt_client::{
+//!     PublishMsg, QoS, Router,
+//!     packet::publish::Publish,
+//!     router::Params,
+//! };
+//!
+//! #[derive(Default)]
+//! struct App {
+//!     setpoint: Option<(&'static str, u8)>,
+//!     ignored: usize,
+//! }
+//!
+//! fn on_setpoint(app: &mut App, publish: &Publish<'_>, params: &Params<'_>) {
+//!     if params.get(0) == Some("kitchen") {
+//!         app.setpoint = Some(("kitchen", publish.payload.as_bytes()[0]));
+//!     }
+//! }
+//!
+//! fn ignore(app: &mut App, _: &Publish<'_>, _: &Params<'_>) {
+//!     app.ignored += 1;
+//! }
+//!
+//! let mut router: Router<'_, App, 4> = Router::new(ignore);
+//! router.add("rooms/+/setpoint", on_setpoint).unwrap();
+//!
+//! let mut app = App::default();
+//! router.dispatch(&mut app, &Publish::from(PublishMsg {
+//!     qos: QoS::AtMostOnce,
+//!     retain: false,
+//!     topic: "rooms/kitchen/setpoint",
+//!     payload: &[21],
+//! }));
+//! assert_eq!(app.setpoint, Some(("kitchen", 21)));
+//! ```
+//!
+//! In the poll loop, [`Router::handle`] takes care of [`Event::Received`] and
+//! hands every other event back.
+
+use heapless::Vec;
+
+use crate::{packet::publish::Publish, session::Event, topic};
+
+/// Handler of the messages matching a route.
+pub type Handler<Ctx> = fn(ctx: &mut Ctx, publish: &Publish<'_>, params: &Params<'_>);
+
+/// Topic levels the `+` wildcards of a route's filter matched.
+pub struct Params<'a> {
+    filter: &'a str,
+    topic: &'a str,
+}
+
+impl<'a> Params<'a> {
+    /// Level matched by the `n`th `+`, counting from 0.
+    pub fn get(&self, n: usize) -> Option<&'a str> {
+        self.iter().nth(n)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'a str> + use<'a> {
+        self.filter
+            .split('/')
+            .zip(self.topic.split('/'))
+            .filter(|(filter, _)| *filter == "+")
+            .map(|(_, topic)| topic)
+    }
+}
+
+struct Route<'r, Ctx> {
+    filter: &'r str,
+    handler: Handler<Ctx>,
+}
+
+/// Table of up to `N` topic filters with their handlers.
+pub struct Router<'r, Ctx, const N: usize> {
+    routes: Vec<Route<'r, Ctx>, N>,
+    default: Handler<Ctx>,
+}
+
+impl<'r, Ctx, const N: usize> Router<'r, Ctx, N> {
+    /// `default` gets every message no route matches.
+    pub fn new(default: Handler<Ctx>) -> Self {
+        Self {
+            routes: Vec::new(),
+            default,
+        }
+    }
+
+    /// Adds a route. Routes are tried in the order they were added, so add
+    /// specific filters before the wildcards covering them.
+    pub fn add(&mut self, filter: &'r str, handler: Handler<Ctx>) -> Result<(), crate::Error> {
+        if !topic::is_valid_filter(filter) {
+            return Err(crate::Error::InvalidTopicFilter);
+        }
+
+        self.routes
+            .push(Route { filter, handler })
+            .map_err(|_| crate::Error::VectorIsFull)
+    }
+
+    /// Calls the handler of the first route matching the topic of `publish`, or
+    /// the default handler.
+    pub fn dispatch(&self, ctx: &mut Ctx, publish: &Publish<'_>) {
+        // Topics are checked when decoded, so this never falls back in practice
+        let topic = publish.topic.as_str().unwrap_or_default();
+
+        let (filter, handler) = self
+            .routes
+            .iter()
+            .find(|route| topic::filter_matches(route.filter, topic))
+            .map_or(("", self.default), |route| (route.filter, route.handler));
+
+        handler(ctx, publish, &Params { filter, topic });
+    }
+
+    /// Dispatches [`Event::Received`] and returns any other event.
+    pub fn handle<'a>(&self, ctx: &mut Ctx, event: Event<'a>) -> Option<Event<'a>> {
+        match event {
+            Event::Received(publish) => {
+                self.dispatch(ctx, &publish);
+                None
+            }
+            event => Some(event),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{QoS, publish};
+
+    #[derive(Default)]
+    struct Calls {
+        exact: usize,
+        rooms: heapless::Vec<(heapless::String<8>, heapless::String<8>), 2>,
+        fallback: usize,
+    }
+
+    fn exact(calls: &mut Calls, _: &Publish<'_>, params: &Params<'_>) {
+        assert_eq!(params.iter().count(), 0);
+        calls.exact += 1;
+    }
+
+    fn room(calls: &mut Calls, _: &Publish<'_>, params: &Params<'_>) {
+        let (room, sensor) = (params.get(0).unwrap(), params.get(1).unwrap());
+        assert!(params.get(2).is_none());
+
+        calls
+            .rooms
+            .push((room.try_into().unwrap(), sensor.try_into().unwrap()))
+            .unwrap();
+    }
+
+    fn fallback(calls: &mut Calls, _: &Publish<'_>, params: &Params<'_>) {
+        assert!(params.get(0).is_none());
+        calls.fallback += 1;
+    }
+
+    fn received(topic: &str) -> Event<'_> {
+        Event::Received(publish::Publish::from(publish::Msg {
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic,
+            payload: b"",
+        }))
+    }
+
+    #[test]
+    fn first_matching_route_wins() {
+        let mut router: Router<'_, Calls, 2> = Router::new(fallback);
+        router.add("rooms/hall/temp", exact).unwrap();
+        router.add("rooms/+/+/#", room).unwrap();
+        assert!(matches!(
+            router.add("rooms/#", exact),
+            Err(crate::Error::VectorIsFull)
+        ));
+
+        let mut calls = Calls::default();
+        for topic in [
+            "rooms/hall/temp",
+            "rooms/kitchen/temp",
+            "rooms/hall/humidity/raw",
+            "rooms/hall",
+        ] {
+            assert!(router.handle(&mut calls, received(topic)).is_none());
+        }
+
+        assert_eq!(calls.exact, 1);
+        let rooms = calls
+            .rooms
+            .iter()
+            .map(|(room, sensor)| (room.as_str(), sensor.as_str()));
+        assert!(rooms.eq([("kitchen", "temp"), ("hall", "humidity")]));
+        assert_eq!(calls.fallback, 1);
+
+        assert!(matches!(
+            router.handle(&mut calls, Event::Published),
+            Some(Event::Published)
+        ));
+    }
+
+    #[test]
+    fn wildcard_route_receives_from_client() {
+        use embedded_time::{duration, rate::Fraction};
+
+        use crate::{
+            Client,
+            packet::subscribe,
+            testing::{self, MockClock, MockTransport, block_on},
+        };
+
+        let opts = || testing::connect_options("c");
+        let clock = MockClock::new();
+        let mut broker = MockTransport::new();
+        broker
+            .expect(testing::connect(opts()))
+            .reply(testing::connack(false))
+            .expect(testing::subscribe(1, "rooms/+/+/#", QoS::AtMostOnce))
+            .reply(testing::suback(1, Some(QoS::AtMostOnce)))
+            .reply(testing::publish(
+                "rooms/hall/temp/raw",
+                b"",
+                QoS::AtMostOnce,
+                None,
+            ));
+        let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+        let keep_alive = duration::Generic::new(10, Fraction::new(1, 1));
+        let mut client: Client<'_, _, _, 2, 2, 2, 4> =
+            Client::try_new(&clock, keep_alive, &mut broker, &mut rx_buf, &mut tx_buf).unwrap();
+
+        let mut router: Router<'_, Calls, 2> = Router::new(fallback);
+        router.add("rooms/+/+/#", room).unwrap();
+
+        client.schedule_connect(opts()).unwrap();
+        client
+            .schedule_subscribe(subscribe::Options {
+                qos: None,
+                topic: "rooms/+/+/#",
+            })
+            .unwrap();
+
+        let mut calls = Calls::default();
+        for _ in 0..5 {
+            let event = block_on(client.poll()).unwrap();
+            if let Some(event) = event.and_then(|event| router.handle(&mut calls, event)) {
+                assert!(matches!(event, Event::Connected | Event::Subscribed { .. }));
+            }
+        }
+
+        assert_eq!(calls.rooms.len(), 1);
+        assert_eq!(calls.rooms[0].0, "hall");
+        assert_eq!(calls.fallback, 0);
+
+        drop(client);
+        broker.assert_done();
+    }
+
+    #[test]
+    fn rejects_invalid_filters() {
+        let mut router: Router<'_, Calls, 2> = Router::new(fallback);
+
+        assert!(matches!(
+            router.add("rooms/#/temp", exact),
+            Err(crate::Error::InvalidTopicFilter)
+        ));
+        assert!(matches!(
+            router.add("rooms+", exact),
+            Err(crate::Error::InvalidTopicFilter)
+        ));
+    }
+}
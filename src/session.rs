@@ -1,20 +1,25 @@
 use heapless::Vec;
 
 use crate::{
-    incoming,
+    ProtocolError, ProtocolErrorKind, StateError,
+    incoming::{self, PubInState},
     packet::{
         Packet, PacketId, QoS,
-        connect::{self, ConnAck},
-        publish,
+        connect::{self, ConnAck, ConnectReturnCode},
+        publish::{self, AckToken},
         subscribe::{self, SubAck, Subscribe},
         unsubscribe::Unsubscribe,
     },
     packet_id_pool::PacketIdPool,
+    protocol::PacketType,
+    topic,
 };
 
-#[derive(PartialEq)]
-enum State {
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SessionState {
     Disconnected,
+    /// CONNECT sent, waiting for CONNACK.
     Connecting,
     Connected,
 }
@@ -22,65 +27,113 @@ enum State {
 pub(crate) enum Action<'a> {
     Send(Packet<'a>),
     Event(Event<'a>),
+    SendAndEvent(Packet<'a>, Event<'a>),
+    /// A SUBACK settled the subscription at this index. Report it with
+    /// [`Session::suback_event`], once the session isn't borrowed mutably any more.
+    SubAck(usize),
     Nothing,
 }
 
 pub enum Event<'a> {
     Connected,
+    /// A message on a subscribed topic. In manual acknowledgement mode, QoS 1
+    /// and 2 messages wait for an ack with [`publish::Publish::ack_token`]. Not
+    /// reported with an inbox, see [`crate::inbox`].
     Received(publish::Publish<'a>),
-    Subscribed,
-    SubscribeFailed,
+    /// The broker accepted the subscription to `topic`, granting `qos`. Also
+    /// reported for the automatic SUBSCRIBEs after CONNACK.
+    Subscribed {
+        topic: &'a str,
+        qos: QoS,
+    },
+    /// The broker refused the subscription to `topic`. It isn't retried until
+    /// subscribed again.
+    SubscribeFailed {
+        topic: &'a str,
+    },
     Unsubscribed,
     Published,
     Disconnected,
+    /// No CONNACK within the connect timeout. The session is disconnected.
+    ConnectTimeout,
+    /// No SUBACK or UNSUBACK within the subscribe timeout. The request is sent
+    /// again with a new packet id. Reported once for all requests that expired
+    /// together.
+    SubscribeTimeout,
+    /// The broker sent something odd that doesn't affect the session, e.g. a
+    /// PUBLISH right after UNSUBACK. Any acknowledgement has still been queued.
+    Warning(ProtocolError),
 }
 
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(Clone, PartialEq)]
 enum SubState {
+    /// Requested before CONNACK, SUBSCRIBE goes out once connected.
     New,
     Pending(PacketId),
+    /// Unsubscribed while the SUBSCRIBE was in flight, UNSUBSCRIBE follows its SUBACK.
+    PendingUnsub(PacketId),
     Active,
+    /// UNSUBSCRIBE goes out once connected.
+    UnsubDeferred,
     UnsubPending(PacketId),
     Failed,
 }
 
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[derive(Clone)]
-pub(crate) struct Subscription<'s> {
-    pub(crate) topic: &'s str,
-    pub(crate) qos: QoS,
+pub(crate) struct Subscription<const MAX_FILTER_LEN: usize> {
+    pub(crate) topic: heapless::String<MAX_FILTER_LEN>,
+    /// Asked for in every SUBSCRIBE, including the automatic ones after CONNACK.
+    pub(crate) requested: QoS,
+    /// What the last SUBACK granted, meaningful once active.
+    pub(crate) granted: QoS,
     state: SubState,
 }
 
-impl<'a> From<subscribe::Options<'a>> for Subscription<'a> {
-    fn from(value: subscribe::Options<'a>) -> Self {
-        Self {
-            topic: value.topic,
-            qos: value.qos.unwrap_or_default(),
-            state: SubState::New,
+impl<const MAX_FILTER_LEN: usize> Subscription<MAX_FILTER_LEN> {
+    /// SUBSCRIBE, or UNSUBSCRIBE unless `subscribe`, for this filter.
+    fn packet(&self, packet_id: PacketId, subscribe: bool) -> Packet<'_> {
+        if subscribe {
+            Packet::Subscribe(Subscribe::single(packet_id, &self.topic, self.requested))
+        } else {
+            Packet::Unsubscribe(Unsubscribe::single(packet_id, &self.topic))
         }
     }
 }
 
-pub(crate) struct Session<'s, const N_PUB_IN: usize, const N_PUB_OUT: usize, const N_SUB: usize> {
-    state: State,
-    session_present: bool,
+fn violation(
+    packet_type: PacketType,
+    packet_id: Option<PacketId>,
+    kind: ProtocolErrorKind,
+) -> ProtocolError {
+    ProtocolError::new(packet_type, packet_id, kind)
+}
+
+pub(crate) struct Session<
+    const N_PUB_IN: usize,
+    const N_PUB_OUT: usize,
+    const N_SUB: usize,
+    const MAX_FILTER_LEN: usize,
+> {
+    state: SessionState,
     pool: PacketIdPool<N_PUB_OUT, N_SUB>,
-    subscriptions: Vec<Subscription<'s>, N_SUB>,
+    subscriptions: Vec<Subscription<MAX_FILTER_LEN>, N_SUB>,
     pub_inflight_in: incoming::Publish<N_PUB_IN>,
+    manual_ack: bool,
+    strict_qos: bool,
 }
 
-impl<'s, const N_PUB_IN: usize, const N_PUB_OUT: usize, const N_SUB: usize>
-    Session<'s, N_PUB_IN, N_PUB_OUT, N_SUB>
+impl<const N_PUB_IN: usize, const N_PUB_OUT: usize, const N_SUB: usize, const MAX_FILTER_LEN: usize>
+    Session<N_PUB_IN, N_PUB_OUT, N_SUB, MAX_FILTER_LEN>
 {
     pub(crate) fn new() -> Self {
         Self {
-            state: State::Disconnected,
-            session_present: false,
+            state: SessionState::Disconnected,
             pool: PacketIdPool::new(),
             subscriptions: Vec::new(),
             pub_inflight_in: incoming::Publish::new(),
+            manual_ack: false,
+            strict_qos: false,
         }
     }
 
@@ -88,15 +141,14 @@ impl<'s, const N_PUB_IN: usize, const N_PUB_OUT: usize, const N_SUB: usize>
         &mut self,
         opts: connect::Options<'a>,
     ) -> Result<Packet<'a>, crate::Error> {
-        self.ensure_state(State::Disconnected)?;
+        self.ensure_state(SessionState::Disconnected)?;
 
-        self.state = State::Connecting;
-        self.session_present = false;
+        self.state = SessionState::Connecting;
 
         self.pool.clear();
 
         if opts.clean_session {
-            self.subscriptions.clear();
+            self.restore_subscriptions(false);
         }
 
         let packet = connect::Connect::from(opts);
@@ -104,17 +156,19 @@ impl<'s, const N_PUB_IN: usize, const N_PUB_OUT: usize, const N_SUB: usize>
         Ok(Packet::Connect(packet))
     }
 
-    pub(crate) fn on_connack(&mut self, packet: &ConnAck) -> Result<Action<'_>, crate::Error> {
-        self.ensure_state(State::Connecting)?;
+    pub(crate) fn on_connack(&mut self, packet: &ConnAck) -> Result<Action<'static>, crate::Error> {
+        self.expect_packet(SessionState::Connecting, PacketType::ConnAck, None)?;
+
+        if packet.return_code != ConnectReturnCode::Accepted {
+            self.drop_connection();
+            return Err(crate::Error::ConnectionRefused(packet.return_code));
+        }
 
-        self.state = State::Connected;
-        self.session_present = packet.session_present;
+        self.state = SessionState::Connected;
 
         self.pool.clear();
 
-        if !packet.session_present {
-            self.subscriptions.clear();
-        }
+        self.restore_subscriptions(packet.session_present);
 
         Ok(Action::Event(Event::Connected))
     }
@@ -122,31 +176,33 @@ impl<'s, const N_PUB_IN: usize, const N_PUB_OUT: usize, const N_SUB: usize>
     pub(crate) fn publish<'a>(
         &mut self,
         msg: publish::Msg<'a>,
-    ) -> Result<Packet<'a>, crate::Error> {
-        self.ensure_state(State::Connected)?;
+    ) -> Result<publish::Publish<'a>, crate::Error> {
+        self.ensure_state(SessionState::Connected)?;
 
         let qos = msg.qos;
         let mut packet = publish::Publish::from(msg);
 
         match qos {
-            QoS::AtMostOnce => Ok(Packet::Publish(packet)),
+            QoS::AtMostOnce => Ok(packet),
             QoS::AtLeastOnce | QoS::ExactlyOnce => {
                 let packet_id = self.pool.next_pub_id(qos == QoS::AtLeastOnce)?;
                 packet.packet_id = Some(packet_id);
 
-                Ok(Packet::Publish(packet))
+                Ok(packet)
             }
         }
     }
 
-    pub(crate) fn subscribe<'a: 's>(
+    /// The session keeps its own copy of the filter, the SUBSCRIBE packet borrows `opts`.
+    ///
+    /// Before CONNACK the subscription is only recorded, see [`Self::next_deferred`].
+    /// Subscribing again with another QoS fails once the SUBSCRIBE has been sent.
+    pub(crate) fn subscribe<'a>(
         &mut self,
         opts: subscribe::Options<'a>,
-    ) -> Result<Option<Packet<'a>>, crate::Error>
-    where
-        's: 'a,
-    {
-        self.ensure_state(State::Connected)?;
+    ) -> Result<Option<Packet<'a>>, crate::Error> {
+        let connected = self.is_connected();
+        let qos = opts.qos.unwrap_or_default();
 
         if let Some(existing) = self
             .subscriptions
@@ -154,48 +210,106 @@ impl<'s, const N_PUB_IN: usize, const N_PUB_OUT: usize, const N_SUB: usize>
             .find(|s| s.topic == opts.topic)
         {
             match existing.state {
-                SubState::Active | SubState::Pending(_) => return Ok(None),
-                SubState::New | SubState::Failed => {
-                    existing.qos = opts.qos.unwrap_or_default();
+                // Not sent yet, so it simply goes out with the new QoS
+                SubState::New => {
+                    existing.requested = qos;
+                    return Ok(None);
+                }
+                SubState::Failed => {
+                    existing.requested = qos;
+                }
+                SubState::UnsubPending(_) => return Err(StateError::SubscriptionPending.into()),
+                _ if existing.requested != qos => return Err(StateError::QoSMismatch.into()),
+                SubState::Pending(_) | SubState::Active => return Ok(None),
+                // Still subscribed at the broker, so just drop the UNSUBSCRIBE
+                SubState::PendingUnsub(id) => {
+                    existing.state = SubState::Pending(id);
+                    return Ok(None);
+                }
+                SubState::UnsubDeferred => {
+                    existing.state = SubState::Active;
+                    return Ok(None);
                 }
-                SubState::UnsubPending(_) => return Err(crate::Error::ProtocolViolation),
             };
 
+            if !connected {
+                existing.state = SubState::New;
+                return Ok(None);
+            }
+
             let id = self.pool.next_sub_id()?;
             existing.state = SubState::Pending(id);
-            let packet = Subscribe::single(id, existing.clone());
+            let packet = Subscribe::single(id, opts.topic, existing.requested);
 
             return Ok(Some(Packet::Subscribe(packet)));
         }
 
-        let id = self.pool.next_sub_id()?;
-        let mut sub = Subscription::from(opts);
-        sub.state = SubState::Pending(id);
+        let topic = opts
+            .topic
+            .try_into()
+            .map_err(|_| crate::Error::FilterTooLong)?;
+        if self.subscriptions.is_full() {
+            return Err(crate::Error::SubVectorIsFull);
+        }
+        let mut sub = Subscription {
+            topic,
+            requested: qos,
+            granted: qos,
+            state: SubState::New,
+        };
+
+        let packet = if connected {
+            let id = self.pool.next_sub_id()?;
+            sub.state = SubState::Pending(id);
+            Some(Packet::Subscribe(Subscribe::single(
+                id,
+                opts.topic,
+                sub.requested,
+            )))
+        } else {
+            None
+        };
 
         self.subscriptions
-            .push(sub.clone())
+            .push(sub)
             .map_err(|_| crate::Error::SubVectorIsFull)?;
-        let packet = Subscribe::single(id, sub);
 
-        Ok(Some(Packet::Subscribe(packet)))
+        Ok(packet)
     }
 
+    /// Before CONNACK, or while the SUBSCRIBE is in flight, the UNSUBSCRIBE is
+    /// deferred, see [`Self::next_deferred`].
     pub(crate) fn unsubscribe<'a>(
         &mut self,
         topic: &'a str,
     ) -> Result<Option<Packet<'a>>, crate::Error> {
-        self.ensure_state(State::Connected)?;
+        let connected = self.is_connected();
 
-        let sub = self
+        let index = self
             .subscriptions
-            .iter_mut()
-            .find(|sub| sub.topic == topic)
-            .ok_or(crate::Error::WrongTopicToUnsubscribe)?;
+            .iter()
+            .position(|sub| sub.topic == topic)
+            .ok_or(StateError::NotSubscribed)?;
+        let sub = &mut self.subscriptions[index];
 
         match sub.state {
-            SubState::Active => {}
-            SubState::UnsubPending(_) => return Ok(None),
-            _ => return Err(crate::Error::ProtocolViolation),
+            SubState::Active if connected => {}
+            SubState::Active => {
+                sub.state = SubState::UnsubDeferred;
+                return Ok(None);
+            }
+            SubState::Pending(id) => {
+                sub.state = SubState::PendingUnsub(id);
+                return Ok(None);
+            }
+            SubState::PendingUnsub(_) | SubState::UnsubDeferred | SubState::UnsubPending(_) => {
+                return Ok(None);
+            }
+            // Never sent or refused, so the broker doesn't know about it
+            SubState::New | SubState::Failed => {
+                self.subscriptions.remove(index);
+                return Ok(None);
+            }
         }
 
         let packet_id = self.pool.next_unsub_id()?;
@@ -206,31 +320,98 @@ impl<'s, const N_PUB_IN: usize, const N_PUB_OUT: usize, const N_SUB: usize>
         Ok(Some(Packet::Unsubscribe(unsub)))
     }
 
+    /// Next SUBSCRIBE or UNSUBSCRIBE put off by [`Self::subscribe`] or
+    /// [`Self::unsubscribe`], once connected. Nothing changes unless `fits` accepts it.
+    pub(crate) fn next_deferred(
+        &mut self,
+        fits: impl FnOnce(&Packet<'_>) -> bool,
+    ) -> Option<Packet<'_>> {
+        if !self.is_connected() {
+            return None;
+        }
+
+        let index = self
+            .subscriptions
+            .iter()
+            .position(|sub| matches!(sub.state, SubState::New | SubState::UnsubDeferred))?;
+
+        let subscribe = self.subscriptions[index].state == SubState::New;
+        let id = if subscribe {
+            self.pool.next_sub_id().ok()?
+        } else {
+            self.pool.next_unsub_id().ok()?
+        };
+
+        if !fits(&self.subscriptions[index].packet(id, subscribe)) {
+            // Not handed out yet, so releasing can't fail
+            let _ = if subscribe {
+                self.pool.release_sub_id(&id)
+            } else {
+                self.pool.release_unsub_id(&id)
+            };
+            return None;
+        }
+
+        let sub = &mut self.subscriptions[index];
+        sub.state = if subscribe {
+            SubState::Pending(id)
+        } else {
+            SubState::UnsubPending(id)
+        };
+
+        Some(sub.packet(id, subscribe))
+    }
+
     pub(crate) fn disconnect(&mut self) -> Option<Packet<'_>> {
-        if self.state == State::Disconnected {
+        if self.state == SessionState::Disconnected {
             return None;
         }
 
-        self.state = State::Disconnected;
+        self.state = SessionState::Disconnected;
         self.pool.clear();
         self.pub_inflight_in.clear();
 
-        if !self.session_present {
-            self.subscriptions.clear();
-        }
-
         Some(Packet::Disconnect)
     }
 
     pub(crate) fn ping(&mut self) -> Result<Packet<'_>, crate::Error> {
-        self.ensure_state(State::Connected)?;
+        self.ensure_state(SessionState::Connected)?;
 
         Ok(Packet::PingReq)
     }
 
-    fn ensure_state(&self, state: State) -> Result<(), crate::Error> {
-        if self.state != state {
-            return Err(crate::Error::ProtocolViolation);
+    pub(crate) fn state(&self) -> SessionState {
+        self.state
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        self.state == SessionState::Connected
+    }
+
+    fn ensure_state(&self, expected: SessionState) -> Result<(), crate::Error> {
+        if self.state != expected {
+            return Err(StateError::WrongState {
+                expected,
+                actual: self.state,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Like [`Self::ensure_state`], for packets from the broker.
+    fn expect_packet(
+        &self,
+        expected: SessionState,
+        packet_type: PacketType,
+        packet_id: Option<PacketId>,
+    ) -> Result<(), crate::Error> {
+        if self.state != expected {
+            let mut err = violation(packet_type, packet_id, ProtocolErrorKind::UnexpectedPacket);
+            err.expected_state = Some(expected);
+
+            return Err(err.into());
         }
 
         Ok(())
@@ -240,118 +421,262 @@ impl<'s, const N_PUB_IN: usize, const N_PUB_OUT: usize, const N_SUB: usize>
         &mut self,
         packet: publish::Publish<'a>,
     ) -> Result<Action<'a>, crate::Error> {
-        self.ensure_state(State::Connected)?;
+        let packet_type = PacketType::Publish;
+        self.expect_packet(SessionState::Connected, packet_type, packet.packet_id)?;
 
         if packet.flags.dup && packet.flags.qos == QoS::AtMostOnce {
-            return Err(crate::Error::ProtocolViolation);
+            return Err(violation(packet_type, None, ProtocolErrorKind::InvalidPublish).into());
         }
 
-        let _ = self
+        let missing_id = violation(packet_type, None, ProtocolErrorKind::InvalidPublish);
+
+        // Brokers may still deliver on a topic whose UNSUBACK has just arrived.
+        // With overlapping filters the message may come with the highest grant.
+        let topic = packet.topic.as_str()?;
+        let granted = self
             .subscriptions
             .iter()
-            .find(|sub| sub.state == SubState::Active && sub.topic == packet.topic)
-            .ok_or(crate::Error::Unsubscribed)?;
+            .filter(|sub| sub.state == SubState::Active && topic::filter_matches(&sub.topic, topic))
+            .map(|sub| sub.granted as u8)
+            .max();
+        let rejected = match granted {
+            None => Some(ProtocolErrorKind::NotSubscribed),
+            Some(granted) if self.strict_qos && packet.flags.qos as u8 > granted => {
+                Some(ProtocolErrorKind::QoSNotGranted)
+            }
+            Some(_) => None,
+        };
+        // The application never sees the others, so they're acked right away
+        let manual_ack = self.manual_ack && rejected.is_none();
+
+        let ack = match packet.flags.qos {
+            QoS::AtMostOnce => None,
+            qos => {
+                let id = packet.packet_id.ok_or(missing_id)?;
+
+                let tracked = match self.pub_inflight_in.track(&id, qos, manual_ack) {
+                    // Without a PUBACK the broker sends it again on the next connection
+                    Err(crate::Error::VectorIsFull) if manual_ack => {
+                        let kind = ProtocolErrorKind::TooManyUnacked;
+                        return Err(violation(packet_type, Some(id), kind).into());
+                    }
+                    tracked => tracked?,
+                };
+
+                match tracked {
+                    // Delivered already, the application acks it
+                    Some(PubInState::AwaitAck(_)) => return Ok(Action::Nothing),
+                    Some(_) => return Ok(Action::Send(Packet::PubRec(id))),
+                    None if manual_ack => None,
+                    None if qos == QoS::AtLeastOnce => Some(Packet::PubAck(id)),
+                    None => Some(Packet::PubRec(id)),
+                }
+            }
+        };
 
-        match packet.flags.qos {
-            QoS::AtMostOnce => Ok(Action::Event(Event::Received(packet))),
-            QoS::AtLeastOnce => {
-                let id = packet.packet_id.ok_or(crate::Error::ProtocolViolation)?;
-                self.pub_inflight_in.track(&id, true)?;
+        if let Some(kind) = rejected {
+            let warning = Event::Warning(violation(packet_type, packet.packet_id, kind));
 
-                Ok(Action::Send(Packet::PubAck(id)))
-            }
-            QoS::ExactlyOnce => {
-                let id = packet.packet_id.ok_or(crate::Error::ProtocolViolation)?;
-                self.pub_inflight_in.track(&id, false)?;
+            return Ok(match ack {
+                Some(ack) => Action::SendAndEvent(ack, warning),
+                None => Action::Event(warning),
+            });
+        }
 
-                Ok(Action::Send(Packet::PubRec(id)))
-            }
+        let received = Event::Received(packet);
+
+        match ack {
+            Some(ack) => Ok(Action::SendAndEvent(ack, received)),
+            None => Ok(Action::Event(received)),
+        }
+    }
+
+    pub(crate) fn set_manual_ack(&mut self, enabled: bool) {
+        self.manual_ack = enabled;
+    }
+
+    pub(crate) fn set_strict_qos(&mut self, enabled: bool) {
+        self.strict_qos = enabled;
+    }
+
+    /// PUBACK or PUBREC for a message held back for manual acknowledgement.
+    pub(crate) fn ack(&mut self, token: AckToken) -> Result<Packet<'static>, crate::Error> {
+        self.ensure_state(SessionState::Connected)?;
+
+        match self.pub_inflight_in.ack(&token.0) {
+            Some(QoS::ExactlyOnce) => Ok(Packet::PubRec(token.0)),
+            Some(_) => Ok(Packet::PubAck(token.0)),
+            None => Err(StateError::NotAwaitingAck.into()),
         }
     }
 
-    pub(crate) fn on_puback(&mut self, packet_id: &PacketId) -> Result<Action<'_>, crate::Error> {
-        self.ensure_state(State::Connected)?;
-        self.pool.release_pub_id(packet_id, true)?;
+    pub(crate) fn on_puback(
+        &mut self,
+        packet_id: &PacketId,
+    ) -> Result<Action<'static>, crate::Error> {
+        let packet_type = PacketType::PubAck;
+        self.expect_packet(SessionState::Connected, packet_type, Some(*packet_id))?;
+        self.pool
+            .release_pub_id(packet_id, true)
+            .map_err(|kind| violation(packet_type, Some(*packet_id), kind))?;
 
         Ok(Action::Event(Event::Published))
     }
 
-    pub(crate) fn on_pubrec(&mut self, packet_id: &PacketId) -> Result<Action<'_>, crate::Error> {
-        self.ensure_state(State::Connected)?;
-        self.pool.set_pubrel(packet_id)?;
+    pub(crate) fn on_pubrec(
+        &mut self,
+        packet_id: &PacketId,
+    ) -> Result<Action<'static>, crate::Error> {
+        let packet_type = PacketType::PubRec;
+        self.expect_packet(SessionState::Connected, packet_type, Some(*packet_id))?;
+        self.pool
+            .set_pubrel(packet_id)
+            .map_err(|kind| violation(packet_type, Some(*packet_id), kind))?;
 
         Ok(Action::Send(Packet::PubRel(*packet_id)))
     }
 
-    pub(crate) fn on_pubrel(&mut self, packet_id: &PacketId) -> Result<Action<'_>, crate::Error> {
-        self.ensure_state(State::Connected)?;
-        self.pub_inflight_in.mark_complete(packet_id)?;
-
-        Ok(Action::Send(Packet::PubComp(*packet_id)))
+    pub(crate) fn on_pubrel(
+        &mut self,
+        packet_id: &PacketId,
+    ) -> Result<Action<'static>, crate::Error> {
+        let packet_type = PacketType::PubRel;
+        self.expect_packet(SessionState::Connected, packet_type, Some(*packet_id))?;
+        let pubcomp = Packet::PubComp(*packet_id);
+
+        // PUBREL is always answered (4.3.3), even if the id was lost to a reconnect
+        match self.pub_inflight_in.mark_complete(packet_id) {
+            Ok(()) => Ok(Action::Send(pubcomp)),
+            Err(kind) => {
+                let warning = Event::Warning(violation(packet_type, Some(*packet_id), kind));
+                Ok(Action::SendAndEvent(pubcomp, warning))
+            }
+        }
     }
 
-    pub(crate) fn on_pubcomp(&mut self, packet_id: &PacketId) -> Result<Action<'_>, crate::Error> {
-        self.ensure_state(State::Connected)?;
-        self.pool.release_pub_id(packet_id, false)?;
+    pub(crate) fn on_pubcomp(
+        &mut self,
+        packet_id: &PacketId,
+    ) -> Result<Action<'static>, crate::Error> {
+        let packet_type = PacketType::PubComp;
+        self.expect_packet(SessionState::Connected, packet_type, Some(*packet_id))?;
+        self.pool
+            .release_pub_id(packet_id, false)
+            .map_err(|kind| violation(packet_type, Some(*packet_id), kind))?;
 
         Ok(Action::Event(Event::Published))
     }
 
-    pub(crate) fn on_suback(&mut self, packet: &SubAck<1>) -> Result<Action<'_>, crate::Error> {
-        self.ensure_state(State::Connected)?;
-        self.pool.release_sub_id(&packet.packet_id)?;
-
-        if packet.return_codes.is_empty() {
-            return Err(crate::Error::ProtocolViolation);
+    pub(crate) fn on_suback(
+        &mut self,
+        packet: &SubAck<1>,
+    ) -> Result<Action<'static>, crate::Error> {
+        let packet_type = PacketType::SubAck;
+        let packet_id = Some(packet.packet_id);
+        self.expect_packet(SessionState::Connected, packet_type, packet_id)?;
+        self.pool
+            .release_sub_id(&packet.packet_id)
+            .map_err(|kind| violation(packet_type, packet_id, kind))?;
+
+        if packet.return_codes.len() != 1 {
+            return Err(violation(packet_type, packet_id, ProtocolErrorKind::Unsupported).into());
         }
 
-        if packet.return_codes.len() > 1 {
-            return Err(crate::Error::UnsupportedIncomingPacket);
-        }
+        let unknown_id = violation(packet_type, packet_id, ProtocolErrorKind::UnknownPacketId);
+        let awaits_suback = |sub: &Subscription<MAX_FILTER_LEN>| matches!(sub.state, SubState::Pending(id) | SubState::PendingUnsub(id) if id == packet.packet_id);
 
         if self
             .subscriptions
             .iter()
-            .filter(|sub| sub.state == SubState::Pending(packet.packet_id))
+            .filter(|sub| awaits_suback(sub))
             .count()
             != 1
         {
-            return Err(crate::Error::ProtocolViolation);
+            return Err(unknown_id.into());
         }
 
-        let sub = self
+        let index = self
             .subscriptions
-            .iter_mut()
-            .find(|sub| sub.state == SubState::Pending(packet.packet_id))
-            .ok_or(crate::Error::ProtocolViolation)?;
-
-        match packet.return_codes[0] {
-            subscribe::SubAckReturnCode::SuccessMaxQoS0 => {
-                sub.qos = QoS::AtMostOnce;
-                sub.state = SubState::Active;
-                Ok(Action::Event(Event::Subscribed))
+            .iter()
+            .position(awaits_suback)
+            .ok_or(unknown_id)?;
+        let sub = &mut self.subscriptions[index];
+        let unsubscribe = matches!(sub.state, SubState::PendingUnsub(_));
+
+        let granted = match packet.return_codes[0] {
+            subscribe::SubAckReturnCode::SuccessMaxQoS0 => QoS::AtMostOnce,
+            subscribe::SubAckReturnCode::SuccessMaxQoS1 => QoS::AtLeastOnce,
+            subscribe::SubAckReturnCode::SuccessMaxQoS2 => QoS::ExactlyOnce,
+            // Also when unsubscribed meanwhile, as there's nothing left to unsubscribe
+            subscribe::SubAckReturnCode::Failure => {
+                sub.state = SubState::Failed;
+                return Ok(Action::SubAck(index));
             }
-            subscribe::SubAckReturnCode::SuccessMaxQoS1 => {
-                sub.qos = QoS::AtLeastOnce;
-                sub.state = SubState::Active;
-                Ok(Action::Event(Event::Subscribed))
+        };
+
+        sub.granted = granted;
+        sub.state = if unsubscribe {
+            SubState::UnsubDeferred
+        } else {
+            SubState::Active
+        };
+
+        Ok(Action::SubAck(index))
+    }
+
+    /// Gives up on the SUBSCRIBE or UNSUBSCRIBE with `packet_id`, so that
+    /// [`Self::next_deferred`] sends it again. `None` if it isn't in flight.
+    pub(crate) fn on_response_timeout(&mut self, packet_id: PacketId) -> Option<Event<'static>> {
+        let sub = self.subscriptions.iter_mut().find(|sub| {
+            matches!(
+                sub.state,
+                SubState::Pending(id) | SubState::PendingUnsub(id) | SubState::UnsubPending(id)
+                    if id == packet_id
+            )
+        })?;
+
+        // In flight, so releasing can't fail
+        let _ = match sub.state {
+            SubState::Pending(_) => {
+                sub.state = SubState::New;
+                self.pool.release_sub_id(&packet_id)
             }
-            subscribe::SubAckReturnCode::SuccessMaxQoS2 => {
-                sub.qos = QoS::ExactlyOnce;
-                sub.state = SubState::Active;
-                Ok(Action::Event(Event::Subscribed))
+            SubState::PendingUnsub(_) => {
+                sub.state = SubState::UnsubDeferred;
+                self.pool.release_sub_id(&packet_id)
             }
-            subscribe::SubAckReturnCode::Failure => {
-                sub.state = SubState::Failed;
-                Ok(Action::Event(Event::SubscribeFailed))
+            _ => {
+                sub.state = SubState::UnsubDeferred;
+                self.pool.release_unsub_id(&packet_id)
             }
-        }
+        };
+
+        Some(Event::SubscribeTimeout)
+    }
+
+    /// The event for [`Action::SubAck`].
+    pub(crate) fn suback_event(&self, index: usize) -> Option<Event<'_>> {
+        let sub = self.subscriptions.get(index)?;
+
+        Some(match sub.state {
+            SubState::Failed => Event::SubscribeFailed { topic: &sub.topic },
+            _ => Event::Subscribed {
+                topic: &sub.topic,
+                qos: sub.granted,
+            },
+        })
     }
 
-    pub(crate) fn on_unsuback(&mut self, packet_id: &PacketId) -> Result<Action<'_>, crate::Error> {
-        self.ensure_state(State::Connected)?;
+    pub(crate) fn on_unsuback(
+        &mut self,
+        packet_id: &PacketId,
+    ) -> Result<Action<'static>, crate::Error> {
+        let packet_type = PacketType::UnsubAck;
+        self.expect_packet(SessionState::Connected, packet_type, Some(*packet_id))?;
 
-        self.pool.release_unsub_id(packet_id)?;
+        self.pool
+            .release_unsub_id(packet_id)
+            .map_err(|kind| violation(packet_type, Some(*packet_id), kind))?;
 
         let removed = {
             let before = self.subscriptions.len();
@@ -361,35 +686,610 @@ impl<'s, const N_PUB_IN: usize, const N_PUB_OUT: usize, const N_SUB: usize>
         };
 
         if removed != 1 {
-            return Err(crate::Error::ProtocolViolation);
+            return Err(violation(
+                packet_type,
+                Some(*packet_id),
+                ProtocolErrorKind::UnknownPacketId,
+            )
+            .into());
         }
 
         Ok(Action::Event(Event::Unsubscribed))
     }
 
-    pub(crate) fn on_pingreq(&self) -> Result<Action<'_>, crate::Error> {
-        self.ensure_state(State::Connected)?;
+    pub(crate) fn on_pingreq(&self) -> Result<Action<'static>, crate::Error> {
+        self.expect_packet(SessionState::Connected, PacketType::PingReq, None)?;
         Ok(Action::Send(Packet::PingResp))
     }
 
-    pub(crate) fn on_pingresp(&mut self) -> Result<Action<'_>, crate::Error> {
-        self.ensure_state(State::Connected)?;
+    pub(crate) fn on_pingresp(&mut self) -> Result<Action<'static>, crate::Error> {
+        self.expect_packet(SessionState::Connected, PacketType::PingResp, None)?;
         Ok(Action::Nothing)
     }
 
-    pub(crate) fn on_disconnect(&mut self) -> Action<'_> {
-        if self.state == State::Disconnected {
+    pub(crate) fn on_disconnect(&mut self) -> Action<'static> {
+        if self.state == SessionState::Disconnected {
             return Action::Nothing;
         }
 
-        self.state = State::Disconnected;
+        self.drop_connection();
+
+        Action::Event(Event::Disconnected)
+    }
+
+    /// Forgets the network connection without a DISCONNECT, e.g. after a fatal error.
+    pub(crate) fn drop_connection(&mut self) {
+        self.state = SessionState::Disconnected;
         self.pool.clear();
         self.pub_inflight_in.clear();
+    }
+
+    /// Brings the subscriptions in line with what the broker kept, so that
+    /// [`Self::next_deferred`] (re)sends whatever is missing.
+    ///
+    /// Without a session every wanted filter is subscribed again. With one, only
+    /// requests from the previous connection are resent, as brokers don't retry
+    /// SUBACK or UNSUBACK. Refused subscriptions aren't retried, and are
+    /// forgotten along with the session.
+    fn restore_subscriptions(&mut self, session_present: bool) {
+        self.subscriptions.retain_mut(|sub| {
+            sub.state = match (&sub.state, session_present) {
+                (SubState::Failed, true) => SubState::Failed,
+                (SubState::Failed, false) => return false,
+                (SubState::Active, true) => SubState::Active,
+                (SubState::New | SubState::Pending(_), true) => SubState::New,
+                (SubState::New | SubState::Pending(_) | SubState::Active, false) => SubState::New,
+                (
+                    SubState::PendingUnsub(_) | SubState::UnsubDeferred | SubState::UnsubPending(_),
+                    true,
+                ) => SubState::UnsubDeferred,
+                (
+                    SubState::PendingUnsub(_) | SubState::UnsubDeferred | SubState::UnsubPending(_),
+                    false,
+                ) => return false,
+            };
+
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
 
-        if !self.session_present {
-            self.subscriptions.clear();
+    use super::*;
+    use crate::testing;
+
+    fn opts() -> connect::Options<'static> {
+        connect::Options {
+            clean_session: false,
+            ..testing::connect_options("c")
         }
+    }
 
-        Action::Event(Event::Disconnected)
+    fn connected() -> Session<2, 2, 2, 8> {
+        let mut session = Session::new();
+        session.connect(opts()).unwrap();
+
+        let Packet::ConnAck(connack) = testing::connack(false) else {
+            unreachable!()
+        };
+        assert!(matches!(
+            session.on_connack(&connack),
+            Ok(Action::Event(Event::Connected))
+        ));
+
+        session
+    }
+
+    fn suback(packet_id: u16, granted: Option<QoS>) -> SubAck {
+        let Packet::SubAck(suback) = testing::suback(packet_id, granted) else {
+            unreachable!()
+        };
+        suback
+    }
+
+    fn subscribe(session: &mut Session<2, 2, 2, 8>, topic: &'static str) -> PacketId {
+        subscribe_to(session, topic)
+    }
+
+    fn subscribe_to(session: &mut Session<2, 2, 2, 8>, topic: &str) -> PacketId {
+        let packet = session
+            .subscribe(subscribe::Options {
+                qos: Some(QoS::ExactlyOnce),
+                topic,
+            })
+            .unwrap();
+
+        match packet {
+            Some(Packet::Subscribe(subscribe)) => subscribe.packet_id,
+            _ => panic!("Expected Subscribe"),
+        }
+    }
+
+    #[test]
+    fn nothing_before_connack() {
+        let mut session: Session<2, 2, 2, 8> = Session::new();
+
+        assert!(matches!(
+            session.ping(),
+            Err(crate::Error::State(StateError::WrongState {
+                expected: SessionState::Connected,
+                actual: SessionState::Disconnected,
+            }))
+        ));
+
+        session.connect(opts()).unwrap();
+        assert!(matches!(
+            session.connect(opts()),
+            Err(crate::Error::State(StateError::WrongState {
+                actual: SessionState::Connecting,
+                ..
+            }))
+        ));
+        let Err(err) = session.on_puback(&PacketId(1)) else {
+            panic!("Expected an error")
+        };
+        let mut msg = heapless::String::<80>::new();
+        write!(msg, "{err}").unwrap();
+        assert_eq!(
+            msg,
+            "protocol violation: unexpected PUBACK (packet id 1), session not Connected"
+        );
+    }
+
+    #[test]
+    fn refused_connack_disconnects() {
+        let mut session: Session<2, 2, 2, 8> = Session::new();
+        session.connect(opts()).unwrap();
+
+        let Packet::ConnAck(connack) = testing::connack_refused(5) else {
+            unreachable!()
+        };
+        assert!(matches!(
+            session.on_connack(&connack),
+            Err(crate::Error::ConnectionRefused(
+                ConnectReturnCode::NotAuthorized
+            ))
+        ));
+        assert_eq!(session.state(), SessionState::Disconnected);
+        assert!(session.ping().is_err());
+    }
+
+    #[test]
+    fn subscription_lifecycle() {
+        let mut session = connected();
+
+        let id = subscribe(&mut session, "a");
+        // Already pending, so no second SUBSCRIBE
+        assert!(
+            session
+                .subscribe(subscribe::Options {
+                    qos: Some(QoS::ExactlyOnce),
+                    topic: "a",
+                })
+                .unwrap()
+                .is_none()
+        );
+
+        // Publishes on a topic without an active subscription only warn
+        let publish = publish::Publish::from(publish::Msg {
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic: "a",
+            payload: b"",
+        });
+        assert!(matches!(
+            session.on_publish(publish),
+            Ok(Action::Event(Event::Warning(ProtocolError {
+                packet_type: PacketType::Publish,
+                kind: ProtocolErrorKind::NotSubscribed,
+                ..
+            })))
+        ));
+
+        // The broker downgrades to QoS 1
+        assert!(matches!(
+            session.on_suback(&suback(id.0, Some(QoS::AtLeastOnce))),
+            Ok(Action::SubAck(_))
+        ));
+        assert_eq!(session.subscriptions[0].granted, QoS::AtLeastOnce);
+        assert_eq!(session.subscriptions[0].requested, QoS::ExactlyOnce);
+
+        let Some(Packet::Unsubscribe(unsubscribe)) = session.unsubscribe("a").unwrap() else {
+            panic!("Expected Unsubscribe")
+        };
+        assert!(matches!(
+            session.on_unsuback(&unsubscribe.packet_id),
+            Ok(Action::Event(Event::Unsubscribed))
+        ));
+        assert!(session.subscriptions.is_empty());
+        assert!(matches!(
+            session.unsubscribe("a"),
+            Err(crate::Error::State(StateError::NotSubscribed))
+        ));
+    }
+
+    #[test]
+    fn refused_subscription_can_be_retried() {
+        let mut session = connected();
+
+        let id = subscribe(&mut session, "a");
+        assert!(matches!(
+            session.on_suback(&suback(id.0, None)),
+            Ok(Action::SubAck(_))
+        ));
+
+        let retry = subscribe(&mut session, "a");
+        assert_ne!(retry, id);
+        assert_eq!(session.subscriptions.len(), 1);
+    }
+
+    #[test]
+    fn resubscribing_with_another_qos() {
+        let mut session: Session<2, 2, 2, 8> = Session::new();
+        let options = |qos| subscribe::Options {
+            qos: Some(qos),
+            topic: "a",
+        };
+
+        // Not sent yet, so the new QoS replaces the old one
+        assert!(
+            session
+                .subscribe(options(QoS::AtMostOnce))
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            session
+                .subscribe(options(QoS::AtLeastOnce))
+                .unwrap()
+                .is_none()
+        );
+        session.connect(opts()).unwrap();
+        let Packet::ConnAck(connack) = testing::connack(false) else {
+            unreachable!()
+        };
+        session.on_connack(&connack).unwrap();
+        let id = match session.next_deferred(|_| true) {
+            Some(Packet::Subscribe(subscribe)) => {
+                assert_eq!(subscribe.topics()[0].qos(), QoS::AtLeastOnce);
+                subscribe.packet_id
+            }
+            _ => panic!("Expected Subscribe"),
+        };
+
+        // Sent, so the requested QoS stays while pending and once active
+        let mismatch = |result| matches!(result, Err(crate::Error::State(StateError::QoSMismatch)));
+        assert!(mismatch(session.subscribe(options(QoS::ExactlyOnce))));
+        session
+            .on_suback(&suback(id.0, Some(QoS::AtLeastOnce)))
+            .unwrap();
+        assert!(mismatch(session.subscribe(options(QoS::ExactlyOnce))));
+        assert_eq!(session.subscriptions[0].requested, QoS::AtLeastOnce);
+    }
+
+    #[test]
+    fn qos2_publish_flow() {
+        let mut session = connected();
+
+        let publish = session
+            .publish(publish::Msg {
+                qos: QoS::ExactlyOnce,
+                retain: false,
+                topic: "a",
+                payload: b"x",
+            })
+            .unwrap();
+        let id = publish.packet_id.unwrap();
+
+        // PUBCOMP before PUBREC is out of order
+        assert!(session.on_pubcomp(&id).is_err());
+        assert!(matches!(
+            session.on_pubrec(&id),
+            Ok(Action::Send(Packet::PubRel(rel))) if rel == id
+        ));
+        assert!(matches!(
+            session.on_pubcomp(&id),
+            Ok(Action::Event(Event::Published))
+        ));
+        assert!(session.on_pubcomp(&id).is_err());
+    }
+
+    #[test]
+    fn manual_ack_limits_messages_in_flight() {
+        fn publish(session: &mut Session<2, 2, 2, 8>, packet_id: u16) -> Action<'static> {
+            let Packet::Publish(publish) =
+                testing::publish("a", b"", QoS::AtLeastOnce, Some(packet_id))
+            else {
+                unreachable!()
+            };
+            session.on_publish(publish).unwrap()
+        }
+
+        let mut session = connected();
+        let id = subscribe(&mut session, "a");
+        session
+            .on_suback(&suback(id.0, Some(QoS::AtLeastOnce)))
+            .unwrap();
+        session.set_manual_ack(true);
+
+        assert!(matches!(
+            publish(&mut session, 1),
+            Action::Event(Event::Received(_))
+        ));
+        // Redelivered before the application got to it
+        assert!(matches!(publish(&mut session, 1), Action::Nothing));
+        assert!(matches!(
+            publish(&mut session, 2),
+            Action::Event(Event::Received(_))
+        ));
+
+        // N_PUB_IN messages wait for the application already
+        let Packet::Publish(third) = testing::publish("a", b"", QoS::AtLeastOnce, Some(3)) else {
+            unreachable!()
+        };
+        let Err(crate::Error::Protocol(err)) = session.on_publish(third) else {
+            panic!("Expected a protocol error")
+        };
+        assert_eq!(err.kind, crate::ProtocolErrorKind::TooManyUnacked);
+        assert!(!err.is_fatal());
+
+        assert!(matches!(
+            session.ack(AckToken(PacketId(1))),
+            Ok(Packet::PubAck(PacketId(1)))
+        ));
+        assert!(matches!(
+            publish(&mut session, 3),
+            Action::Event(Event::Received(_))
+        ));
+    }
+
+    #[test]
+    fn strict_qos_rejects_above_granted() {
+        let mut session = connected();
+        let id = subscribe(&mut session, "a");
+        session
+            .on_suback(&suback(id.0, Some(QoS::AtMostOnce)))
+            .unwrap();
+
+        let Packet::Publish(publish) = testing::publish("a", b"", QoS::AtLeastOnce, Some(1)) else {
+            unreachable!()
+        };
+        assert!(matches!(
+            session.on_publish(publish),
+            Ok(Action::SendAndEvent(_, Event::Received(_)))
+        ));
+
+        session.set_strict_qos(true);
+        let Packet::Publish(publish) = testing::publish("a", b"", QoS::AtLeastOnce, Some(2)) else {
+            unreachable!()
+        };
+        let Ok(Action::SendAndEvent(Packet::PubAck(PacketId(2)), Event::Warning(err))) =
+            session.on_publish(publish)
+        else {
+            panic!("Expected a PUBACK and a warning")
+        };
+        assert_eq!(err.kind, ProtocolErrorKind::QoSNotGranted);
+        assert!(!err.is_fatal());
+    }
+
+    #[test]
+    fn wildcard_subscriptions_match() {
+        let mut session = connected();
+        session.set_strict_qos(true);
+        for (filter, granted) in [("a/+", QoS::AtMostOnce), ("a/#", QoS::AtLeastOnce)] {
+            let id = subscribe(&mut session, filter);
+            session.on_suback(&suback(id.0, Some(granted))).unwrap();
+        }
+
+        // Granted QoS 1 through "a/#"
+        let Packet::Publish(publish) = testing::publish("a/b", b"", QoS::AtLeastOnce, Some(1))
+        else {
+            unreachable!()
+        };
+        assert!(matches!(
+            session.on_publish(publish),
+            Ok(Action::SendAndEvent(Packet::PubAck(_), Event::Received(_)))
+        ));
+
+        let Packet::Publish(publish) = testing::publish("b/a", b"", QoS::AtMostOnce, None) else {
+            unreachable!()
+        };
+        assert!(matches!(
+            session.on_publish(publish),
+            Ok(Action::Event(Event::Warning(ProtocolError {
+                kind: ProtocolErrorKind::NotSubscribed,
+                ..
+            })))
+        ));
+    }
+
+    #[test]
+    fn subscriptions_own_their_filters() {
+        let mut session = connected();
+
+        for device in [7, 8] {
+            let mut topic = heapless::String::<8>::new();
+            write!(topic, "dev/{device}").unwrap();
+            let id = subscribe_to(&mut session, &topic);
+            drop(topic);
+
+            assert!(matches!(
+                session.on_suback(&suback(id.0, Some(QoS::AtMostOnce))),
+                Ok(Action::SubAck(_))
+            ));
+        }
+
+        let publish = publish::Publish::from(publish::Msg {
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic: "dev/8",
+            payload: b"",
+        });
+        assert!(matches!(
+            session.on_publish(publish),
+            Ok(Action::Event(Event::Received(_)))
+        ));
+
+        // 9 bytes don't fit MAX_FILTER_LEN, and no packet id is taken
+        assert!(matches!(
+            session.subscribe(subscribe::Options {
+                qos: None,
+                topic: "dev/1/cmd",
+            }),
+            Err(crate::Error::FilterTooLong)
+        ));
+        assert_eq!(session.pool.next_sub_id().unwrap().get(), 3);
+    }
+
+    #[test]
+    fn subscriptions_deferred_until_connack() {
+        let mut session: Session<2, 2, 2, 8> = Session::new();
+        let options = |topic| subscribe::Options {
+            qos: Some(QoS::AtLeastOnce),
+            topic,
+        };
+
+        assert!(session.subscribe(options("a")).unwrap().is_none());
+        session.connect(opts()).unwrap();
+        assert!(session.subscribe(options("b")).unwrap().is_none());
+        // Never sent, so simply forgotten
+        assert!(session.unsubscribe("b").unwrap().is_none());
+        assert!(session.next_deferred(|_| true).is_none());
+
+        let Packet::ConnAck(connack) = testing::connack(false) else {
+            unreachable!()
+        };
+        session.on_connack(&connack).unwrap();
+
+        // No room, so nothing changes
+        assert!(session.next_deferred(|_| false).is_none());
+        let id = match session.next_deferred(|_| true) {
+            Some(Packet::Subscribe(subscribe)) => subscribe.packet_id,
+            _ => panic!("Expected Subscribe"),
+        };
+        assert!(session.next_deferred(|_| true).is_none());
+        assert!(matches!(
+            session.on_suback(&suback(id.0, Some(QoS::AtLeastOnce))),
+            Ok(Action::SubAck(_))
+        ));
+    }
+
+    #[test]
+    fn unsubscribe_waits_for_suback() {
+        let mut session = connected();
+
+        let id = subscribe(&mut session, "a");
+        assert!(session.unsubscribe("a").unwrap().is_none());
+        assert!(session.next_deferred(|_| true).is_none());
+
+        assert!(matches!(
+            session.on_suback(&suback(id.0, Some(QoS::AtMostOnce))),
+            Ok(Action::SubAck(_))
+        ));
+        let id = match session.next_deferred(|_| true) {
+            Some(Packet::Unsubscribe(unsubscribe)) => unsubscribe.packet_id,
+            _ => panic!("Expected Unsubscribe"),
+        };
+        assert!(matches!(
+            session.on_unsuback(&id),
+            Ok(Action::Event(Event::Unsubscribed))
+        ));
+        assert!(session.subscriptions.is_empty());
+
+        // A refused subscription has nothing left to unsubscribe
+        let id = subscribe(&mut session, "b");
+        session.unsubscribe("b").unwrap();
+        let Ok(Action::SubAck(index)) = session.on_suback(&suback(id.0, None)) else {
+            panic!("Expected SubAck")
+        };
+        assert!(matches!(
+            session.suback_event(index),
+            Some(Event::SubscribeFailed { topic: "b" })
+        ));
+        assert!(session.next_deferred(|_| true).is_none());
+        // Which frees its slot
+        assert!(session.unsubscribe("b").unwrap().is_none());
+        assert!(session.subscriptions.is_empty());
+    }
+
+    fn reconnect(session: &mut Session<2, 2, 2, 8>, session_present: bool) {
+        session.drop_connection();
+        session.connect(opts()).unwrap();
+        let Packet::ConnAck(connack) = testing::connack(session_present) else {
+            unreachable!()
+        };
+        session.on_connack(&connack).unwrap();
+    }
+
+    fn next_subscribe(session: &mut Session<2, 2, 2, 8>) -> Option<PacketId> {
+        match session.next_deferred(|_| true)? {
+            Packet::Subscribe(subscribe) => Some(subscribe.packet_id),
+            _ => panic!("Expected Subscribe"),
+        }
+    }
+
+    #[test]
+    fn resubscribed_without_session_present() {
+        let mut session = connected();
+        for (topic, granted) in [("a", Some(QoS::AtMostOnce)), ("b", None)] {
+            let id = subscribe(&mut session, topic);
+            session.on_suback(&suback(id.0, granted)).unwrap();
+        }
+
+        // The broker kept the session, so there's nothing to resend
+        reconnect(&mut session, true);
+        assert!(next_subscribe(&mut session).is_none());
+
+        // Refused "b" isn't retried, and is forgotten with the session. "a" asks
+        // for the QoS it was subscribed with, not the one granted before.
+        reconnect(&mut session, false);
+        let mut buf = [0u8; 16];
+        let len = session
+            .next_deferred(|_| true)
+            .unwrap()
+            .encode_to(&mut buf)
+            .unwrap();
+        let Ok((Packet::<1>::Subscribe(resent), _)) = Packet::decode(&buf[..len]) else {
+            panic!("Expected Subscribe")
+        };
+        assert_eq!(resent.topics()[0].qos(), QoS::ExactlyOnce);
+        let id = resent.packet_id;
+        assert!(next_subscribe(&mut session).is_none());
+        assert_eq!(session.subscriptions.len(), 1);
+
+        let Ok(Action::SubAck(index)) = session.on_suback(&suback(id.0, Some(QoS::AtLeastOnce)))
+        else {
+            panic!("Expected SubAck")
+        };
+        assert!(matches!(
+            session.suback_event(index),
+            Some(Event::Subscribed {
+                topic: "a",
+                qos: QoS::AtLeastOnce
+            })
+        ));
+    }
+
+    #[test]
+    fn requests_in_flight_resent_after_reconnect() {
+        let mut session = connected();
+        let id = subscribe(&mut session, "a");
+        session
+            .on_suback(&suback(id.0, Some(QoS::AtMostOnce)))
+            .unwrap();
+        subscribe(&mut session, "b");
+        session.unsubscribe("a").unwrap();
+
+        // SUBACK and UNSUBACK were lost with the connection
+        reconnect(&mut session, true);
+        match session.next_deferred(|_| true) {
+            Some(Packet::Unsubscribe(unsubscribe)) => assert_eq!(unsubscribe.topics[0], "a"),
+            _ => panic!("Expected Unsubscribe"),
+        }
+        let id = next_subscribe(&mut session).unwrap();
+        assert!(session.on_suback(&suback(id.0, None)).is_ok());
     }
 }
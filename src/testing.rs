@@ -0,0 +1,526 @@
+//! Test doubles for code built on this crate: a scripted [`MockTransport`]
+//! standing in for the broker, a [`MockClock`] that only moves when told to,
+//! a [`RamStorage`] for persistent queues, and constructors for the packets on
+//! both sides of the script.
+//!
+//! ```
+//! use embedded_time::{duration::Generic, rate::Fraction};
+//! use mqtt_client::{Client, Event, testing::{self, MockClock, MockTransport}};
+//!
+//! let opts = || testing::connect_options("sensor");
+//!
+//! let clock = MockClock::new();
+//! let mut broker = MockTransport::new();
+//! broker
+//!     .expect(testing::connect(opts()))
+//!     .reply(testing::connack(false))
+//!     .expect(testing::pingreq());
+//!
+//! let (mut rx_buf, mut tx_buf) = ([0u8; 64], [0u8; 64]);
+//! let keep_alive = Generic::new(10, Fraction::new(1, 1));
+//! let mut client: Client<'_, _, _, 1, 1, 1, 4> =
+//!     Client::try_new(&clock, keep_alive, &mut broker, &mut rx_buf, &mut tx_buf).unwrap();
+//!
+//! client.schedule_connect(opts()).unwrap();
+//! testing::block_on(client.poll()).unwrap();
+//! let event = testing::block_on(client.poll()).unwrap();
+//! assert!(matches!(event, Some(Event::Connected)));
+//!
+//! clock.advance(5_000);
+//! testing::block_on(client.poll()).unwrap();
+//!
+//! drop(client);
+//! broker.assert_done();
+//! ```
+
+use core::{
+    cell::Cell,
+    convert::Infallible,
+    ops::Range,
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+
+use embedded_time::{Clock, Instant, rate::Fraction};
+use heapless::Vec;
+
+use crate::{
+    packet::{
+        Packet, PacketId, QoS,
+        connect::{self, ConnAck, ConnectReturnCode},
+        publish,
+        subscribe::{SubAck, SubAckReturnCode, Subscribe},
+        unsubscribe::Unsubscribe,
+    },
+    persistent::Storage,
+};
+
+/// Millisecond clock that only moves when told to. Hand out `&MockClock`,
+/// which is what implements [`Clock`], and keep advancing the original.
+#[derive(Debug, Default)]
+pub struct MockClock {
+    now: Cell<u32>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, ms: u32) {
+        self.now.set(self.now.get().wrapping_add(ms));
+    }
+
+    pub fn set(&self, ms: u32) {
+        self.now.set(ms);
+    }
+}
+
+impl Clock for &MockClock {
+    type T = u32;
+
+    const SCALING_FACTOR: Fraction = Fraction::new(1, 1_000);
+
+    fn try_now(&self) -> Result<Instant<Self>, embedded_time::clock::Error> {
+        Ok(Instant::new(self.now.get()))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    /// Bytes the client reads.
+    Reply,
+    /// Bytes the client has to write.
+    Expect,
+}
+
+struct Step {
+    direction: Direction,
+    range: Range<usize>,
+}
+
+/// Transport that plays back a script of expected writes and canned replies,
+/// panicking as soon as the client strays from it.
+///
+/// Reads past the end of the script return end of stream. Implements both the
+/// async and the blocking `embedded_io` traits; hand `&mut MockTransport` to
+/// the client and call [`Self::assert_done`] once it's dropped.
+pub struct MockTransport<const N_STEPS: usize = 16, const BUF_LEN: usize = 512> {
+    buf: Vec<u8, BUF_LEN>,
+    steps: Vec<Step, N_STEPS>,
+    next: usize,
+    offset: usize,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<const N_STEPS: usize, const BUF_LEN: usize> Default for MockTransport<N_STEPS, BUF_LEN> {
+    fn default() -> Self {
+        Self {
+            buf: Vec::new(),
+            steps: Vec::new(),
+            next: 0,
+            offset: 0,
+        }
+    }
+}
+
+impl<const N_STEPS: usize, const BUF_LEN: usize> MockTransport<N_STEPS, BUF_LEN> {
+    /// Scripts a packet the client has to write next.
+    pub fn expect(&mut self, packet: Packet<'_>) -> &mut Self {
+        self.push_packet(Direction::Expect, packet)
+    }
+
+    pub fn expect_bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.push_bytes(Direction::Expect, bytes)
+    }
+
+    /// Scripts a packet the client reads next.
+    pub fn reply(&mut self, packet: Packet<'_>) -> &mut Self {
+        self.push_packet(Direction::Reply, packet)
+    }
+
+    pub fn reply_bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.push_bytes(Direction::Reply, bytes)
+    }
+
+    /// Panics unless the client went through the whole script.
+    pub fn assert_done(&self) {
+        if let Some(step) = self.steps.get(self.next) {
+            panic!(
+                "script stopped at step {}: {} {:02x?}",
+                self.next,
+                match step.direction {
+                    Direction::Reply => "reply",
+                    Direction::Expect => "expected write",
+                },
+                &self.buf[step.range.start + self.offset..step.range.end],
+            );
+        }
+    }
+
+    fn push_packet(&mut self, direction: Direction, packet: Packet<'_>) -> &mut Self {
+        let start = self.buf.len();
+        let len = packet.encoded_len().expect("packet too long to encode");
+        self.buf
+            .resize(start + len, 0)
+            .expect("script exceeds BUF_LEN");
+
+        packet
+            .encode_to(&mut self.buf[start..])
+            .expect("packet failed to encode");
+
+        self.push_step(direction, start)
+    }
+
+    fn push_bytes(&mut self, direction: Direction, bytes: &[u8]) -> &mut Self {
+        let start = self.buf.len();
+        self.buf
+            .extend_from_slice(bytes)
+            .expect("script exceeds BUF_LEN");
+
+        self.push_step(direction, start)
+    }
+
+    fn push_step(&mut self, direction: Direction, start: usize) -> &mut Self {
+        let range = start..self.buf.len();
+        if self.steps.push(Step { direction, range }).is_err() {
+            panic!("script exceeds N_STEPS");
+        }
+
+        self
+    }
+
+    /// Rest of the current step, if it goes in `direction`.
+    fn current(&self, direction: Direction) -> Option<&[u8]> {
+        let step = self.steps.get(self.next)?;
+        let rest = &self.buf[step.range.start + self.offset..step.range.end];
+
+        if step.direction != direction {
+            panic!(
+                "step {}: client {} while {:02x?} is {}",
+                self.next,
+                match direction {
+                    Direction::Reply => "reads",
+                    Direction::Expect => "writes",
+                },
+                rest,
+                match step.direction {
+                    Direction::Reply => "waiting to be read",
+                    Direction::Expect => "expected to be written",
+                },
+            );
+        }
+
+        Some(rest)
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.offset += n;
+
+        if self.offset == self.steps[self.next].range.len() {
+            self.next += 1;
+            self.offset = 0;
+        }
+    }
+
+    fn read_script(&mut self, buf: &mut [u8]) -> usize {
+        let Some(rest) = self.current(Direction::Reply) else {
+            return 0;
+        };
+
+        let n = rest.len().min(buf.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        self.advance(n);
+
+        n
+    }
+
+    fn write_script(&mut self, buf: &[u8]) -> usize {
+        let Some(rest) = self.current(Direction::Expect) else {
+            panic!("write of {buf:02x?} after the end of the script");
+        };
+
+        let n = rest.len().min(buf.len());
+        if buf[..n] != rest[..n] {
+            panic!(
+                "step {}: expected write of {:02x?}, got {:02x?}",
+                self.next, rest, buf
+            );
+        }
+        self.advance(n);
+
+        n
+    }
+}
+
+impl<const N_STEPS: usize, const BUF_LEN: usize> embedded_io::ErrorType
+    for MockTransport<N_STEPS, BUF_LEN>
+{
+    type Error = Infallible;
+}
+
+impl<const N_STEPS: usize, const BUF_LEN: usize> embedded_io_async::Read
+    for MockTransport<N_STEPS, BUF_LEN>
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Ok(self.read_script(buf))
+    }
+}
+
+impl<const N_STEPS: usize, const BUF_LEN: usize> embedded_io_async::Write
+    for MockTransport<N_STEPS, BUF_LEN>
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        Ok(self.write_script(buf))
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl<const N_STEPS: usize, const BUF_LEN: usize> embedded_io::Read
+    for MockTransport<N_STEPS, BUF_LEN>
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Ok(self.read_script(buf))
+    }
+}
+
+impl<const N_STEPS: usize, const BUF_LEN: usize> embedded_io::Write
+    for MockTransport<N_STEPS, BUF_LEN>
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        Ok(self.write_script(buf))
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// [`Storage`] in RAM that enforces the rules of NOR flash: writes are aligned
+/// and only go to erased bytes, and erases cover whole sectors.
+///
+/// Hand out `&mut RamStorage` and open a new queue on it to simulate a reboot.
+pub struct RamStorage<const SIZE: usize, const SECTOR_SIZE: usize = 64, const WRITE_SIZE: usize = 4>
+{
+    bytes: [u8; SIZE],
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RamStorageError {
+    OutOfBounds,
+    Unaligned,
+    /// Writing to bytes that weren't erased.
+    NotErased,
+}
+
+impl<const SIZE: usize, const SECTOR_SIZE: usize, const WRITE_SIZE: usize>
+    RamStorage<SIZE, SECTOR_SIZE, WRITE_SIZE>
+{
+    /// Erased storage.
+    pub fn new() -> Self {
+        Self {
+            bytes: [0xFF; SIZE],
+        }
+    }
+
+    /// Raw contents, e.g. to tear a record as a power loss would.
+    pub fn as_mut_bytes(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+
+    fn range(&self, offset: u32, len: usize) -> Result<Range<usize>, RamStorageError> {
+        let start = offset as usize;
+        let end = start + len;
+
+        if end > SIZE {
+            return Err(RamStorageError::OutOfBounds);
+        }
+
+        Ok(start..end)
+    }
+}
+
+impl<const SIZE: usize, const SECTOR_SIZE: usize, const WRITE_SIZE: usize> Default
+    for RamStorage<SIZE, SECTOR_SIZE, WRITE_SIZE>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SIZE: usize, const SECTOR_SIZE: usize, const WRITE_SIZE: usize> Storage
+    for RamStorage<SIZE, SECTOR_SIZE, WRITE_SIZE>
+{
+    type Error = RamStorageError;
+
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const SECTOR_SIZE: usize = SECTOR_SIZE;
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let range = self.range(offset, bytes.len())?;
+        bytes.copy_from_slice(&self.bytes[range]);
+
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let range = self.range(offset, bytes.len())?;
+        if range.start % WRITE_SIZE != 0 || range.len() % WRITE_SIZE != 0 {
+            return Err(RamStorageError::Unaligned);
+        }
+
+        let target = &mut self.bytes[range];
+        if target.iter().any(|byte| *byte != 0xFF) {
+            return Err(RamStorageError::NotErased);
+        }
+        target.copy_from_slice(bytes);
+
+        Ok(())
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let range = self.range(from, to.saturating_sub(from) as usize)?;
+        if range.start % SECTOR_SIZE != 0 || range.len() % SECTOR_SIZE != 0 {
+            return Err(RamStorageError::Unaligned);
+        }
+        self.bytes[range].fill(0xFF);
+
+        Ok(())
+    }
+}
+
+/// Runs a future to completion by polling it in a loop. [`MockTransport`]
+/// never returns pending, so no executor is needed.
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut fut = pin!(fut);
+
+    loop {
+        if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+            return out;
+        }
+    }
+}
+
+fn id(packet_id: u16) -> PacketId {
+    PacketId::try_from(packet_id).expect("packet id must not be 0")
+}
+
+/// Clean session with a 10 second keep-alive, no will and no credentials.
+/// Change the rest with struct update syntax.
+pub fn connect_options(client_id: &str) -> connect::Options<'_> {
+    connect::Options {
+        clean_session: true,
+        keep_alive: 10,
+        client_id,
+        will: None,
+        username: None,
+        password: None,
+    }
+}
+
+pub fn connect(opts: connect::Options<'_>) -> Packet<'_> {
+    Packet::Connect(opts.into())
+}
+
+pub fn connack(session_present: bool) -> Packet<'static> {
+    Packet::ConnAck(ConnAck {
+        session_present,
+        return_code: ConnectReturnCode::Accepted,
+    })
+}
+
+/// CONNACK refusing the connection with return code `code` (1 to 5).
+pub fn connack_refused(code: u8) -> Packet<'static> {
+    Packet::ConnAck(ConnAck {
+        session_present: false,
+        return_code: ConnectReturnCode::try_from(code).expect("invalid CONNACK return code"),
+    })
+}
+
+/// PUBLISH as either side sends it. `packet_id` is needed for QoS 1 and 2.
+pub fn publish<'a>(
+    topic: &'a str,
+    payload: &'a [u8],
+    qos: QoS,
+    packet_id: Option<u16>,
+) -> Packet<'a> {
+    let mut publish = publish::Publish::from(publish::Msg {
+        qos,
+        retain: false,
+        topic,
+        payload,
+    });
+    publish.packet_id = packet_id.map(id);
+
+    Packet::Publish(publish)
+}
+
+pub fn puback(packet_id: u16) -> Packet<'static> {
+    Packet::PubAck(id(packet_id))
+}
+
+pub fn pubrec(packet_id: u16) -> Packet<'static> {
+    Packet::PubRec(id(packet_id))
+}
+
+pub fn pubrel(packet_id: u16) -> Packet<'static> {
+    Packet::PubRel(id(packet_id))
+}
+
+pub fn pubcomp(packet_id: u16) -> Packet<'static> {
+    Packet::PubComp(id(packet_id))
+}
+
+pub fn subscribe(packet_id: u16, topic: &str, qos: QoS) -> Packet<'_> {
+    Packet::Subscribe(Subscribe::single(id(packet_id), topic, qos))
+}
+
+/// SUBACK granting `granted`, or refusing the subscription if `None`.
+pub fn suback(packet_id: u16, granted: Option<QoS>) -> Packet<'static> {
+    let code = match granted {
+        Some(QoS::AtMostOnce) => SubAckReturnCode::SuccessMaxQoS0,
+        Some(QoS::AtLeastOnce) => SubAckReturnCode::SuccessMaxQoS1,
+        Some(QoS::ExactlyOnce) => SubAckReturnCode::SuccessMaxQoS2,
+        None => SubAckReturnCode::Failure,
+    };
+    let mut return_codes = Vec::new();
+    let _ = return_codes.push(code);
+
+    Packet::SubAck(SubAck {
+        packet_id: id(packet_id),
+        return_codes,
+    })
+}
+
+pub fn unsubscribe(packet_id: u16, topic: &str) -> Packet<'_> {
+    Packet::Unsubscribe(Unsubscribe::single(id(packet_id), topic))
+}
+
+pub fn unsuback(packet_id: u16) -> Packet<'static> {
+    Packet::UnsubAck(id(packet_id))
+}
+
+pub fn pingreq() -> Packet<'static> {
+    Packet::PingReq
+}
+
+pub fn pingresp() -> Packet<'static> {
+    Packet::PingResp
+}
+
+pub fn disconnect() -> Packet<'static> {
+    Packet::Disconnect
+}
@@ -1,10 +1,12 @@
 use embedded_time::{Clock, Instant, rate::Fraction};
 
 // Copied almost 1-to-1 from https://github.com/SimonIT/embassy-embedded-time/blob/main/src/lib.rs
+#[cfg(feature = "embassy")]
 pub struct EmbassyClock {
     start: embassy_time::Instant,
 }
 
+#[cfg(feature = "embassy")]
 impl Default for EmbassyClock {
     fn default() -> Self {
         Self {
@@ -13,6 +15,7 @@ impl Default for EmbassyClock {
     }
 }
 
+#[cfg(feature = "embassy")]
 impl Clock for EmbassyClock {
     type T = u64;
 
@@ -26,8 +29,10 @@ impl Clock for EmbassyClock {
     }
 }
 
+#[cfg(feature = "embassy")]
 pub struct KeepAlive {}
 
+#[cfg(feature = "embassy")]
 impl KeepAlive {
     pub fn from_us(value: u64) -> embedded_time::duration::Generic<<EmbassyClock as Clock>::T> {
         embedded_time::duration::Generic::new(value, <EmbassyClock as Clock>::SCALING_FACTOR)
@@ -40,3 +45,33 @@ impl KeepAlive {
         embedded_time::duration::Generic::new(ticks, scale)
     }
 }
+
+/// Monotonic microsecond clock backed by [`std::time::Instant`].
+#[cfg(feature = "std")]
+pub struct StdClock {
+    start: std::time::Instant,
+}
+
+#[cfg(feature = "std")]
+impl Default for StdClock {
+    fn default() -> Self {
+        Self {
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    type T = u64;
+
+    const SCALING_FACTOR: Fraction = Fraction::new(1, 1_000_000);
+
+    fn try_now(&self) -> Result<embedded_time::Instant<Self>, embedded_time::clock::Error> {
+        let elapsed = self.start.elapsed().as_micros();
+
+        Ok(Instant::new(
+            u64::try_from(elapsed).map_err(|_| embedded_time::clock::Error::Unspecified)?,
+        ))
+    }
+}
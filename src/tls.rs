@@ -0,0 +1,669 @@
+//! MQTT over TLS 1.3 (port 8883) using `embedded-tls`.
+//!
+//! [`Transport::connect`] performs the TLS handshake on an already connected
+//! stream; the result implements `Read + Write` and can be passed straight to
+//! [`crate::Client::try_new`]. Every write is flushed as its own TLS record.
+//!
+//! The broker is authenticated either by pinning its CA with
+//! [`Provider::with_ca`] or by a pre-shared key from [`psk_config`]. Without a
+//! CA every broker certificate is rejected, unless verification is turned off
+//! with [`Provider::insecure_no_verify`]. Brokers that require mutual TLS get a
+//! client certificate from [`Provider::with_client_cert`].
+//!
+//! # Buffer sizing
+//!
+//! TLS needs two record buffers on top of the client's `rx_buf`/`tx_buf`.
+//! Those keep holding plaintext MQTT packets and are sized exactly as without
+//! TLS. The record read buffer must fit the largest record the broker may
+//! send, [`RECORD_READ_BUF_LEN`] bytes. The record write buffer needs
+//! [`RECORD_OVERHEAD`] bytes on top of the plaintext: with
+//! [`record_write_buf_len`] of the largest outgoing packet, every packet goes
+//! out as a single record.
+//!
+//! ```
+//! use embedded_io_async::{Read, Write};
+//! use embedded_time::{Clock, duration::Generic};
+//! use mqtt_client::{Client, Error, TransportError, tls};
+//!
+//! // Largest MQTT packet sent or received by the application.
+//! const MAX_PACKET_LEN: usize = 1024;
+//!
+//! async fn connect<C: Clock, T: Read + Write>(
+//!     clock: C,
+//!     keep_alive: Generic<C::T>,
+//!     stream: T,
+//!     rng: impl tls::CryptoRngCore,
+//!     ca_der: &[u8],
+//! ) -> Result<(), Error<tls::TlsError>> {
+//!     let mut record_read_buf = [0u8; tls::RECORD_READ_BUF_LEN];
+//!     let mut record_write_buf = [0u8; tls::record_write_buf_len(MAX_PACKET_LEN)];
+//!     // One incoming packet, two queued outgoing ones.
+//!     let mut rx_buf = [0u8; MAX_PACKET_LEN];
+//!     let mut tx_buf = [0u8; 2 * MAX_PACKET_LEN];
+//!
+//!     let config = tls::config("broker.example.com");
+//!     let provider = tls::Provider::new(rng).with_ca(ca_der);
+//!     let transport = tls::Transport::connect(
+//!         stream,
+//!         &mut record_read_buf,
+//!         &mut record_write_buf,
+//!         &config,
+//!         provider,
+//!     )
+//!     .await
+//!     .map_err(TransportError)?;
+//!
+//!     let client: Client<'_, C, _, 4, 4, 4, 8> =
+//!         Client::try_new(clock, keep_alive, transport, &mut rx_buf, &mut tx_buf)
+//!             .map_err(Error::widen)?;
+//!     // ...
+//!     # drop(client);
+//!
+//!     Ok(())
+//! }
+//!
+//! assert_eq!(tls::RECORD_READ_BUF_LEN, 16_640);
+//! assert_eq!(tls::record_write_buf_len(MAX_PACKET_LEN), 1152);
+//! ```
+
+use embedded_io_async::{ErrorType, Read, Write};
+use embedded_tls::{
+    Certificate, CertificateRef, CertificateVerifyRef, CryptoProvider, NoClock, SignatureScheme,
+    TlsCipherSuite, TlsClock, TlsConnection, TlsContext, TlsVerifier, UnsecureProvider,
+    pki::CertVerifier,
+};
+
+pub use embedded_tls::{
+    Aes128GcmSha256, Aes256GcmSha384, CryptoRngCore, TLS_RECORD_OVERHEAD as RECORD_OVERHEAD,
+    TlsConfig, TlsError,
+};
+
+/// Largest TLS 1.3 record: 2^14 bytes of plaintext plus 256 bytes of expansion.
+pub const RECORD_READ_BUF_LEN: usize = 16_640;
+
+/// Record write buffer that sends packets of up to `max_packet_len` bytes as a single record.
+pub const fn record_write_buf_len(max_packet_len: usize) -> usize {
+    max_packet_len + RECORD_OVERHEAD
+}
+
+/// Config for a broker authenticated by its certificate. `server_name` is sent
+/// as SNI and checked against the certificate when a CA is pinned.
+pub fn config(server_name: &str) -> TlsConfig<'_> {
+    TlsConfig::new().with_server_name(server_name)
+}
+
+/// Config for a broker authenticated by a pre-shared key; no CA is needed.
+pub fn psk_config<'a>(server_name: &'a str, identity: &'a [u8], key: &'a [u8]) -> TlsConfig<'a> {
+    TlsConfig::new()
+        .with_server_name(server_name)
+        .with_psk(key, &[identity])
+}
+
+/// Crypto for the handshake: randomness, the pinned CA and the client certificate.
+///
+/// `CERT_SIZE` bounds the DER size of the broker certificate kept for
+/// verification. Validity periods are only checked with a real `Clk`.
+pub struct Provider<'a, R, CS = Aes128GcmSha256, Clk = NoClock, const CERT_SIZE: usize = 4096>
+where
+    CS: TlsCipherSuite,
+    Clk: TlsClock,
+{
+    inner: UnsecureProvider<'a, CS, R>,
+    verifier: Verifier<'a, CS, Clk, CERT_SIZE>,
+    /// `embedded-tls` skips verification when there's no verifier at all.
+    skip_verification: bool,
+}
+
+/// Verifier of the pinned CA. Without one any certificate is rejected, which
+/// leaves PSK handshakes, as they don't send one.
+struct Verifier<'a, CS, Clk, const CERT_SIZE: usize>(Option<CertVerifier<'a, CS, Clk, CERT_SIZE>>)
+where
+    CS: TlsCipherSuite,
+    Clk: TlsClock;
+
+impl<CS, Clk, const CERT_SIZE: usize> TlsVerifier<CS> for Verifier<'_, CS, Clk, CERT_SIZE>
+where
+    CS: TlsCipherSuite,
+    Clk: TlsClock,
+{
+    fn set_hostname_verification(&mut self, hostname: &str) -> Result<(), TlsError> {
+        match &mut self.0 {
+            Some(verifier) => verifier.set_hostname_verification(hostname),
+            None => Ok(()),
+        }
+    }
+
+    fn verify_certificate(
+        &mut self,
+        transcript: &CS::Hash,
+        cert: CertificateRef,
+    ) -> Result<(), TlsError> {
+        match &mut self.0 {
+            Some(verifier) => verifier.verify_certificate(transcript, cert),
+            None => Err(TlsError::InvalidCertificate),
+        }
+    }
+
+    fn verify_signature(&mut self, verify: CertificateVerifyRef) -> Result<(), TlsError> {
+        match &mut self.0 {
+            Some(verifier) => verifier.verify_signature(verify),
+            None => Err(TlsError::InvalidSignature),
+        }
+    }
+}
+
+impl<R: CryptoRngCore> Provider<'_, R> {
+    /// Provider for the default cipher suite. Without [`Self::with_ca`] only
+    /// brokers authenticated by [`psk_config`] are accepted.
+    pub fn new(rng: R) -> Self {
+        Self::from_rng(rng)
+    }
+}
+
+impl<'a, R, CS, Clk, const CERT_SIZE: usize> Provider<'a, R, CS, Clk, CERT_SIZE>
+where
+    R: CryptoRngCore,
+    CS: TlsCipherSuite,
+    Clk: TlsClock,
+{
+    /// Like [`Provider::new`], for any cipher suite, clock or certificate size.
+    pub fn from_rng(rng: R) -> Self {
+        Self {
+            inner: UnsecureProvider::new::<CS>(rng),
+            verifier: Verifier(None),
+            skip_verification: false,
+        }
+    }
+
+    /// Only accepts brokers whose certificate chains up to `ca_der`, an X.509 certificate in DER.
+    pub fn with_ca(mut self, ca_der: &'a [u8]) -> Self {
+        self.verifier = Verifier(Some(CertVerifier::new(Certificate::X509(ca_der))));
+        self.skip_verification = false;
+        self
+    }
+
+    /// Accepts any broker certificate. Anyone on the path can impersonate the
+    /// broker, so this is only for development against self-signed brokers.
+    pub fn insecure_no_verify(mut self) -> Self {
+        self.verifier = Verifier(None);
+        self.skip_verification = true;
+        self
+    }
+
+    /// Client certificate in DER and its P-256 private key in SEC1 DER, for mutual TLS.
+    /// The record write buffer has to fit the certificate during the handshake.
+    pub fn with_client_cert(mut self, cert_der: &'a [u8], key_der: &'a [u8]) -> Self {
+        self.inner = self
+            .inner
+            .with_cert(Certificate::X509(cert_der))
+            .with_priv_key(key_der);
+        self
+    }
+}
+
+impl<'a, R, CS, Clk, const CERT_SIZE: usize> CryptoProvider for Provider<'a, R, CS, Clk, CERT_SIZE>
+where
+    R: CryptoRngCore,
+    CS: TlsCipherSuite,
+    Clk: TlsClock,
+{
+    type CipherSuite = CS;
+    type Signature = <UnsecureProvider<'a, CS, R> as CryptoProvider>::Signature;
+
+    fn rng(&mut self) -> impl CryptoRngCore {
+        self.inner.rng()
+    }
+
+    fn verifier(&mut self) -> Result<&mut impl TlsVerifier<CS>, TlsError> {
+        if self.skip_verification {
+            return Err(TlsError::Unimplemented);
+        }
+
+        Ok(&mut self.verifier)
+    }
+
+    fn signer(
+        &mut self,
+    ) -> Result<(impl signature::SignerMut<Self::Signature>, SignatureScheme), TlsError> {
+        self.inner.signer()
+    }
+
+    fn client_cert(&mut self) -> Option<Certificate<impl AsRef<[u8]>>> {
+        self.inner.client_cert()
+    }
+}
+
+pub struct Transport<'a, T, CS = Aes128GcmSha256>
+where
+    T: Read + Write + 'a,
+    CS: TlsCipherSuite + 'static,
+{
+    connection: TlsConnection<'a, T, CS>,
+}
+
+impl<'a, T, CS> Transport<'a, T, CS>
+where
+    T: Read + Write + 'a,
+    CS: TlsCipherSuite + 'static,
+{
+    /// Runs the handshake on `inner`, a connected stream to the broker.
+    pub async fn connect<P>(
+        inner: T,
+        record_read_buf: &'a mut [u8],
+        record_write_buf: &'a mut [u8],
+        config: &TlsConfig<'_>,
+        provider: P,
+    ) -> Result<Self, TlsError>
+    where
+        P: CryptoProvider<CipherSuite = CS>,
+    {
+        let mut connection = TlsConnection::new(inner, record_read_buf, record_write_buf);
+        connection.open(TlsContext::new(config, provider)).await?;
+
+        Ok(Self { connection })
+    }
+
+    /// Sends `close_notify` and hands back the stream.
+    pub async fn close(self) -> Result<T, TlsError> {
+        self.connection.close().await.map_err(|(_, err)| err)
+    }
+}
+
+impl<'a, T, CS> ErrorType for Transport<'a, T, CS>
+where
+    T: Read + Write + 'a,
+    CS: TlsCipherSuite + 'static,
+{
+    type Error = TlsError;
+}
+
+impl<'a, T, CS> Read for Transport<'a, T, CS>
+where
+    T: Read + Write + 'a,
+    CS: TlsCipherSuite + 'static,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.connection.read(buf).await
+    }
+}
+
+impl<'a, T, CS> Write for Transport<'a, T, CS>
+where
+    T: Read + Write + 'a,
+    CS: TlsCipherSuite + 'static,
+{
+    // The client never flushes, so a record must not wait for more data.
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let n = self.connection.write(buf).await?;
+        self.connection.flush().await?;
+
+        Ok(n)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.connection.flush().await
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use std::sync::Arc;
+
+    use embedded_time::{duration, rate::Fraction};
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::{
+        TlsAcceptor,
+        rustls::{
+            RootCertStore, ServerConfig,
+            crypto::ring,
+            pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+            server::WebPkiClientVerifier,
+            version::TLS13,
+        },
+    };
+
+    use super::*;
+    use crate::{Client, net::TokioTransport, packet::connect, session, testing, time::StdClock};
+
+    /// Not cryptographically secure, but good enough to drive a handshake.
+    struct TestRng(u64);
+
+    impl rand_core::RngCore for TestRng {
+        fn next_u32(&mut self) -> u32 {
+            self.next_u64() as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            // xorshift64
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            rand_core::impls::fill_bytes_via_next(self, dest)
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl rand_core::CryptoRng for TestRng {}
+
+    fn rng() -> TestRng {
+        TestRng(0x2545_F491_4F6C_DD1D)
+    }
+
+    struct Pki {
+        ca: CertificateDer<'static>,
+        server_cert: CertificateDer<'static>,
+        server_key: PrivatePkcs8KeyDer<'static>,
+        client_cert: CertificateDer<'static>,
+        /// SEC1, as [`Provider::with_client_cert`] takes it.
+        client_key: Vec<u8>,
+    }
+
+    fn pki() -> Pki {
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let server_cert = CertificateParams::new(vec!["localhost".into()])
+            .unwrap()
+            .signed_by(&server_key, &ca)
+            .unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let client_cert = CertificateParams::new(vec!["client".into()])
+            .unwrap()
+            .signed_by(&client_key, &ca)
+            .unwrap();
+        let client_key = {
+            use p256::pkcs8::DecodePrivateKey;
+
+            let key = p256::SecretKey::from_pkcs8_der(&client_key.serialize_der()).unwrap();
+            key.to_sec1_der().unwrap().to_vec()
+        };
+
+        Pki {
+            ca: ca.der().clone(),
+            server_cert: server_cert.der().clone(),
+            server_key: PrivatePkcs8KeyDer::from(server_key.serialize_der()),
+            client_cert: client_cert.der().clone(),
+            client_key,
+        }
+    }
+
+    /// Server config of the broker, which asks for a client certificate signed
+    /// by the CA if `mutual`.
+    fn server_config(pki: &Pki, mutual: bool) -> ServerConfig {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&TLS13])
+            .unwrap();
+
+        let builder = if mutual {
+            let mut roots = RootCertStore::empty();
+            roots.add(pki.ca.clone()).unwrap();
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .unwrap();
+            builder.with_client_cert_verifier(verifier)
+        } else {
+            builder.with_no_client_auth()
+        };
+
+        builder
+            .with_single_cert(
+                vec![pki.server_cert.clone()],
+                PrivateKeyDer::Pkcs8(pki.server_key.clone_key()),
+            )
+            .unwrap()
+    }
+
+    /// Accepts one TLS connection, answers CONNECT with CONNACK and returns what it read next.
+    async fn broker(listener: TcpListener, config: ServerConfig) -> Vec<u8> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let Ok(mut stream) = TlsAcceptor::from(Arc::new(config)).accept(stream).await else {
+            return Vec::new();
+        };
+
+        let mut buf = [0u8; 64];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_eq!(buf[0], 0x10);
+        assert_eq!(usize::from(buf[1]) + 2, n);
+        stream.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+
+        let n = stream.read(&mut buf).await.unwrap();
+        buf[..n].to_vec()
+    }
+
+    /// Connects the client over `transport` and disconnects again.
+    async fn connect_and_disconnect<T: Read + Write>(transport: T) {
+        let (mut rx_buf, mut tx_buf) = ([0u8; 128], [0u8; 128]);
+        let keep_alive = duration::Generic::new(30, Fraction::new(1, 1));
+        let mut client: Client<'_, _, _, 1, 1, 1, 4> = Client::try_new(
+            StdClock::default(),
+            keep_alive,
+            transport,
+            &mut rx_buf,
+            &mut tx_buf,
+        )
+        .unwrap();
+
+        client
+            .schedule_connect(connect::Options {
+                keep_alive: 30,
+                ..testing::connect_options("tls")
+            })
+            .unwrap();
+        assert!(client.poll_io().await.unwrap().is_none());
+        assert!(matches!(
+            client.poll_io().await.unwrap(),
+            Some(session::Event::Connected)
+        ));
+
+        client.schedule_disconnect().unwrap();
+        assert!(client.poll_io().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn connect_through_tls_with_pinned_ca() {
+        let pki = pki();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = tokio::spawn(broker(listener, server_config(&pki, false)));
+
+        let mut record_read_buf = [0u8; RECORD_READ_BUF_LEN];
+        let mut record_write_buf = [0u8; record_write_buf_len(128)];
+        let stream = TokioTransport::new(TcpStream::connect(addr).await.unwrap());
+        let transport = Transport::connect(
+            stream,
+            &mut record_read_buf,
+            &mut record_write_buf,
+            &config("localhost"),
+            Provider::new(rng()).with_ca(&pki.ca),
+        )
+        .await
+        .unwrap();
+        connect_and_disconnect(transport).await;
+
+        assert_eq!(broker.await.unwrap(), [0xE0, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn reject_broker_signed_by_other_ca() {
+        let pki = pki();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let other_ca = self::pki().ca;
+        let broker = tokio::spawn(broker(listener, server_config(&pki, false)));
+
+        let mut record_read_buf = [0u8; RECORD_READ_BUF_LEN];
+        let mut record_write_buf = [0u8; record_write_buf_len(128)];
+        let stream = TokioTransport::new(TcpStream::connect(addr).await.unwrap());
+        let result = Transport::connect(
+            stream,
+            &mut record_read_buf,
+            &mut record_write_buf,
+            &config("localhost"),
+            Provider::new(rng()).with_ca(&other_ca),
+        )
+        .await;
+
+        assert!(matches!(result, Err(TlsError::InvalidCertificate)));
+        drop(result);
+        assert!(broker.await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn certificate_verified_unless_opted_out() {
+        let pki = pki();
+
+        for insecure in [false, true] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let broker = tokio::spawn(broker(listener, server_config(&pki, false)));
+
+            let mut record_read_buf = [0u8; RECORD_READ_BUF_LEN];
+            let mut record_write_buf = [0u8; record_write_buf_len(128)];
+            let stream = TokioTransport::new(TcpStream::connect(addr).await.unwrap());
+            let provider = match insecure {
+                true => Provider::new(rng()).insecure_no_verify(),
+                false => Provider::new(rng()),
+            };
+            let result = Transport::connect(
+                stream,
+                &mut record_read_buf,
+                &mut record_write_buf,
+                &config("localhost"),
+                provider,
+            )
+            .await;
+
+            match result {
+                Ok(transport) if insecure => {
+                    connect_and_disconnect(transport).await;
+                    assert_eq!(broker.await.unwrap(), [0xE0, 0x00]);
+                }
+                // No CA, so the certificate isn't trusted
+                Err(TlsError::InvalidCertificate) if !insecure => {
+                    assert!(broker.await.unwrap().is_empty());
+                }
+                _ => panic!("Unexpected handshake result, insecure: {insecure}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_with_client_certificate() {
+        let pki = pki();
+
+        for with_cert in [true, false] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let broker = tokio::spawn(broker(listener, server_config(&pki, true)));
+
+            let mut record_read_buf = [0u8; RECORD_READ_BUF_LEN];
+            // Fits the client certificate
+            let mut record_write_buf = [0u8; record_write_buf_len(1024)];
+            let stream = TokioTransport::new(TcpStream::connect(addr).await.unwrap());
+            let provider = Provider::new(rng()).with_ca(&pki.ca);
+            let provider = match with_cert {
+                true => provider.with_client_cert(&pki.client_cert, &pki.client_key),
+                false => provider,
+            };
+            let transport = Transport::connect(
+                stream,
+                &mut record_read_buf,
+                &mut record_write_buf,
+                &config("localhost"),
+                provider,
+            )
+            .await
+            .unwrap();
+
+            if with_cert {
+                connect_and_disconnect(transport).await;
+                assert_eq!(broker.await.unwrap(), [0xE0, 0x00]);
+            } else {
+                // The broker only finds out after the client's Finished
+                drop(transport);
+                assert!(broker.await.unwrap().is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_with_psk() {
+        use std::io::{Read as _, Write as _};
+
+        use openssl::ssl::{SslAcceptor, SslMethod, SslVersion};
+
+        const IDENTITY: &[u8] = b"sensor-1";
+        const KEY: &[u8] = &[0x5A; 32];
+
+        let pki = pki();
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).unwrap();
+        acceptor
+            .set_min_proto_version(Some(SslVersion::TLS1_3))
+            .unwrap();
+        // A certificate the client would reject, were it sent
+        acceptor
+            .set_certificate(&openssl::x509::X509::from_der(&pki.server_cert).unwrap())
+            .unwrap();
+        acceptor
+            .set_private_key(
+                &openssl::pkey::PKey::private_key_from_der(pki.server_key.secret_pkcs8_der())
+                    .unwrap(),
+            )
+            .unwrap();
+        acceptor.set_psk_server_callback(|_, identity, key| {
+            assert_eq!(identity, Some(IDENTITY));
+            key[..KEY.len()].copy_from_slice(KEY);
+            Ok(KEY.len())
+        });
+        let acceptor = acceptor.build();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = tokio::task::spawn_blocking(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = acceptor.accept(stream).unwrap();
+
+            let mut buf = [0u8; 64];
+            let n = stream.read(&mut buf).unwrap();
+            assert_eq!(buf[0], 0x10);
+            assert_eq!(usize::from(buf[1]) + 2, n);
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+
+            let n = stream.read(&mut buf).unwrap();
+            buf[..n].to_vec()
+        });
+
+        let mut record_read_buf = [0u8; RECORD_READ_BUF_LEN];
+        let mut record_write_buf = [0u8; record_write_buf_len(128)];
+        let stream = TokioTransport::new(TcpStream::connect(addr).await.unwrap());
+        // No CA
+        let transport = Transport::connect(
+            stream,
+            &mut record_read_buf,
+            &mut record_write_buf,
+            &psk_config("localhost", IDENTITY, KEY),
+            Provider::new(rng()),
+        )
+        .await
+        .unwrap();
+        connect_and_disconnect(transport).await;
+
+        assert_eq!(broker.await.unwrap(), [0xE0, 0x00]);
+    }
+}
@@ -0,0 +1,62 @@
+/// Whether `topic` matches `filter`, e.g. when sending retained messages to a new subscription.
+pub(crate) fn filter_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut topic_levels = topic.split('/');
+
+    for level in filter.split('/') {
+        if level == "#" {
+            return true;
+        }
+
+        match topic_levels.next() {
+            Some(topic_level) if level == "+" || level == topic_level => {}
+            _ => return false,
+        }
+    }
+
+    topic_levels.next().is_none()
+}
+
+/// `#` only as the whole last level, `+` only as a whole level (4.7.1).
+pub(crate) fn is_valid_filter(filter: &str) -> bool {
+    let mut levels = filter.split('/').peekable();
+
+    while let Some(level) = levels.next() {
+        let multi = level.contains('#') && (level != "#" || levels.peek().is_some());
+        let single = level.contains('+') && level != "+";
+
+        if multi || single {
+            return false;
+        }
+    }
+
+    !filter.is_empty()
+}
+
+/// Topic names in PUBLISH must not contain wildcards (4.7.3).
+pub(crate) fn is_valid_topic(topic: &str) -> bool {
+    !topic.is_empty() && !topic.contains(['+', '#'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_validation() {
+        assert!(is_valid_filter("a/+/b/#"));
+        assert!(is_valid_filter("/"));
+        assert!(!is_valid_filter(""));
+        assert!(!is_valid_filter("a/#/b"));
+        assert!(!is_valid_filter("a/b#"));
+        assert!(!is_valid_filter("a+/b"));
+
+        assert!(filter_matches("a/+/c", "a/b/c"));
+        assert!(filter_matches("a/#", "a"));
+        assert!(!filter_matches("a/+", "a/b/c"));
+        assert!(!filter_matches("#", "$SYS/x"));
+    }
+}
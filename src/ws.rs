@@ -0,0 +1,811 @@
+//! MQTT over WebSocket (RFC 6455) for networks that only let HTTP(S) out.
+//!
+//! [`Transport::connect`] performs the HTTP Upgrade handshake on an already
+//! connected stream; the result implements `Read + Write` and can be passed
+//! straight to [`crate::Client::try_new`]. Every write is sent as one masked
+//! binary frame, incoming binary frames are handed out as a plain byte stream.
+
+use core::fmt;
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use heapless::Vec;
+use rand_core::RngCore;
+use sha1::{Digest, Sha1};
+
+const GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Base64 of a 16-byte nonce and of a SHA-1 digest.
+const KEY_LEN: usize = 24;
+const ACCEPT_LEN: usize = 28;
+const MAX_LINE_LEN: usize = 128;
+const MAX_CONTROL_LEN: usize = 125;
+const MAX_HEADER_LEN: usize = 14;
+// Header of a masked frame with a payload of up to 125 bytes.
+const CONTROL_HEADER_LEN: usize = 6;
+const MASK_CHUNK_LEN: usize = 64;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    Transport(E),
+    /// The server didn't answer with a valid `101 Switching Protocols` for `mqtt`.
+    HandshakeFailed,
+    /// The server sent a frame MQTT can't use: text, masked, fragmented or
+    /// oversized control frames, or a continuation frame outside a message.
+    InvalidFrame,
+}
+
+impl<E: fmt::Debug> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transport(err) => write!(f, "transport error: {err:?}"),
+            Self::HandshakeFailed => f.write_str("WebSocket handshake failed"),
+            Self::InvalidFrame => f.write_str("invalid WebSocket frame"),
+        }
+    }
+}
+
+impl<E: fmt::Debug> core::error::Error for Error<E> {}
+
+impl<E: embedded_io_async::Error> embedded_io_async::Error for Error<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Transport(err) => err.kind(),
+            Self::HandshakeFailed => ErrorKind::ConnectionRefused,
+            Self::InvalidFrame => ErrorKind::InvalidData,
+        }
+    }
+}
+
+/// Where `read` is within the incoming stream. Kept in `self` so that dropping a pending
+/// read anywhere, control frames and their replies included, doesn't lose the framing.
+#[derive(Default)]
+struct Incoming {
+    header: [u8; 10],
+    header_len: usize,
+    payload_left: u64,
+    /// The last data frame had no FIN, so a continuation frame comes next.
+    in_message: bool,
+    control: Option<Control>,
+    /// Pong or close frame to send back, and how much of it is written.
+    reply: Vec<u8, { CONTROL_HEADER_LEN + MAX_CONTROL_LEN }>,
+    reply_written: usize,
+    closed: bool,
+}
+
+/// Control frame whose payload is being read.
+struct Control {
+    opcode: u8,
+    payload: [u8; MAX_CONTROL_LEN],
+    len: usize,
+    read: usize,
+}
+
+pub struct Transport<T, R> {
+    inner: T,
+    rng: R,
+    incoming: Incoming,
+}
+
+impl<T, R> Transport<T, R>
+where
+    T: Read + Write,
+    R: RngCore,
+{
+    /// Upgrades `inner`, a connected stream to `host`, to a WebSocket on `path`.
+    pub async fn connect(
+        mut inner: T,
+        mut rng: R,
+        host: &str,
+        path: &str,
+    ) -> Result<Self, Error<T::Error>> {
+        let mut nonce = [0u8; 16];
+        rng.fill_bytes(&mut nonce);
+        let mut key = [0u8; KEY_LEN];
+        STANDARD
+            .encode_slice(nonce, &mut key)
+            .map_err(|_| Error::HandshakeFailed)?;
+
+        for part in [
+            b"GET ".as_slice(),
+            path.as_bytes(),
+            b" HTTP/1.1\r\nHost: ",
+            host.as_bytes(),
+            b"\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: ",
+            &key,
+            b"\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: mqtt\r\n\r\n",
+        ] {
+            inner.write_all(part).await.map_err(Error::Transport)?;
+        }
+        inner.flush().await.map_err(Error::Transport)?;
+
+        read_handshake_response(&mut inner, &accept_key(&key)?).await?;
+
+        Ok(Self {
+            inner,
+            rng,
+            incoming: Incoming::default(),
+        })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Header of a masked frame with FIN set: every write is a complete message.
+    fn frame_header(&mut self, opcode: u8, len: usize) -> (Vec<u8, MAX_HEADER_LEN>, [u8; 4]) {
+        let mut header = Vec::new();
+
+        let _ = header.push(0x80 | opcode);
+        if len < 126 {
+            let _ = header.push(0x80 | len as u8);
+        } else if let Ok(len) = u16::try_from(len) {
+            let _ = header.push(0x80 | 126);
+            let _ = header.extend_from_slice(&len.to_be_bytes());
+        } else {
+            let _ = header.push(0x80 | 127);
+            let _ = header.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+
+        let mask = self.rng.next_u32().to_be_bytes();
+        let _ = header.extend_from_slice(&mask);
+
+        (header, mask)
+    }
+
+    async fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), Error<T::Error>> {
+        self.write_reply().await?;
+
+        let (header, mask) = self.frame_header(opcode, payload.len());
+
+        self.inner
+            .write_all(&header)
+            .await
+            .map_err(Error::Transport)?;
+
+        let mut chunk = [0u8; MASK_CHUNK_LEN];
+        for (index, part) in payload.chunks(MASK_CHUNK_LEN).enumerate() {
+            for (offset, (dst, src)) in chunk.iter_mut().zip(part).enumerate() {
+                *dst = src ^ mask[(index * MASK_CHUNK_LEN + offset) % 4];
+            }
+
+            self.inner
+                .write_all(&chunk[..part.len()])
+                .await
+                .map_err(Error::Transport)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the rest of the current frame header; `Some(total_len)` once it's complete.
+    async fn read_header(&mut self) -> Result<Option<usize>, Error<T::Error>> {
+        let incoming = &mut self.incoming;
+        let needed = match incoming.header_len {
+            0 | 1 => 2,
+            _ => match incoming.header[1] & 0x7F {
+                126 => 4,
+                127 => 10,
+                _ => 2,
+            },
+        };
+
+        if incoming.header_len == needed {
+            return Ok(Some(needed));
+        }
+
+        let n = self
+            .inner
+            .read(&mut incoming.header[incoming.header_len..needed])
+            .await
+            .map_err(Error::Transport)?;
+
+        if n == 0 {
+            // EOF between frames is a plain close, inside a header it's a broken frame.
+            if incoming.header_len != 0 {
+                return Err(Error::InvalidFrame);
+            }
+
+            incoming.closed = true;
+            return Ok(None);
+        }
+
+        incoming.header_len += n;
+
+        Ok(None)
+    }
+
+    /// Handles a complete frame header, setting up the read of its payload.
+    fn on_header(&mut self, header_len: usize) -> Result<(), Error<T::Error>> {
+        let incoming = &mut self.incoming;
+        let header = incoming.header;
+        incoming.header_len = 0;
+
+        if header[1] & 0x80 != 0 {
+            return Err(Error::InvalidFrame);
+        }
+
+        let fin = header[0] & 0x80 != 0;
+        let len = match header_len {
+            4 => u64::from(u16::from_be_bytes([header[2], header[3]])),
+            10 => u64::from_be_bytes([
+                header[2], header[3], header[4], header[5], header[6], header[7], header[8],
+                header[9],
+            ]),
+            _ => u64::from(header[1] & 0x7F),
+        };
+
+        match header[0] & 0x0F {
+            // A new message can't start inside another, nor a continuation outside one.
+            opcode @ (OP_BINARY | OP_CONTINUATION) => {
+                if incoming.in_message != (opcode == OP_CONTINUATION) {
+                    return Err(Error::InvalidFrame);
+                }
+
+                incoming.in_message = !fin;
+                incoming.payload_left = len;
+                Ok(())
+            }
+            opcode @ (OP_CLOSE | OP_PING | OP_PONG) => {
+                let len = usize::try_from(len)
+                    .ok()
+                    .filter(|len| fin && *len <= MAX_CONTROL_LEN)
+                    .ok_or(Error::InvalidFrame)?;
+
+                incoming.control = Some(Control {
+                    opcode,
+                    payload: [0; MAX_CONTROL_LEN],
+                    len,
+                    read: 0,
+                });
+                Ok(())
+            }
+            _ => Err(Error::InvalidFrame),
+        }
+    }
+
+    /// Reads more of the current control frame's payload and handles it once complete.
+    async fn read_control(&mut self) -> Result<(), Error<T::Error>> {
+        let Some(control) = self.incoming.control.as_mut() else {
+            return Ok(());
+        };
+
+        if control.read < control.len {
+            let n = self
+                .inner
+                .read(&mut control.payload[control.read..control.len])
+                .await
+                .map_err(Error::Transport)?;
+
+            if n == 0 {
+                return Err(Error::InvalidFrame);
+            }
+
+            control.read += n;
+            return Ok(());
+        }
+
+        let Some(control) = self.incoming.control.take() else {
+            return Ok(());
+        };
+        let payload = &control.payload[..control.len];
+
+        match control.opcode {
+            OP_PING => self.queue_reply(OP_PONG, payload),
+            OP_CLOSE => {
+                self.incoming.closed = true;
+                self.queue_reply(OP_CLOSE, &payload[..payload.len().min(2)]);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Masks the answer to a control frame into `incoming`, so that it survives a dropped read.
+    fn queue_reply(&mut self, opcode: u8, payload: &[u8]) {
+        let (header, mask) = self.frame_header(opcode, payload.len());
+        let reply = &mut self.incoming.reply;
+
+        reply.clear();
+        let _ = reply.extend_from_slice(&header);
+        for (index, byte) in payload.iter().enumerate() {
+            let _ = reply.push(byte ^ mask[index % 4]);
+        }
+        self.incoming.reply_written = 0;
+    }
+
+    /// Writes what's left of a queued control frame answer.
+    async fn write_reply(&mut self) -> Result<(), Error<T::Error>> {
+        let incoming = &mut self.incoming;
+
+        while incoming.reply_written < incoming.reply.len() {
+            let n = self
+                .inner
+                .write(&incoming.reply[incoming.reply_written..])
+                .await
+                .map_err(Error::Transport)?;
+
+            incoming.reply_written += n;
+        }
+
+        incoming.reply.clear();
+        incoming.reply_written = 0;
+
+        Ok(())
+    }
+}
+
+impl<T: ErrorType, R> ErrorType for Transport<T, R> {
+    type Error = Error<T::Error>;
+}
+
+impl<T, R> Read for Transport<T, R>
+where
+    T: Read + Write,
+    R: RngCore,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        while self.incoming.payload_left == 0 {
+            self.write_reply().await?;
+
+            if self.incoming.closed {
+                return Ok(0);
+            }
+
+            if self.incoming.control.is_some() {
+                self.read_control().await?;
+            } else if let Some(header_len) = self.read_header().await? {
+                self.on_header(header_len)?;
+            }
+        }
+
+        let len = usize::try_from(self.incoming.payload_left)
+            .unwrap_or(usize::MAX)
+            .min(buf.len());
+        let n = self
+            .inner
+            .read(&mut buf[..len])
+            .await
+            .map_err(Error::Transport)?;
+
+        self.incoming.payload_left -= n as u64;
+
+        Ok(n)
+    }
+}
+
+impl<T, R> Write for Transport<T, R>
+where
+    T: Read + Write,
+    R: RngCore,
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.write_frame(OP_BINARY, buf).await?;
+
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush().await.map_err(Error::Transport)
+    }
+}
+
+fn accept_key<E>(key: &[u8]) -> Result<[u8; ACCEPT_LEN], Error<E>> {
+    let mut sha1 = Sha1::new();
+    sha1.update(key);
+    sha1.update(GUID);
+
+    let mut accept = [0u8; ACCEPT_LEN];
+    STANDARD
+        .encode_slice(sha1.finalize(), &mut accept)
+        .map_err(|_| Error::HandshakeFailed)?;
+
+    Ok(accept)
+}
+
+/// Reads the response one byte at a time so nothing after the headers is consumed.
+async fn read_handshake_response<T: Read>(
+    inner: &mut T,
+    accept: &[u8],
+) -> Result<(), Error<T::Error>> {
+    let mut line = Vec::<u8, MAX_LINE_LEN>::new();
+    let mut truncated = false;
+    let mut status_ok = None;
+    let mut upgrade_ok = false;
+    let mut connection_ok = false;
+    let mut accept_ok = false;
+    let mut protocol_ok = false;
+
+    loop {
+        let mut byte = [0u8];
+        if inner.read(&mut byte).await.map_err(Error::Transport)? == 0 {
+            return Err(Error::HandshakeFailed);
+        }
+
+        if byte[0] != b'\n' {
+            truncated |= line.push(byte[0]).is_err();
+            continue;
+        }
+
+        let text = line.strip_suffix(b"\r").unwrap_or(&line);
+
+        if text.is_empty() {
+            break;
+        }
+
+        match status_ok {
+            None => status_ok = Some(!truncated && text.starts_with(b"HTTP/1.1 101")),
+            Some(_) if !truncated => {
+                if let Some((name, value)) = split_header(text) {
+                    if name.eq_ignore_ascii_case(b"upgrade") {
+                        upgrade_ok = value.eq_ignore_ascii_case(b"websocket");
+                    } else if name.eq_ignore_ascii_case(b"connection") {
+                        // A list of options, which has to include the upgrade
+                        connection_ok |= value
+                            .split(|b| *b == b',')
+                            .any(|option| option.trim_ascii().eq_ignore_ascii_case(b"upgrade"));
+                    } else if name.eq_ignore_ascii_case(b"sec-websocket-accept") {
+                        accept_ok = value == accept;
+                    } else if name.eq_ignore_ascii_case(b"sec-websocket-protocol") {
+                        protocol_ok = value == b"mqtt";
+                    }
+                }
+            }
+            Some(_) => {}
+        }
+
+        line.clear();
+        truncated = false;
+    }
+
+    if status_ok == Some(true) && upgrade_ok && connection_ok && accept_ok && protocol_ok {
+        Ok(())
+    } else {
+        Err(Error::HandshakeFailed)
+    }
+}
+
+fn split_header(line: &[u8]) -> Option<(&[u8], &[u8])> {
+    let colon = line.iter().position(|b| *b == b':')?;
+    let (name, value) = line.split_at(colon);
+
+    Some((name.trim_ascii(), value[1..].trim_ascii()))
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        convert::Infallible,
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use super::*;
+
+    // RFC 6455, section 1.3
+    const NONCE: &[u8; 16] = b"the sample nonce";
+    const RESPONSE: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+        Sec-WebSocket-Protocol: mqtt\r\n\r\n";
+
+    struct FixedRng;
+
+    impl RngCore for FixedRng {
+        fn next_u32(&mut self) -> u32 {
+            0x0102_0304
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            0x0102_0304
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            dest.copy_from_slice(&NONCE[..dest.len()]);
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    struct Server<'a> {
+        rx: &'a [u8],
+        tx: Vec<u8, 512>,
+    }
+
+    impl ErrorType for Server<'_> {
+        type Error = Infallible;
+    }
+
+    impl Read for Server<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let n = buf.len().min(self.rx.len());
+            buf[..n].copy_from_slice(&self.rx[..n]);
+            self.rx = &self.rx[n..];
+            Ok(n)
+        }
+    }
+
+    impl Write for Server<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.tx.extend_from_slice(buf).unwrap();
+            Ok(buf.len())
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    /// Server that moves one byte per call, and only every other call.
+    struct Trickle<'a> {
+        server: Server<'a>,
+        stalled: bool,
+    }
+
+    impl Trickle<'_> {
+        fn step(&mut self) -> Poll<()> {
+            self.stalled = !self.stalled;
+            if self.stalled {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        }
+    }
+
+    impl ErrorType for Trickle<'_> {
+        type Error = Infallible;
+    }
+
+    impl Read for Trickle<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            core::future::poll_fn(|_| self.step()).await;
+            let len = buf.len().min(1);
+            self.server.read(&mut buf[..len]).await
+        }
+    }
+
+    impl Write for Trickle<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            core::future::poll_fn(|_| self.step()).await;
+            self.server.write(&buf[..buf.len().min(1)]).await
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut fut = pin!(fut);
+
+        loop {
+            if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+                return out;
+            }
+        }
+    }
+
+    fn connect<'a>(rx: &'a [u8]) -> Transport<Server<'a>, FixedRng> {
+        let server = Server { rx, tx: Vec::new() };
+        let mut transport =
+            block_on(Transport::connect(server, FixedRng, "broker", "/mqtt")).unwrap();
+
+        let request = core::str::from_utf8(&transport.inner.tx).unwrap();
+        assert!(request.starts_with("GET /mqtt HTTP/1.1\r\nHost: broker\r\n"));
+        assert!(request.contains("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n"));
+        assert!(request.contains("Sec-WebSocket-Protocol: mqtt\r\n"));
+        assert!(request.ends_with("\r\n\r\n"));
+        transport.inner.tx.clear();
+
+        transport
+    }
+
+    fn with_response<const N: usize>(frames: &[u8]) -> Vec<u8, N> {
+        let mut rx = Vec::new();
+        rx.extend_from_slice(RESPONSE).unwrap();
+        rx.extend_from_slice(frames).unwrap();
+        rx
+    }
+
+    #[test]
+    fn handshake_rejects_wrong_accept() {
+        let response = b"HTTP/1.1 101 Switching Protocols\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Accept: AAAAAAAAAAAAAAAAAAAAAAAAAAA=\r\n\
+            Sec-WebSocket-Protocol: mqtt\r\n\r\n";
+        let server = Server {
+            rx: response,
+            tx: Vec::new(),
+        };
+
+        let res = block_on(Transport::connect(server, FixedRng, "broker", "/mqtt"));
+        assert!(matches!(res, Err(Error::HandshakeFailed)));
+    }
+
+    #[test]
+    fn handshake_requires_mqtt_subprotocol() {
+        let response = b"HTTP/1.1 101 Switching Protocols\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+        let server = Server {
+            rx: response,
+            tx: Vec::new(),
+        };
+
+        let res = block_on(Transport::connect(server, FixedRng, "broker", "/mqtt"));
+        assert!(matches!(res, Err(Error::HandshakeFailed)));
+    }
+
+    #[test]
+    fn handshake_requires_connection_upgrade() {
+        let handshake = |connection: &'static [u8]| {
+            let mut rx = Vec::<u8, 256>::new();
+            rx.extend_from_slice(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n")
+                .unwrap();
+            rx.extend_from_slice(connection).unwrap();
+            rx.extend_from_slice(
+                b"Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+                Sec-WebSocket-Protocol: mqtt\r\n\r\n",
+            )
+            .unwrap();
+            let server = Server {
+                rx: rx.as_slice(),
+                tx: Vec::new(),
+            };
+
+            block_on(Transport::connect(server, FixedRng, "broker", "/mqtt")).map(|_| ())
+        };
+
+        assert!(matches!(handshake(b""), Err(Error::HandshakeFailed)));
+        assert!(matches!(
+            handshake(b"Connection: keep-alive\r\n"),
+            Err(Error::HandshakeFailed)
+        ));
+        assert!(handshake(b"Connection: keep-alive, upgrade\r\n").is_ok());
+    }
+
+    #[test]
+    fn write_sends_masked_binary_frame() {
+        let rx = with_response::<256>(&[]);
+        let mut transport = connect(&rx);
+
+        block_on(transport.write_all(&[0xC0, 0x00, 0xAA])).unwrap();
+
+        assert_eq!(
+            transport.inner.tx.as_slice(),
+            &[0x82, 0x83, 0x01, 0x02, 0x03, 0x04, 0xC1, 0x02, 0xA9]
+        );
+    }
+
+    #[test]
+    fn read_reassembles_fragments_and_answers_ping() {
+        let frames = [
+            0x02, 0x02, 0x20, 0x02, // binary, not final: first half of CONNACK
+            0x89, 0x01, 0x42, // ping in between fragments
+            0x80, 0x02, 0x00, 0x00, // final continuation
+        ];
+        let rx = with_response::<256>(&frames);
+        let mut transport = connect(&rx);
+
+        let mut buf = [0u8; 4];
+        block_on(transport.read_exact(&mut buf)).unwrap();
+
+        assert_eq!(buf, [0x20, 0x02, 0x00, 0x00]);
+        // pong echoing the payload, masked with 01 02 03 04
+        assert_eq!(
+            transport.inner.tx.as_slice(),
+            &[0x8A, 0x81, 0x01, 0x02, 0x03, 0x04, 0x43]
+        );
+    }
+
+    #[test]
+    fn read_survives_being_dropped_at_every_await() {
+        let frames = [
+            0x02, 0x02, 0x20, 0x02, // binary, not final: first half of CONNACK
+            0x89, 0x03, 0x42, 0x43, 0x44, // ping in between fragments
+            0x80, 0x02, 0x00, 0x00, // final continuation
+        ];
+        let mut transport = Transport {
+            inner: Trickle {
+                server: Server {
+                    rx: &frames,
+                    tx: Vec::new(),
+                },
+                stalled: false,
+            },
+            rng: FixedRng,
+            incoming: Incoming::default(),
+        };
+
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut received = Vec::<u8, 4>::new();
+        while received.len() < 4 {
+            let mut buf = [0u8; 4];
+            let polled = pin!(transport.read(&mut buf)).poll(&mut cx);
+            if let Poll::Ready(n) = polled {
+                received.extend_from_slice(&buf[..n.unwrap()]).unwrap();
+            }
+        }
+
+        assert_eq!(received, [0x20, 0x02, 0x00, 0x00]);
+        assert_eq!(
+            transport.inner.server.tx.as_slice(),
+            &[0x8A, 0x83, 0x01, 0x02, 0x03, 0x04, 0x43, 0x41, 0x47]
+        );
+    }
+
+    #[test]
+    fn read_rejects_misplaced_fragments() {
+        for frames in [
+            [0x80, 0x01, 0x00, 0x00, 0x00].as_slice(), // continuation without a message
+            &[0x02, 0x01, 0x00, 0x82, 0x01, 0x00],     // new message inside another
+            &[0x09, 0x00],                             // fragmented ping
+        ] {
+            let rx = with_response::<256>(frames);
+            let mut transport = connect(&rx);
+
+            let mut buf = [0u8; 4];
+            assert!(matches!(
+                block_on(transport.read_exact(&mut buf)),
+                Err(embedded_io_async::ReadExactError::Other(
+                    Error::InvalidFrame
+                ))
+            ));
+        }
+    }
+
+    #[test]
+    fn read_extended_length_frame() {
+        let mut frames = [0xAB; 4 + 300];
+        frames[..4].copy_from_slice(&[0x82, 126, 0x01, 0x2C]);
+        let rx = with_response::<512>(&frames);
+        let mut transport = connect(&rx);
+
+        let mut buf = [0u8; 300];
+        block_on(transport.read_exact(&mut buf)).unwrap();
+
+        assert_eq!(buf, [0xAB; 300]);
+    }
+
+    #[test]
+    fn read_close_is_eof() {
+        let rx = with_response::<256>(&[0x88, 0x02, 0x03, 0xE8]);
+        let mut transport = connect(&rx);
+
+        let mut buf = [0u8; 4];
+        assert_eq!(block_on(transport.read(&mut buf)).unwrap(), 0);
+        assert_eq!(&transport.inner.tx[..2], &[0x88, 0x82]);
+    }
+
+    #[test]
+    fn read_rejects_text_frame() {
+        let rx = with_response::<256>(&[0x81, 0x01, b'x']);
+        let mut transport = connect(&rx);
+
+        let mut buf = [0u8; 4];
+        assert!(matches!(
+            block_on(transport.read(&mut buf)),
+            Err(Error::InvalidFrame)
+        ));
+    }
+}